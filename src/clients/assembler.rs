@@ -3,9 +3,11 @@ use std::string::String;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
-use wg_2024::packet::{Fragment, Packet, FRAGMENT_DSIZE};
+use wg_2024::packet::{Fragment, Packet};
 use crate::common_data::common::{ChatRequest, ChatResponse, MediaServer, ServerType, TextServer, WebBrowserCommands};
 use crate::common_data::common::MessageChat;
+use crate::common_data::common::SenderRole;
+use crate::common_data::fragmentation::{self, Payload};
 
 pub trait Serialization{
     fn stringify(&self) -> String where Self: Serialize{ //to serialize
@@ -16,37 +18,23 @@ pub trait Serialization{
     }
 }
 pub trait Fragmentation: Serialization{
-    fn fragment_message(&self)-> HashMap<u64, Fragment> where Self: Serialize{
-        let serialized_message = self.stringify(); //serialize message in a string
-        let tot_size = serialized_message.len();
-        let tot_fragment = ((tot_size + FRAGMENT_DSIZE - 1 ) / FRAGMENT_DSIZE )as u64; //amount of fragments needed
-        let mut fragments = HashMap::new();
-
-        for f in 0..tot_fragment{ //f is the fragment index
-            let start = (f as usize) * FRAGMENT_DSIZE; //starting index for the fragment, multiply because we're skipping all data already assigned
-            let end = usize::min(start + FRAGMENT_DSIZE, tot_size); //end index, ensuring that maximum size of fragment is respected
-            let fragment_data = &serialized_message[start..end]; //the fragmented data using indexes
-            let fragment = Fragment::from_string(f, tot_fragment, fragment_data.to_string()); //function from wgc
-            fragments.insert(fragment.fragment_index, fragment); //hashmap with key: fragment index and the corresponding fragment
-        }
+    fn fragment_message(&self, sender: SenderRole)-> HashMap<u64, Fragment> where Self: Payload + Sized{
+        //the message travels wrapped in an envelope, so the server knows its kind without guessing
+        let fragments = fragmentation::fragment_envelope(self, sender).unwrap_or_else(|err| {
+            println!("failed to fragment message: {}", err);
+            Vec::new()
+        });
         fragments
-    }
-    fn reassemble_msg(fragments: &HashMap<u64, Fragment>)-> Result<Self, String> where Self:Sized + DeserializeOwned{
-        let mut ordered_frag: Vec<(&u64, &Fragment)> = fragments.iter().collect();
-        ordered_frag.sort_by_key(|&(k, _)| k);
-
-        let mut reassembled_frag = Vec::new();
-        for (_, frag) in ordered_frag{
-            reassembled_frag.extend_from_slice(&frag.data[..frag.length as usize]);
-        }
-
-        let convert_to_string = String::from_utf8(reassembled_frag).map_err(|e| e.to_string())?;
-        let message = Serialization::from_string(&convert_to_string)?;
-        Ok(message)
+            .into_iter()
+            .map(|fragment| (fragment.fragment_index, fragment)) //hashmap with key: fragment index and the corresponding fragment
+            .collect()
     }
     fn create_packet(fragments: &HashMap<u64, Fragment>, path: Vec<NodeId>, session_id: u64) ->Vec<Packet>{
         let mut res = Vec::new();
-        for (_, fragment) in fragments{
+        // HashMap iteration order changes between runs, always send fragments by index
+        let mut indexes: Vec<&u64> = fragments.keys().collect();
+        indexes.sort();
+        for fragment in indexes.into_iter().map(|index| &fragments[index]){
             let packet = Packet::new_fragment(
                 SourceRoutingHeader::new(path.clone(), 0),
                 session_id,
@@ -61,7 +49,7 @@ pub trait Fragmentation: Serialization{
 
 impl MessageChat{
     pub fn new(content: String, from_id: NodeId, to_id: NodeId) -> MessageChat{
-        MessageChat{ content, from_id, to_id }
+        MessageChat{ content, from_id, to_id, message_id: 0 }
     }
 }
 
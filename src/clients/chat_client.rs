@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use petgraph::algo::dijkstra;
 use petgraph::{Direction};
@@ -7,8 +8,11 @@ use wg_2024::config::{Client};
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{FloodRequest, FloodResponse, Fragment, NackType, NodeType, Packet, PacketType};
 use crate::clients::assembler::{Fragmentation, NodeData};
-use crate::common_data::common::{ChatRequest, MessageChat, CommandChat, ChatResponse, ServerType, ChatClientEvent, ClientType, BackGroundFlood, RequestEvent};
-use crate::common_data::common::ChatClientEvent::{ClientList, ClientType as OtherClientType, IncomingMessage, RegisteredSuccess};
+use crate::clients::retransmission::{Expired, RetransmissionTimers};
+use crate::common_data::fragmentation::{FragmentError, Reassembler};
+use crate::common_data::sessions::SessionTable;
+use crate::common_data::common::{ChatRequest, MessageChat, CommandChat, ChatResponse, ServerType, ChatClientEvent, ClientType, BackGroundFlood, RequestEvent, Message, SenderRole, SessionKey, SessionState, MessageStatus, Receipt, RoomMessage};
+use crate::common_data::common::ChatClientEvent::{ClientList, ClientType as OtherClientType, DeliveryFailed, IncomingMessage, RegisteredSuccess};
 
 pub struct ChatClient {
     pub config: Client,
@@ -20,8 +24,12 @@ pub struct ChatClient {
     pub flood: Vec<FloodResponse> ,//to store all the flood responses found
     pub unique_flood_id: u64,
     pub session_id_packet: u64,
-    pub incoming_fragments: HashMap<(u64, NodeId ), HashMap<u64, Fragment>>,
+    pub incoming_fragments: HashMap<(u64, NodeId ), Reassembler>,
     pub fragments_sent: HashMap<u64, HashMap<u64, Fragment> >, //used for sending the correct fragment if was lost in the process
+    pub timers: RetransmissionTimers, //one timer per fragment waiting for its ack
+    pub sessions: SessionTable, //state of every incoming and outgoing session, so the stuck ones can be dropped
+    pub message_ids: HashMap<(NodeId, NodeId), u64>, //next message id of every (server, destination client) conversation
+    pub unread: HashMap<(NodeId, NodeId), u64>, //(server, sender client) -> last message delivered and not read yet
     pub problematic_nodes: Vec<NodeId>,
     pub chat_servers: Vec<NodeId>,
     pub clients: Vec<NodeId>,
@@ -51,6 +59,10 @@ impl ChatClient {
             session_id_packet: 0,
             incoming_fragments: HashMap::new(),
             fragments_sent: HashMap::new(),
+            timers: RetransmissionTimers::default(),
+            sessions: SessionTable::default(),
+            message_ids: HashMap::new(),
+            unread: HashMap::new(),
             problematic_nodes: Vec::new(),
             chat_servers: Vec::new(),
             clients: vec![id],
@@ -65,6 +77,10 @@ impl ChatClient {
         self.send_type_client();
 
         loop{
+            //wake up in time for the next expired timer, or every second when nothing is in flight
+            let wait = self.timers.next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::from_secs(1));
             select_biased! {
                 recv(self.receiver_msg) -> message =>{
                     if let Ok(message) = message {
@@ -82,9 +98,10 @@ impl ChatClient {
                         self.handle_sim_command(command);
                     }
                 }
-                
+                default(wait) => {}
             }
-
+            self.check_timeouts();
+            self.sweep_sessions();
         }
 
     }
@@ -101,8 +118,14 @@ impl ChatClient {
             CommandChat::RegisterClient(id_server) => {
                 self.register_client(id_server);
             }
+            CommandChat::Unregister(id_server) => {
+                self.send_request(ChatRequest::EndChat(self.config.id), id_server, RequestEvent::Unregister);
+            }
             CommandChat::SendMessage(destination_id, id_server,  content) => {
-                let message_to_send = MessageChat::new(content, self.config.id.clone(), destination_id);
+                let mut message_to_send = MessageChat::new(content, self.config.id.clone(), destination_id);
+                let next_id = self.message_ids.entry((id_server, destination_id)).or_insert(0);
+                message_to_send.message_id = *next_id; //the n-th message of the conversation in the chat window
+                *next_id += 1;
                 self.send_message(message_to_send, id_server);
             }
             CommandChat::SearchChatServers => {
@@ -120,6 +143,31 @@ impl ChatClient {
             CommandChat::PdrChanged(node_id) => {
                 self.reset_data(node_id)
             }
+            CommandChat::ReadMessages(sender_id, id_server) => {
+                if let Some(message_id) = self.unread.remove(&(id_server, sender_id)){
+                    //one receipt is enough, it covers every message up to the last one delivered
+                    self.send_receipt(Receipt{ message_id, from_id: self.config.id, to_id: sender_id, status: MessageStatus::Read }, id_server);
+                }
+            }
+            CommandChat::CreateRoom(id_server, room) => {
+                self.send_request(ChatRequest::CreateRoom(room, self.config.id), id_server, RequestEvent::Room);
+            }
+            CommandChat::JoinRoom(id_server, room) => {
+                self.send_request(ChatRequest::JoinRoom(room, self.config.id), id_server, RequestEvent::Room);
+            }
+            CommandChat::LeaveRoom(id_server, room) => {
+                self.send_request(ChatRequest::LeaveRoom(room, self.config.id), id_server, RequestEvent::Room);
+            }
+            CommandChat::RoomMembers(id_server, room) => {
+                self.send_request(ChatRequest::RoomMembers(room), id_server, RequestEvent::Room);
+            }
+            CommandChat::SendRoomMessage(id_server, room, content) => {
+                let message = RoomMessage{ room, content, from_id: self.config.id };
+                self.send_request(ChatRequest::SendRoomMessage(message), id_server, RequestEvent::Room);
+            }
+            CommandChat::GetHistory(id_server, peer, since) => {
+                self.send_request(ChatRequest::GetHistory(peer, since), id_server, RequestEvent::History);
+            }
         }
     }
     
@@ -180,11 +228,11 @@ impl ChatClient {
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
         
-        let fragments = ChatRequest::fragment_message(&request_to_send);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = ChatRequest::fragment_message(&request_to_send, SenderRole::ChatClient);
 
         match self.find_best_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
                 
@@ -216,11 +264,11 @@ impl ChatClient {
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
 
-        let fragments = ChatRequest::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = ChatRequest::fragment_message(&request, SenderRole::ChatClient);
 
         match self.find_best_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
                 
@@ -241,6 +289,73 @@ impl ChatClient {
         }
 
     }
+    fn send_receipt(&mut self, receipt: Receipt, id_server: NodeId) {
+        if !self.servers.contains(&id_server) {
+            return;
+        }
+
+        let request = ChatRequest::Receipt(receipt.clone());
+
+        let session_id = self.session_id_packet;
+        self.session_id_packet += 1;
+
+        let fragments = ChatRequest::fragment_message(&request, SenderRole::ChatClient);
+
+        match self.find_best_route(&id_server) {
+            Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
+                let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
+                self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
+                for packet in packets_to_send {
+                    if let PacketType::MsgFragment(fragment) = packet.pack_type.clone(){
+                        if let Err(_) = self.event_send.send(ChatClientEvent::InfoRequest(self.config.id, RequestEvent::Receipt(fragment.total_n_fragments), packet.session_id )){
+                            println!("chat client failed to notify SC about the receipt")
+                        }
+                    }
+                    if let Some(next_hop) = route.get(1) {
+                        if let Err(()) = self.send_packet(next_hop, packet){
+                            self.send_receipt(receipt.clone(), id_server);
+                        }
+                    } else { return; }
+                }
+            }
+            Err(_) => { println!("No route found for the server") }
+        }
+    }
+
+    //requests that only need a reply (rooms, history), event tells the SC what kind of request it was
+    fn send_request(&mut self, request: ChatRequest, id_server: NodeId, event: fn(u64) -> RequestEvent) {
+        if !self.servers.contains(&id_server) {
+            return;
+        }
+
+        let session_id = self.session_id_packet;
+        self.session_id_packet += 1;
+
+        let fragments = ChatRequest::fragment_message(&request, SenderRole::ChatClient);
+
+        match self.find_best_route(&id_server) {
+            Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
+                let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
+                self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
+                for packet in packets_to_send {
+                    if let PacketType::MsgFragment(fragment) = packet.pack_type.clone(){
+                        if let Err(_) = self.event_send.send(ChatClientEvent::InfoRequest(self.config.id, event(fragment.total_n_fragments), packet.session_id )){
+                            println!("chat client failed to notify SC about the request")
+                        }
+                    }
+                    if let Some(next_hop) = route.get(1) {
+                        if let Err(()) = self.send_packet(next_hop, packet){
+                            self.send_request(request.clone(), id_server, event);
+                        }
+                    } else { return; }
+                }
+            }
+            Err(_) => { println!("No route found for the server") }
+        }
+    }
+
     fn send_message(&mut self, message: MessageChat, id_server: NodeId) {
         if !self.servers.contains(&id_server) {
             return;
@@ -251,11 +366,11 @@ impl ChatClient {
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
 
-        let fragments = ChatRequest::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = ChatRequest::fragment_message(&request, SenderRole::ChatClient);
 
         match self.find_best_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
                 for packet in packets_to_send {
@@ -279,6 +394,7 @@ impl ChatClient {
     fn handle_fragments(& mut self, packet: Packet){ 
         let src_id = packet.routing_header.hops.first().unwrap();
         let check = (packet.session_id, *src_id);
+        let key = SessionKey::Incoming(*src_id, packet.session_id);
 
         let ack = self.ack(&packet);
         let prev = packet.routing_header.hops[packet.routing_header.hop_index-1];
@@ -287,17 +403,47 @@ impl ChatClient {
         }
 
         if let PacketType::MsgFragment(fragment) = packet.pack_type{
-            if !self.incoming_fragments.contains_key(&check){
-                self.incoming_fragments.insert(check, HashMap::new());
+            match self.sessions.state(key) {
+                Some(SessionState::Open) => self.sessions.touch(key, Instant::now()),
+                Some(_) => { //late duplicate or replay of a session already closed
+                    self.sessions.duplicate();
+                    return;
+                }
+                None => {
+                    if !self.sessions.start(key, fragment.total_n_fragments, Instant::now()) { //too big to ever fit in memory
+                        return;
+                    }
+                }
+            }
+            let inserted = Reassembler::entry(&mut self.incoming_fragments, check, fragment.total_n_fragments)
+                .and_then(|fragments| fragments.insert(&fragment));
+            if let Err(err) = inserted {
+                match err {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    FragmentError::InvalidTotal(_) => self.sessions.close(key, SessionState::Failed, Instant::now()), //never started
+                    _ => {}
+                }
+                println!("Fragment from {} discarded: {}", src_id, err);
+                return;
             }
-            self.incoming_fragments
-                .get_mut(&check)
-                .unwrap()
-                .insert(fragment.fragment_index, fragment.clone());
 
-            if let Some(fragments) = self.incoming_fragments.get_mut(&check){
-                if fragments.len() as u64 == fragment.total_n_fragments {
-                    let incoming_message = ChatResponse::reassemble_msg(&fragments).unwrap();
+            if let Some(fragments) = self.incoming_fragments.get(&check){
+                if fragments.is_complete() {
+                    let incoming_message = match fragments.envelope().map(|envelope| envelope.message) {
+                        Ok(Message::ChatResponse(message)) => message,
+                        Ok(other) => {
+                            println!("Chat client {} can't handle {} messages from {}", self.config.id, other.kind(), src_id);
+                            self.incoming_fragments.remove(&check);
+                            self.sessions.close(key, SessionState::Failed, Instant::now());
+                            return;
+                        }
+                        Err(err) => {
+                            println!("Rejected message from {}: {}", src_id, err);
+                            self.incoming_fragments.remove(&check);
+                            self.sessions.close(key, SessionState::Failed, Instant::now());
+                            return;
+                        }
+                    };
                     match incoming_message {
                         ChatResponse::ServerTypeChat(server_type) => {
                             if server_type == ServerType::CommunicationServer && !self.chat_servers.contains(&src_id) {
@@ -310,9 +456,16 @@ impl ChatClient {
                         },
                         ChatResponse::EndChat(response) =>{
                             if response {
-                                println!("chat ended");
+                                if let Err(_) = self.event_send.send(ChatClientEvent::Unregistered((self.config.id, src_id.clone()))){
+                                    println!("could not send to simulation control");
+                                }
                             }else { println!("error in the request: end the chat") }
                         },
+                        ChatResponse::Presence(client, joined) =>{
+                            if let Err(_) = self.event_send.send(ChatClientEvent::Presence((self.config.id, src_id.clone()), client, joined)){
+                                println!("failed to send the presence update to simulation control");
+                            }
+                        },
 
                         ChatResponse::RegisterClient(response) => {
                             if response{
@@ -334,21 +487,65 @@ impl ChatClient {
                         },
                         
                         ChatResponse::ForwardMessage(message_chat) =>{
-                            let sender = message_chat.from_id;
-                            if let Err(str) = self.event_send.send(IncomingMessage((self.config.id.clone(), src_id.clone(), sender), message_chat.content)){
-                                println!("failed to send message to simulation control: {}", str);
+                            self.deliver_message(message_chat, *src_id, *src_id);
+                        }
+                        ChatResponse::ForwardRelayedMessage(message_chat, origin) =>{
+                            self.deliver_message(message_chat, *src_id, origin);
+                        }
+                        ChatResponse::ForwardReceipt(receipt) =>{
+                            //the id comes from the other client: only the messages sent in this conversation can have a receipt
+                            let sent = self.message_ids.get(&(*src_id, receipt.from_id)).copied().unwrap_or(0);
+                            if receipt.message_id >= sent {
+                                println!("client {} got a receipt for message {} to {}, but sent only {}", self.config.id, receipt.message_id, receipt.from_id, sent);
+                            } else if let Err(_) = self.event_send.send(ChatClientEvent::MessageStatus((self.config.id, src_id.clone(), receipt.from_id), receipt.message_id, receipt.status)){
+                                println!("failed to send the receipt to simulation control");
+                            }
+                        }
+                        ChatResponse::Room(room, result) =>{
+                            if let Err(_) = self.event_send.send(ChatClientEvent::Room((self.config.id, src_id.clone()), room, result)){
+                                println!("failed to send the room outcome to simulation control");
+                            }
+                        }
+                        ChatResponse::RoomMembers(room, members) =>{
+                            if let Err(_) = self.event_send.send(ChatClientEvent::RoomMembers((self.config.id, src_id.clone()), room, members)){
+                                println!("failed to send the room members to simulation control");
+                            }
+                        }
+                        ChatResponse::History(peer, result) =>{
+                            if let Err(_) = self.event_send.send(ChatClientEvent::History((self.config.id, src_id.clone(), peer), result)){
+                                println!("failed to send the history to simulation control");
+                            }
+                        }
+                        ChatResponse::ForwardRoomMessage(message) =>{
+                            if let Err(_) = self.event_send.send(ChatClientEvent::IncomingRoomMessage((self.config.id, src_id.clone(), message.from_id), message.room, message.content)){
+                                println!("failed to send the room message to simulation control");
                             }
                         }
                         _ =>{}
                     }
 
                     self.incoming_fragments.remove(&check); //removes fragments from tracking
+                    self.sessions.close(key, SessionState::Complete, Instant::now());
                 }
             }
         }
     }
 
+    //a message forwarded by id_server, the sender is registered on origin (id_server itself unless a peer relayed it)
+    //receipts go back through id_server, which relays them to origin if needed
+    fn deliver_message(&mut self, message_chat: MessageChat, id_server: NodeId, origin: NodeId){
+        let sender = message_chat.from_id;
+        let message_id = message_chat.message_id;
+        if let Err(str) = self.event_send.send(IncomingMessage((self.config.id.clone(), id_server, sender), message_chat.content, origin)){
+            println!("failed to send message to simulation control: {}", str);
+        }
+        let unread = self.unread.entry((id_server, sender)).or_insert(message_id);
+        *unread = (*unread).max(message_id);
+        self.send_receipt(Receipt{ message_id, from_id: self.config.id, to_id: sender, status: MessageStatus::Delivered }, id_server);
+    }
+
     fn handle_nacks(& mut self, packet: Packet){
+        self.sessions.touch(SessionKey::Outgoing(packet.session_id), Instant::now());
         if let PacketType::Nack(nack) = packet.clone().pack_type{
             match nack.nack_type{
                 NackType::ErrorInRouting(id) => {
@@ -360,14 +557,14 @@ impl ChatClient {
                     let src = packet.routing_header.hops[0];
                     self.topology.remove_edge(src, dest);
                     
-                    self.resend_fragment_lost(packet);
+                    self.resend_fragment(packet.session_id, nack.fragment_index);
                 }
                 NackType::Dropped => {
                     let failing_node = packet.routing_header.hops[0];
                     let data = self.node_data.get_mut(&failing_node).unwrap();
                     data.dropped += 1;
                     
-                    self.resend_fragment_lost(packet);
+                    self.resend_fragment(packet.session_id, nack.fragment_index);
                 }
                 _ => {}
 
@@ -376,48 +573,64 @@ impl ChatClient {
     }
     
    
-    fn resend_fragment_lost(& mut self, packet: Packet){
-        if let PacketType::Nack(nack) = packet.clone().pack_type{
-            let fragments_session = if let Some(fragments_session) = self.fragments_sent.get(&packet.session_id){
-                fragments_session
-            }else { 
-                println!("no fragments found for this session id");
-                return;
-            };
-            
-            let fragment_lost = if let Some(fragment_lost) = fragments_session.get(&nack.fragment_index){
-                fragment_lost.clone()
-            }else {
+    fn resend_fragment(& mut self, session_id: u64, fragment_index: u64){
+        let fragments_session = if let Some(fragments_session) = self.fragments_sent.get(&session_id){
+            fragments_session
+        }else { 
+            println!("no fragments found for this session id");
+            return;
+        };
+        
+        let fragment_lost = if let Some(fragment_lost) = fragments_session.get(&fragment_index){
+            fragment_lost.clone()
+        }else {
+            return;
+        };
+
+        let destination_id = match self.packet_sent.get(&session_id){
+            Some((destination_id, _)) => destination_id.clone(),
+            None => {
                 return;
-            };
+            }
+        };
 
-            let destination_id = match self.packet_sent.get(&packet.session_id){
-                Some((destination_id, _)) => destination_id.clone(),
-                None => {
-                    return;
-                }
-            };
+        match self.find_best_route(&destination_id) {
+            Ok(route) => {
+                //println!("route re-computed: {:?}", route);
+                let packet_to_send = Packet::new_fragment(
+                    SourceRoutingHeader::new(route.clone(), 0),
+                    session_id,
+                    fragment_lost
+                );
+
+                if let Some(next_hop) = route.get(1){
+                    if let Err(()) = self.send_packet(next_hop, packet_to_send){
+                        self.resend_fragment(session_id, fragment_index);
+                    }
+                }else { return; }
 
-            match self.find_best_route(&destination_id) {
-                Ok(route) => {
-                    //println!("route re-computed: {:?}", route);
-                    let packet_to_send = Packet::new_fragment(
-                        SourceRoutingHeader::new(route.clone(), 0),
-                        packet.session_id,
-                        fragment_lost
-                    );
+            }
+            Err(_) => println!("no route found to resend packet"),
+        }
+    }
 
-                    if let Some(next_hop) = route.get(1){
-                        if let Err(()) = self.send_packet(next_hop, packet_to_send){
-                            self.resend_fragment_lost(packet);
+    //resends the fragments whose ack didn't come back in time, or gives up their session after too many retries
+    fn check_timeouts(&mut self){
+        for expired in self.timers.expired(Instant::now()){
+            match expired {
+                Expired::Resend(session_id, fragment_index) => {
+                    self.resend_fragment(session_id, fragment_index);
+                }
+                Expired::GaveUp(session_id) => {
+                    self.sessions.close(SessionKey::Outgoing(session_id), SessionState::Failed, Instant::now());
+                    self.fragments_sent.remove(&session_id);
+                    if let Some((destination_id, _)) = self.packet_sent.remove(&session_id){
+                        if let Err(_) = self.event_send.send(DeliveryFailed(self.config.id, destination_id, session_id)){
+                            println!("chat client failed to notify SC about a failed delivery")
                         }
-                    }else { return; }
-
+                    }
                 }
-                Err(_) => println!("no route found to resend packet"),
             }
-            
-            
         }
     }
 
@@ -425,12 +638,52 @@ impl ChatClient {
         if let PacketType::Ack(ack) = packet.pack_type{
             self.problematic_nodes.clear();
             
-            self.fragments_sent.retain(|index, _| *index != ack.fragment_index); //this filters the hashmap, removing the ones with that index
+            //only the acked fragment of that session is done, the others may still need a resend
+            if let Some(fragments_session) = self.fragments_sent.get_mut(&packet.session_id){
+                fragments_session.remove(&ack.fragment_index);
+            }
+            if self.timers.ack(packet.session_id, ack.fragment_index){
+                self.fragments_sent.remove(&packet.session_id);
+                self.packet_sent.remove(&packet.session_id);
+                self.sessions.close(SessionKey::Outgoing(packet.session_id), SessionState::Complete, Instant::now());
+            }else {
+                self.sessions.touch(SessionKey::Outgoing(packet.session_id), Instant::now());
+            }
             let data = self.node_data.get_mut(&packet.routing_header.hops.iter().rev().nth(1).unwrap()).unwrap();
             data.forwarded += 1;
         }
     }
 
+    //keeps the fragments of a request until they are all acked, once a route was found: a request that can't leave would only expire
+    fn store_sent(&mut self, session_id: u64, fragments: HashMap<u64, Fragment>){
+        self.sessions.start(SessionKey::Outgoing(session_id), fragments.len() as u64, Instant::now());
+        self.fragments_sent.insert(session_id, fragments);
+    }
+
+    //frees the buffers of the sessions failed or expired and reports them, with the duplicates discarded, to the SC
+    fn sweep_sessions(&mut self){
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(src_id, session_id) => {
+                    self.incoming_fragments.remove(&(session_id, src_id));
+                }
+                SessionKey::Outgoing(session_id) => {
+                    self.fragments_sent.remove(&session_id);
+                    self.packet_sent.remove(&session_id);
+                    self.timers.forget(session_id);
+                }
+            }
+            if let Err(_) = self.event_send.send(ChatClientEvent::StuckSession(self.config.id, key, state)){
+                println!("chat client failed to notify SC about a stuck session")
+            }
+        });
+        if let Some(duplicates) = duplicates{
+            if let Err(_) = self.event_send.send(ChatClientEvent::Duplicates(self.config.id, duplicates)){
+                println!("chat client failed to notify SC about duplicated fragments")
+            }
+        }
+    }
+
     fn initiate_flooding(&mut self) { //this sends a flood request to its immediate neighbors
         let flood_id = self.unique_flood_id;
         self.unique_flood_id += 1;
@@ -593,6 +846,12 @@ impl ChatClient {
             if let Err(err) = sender.send(packet.clone()){
                 println!("Error sending command to SC : {}", err);
             }
+            //every fragment of our own requests waits for its ack
+            if let PacketType::MsgFragment(fragment) = &packet.pack_type{
+                if self.fragments_sent.contains_key(&packet.session_id){
+                    self.timers.start(packet.session_id, fragment.fragment_index, Instant::now());
+                }
+            }
             Ok(())
         }else {
             self.topology.remove_edge(self.config.id, *destination_id);
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use crate::common_data::common::Validator;
+
+/// A saved copy checked with its server less than this ago is served without asking again
+pub const FRESH_FOR: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Entry {
+    path: String,         //where the copy was saved
+    validator: Validator, //of the copy, sent to the server to know if it changed
+    checked: Instant,     //last time the server sent or confirmed it
+}
+
+/// What the web browser does with a request for a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Fresh(String),    //path of the saved copy, served as it is
+    Stale(Validator), //the server is asked to send the file only if it changed
+    Missing,          //never saved, or the copy on disk is gone or was overwritten
+}
+
+/// Files saved by a web browser, keyed by (server, file id)
+#[derive(Debug, Clone)]
+pub struct ContentCache {
+    entries: HashMap<(NodeId, String), Entry>,
+    fresh_for: Duration,
+}
+
+impl Default for ContentCache {
+    fn default() -> Self {
+        ContentCache::new(FRESH_FOR)
+    }
+}
+
+impl ContentCache {
+    pub fn new(fresh_for: Duration) -> Self {
+        ContentCache { entries: HashMap::new(), fresh_for }
+    }
+
+    pub fn lookup(&mut self, server: NodeId, id: &str, now: Instant) -> Lookup {
+        let key = (server, id.to_string());
+        let Some(entry) = self.entries.get(&key) else {
+            return Lookup::Missing;
+        };
+        //every browser saves in the same folder, the copy may have been replaced by another file with the same name
+        let intact = fs::read(&entry.path).map(|bytes| Validator::of(&bytes) == entry.validator).unwrap_or(false);
+        if !intact {
+            self.entries.remove(&key);
+            return Lookup::Missing;
+        }
+        if now.saturating_duration_since(entry.checked) < self.fresh_for {
+            Lookup::Fresh(entry.path.clone())
+        } else {
+            Lookup::Stale(entry.validator)
+        }
+    }
+
+    /// A file was downloaded and saved
+    pub fn store(&mut self, server: NodeId, id: String, path: String, validator: Validator, now: Instant) {
+        self.entries.insert((server, id), Entry { path, validator, checked: now });
+    }
+
+    /// The server answered that the copy didn't change, returns where it is saved
+    pub fn confirm(&mut self, server: NodeId, id: &str, now: Instant) -> Option<String> {
+        let entry = self.entries.get_mut(&(server, id.to_string()))?;
+        entry.checked = now;
+        Some(entry.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use super::{ContentCache, Lookup};
+    use crate::common_data::common::Validator;
+
+    const SERVER: u8 = 20;
+    const OTHER_SERVER: u8 = 21;
+    const FRESH_FOR: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn fresh_copies_are_served_stale_ones_revalidated() {
+        let path = std::env::temp_dir().join(format!("rolling_drones_cache_{}.txt", std::process::id()));
+        let path_name = path.to_string_lossy().to_string();
+        fs::write(&path, b"Nel mezzo del cammin").unwrap();
+        let validator = Validator::of(b"Nel mezzo del cammin");
+
+        let mut cache = ContentCache::new(FRESH_FOR);
+        let start = Instant::now();
+        assert_eq!(cache.lookup(SERVER, "inferno.txt", start), Lookup::Missing);
+
+        cache.store(SERVER, "inferno.txt".to_string(), path_name.clone(), validator, start);
+        assert_eq!(cache.lookup(SERVER, "inferno.txt", start + FRESH_FOR / 2), Lookup::Fresh(path_name.clone()));
+        assert_eq!(cache.lookup(OTHER_SERVER, "inferno.txt", start), Lookup::Missing);
+
+        // too old: the server is asked, and its NotModified makes the copy fresh again
+        assert_eq!(cache.lookup(SERVER, "inferno.txt", start + FRESH_FOR), Lookup::Stale(validator));
+        assert_eq!(cache.confirm(SERVER, "inferno.txt", start + FRESH_FOR), Some(path_name.clone()));
+        assert_eq!(cache.lookup(SERVER, "inferno.txt", start + FRESH_FOR), Lookup::Fresh(path_name.clone()));
+
+        // a copy overwritten on disk is downloaded again
+        fs::write(&path, b"mi ritrovai per una selva oscura").unwrap();
+        assert_eq!(cache.lookup(SERVER, "inferno.txt", start + FRESH_FOR), Lookup::Missing);
+        assert_eq!(cache.confirm(SERVER, "inferno.txt", start + FRESH_FOR), None);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn validator_tells_files_apart() {
+        assert_eq!(Validator::of(b"mars"), Validator::of(b"mars"));
+        assert_ne!(Validator::of(b"mars"), Validator::of(b"marz"));
+        assert_eq!(Validator::of(b"").size, 0);
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use crate::common_data::common::{ByteRange, Validator};
+
+/// Bytes asked with every piece of a download
+pub const CHUNK_SIZE: u64 = 128 * 1024;
+/// A download that got nothing from its server for this long asks its current piece again
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(15);
+/// Times in a row the same piece is asked again before the download is given up
+pub const MAX_RESUMES: u32 = 5;
+
+#[derive(Debug, Clone)]
+struct Download {
+    pieces: Vec<Vec<u8>>,          //pieces received so far, in order
+    received: u64,                 //bytes in the pieces
+    validator: Option<Validator>,  //of the whole file, known from the first piece
+    last_progress: Instant,
+    resumes: u32,
+}
+
+/// What to do once a piece arrived
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    Next(ByteRange),    //ask the next piece, or the first one again if the file changed meanwhile
+    Done(Vec<Vec<u8>>), //every piece arrived, in order
+    Ignored,            //duplicated, out of order or never asked
+}
+
+/// Download that got nothing for too long
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stalled {
+    Resume(NodeId, String, ByteRange), //its current piece is asked again
+    Broken(NodeId, String),            //given up
+}
+
+/// Files a web browser downloads piece by piece, keyed by (server, file id):
+/// when a transfer breaks (a drone crashed, a route went down) only the pieces still missing are asked again
+#[derive(Debug, Clone)]
+pub struct Downloads {
+    downloads: HashMap<(NodeId, String), Download>,
+    stall_timeout: Duration,
+    max_resumes: u32,
+}
+
+impl Default for Downloads {
+    fn default() -> Self {
+        Downloads::new(STALL_TIMEOUT, MAX_RESUMES)
+    }
+}
+
+impl Downloads {
+    pub fn new(stall_timeout: Duration, max_resumes: u32) -> Self {
+        Downloads { downloads: HashMap::new(), stall_timeout, max_resumes }
+    }
+
+    /// Piece to ask first, after the last one received if the file was already being downloaded
+    pub fn start(&mut self, server: NodeId, id: &str, now: Instant) -> ByteRange {
+        let download = self.downloads.entry((server, id.to_string())).or_insert_with(|| Download {
+            pieces: Vec::new(),
+            received: 0,
+            validator: None,
+            last_progress: now,
+            resumes: 0,
+        });
+        download.last_progress = now;
+        next_range(download.received)
+    }
+
+    pub fn received(&mut self, server: NodeId, id: &str, range: ByteRange, validator: Validator, bytes: Vec<u8>, now: Instant) -> Progress {
+        let key = (server, id.to_string());
+        let Some(download) = self.downloads.get_mut(&key) else {
+            return Progress::Ignored;
+        };
+        download.last_progress = now;
+        if download.validator.is_some_and(|known| known != validator) { //the pieces so far belong to an older version
+            download.pieces.clear();
+            download.received = 0;
+            download.validator = None;
+            return Progress::Next(next_range(0));
+        }
+        if range.offset != download.received || range.length != bytes.len() as u64 {
+            return Progress::Ignored;
+        }
+
+        download.validator = Some(validator);
+        download.received += range.length;
+        download.pieces.push(bytes);
+        download.resumes = 0;
+        if download.received < validator.size {
+            return Progress::Next(next_range(download.received));
+        }
+        match self.downloads.remove(&key) {
+            Some(download) => Progress::Done(download.pieces),
+            None => Progress::Ignored,
+        }
+    }
+
+    /// Something arrived from the server, its downloads are still going on
+    pub fn touch(&mut self, server: NodeId, now: Instant) {
+        for ((from, _), download) in self.downloads.iter_mut() {
+            if *from == server {
+                download.last_progress = now;
+            }
+        }
+    }
+
+    /// Downloads that got nothing for too long, the broken ones are forgotten
+    pub fn stalled(&mut self, now: Instant) -> Vec<Stalled> {
+        let mut stalled = Vec::new();
+        for ((server, id), download) in self.downloads.iter_mut() {
+            if now.saturating_duration_since(download.last_progress) < self.stall_timeout {
+                continue;
+            }
+            if download.resumes >= self.max_resumes {
+                stalled.push(Stalled::Broken(*server, id.clone()));
+            } else {
+                download.resumes += 1;
+                download.last_progress = now;
+                stalled.push(Stalled::Resume(*server, id.clone(), next_range(download.received)));
+            }
+        }
+        for broken in &stalled {
+            if let Stalled::Broken(server, id) = broken {
+                self.downloads.remove(&(*server, id.clone()));
+            }
+        }
+        stalled
+    }
+}
+
+fn next_range(offset: u64) -> ByteRange {
+    ByteRange { offset, length: CHUNK_SIZE }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::{Downloads, Progress, Stalled, CHUNK_SIZE};
+    use crate::common_data::common::{ByteRange, Validator};
+
+    const SERVER: u8 = 20;
+    const STALL: Duration = Duration::from_millis(100);
+
+    /// Piece of `bytes` as a server would send it
+    fn piece(bytes: &[u8], range: ByteRange) -> (ByteRange, Vec<u8>) {
+        let start = (range.offset as usize).min(bytes.len());
+        let end = (start + range.length as usize).min(bytes.len());
+        (ByteRange { offset: start as u64, length: (end - start) as u64 }, bytes[start..end].to_vec())
+    }
+
+    #[test]
+    fn pieces_are_asked_in_order_and_stitched() {
+        let file: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let validator = Validator::of(&file);
+        let mut downloads = Downloads::new(STALL, 2);
+        let now = Instant::now();
+
+        let mut range = downloads.start(SERVER, "mars.png", now);
+        assert_eq!(range, ByteRange { offset: 0, length: CHUNK_SIZE });
+        let stitched = loop {
+            let (sent, bytes) = piece(&file, range);
+            match downloads.received(SERVER, "mars.png", sent, validator, bytes, now) {
+                Progress::Next(next) => range = next,
+                Progress::Done(pieces) => break pieces.concat(),
+                Progress::Ignored => panic!("piece {:?} ignored", sent),
+            }
+        };
+        assert_eq!(stitched, file);
+
+        // a late duplicate of a finished download is dropped
+        let (sent, bytes) = piece(&file, ByteRange { offset: 0, length: CHUNK_SIZE });
+        assert_eq!(downloads.received(SERVER, "mars.png", sent, validator, bytes, now), Progress::Ignored);
+    }
+
+    #[test]
+    fn stalled_downloads_resume_from_the_last_piece() {
+        let file = vec![7u8; (CHUNK_SIZE * 3) as usize];
+        let validator = Validator::of(&file);
+        let mut downloads = Downloads::new(STALL, 1);
+        let start = Instant::now();
+
+        let first = downloads.start(SERVER, "earth.png", start);
+        let (sent, bytes) = piece(&file, first);
+        let second = match downloads.received(SERVER, "earth.png", sent, validator, bytes, start) {
+            Progress::Next(next) => next,
+            other => panic!("expected the second piece, got {:?}", other),
+        };
+
+        // out of order pieces are not stitched
+        let (sent, bytes) = piece(&file, ByteRange { offset: CHUNK_SIZE * 2, length: CHUNK_SIZE });
+        assert_eq!(downloads.received(SERVER, "earth.png", sent, validator, bytes, start), Progress::Ignored);
+
+        downloads.touch(SERVER, start + STALL / 2); //fragments still arriving
+        assert!(downloads.stalled(start + STALL).is_empty());
+        assert_eq!(downloads.stalled(start + STALL * 2), vec![Stalled::Resume(SERVER, "earth.png".to_string(), second)]);
+        assert_eq!(downloads.stalled(start + STALL * 3), vec![Stalled::Broken(SERVER, "earth.png".to_string())]);
+        assert!(downloads.stalled(start + STALL * 4).is_empty());
+    }
+
+    #[test]
+    fn a_changed_file_is_downloaded_again() {
+        let old = vec![1u8; (CHUNK_SIZE * 2) as usize];
+        let new = vec![2u8; (CHUNK_SIZE * 2) as usize];
+        let mut downloads = Downloads::default();
+        let now = Instant::now();
+
+        let first = downloads.start(SERVER, "divina_commedia.txt", now);
+        let (sent, bytes) = piece(&old, first);
+        let second = match downloads.received(SERVER, "divina_commedia.txt", sent, Validator::of(&old), bytes, now) {
+            Progress::Next(next) => next,
+            other => panic!("expected the second piece, got {:?}", other),
+        };
+
+        // the server changed the file between two pieces: the old ones are thrown away
+        let (sent, bytes) = piece(&new, second);
+        assert_eq!(downloads.received(SERVER, "divina_commedia.txt", sent, Validator::of(&new), bytes, now), Progress::Next(first));
+        assert_eq!(downloads.start(SERVER, "divina_commedia.txt", now), first);
+    }
+}
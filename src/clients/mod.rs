@@ -1,3 +1,6 @@
 pub mod web_browser;
 pub mod chat_client;
 pub mod assembler;
+pub mod retransmission;
+pub mod content_cache;
+pub mod downloads;
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a fragment waits for its Ack before being sent again
+pub const ACK_TIMEOUT: Duration = Duration::from_millis(400);
+/// Resends of a single fragment before the whole session is given up
+pub const MAX_RETRIES: u32 = 8;
+
+/// What to do with a fragment whose timer expired
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expired {
+    Resend(u64, u64), //session id, fragment index
+    GaveUp(u64),      //session id, every timer of the session was dropped
+}
+
+#[derive(Debug, Clone)]
+struct Timer {
+    deadline: Instant,
+    retries: u32,
+}
+
+/// Selective repeat: every fragment sent and not yet acked has its own timer,
+/// so a fragment (or its Ack, or its Nack) lost silently is sent again instead of hanging the session
+#[derive(Debug, Clone)]
+pub struct RetransmissionTimers {
+    timers: HashMap<(u64, u64), Timer>, //key (session id, fragment index)
+    timeout: Duration,
+    max_retries: u32,
+}
+
+impl Default for RetransmissionTimers {
+    fn default() -> Self {
+        RetransmissionTimers::new(ACK_TIMEOUT, MAX_RETRIES)
+    }
+}
+
+impl RetransmissionTimers {
+    pub fn new(timeout: Duration, max_retries: u32) -> Self {
+        RetransmissionTimers { timers: HashMap::new(), timeout, max_retries }
+    }
+
+    /// (Re)starts the timer of a fragment that just left, keeping the retries already spent on it
+    pub fn start(&mut self, session_id: u64, fragment_index: u64, now: Instant) {
+        let deadline = now + self.timeout;
+        self.timers.entry((session_id, fragment_index))
+            .and_modify(|timer| timer.deadline = deadline)
+            .or_insert(Timer { deadline, retries: 0 });
+    }
+
+    /// Stops the timer of an acked fragment, returns true once the whole session was acked
+    pub fn ack(&mut self, session_id: u64, fragment_index: u64) -> bool {
+        self.timers.remove(&(session_id, fragment_index));
+        !self.timers.keys().any(|(session, _)| *session == session_id)
+    }
+
+    /// Stops every timer of a session dropped for other reasons
+    pub fn forget(&mut self, session_id: u64) {
+        self.timers.retain(|(session, _), _| *session != session_id);
+    }
+
+    /// When the next timer expires, None if nothing is waiting for an Ack
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.values().map(|timer| timer.deadline).min()
+    }
+
+    /// Collects the expired timers, in session and fragment order
+    /// A fragment past max_retries gives up its whole session, reported once
+    pub fn expired(&mut self, now: Instant) -> Vec<Expired> {
+        let mut keys: Vec<(u64, u64)> = self.timers.iter()
+            .filter(|(_, timer)| timer.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort();
+
+        let mut expired = Vec::new();
+        for (session_id, fragment_index) in keys {
+            let Some(timer) = self.timers.get_mut(&(session_id, fragment_index)) else {
+                continue; //session already given up by a previous fragment
+            };
+            if timer.retries >= self.max_retries {
+                self.timers.retain(|(session, _), _| *session != session_id);
+                expired.push(Expired::GaveUp(session_id));
+            } else {
+                timer.retries += 1;
+                timer.deadline = now + self.timeout;
+                expired.push(Expired::Resend(session_id, fragment_index));
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::{Expired, RetransmissionTimers};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn resends_only_the_fragments_not_acked() {
+        let mut timers = RetransmissionTimers::new(TIMEOUT, 3);
+        let start = Instant::now();
+        for index in 0..3 {
+            timers.start(7, index, start);
+        }
+        assert!(!timers.ack(7, 1));
+        assert!(timers.expired(start + TIMEOUT / 2).is_empty());
+
+        let expired = timers.expired(start + TIMEOUT);
+        assert_eq!(expired, vec![Expired::Resend(7, 0), Expired::Resend(7, 2)]);
+        assert_eq!(timers.next_deadline(), Some(start + 2 * TIMEOUT));
+
+        assert!(!timers.ack(7, 0));
+        assert!(timers.ack(7, 2));
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn gives_up_the_session_after_max_retries() {
+        let mut timers = RetransmissionTimers::new(TIMEOUT, 2);
+        let mut now = Instant::now();
+        timers.start(1, 0, now);
+        timers.start(1, 1, now);
+        timers.start(2, 0, now);
+        timers.ack(2, 0);
+
+        for _ in 0..2 {
+            now += TIMEOUT;
+            assert_eq!(timers.expired(now), vec![Expired::Resend(1, 0), Expired::Resend(1, 1)]);
+        }
+        now += TIMEOUT;
+        // reported once, even though both fragments ran out of retries
+        assert_eq!(timers.expired(now), vec![Expired::GaveUp(1)]);
+        assert_eq!(timers.next_deadline(), None);
+    }
+}
@@ -1,7 +1,7 @@
-use crate::common_data::common::{BackGroundFlood, ContentRequest};
+use crate::common_data::common::{BackGroundFlood, ContentRequest, SessionKey, SessionState};
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use base64::Engine;
+use std::time::{Duration, Instant};
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use petgraph::algo::dijkstra;
 use petgraph::Direction;
@@ -9,10 +9,21 @@ use wg_2024::config::Client;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{FloodRequest, FloodResponse, Fragment, NackType, NodeType, Packet, PacketType};
 use crate::clients::assembler::{Fragmentation, NodeData};
-use crate::common_data::common::{ChatRequest, ContentCommands, FileMetaData, MediaId, MediaServer, ServerType, TextServer, WebBrowserCommands, WebBrowserEvents};
-use base64::engine::general_purpose::STANDARD as BASE64;
+use crate::clients::content_cache::{ContentCache, Lookup};
+use crate::clients::downloads::{Downloads, Progress, Stalled};
+use crate::clients::retransmission::{Expired, RetransmissionTimers};
+use crate::common_data::fragmentation::{FragmentError, Reassembler};
+use crate::common_data::hypertext::{Document, Page};
+use crate::common_data::sessions::SessionTable;
+use crate::common_data::common::{ByteRange, ChatRequest, ContentCommands, FileKind, FileMetaData, MediaId, MediaServer, Message, SearchQuery, SenderRole, ServerType, TextServer, Validator, WebBrowserCommands, WebBrowserEvents};
 use petgraph::prelude::UnGraphMap;
 
+/// A fetch waiting for a file list or a media position is given up after this long
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// A page reports the medias still missing after this long as failed: longer than a fetch followed
+/// by a download that stopped, so it only catches the requests that got lost without failing
+pub const PAGE_TIMEOUT: Duration = Duration::from_secs(120);
+
 pub struct WebBrowser {
     pub config: Client, //id and direct neighbours
     pub receiver_msg: Receiver<Packet>, 
@@ -23,8 +34,10 @@ pub struct WebBrowser {
     pub flood: Vec<FloodResponse> ,//to store all the flood responses found
     pub unique_flood_id: u64, 
     pub session_id_packet: u64,
-    pub incoming_fragments: HashMap<(u64, NodeId ), HashMap<u64, Fragment>>, //used to save all the fragments from same sender with same session id, used in handle fragments (session id, source id), fragment index + fragment
+    pub incoming_fragments: HashMap<(u64, NodeId ), Reassembler>, //used to save all the fragments from same sender with same session id, used in handle fragments (session id, source id)
     pub fragments_sent: HashMap<u64, HashMap<u64, Fragment>>, //used for re-sending the correct fragment if one was lost in the process, key session id packet, key 2 fragment index
+    pub timers: RetransmissionTimers, //one timer per fragment waiting for its ack, so silent losses are resent too
+    pub sessions: SessionTable, //state of every incoming and outgoing session, the stuck ones are dropped and reported
     pub problematic_nodes: Vec<NodeId>, //used when receiving an error in routing, to avoid the node in a next path
     pub send_event: Sender<WebBrowserEvents>, //to send information to the simulation control
     pub media_servers: Vec<NodeId>,
@@ -33,7 +46,15 @@ pub struct WebBrowser {
     pub packet_sent: HashMap<u64, (NodeId, Vec<Packet>)>, //the key will be the session id of the packets sent, node id of the destination and all the packets to send
     pub topology_graph: UnGraphMap<NodeId, u32>, //save graph
     pub node_data: HashMap<NodeId, NodeData>, //used in the case of the Dropped error, for every node appearing in the graph
-    pub rcv_flood: Receiver<BackGroundFlood> 
+    pub rcv_flood: Receiver<BackGroundFlood>,
+    pub file_lists: HashMap<NodeId, Vec<String>>, //last list of files sent by every text server, to know who to ask for a file
+    pub media_locations: HashMap<MediaId, NodeId>, //server holding every media already located, a new fetch skips the position request
+    pub waiting_list: HashSet<String>, //fetched files not listed by any text server yet
+    pub waiting_position: HashSet<MediaId>, //fetched medias waiting for their position
+    pub fetch_deadlines: HashMap<String, Instant>, //when the fetches waiting for a list or a position fail
+    pub pending_pages: Vec<(Page, Instant)>, //hypertext documents waiting for their medias, until their deadline
+    pub cache: ContentCache, //files already saved, asked again only if they changed on the server
+    pub downloads: Downloads, //files arriving piece by piece, a broken transfer goes on from the last piece received
 }
 
 impl WebBrowser {
@@ -57,6 +78,8 @@ impl WebBrowser {
             session_id_packet: 0,
             incoming_fragments: HashMap::new(),
             fragments_sent: HashMap::new(),
+            timers: RetransmissionTimers::default(),
+            sessions: SessionTable::default(),
             problematic_nodes: Vec::new(),
             send_event,
             media_servers: Vec::new(),
@@ -65,11 +88,23 @@ impl WebBrowser {
             packet_sent: HashMap::new(),
             topology_graph: UnGraphMap::new(),
             node_data: HashMap::new(),
-            rcv_flood
+            rcv_flood,
+            file_lists: HashMap::new(),
+            media_locations: HashMap::new(),
+            waiting_list: HashSet::new(),
+            waiting_position: HashSet::new(),
+            fetch_deadlines: HashMap::new(),
+            pending_pages: Vec::new(),
+            cache: ContentCache::default(),
+            downloads: Downloads::default(),
         }
     }
     pub fn run(& mut self) {
         loop{
+            //wake up in time for the next expired timer, or every second when nothing is in flight
+            let wait = self.timers.next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::from_secs(1));
             select_biased! {
                  recv(self.receiver_msg) -> message =>{
                     if let Ok(message) = message {
@@ -86,8 +121,13 @@ impl WebBrowser {
                         self.handle_commands(command);
                     }
                 }
-               
+                default(wait) => {}
             }
+            self.check_timeouts();
+            self.check_fetches();
+            self.check_pages();
+            self.check_downloads();
+            self.sweep_sessions();
         }
     }
 
@@ -105,6 +145,12 @@ impl WebBrowser {
             ContentCommands::GetText(id_server, text_id) => {
                 self.get_text(id_server, text_id);
             }
+            ContentCommands::Fetch(path) => {
+                self.fetch(path);
+            }
+            ContentCommands::Search(id_server, query) => {
+                self.search(id_server, query);
+            }
             ContentCommands::SearchTypeServers => {
                 self.search_type_servers();
             }
@@ -179,11 +225,11 @@ impl WebBrowser {
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
         
-        let fragments = WebBrowserCommands::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
 
         match self.find_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id); //create packet knowing what path they need to take
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone())); //saving packets with their session id and their destination,
                 // useful if we need to resend a packet lost, in this way we can find the destination id (id_server) based on the session id of the packets
@@ -215,11 +261,11 @@ impl WebBrowser {
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
 
-        let fragments = WebBrowserCommands::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
 
         match self.find_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
                 
@@ -240,6 +286,40 @@ impl WebBrowser {
         }
     }
 
+    fn search(& mut self, id_server: NodeId, query: SearchQuery) {
+        if !self.servers.contains(&id_server) {
+            return;
+        }
+
+        let request = WebBrowserCommands::Search(query.clone());
+        let session_id = self.session_id_packet;
+        self.session_id_packet += 1;
+
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
+
+        match self.find_route(&id_server) {
+            Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
+                let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
+                self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
+
+                for packet in packets_to_send {
+                    if let PacketType::MsgFragment(fragment) = packet.pack_type.clone(){
+                        if let Err(_) = self.send_event.send(WebBrowserEvents::InfoRequest(self.config.id, ContentRequest::Search(fragment.total_n_fragments), packet.session_id )){
+                            println!("web browser failed to notify SC about search request")
+                        }
+                    }
+                    if let Some(next_hop) = route.get(1) {
+                        if let Err(_) = self.send_messages(next_hop, packet){
+                            self.search(id_server, query.clone());
+                        }
+                    } else { return; }
+                }
+            }
+            Err(_) => { println!("No route found for the destination server") }
+        }
+    }
+
     fn get_position (& mut self, id_server: NodeId, media_id: MediaId){
         if !self.servers.contains(&id_server) {
             return;
@@ -249,11 +329,11 @@ impl WebBrowser {
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
 
-        let fragments = WebBrowserCommands::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
 
         match self.find_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
                 
@@ -278,16 +358,27 @@ impl WebBrowser {
         if !self.servers.contains(&id_media_server) {
             return;
         }
-        let request = WebBrowserCommands::GetMedia(media_id.clone());
-        
+        let request = match self.cache.lookup(id_media_server, &media_id, Instant::now()) {
+            Lookup::Fresh(path) => {
+                self.cache_hit(id_media_server, media_id, path);
+                return;
+            }
+            Lookup::Stale(validator) => WebBrowserCommands::GetIfModified(media_id.clone(), validator),
+            Lookup::Missing => {
+                let range = self.downloads.start(id_media_server, &media_id, Instant::now());
+                self.get_range(id_media_server, media_id, range);
+                return;
+            }
+        };
+
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
 
-        let fragments = WebBrowserCommands::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
 
         match self.find_route(&id_media_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_media_server, packets_to_send.clone()));
                 
@@ -312,15 +403,26 @@ impl WebBrowser {
         if !self.servers.contains(&id_server) {
             return;
         }
-        let request = WebBrowserCommands::GetText(text_id.clone());
+        let request = match self.cache.lookup(id_server, &text_id, Instant::now()) {
+            Lookup::Fresh(path) => {
+                self.cache_hit(id_server, text_id, path);
+                return;
+            }
+            Lookup::Stale(validator) => WebBrowserCommands::GetIfModified(text_id.clone(), validator),
+            Lookup::Missing => {
+                let range = self.downloads.start(id_server, &text_id, Instant::now());
+                self.get_range(id_server, text_id, range);
+                return;
+            }
+        };
         let session_id = self.session_id_packet;
         self.session_id_packet += 1;
 
-        let fragments = WebBrowserCommands::fragment_message(&request);
-        self.fragments_sent.insert(session_id, fragments.clone());
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
 
         match self.find_route(&id_server) {
             Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
                 let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
                 self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
                 
@@ -342,10 +444,244 @@ impl WebBrowser {
 
     }
 
+    /// Asks a piece of a file, the pieces are stitched together once they all arrived
+    fn get_range (& mut self, id_server: NodeId, file_id: String, range: ByteRange) {
+        if !self.servers.contains(&id_server) {
+            return;
+        }
+
+        let request = WebBrowserCommands::GetRange(file_id.clone(), range);
+        let session_id = self.session_id_packet;
+        self.session_id_packet += 1;
+
+        let fragments = WebBrowserCommands::fragment_message(&request, SenderRole::WebBrowser);
+
+        match self.find_route(&id_server) {
+            Ok(route) => {
+                self.store_sent(session_id, fragments.clone());
+                let packets_to_send = ChatRequest::create_packet(&fragments, route.clone(), session_id);
+                self.packet_sent.insert(session_id, (id_server, packets_to_send.clone()));
+
+                for packet in packets_to_send {
+                    if let PacketType::MsgFragment(fragment) = packet.pack_type.clone(){
+                        let content_request = if FileKind::of(&file_id) == FileKind::Text {
+                            ContentRequest::GetText(fragment.total_n_fragments)
+                        } else {
+                            ContentRequest::GetMedia(fragment.total_n_fragments)
+                        };
+                        if let Err(_) = self.send_event.send(WebBrowserEvents::InfoRequest(self.config.id, content_request, packet.session_id )){
+                            println!("web browser failed to notify SC about get range request")
+                        }
+                    }
+                    if let Some(next_hop) = route.get(1) {
+                        if let Err(_) = self.send_messages(next_hop, packet){
+                            self.get_range(id_server, file_id.clone(), range);
+                        }
+                    } else { return; }
+                }
+            }
+            Err(_) => { println!("No route found for the destination server") }
+        }
+    }
+
+    /// Saves a file knowing only its path: the text server listing it is found, a media is located
+    /// (once, the position is cached) and then asked to the server holding it
+    fn fetch(& mut self, path: String) {
+        if let Some(location) = self.media_locations.get(&path).cloned() {
+            if self.servers.contains(&location) {
+                self.get_media(location, path);
+                return;
+            }
+            self.media_locations.remove(&path); //the server is gone, the media is located again
+        }
+
+        let listed_by = self.file_lists.iter()
+            .find(|(_, files)| files.contains(&path))
+            .map(|(text_server, _)| *text_server);
+
+        match listed_by {
+            Some(text_server) if FileKind::of(&path) == FileKind::Text => self.get_text(text_server, path),
+            Some(text_server) => {
+                self.waiting_position.insert(path.clone());
+                self.fetch_deadlines.insert(path.clone(), Instant::now() + FETCH_TIMEOUT);
+                self.get_position(text_server, path);
+            }
+            None if self.text_servers.is_empty() => {
+                self.fetch_failed(path, "no text server found, search the servers first".to_string());
+            }
+            None => { //the lists may be old or never asked, the fetch goes on when one of them has the file
+                self.fetch_deadlines.insert(path.clone(), Instant::now() + FETCH_TIMEOUT);
+                self.waiting_list.insert(path);
+                for text_server in self.text_servers.clone() {
+                    self.get_list(text_server);
+                }
+            }
+        }
+    }
+
+    /// A text server sent its list, the fetches waiting for it go on
+    fn resume_fetches(& mut self, text_server: NodeId, list: Vec<String>) {
+        self.file_lists.insert(text_server, list.clone());
+
+        for path in self.waiting_list.clone() {
+            if list.contains(&path) {
+                self.waiting_list.remove(&path);
+                self.fetch(path);
+            } else if self.text_servers.iter().all(|server| self.file_lists.contains_key(server)) {
+                self.waiting_list.remove(&path);
+                self.fetch_failed(path, "not listed by any text server".to_string());
+            }
+        }
+    }
+
+    fn fetch_failed(& mut self, path: String, reason: String) {
+        if let Err(_) = self.send_event.send(WebBrowserEvents::FetchFailed(self.config.id, path.clone(), reason.clone())) {
+            println!("failed to notify SC about a failed fetch")
+        }
+        self.media_arrived(&path, Err(reason));
+    }
+
+    /// A saved text file embedding medias is a page: its medias are fetched, the page is reported once they all arrived
+    fn load_page(& mut self, path: String) {
+        let Ok(source) = fs::read_to_string(&path) else {
+            return;
+        };
+        let document = Document::parse(&source);
+        if !document.is_hypertext() { //plain text, nothing else to fetch
+            return;
+        }
+        let media = document.media();
+        self.pending_pages.push((Page::new(path, document), Instant::now() + PAGE_TIMEOUT));
+        for media_id in media {
+            self.fetch(media_id);
+        }
+    }
+
+    fn media_arrived(& mut self, media_id: &str, saved: Result<String, String>) {
+        for (page, _) in self.pending_pages.iter_mut() {
+            page.attach(media_id, saved.clone());
+        }
+        self.report_loaded_pages();
+    }
+
+    /// Pages past their deadline are loaded with the medias still missing failed
+    fn check_pages(& mut self){
+        let now = Instant::now();
+        for (page, deadline) in self.pending_pages.iter_mut() {
+            if *deadline <= now {
+                page.give_up("no server answered in time");
+            }
+        }
+        self.report_loaded_pages();
+    }
+
+    fn report_loaded_pages(& mut self) {
+        let (loaded, pending): (Vec<(Page, Instant)>, Vec<(Page, Instant)>) = self.pending_pages.drain(..).partition(|(page, _)| page.is_loaded());
+        self.pending_pages = pending;
+        for (page, _) in loaded {
+            if let Err(_) = self.send_event.send(WebBrowserEvents::PageLoaded(self.config.id, page)) {
+                println!("failed to send loaded page to simulation control")
+            }
+        }
+    }
+
+    /// The saved copy is still valid, it is reported as if it was just downloaded
+    fn cache_hit(& mut self, server: NodeId, id: String, path: String) {
+        if let Err(_) = self.send_event.send(WebBrowserEvents::CacheHit(self.config.id, server, id.clone())) {
+            println!("failed to notify SC about a cache hit")
+        }
+        if FileKind::of(&id) == FileKind::Text {
+            if let Err(_) = self.send_event.send(WebBrowserEvents::SavedTextFile(self.config.id, path.clone())) {
+                println!("failed to send path to text file to simulation control")
+            }
+            self.load_page(path);
+        } else {
+            if let Err(_) = self.send_event.send(WebBrowserEvents::SavedMedia(self.config.id, path.clone())) {
+                println!("failed to send path to media to simulation control")
+            }
+            self.media_arrived(&id, Ok(path));
+        }
+    }
+
+    fn cache_miss(& mut self, server: NodeId, id: String, path: String, validator: Validator) {
+        if let Err(_) = self.send_event.send(WebBrowserEvents::CacheMiss(self.config.id, server, id.clone())) {
+            println!("failed to notify SC about a cache miss")
+        }
+        self.cache.store(server, id, path, validator, Instant::now());
+    }
+
+    fn not_modified(& mut self, server: NodeId, id: String) {
+        match self.cache.confirm(server, &id, Instant::now()) {
+            Some(path) => self.cache_hit(server, id, path),
+            None if FileKind::of(&id) == FileKind::Text => self.get_text(server, id), //the copy was dropped meanwhile, the whole file is needed
+            None => self.get_media(server, id),
+        }
+    }
+
+    /// A whole file or one of its pieces arrived: the next piece is asked, or the file is saved once complete
+    fn file_received(& mut self, server: NodeId, file: FileMetaData, text: bool) {
+        let file_id = format!("{}.{}", file.title, file.extension);
+        let validator = file.validator;
+        let range = file.range;
+        let bytes = match file.into_bytes() { //decode the content of the file, base64 or raw bytes depending on the encoding
+            Ok(bytes) => bytes,
+            Err(_) => {
+                println!("Failed to decode the file");
+                if !text {
+                    self.media_arrived(&file_id, Err("Failed to decode the file".to_string()));
+                }
+                return;
+            }
+        };
+        let pieces = match range {
+            None => vec![bytes], //the whole file in one message
+            Some(range) => match self.downloads.received(server, &file_id, range, validator, bytes, Instant::now()) {
+                Progress::Next(next) => {
+                    self.get_range(server, file_id, next);
+                    return;
+                }
+                Progress::Ignored => return,
+                Progress::Done(pieces) => pieces,
+            },
+        };
+
+        let stitched = pieces.concat();
+        if Validator::of(&stitched) != validator { //a piece got mixed up on the way, the file is downloaded again from the start
+            println!("{} doesn't match its validator, downloading it again", file_id);
+            let first = self.downloads.start(server, &file_id, Instant::now());
+            self.get_range(server, file_id, first);
+            return;
+        }
+
+        match self.save_file("assets/multimedia/SC", &file_id, &stitched) {
+            Ok(path) => {
+                self.cache_miss(server, file_id.clone(), path.clone(), validator);
+                if text {
+                    if let Err(_) = self.send_event.send(WebBrowserEvents::SavedTextFile(self.config.id.clone(), path.clone())) {
+                        println!("failed to send path to text file to simulation control")
+                    }
+                    self.load_page(path);
+                } else {
+                    if let Err(_) = self.send_event.send(WebBrowserEvents::SavedMedia(self.config.id.clone(), path.clone())){
+                        println!("failed to send path to media to simulation control")
+                    }
+                    self.media_arrived(&file_id, Ok(path));
+                }
+            }
+            Err(str) => {
+                println!("{}", str);
+                if !text {
+                    self.media_arrived(&file_id, Err(str));
+                }
+            }
+        }
+    }
+
     // incoming messages from servers
     fn handle_fragments(& mut self, packet: Packet){
         let src_id = packet.routing_header.hops.first().unwrap();
         let check = (packet.session_id, *src_id); //check to find all the fragments coming from the same source id (sender) with same session id
+        let key = SessionKey::Incoming(*src_id, packet.session_id);
 
         let ack = self.create_ack(&packet);
         let prev = packet.routing_header.hops[packet.routing_header.hop_index-1];
@@ -354,90 +690,124 @@ impl WebBrowser {
         }
 
         if let PacketType::MsgFragment(fragment) = packet.pack_type{
-            if !self.incoming_fragments.contains_key(&check){ //if a fragment with the pair check was not yet received, save them as a new element in incoming_fragments
-                self.incoming_fragments.insert(check, HashMap::new());
+            self.downloads.touch(*src_id, Instant::now()); //the server is still sending, its downloads are not stalled
+            match self.sessions.state(key) {
+                Some(SessionState::Open) => self.sessions.touch(key, Instant::now()),
+                Some(_) => { //late duplicate or replay of a session already closed
+                    self.sessions.duplicate();
+                    return;
+                }
+                None => {
+                    if !self.sessions.start(key, fragment.total_n_fragments, Instant::now()) { //too big to ever fit in memory
+                        return;
+                    }
+                }
+            }
+            //if a fragment with the pair check was not yet received, start a new reassembly for it
+            let inserted = Reassembler::entry(&mut self.incoming_fragments, check, fragment.total_n_fragments)
+                .and_then(|fragments| fragments.insert(&fragment));
+            if let Err(err) = inserted { //duplicates and malformed fragments are not processed again
+                match err {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    FragmentError::InvalidTotal(_) => self.sessions.close(key, SessionState::Failed, Instant::now()), //never started
+                    _ => {}
+                }
+                println!("fragment from {} discarded: {}", src_id, err);
+                return;
             }
-            self.incoming_fragments
-                .get_mut(&check)
-                .unwrap()
-                .insert(fragment.fragment_index, fragment.clone()); //if it was received, update the hashmap
 
             if let Some(fragments) = self.incoming_fragments.get(&check){
-                if fragments.len() as u64 == fragment.total_n_fragments{
-                    if let Ok(message) = TextServer::reassemble_msg(fragments) {
-                        match message {
-                            TextServer::ServerTypeText(server_type) => {
-                                if server_type == ServerType::TextServer && !self.text_servers.contains(&src_id) {
-                                    self.text_servers.push(src_id.clone());
-                                }
+                if fragments.is_complete(){
+                    let mut state = SessionState::Complete;
+                    match fragments.envelope().map(|envelope| envelope.message) {
+                        Ok(Message::TextServer(message)) => {
+                            match message {
+                                TextServer::ServerTypeText(server_type) => {
+                                    if server_type == ServerType::TextServer && !self.text_servers.contains(&src_id) {
+                                        self.text_servers.push(src_id.clone());
+                                    }
                                 
-                                if let Err(_) = self.send_event.send(WebBrowserEvents::TextServers(self.config.id.clone(), self.text_servers.clone())) {
-                                    println!("failed to send list of text servers to simulation control")
+                                    if let Err(_) = self.send_event.send(WebBrowserEvents::TextServers(self.config.id.clone(), self.text_servers.clone())) {
+                                        println!("failed to send list of text servers to simulation control")
+                                    }
                                 }
-                            }
 
-                            TextServer::SendFileList(list) => {
-                                if let Err(_) = self.send_event.send(WebBrowserEvents::ListFiles(self.config.id.clone(), list.clone())) {
-                                    println!("failed to send list of files to simulation control")
+                                TextServer::SendFileList(list) => {
+                                    if let Err(_) = self.send_event.send(WebBrowserEvents::ListFiles(self.config.id.clone(), list.clone())) {
+                                        println!("failed to send list of files to simulation control")
+                                    }
+                                    self.resume_fetches(*src_id, list);
                                 }
-                            }
 
-                            TextServer::PositionMedia(media_server_id) => {
-                                if let Err(_) = self.send_event.send(WebBrowserEvents::MediaPosition(self.config.id.clone(), media_server_id.clone())) {
-                                    println!("failed to send media position to simulation control")
+                                TextServer::PositionMedia(media_id, media_server_id) => {
+                                    if let Err(_) = self.send_event.send(WebBrowserEvents::MediaPosition(self.config.id.clone(), media_server_id.clone())) {
+                                        println!("failed to send media position to simulation control")
+                                    }
+                                    self.media_locations.insert(media_id.clone(), media_server_id);
+                                    if self.waiting_position.remove(&media_id) {
+                                        self.get_media(media_server_id, media_id);
+                                    }
+                                }
+
+                                TextServer::Text(text) => {
+                                    self.file_received(*src_id, text, true);
+                                }
+
+                                TextServer::NotModified(text_id) => {
+                                    self.not_modified(*src_id, text_id);
                                 }
-                            }
 
-                            TextServer::Text(text) => {
-                                let path_folder = "assets/multimedia/SC".to_string();
-                                match self.save_file(&path_folder, text) {
-                                    Ok(path) => {
-                                        if let Err(_) = self.send_event.send(WebBrowserEvents::SavedTextFile(self.config.id.clone(), path.clone())) {
-                                            println!("failed to send path to text file to simulation control")
-                                        }
+                                TextServer::SearchResults(hits) => {
+                                    if let Err(_) = self.send_event.send(WebBrowserEvents::SearchResults(self.config.id, *src_id, hits)) {
+                                        println!("failed to send search results to simulation control")
                                     }
-                                    Err(str) => { println!("{}", str) }
                                 }
-                            }
 
-                            _ => {}
+                                _ => {}
+                            }
                         }
-                    }
+                        Ok(Message::MediaServer(message)) => {
+                            match message{
+                                MediaServer::ServerTypeMedia(server_type) => {
+                                    if server_type == ServerType::MediaServer && !self.media_servers.contains(&src_id){
+                                        self.media_servers.push(src_id.clone());
+                                    }
 
-                    if let Ok(message) = MediaServer::reassemble_msg(fragments){
-                        match message{
-                            MediaServer::ServerTypeMedia(server_type) => {
-                                if server_type == ServerType::MediaServer && !self.media_servers.contains(&src_id){
-                                    self.media_servers.push(src_id.clone());
+                                    if let Err(_) = self.send_event.send(WebBrowserEvents::MediaServers(self.config.id.clone(), self.media_servers.clone())){
+                                        println!("failed to send list of text servers to simulation control")
+                                    }
                                 }
 
-                                if let Err(_) = self.send_event.send(WebBrowserEvents::MediaServers(self.config.id.clone(), self.media_servers.clone())){
-                                    println!("failed to send list of text servers to simulation control")
+                                MediaServer::SendMedia(media) => {
+                                    self.file_received(*src_id, media, false);
                                 }
-                            }
 
-                            MediaServer::SendMedia(media) => {
-                                let path_folder = "assets/multimedia/SC".to_string();
-                                match self.save_file(&path_folder, media){
-                                    Ok(path) => {
-                                        if let Err(_) = self.send_event.send(WebBrowserEvents::SavedMedia(self.config.id.clone(), path.clone())){
-                                            println!("failed to send path to media to simulation control")
-                                        }
-                                    }
-                                    Err(str) => {println!("{}", str)}
+                                MediaServer::NotModified(media_id) => {
+                                    self.not_modified(*src_id, media_id);
                                 }
-                            }
 
-                            _ => {}
+                                _ => {}
+                            }
+                        }
+                        Ok(other) => {
+                            println!("web browser {} can't handle {} messages from {}", self.config.id, other.kind(), src_id);
+                            state = SessionState::Failed;
+                        }
+                        Err(err) => {
+                            println!("rejected message from {}: {}", src_id, err);
+                            state = SessionState::Failed;
                         }
                     }
 
+                    self.incoming_fragments.remove(&check); //the message was handled, free its fragments
+                    self.sessions.close(key, state, Instant::now());
                 }
             }
         }
     }
 
     fn handle_nacks(& mut self, packet: Packet){
+        self.sessions.touch(SessionKey::Outgoing(packet.session_id), Instant::now());
         if let PacketType::Nack(nack) = packet.pack_type.clone(){
             match nack.nack_type{
                 NackType::Dropped => {
@@ -445,7 +815,7 @@ impl WebBrowser {
                     let data = self.node_data.get_mut(&failing_node).unwrap(); //finding node in the graph to modify the struct data
                     data.dropped += 1; //used to compute reliability of the path
                     
-                    self.resend_fragment(packet)
+                    self.resend_fragment(packet.session_id, nack.fragment_index)
                 }
 
                 NackType::ErrorInRouting(id) => {
@@ -457,7 +827,7 @@ impl WebBrowser {
                     let src = packet.routing_header.hops[0];
                     self.topology_graph.remove_edge(src, dest); //remove the edge between the drones so that the graph can be updated
                     
-                    self.resend_fragment(packet)
+                    self.resend_fragment(packet.session_id, nack.fragment_index)
                 }
 
                 _ => {}
@@ -465,46 +835,95 @@ impl WebBrowser {
         }
     }
 
-    fn resend_fragment(& mut self, packet: Packet){
-        if let PacketType::Nack(nack) = packet.clone().pack_type{
-            
-            let session_fragments = if let Some(session_fragments) = self.fragments_sent.get(&packet.session_id){
-                session_fragments
-            }else {
-                return;
-            }; //get fragments from the same session id
-            
-            let fragment_lost = if let Some(fragment_lost) = session_fragments.get(&nack.fragment_index){
-                fragment_lost.clone()
-            }else {
+    fn resend_fragment(& mut self, session_id: u64, fragment_index: u64){
+        let session_fragments = if let Some(session_fragments) = self.fragments_sent.get(&session_id){
+            session_fragments
+        }else {
+            return;
+        }; //get fragments from the same session id
+        
+        let fragment_lost = if let Some(fragment_lost) = session_fragments.get(&fragment_index){
+            fragment_lost.clone()
+        }else {
+            return;
+        }; //find fragment lost based on the fragment index
+
+        let destination_id = match self.packet_sent.get(&session_id){
+            Some((destination_id, _)) => destination_id.clone(),
+            None => {
                 return;
-            }; //find fragment lost based on the fragment index
+            }
+        }; //find the destination of the lost packet
 
-            let destination_id = match self.packet_sent.get(&packet.session_id){
-                Some((destination_id, _)) => destination_id.clone(),
-                None => {
-                    return;
-                }
-            }; //find the destination of the lost packet
+        match self.find_route(&destination_id){
+            Ok(route) => {
+                
+                let packet_to_send = Packet::new_fragment(
+                    SourceRoutingHeader::new(route.clone(), 0),
+                    session_id,
+                    fragment_lost
+                ); //create a new packet with the new route, but same content and session id
+
+                if let Some(next_hop) = route.get(1){
+                    if let Err(_) = self.send_messages(next_hop, packet_to_send){
+                        self.resend_fragment(session_id, fragment_index);
+                    }
+                }else { return; }
+            }
+            Err(_) => println!("failed to find the route after receiving nack")
+        }
+    }
 
-            match self.find_route(&destination_id){
-                Ok(route) => {
-                    
-                    let packet_to_send = Packet::new_fragment(
-                        SourceRoutingHeader::new(route.clone(), 0),
-                        packet.session_id,
-                        fragment_lost
-                    ); //create a new packet with the new route, but same content and session id
-
-                    if let Some(next_hop) = route.get(1){
-                        if let Err(_) = self.send_messages(next_hop, packet_to_send){
-                            self.resend_fragment(packet.clone());
+    fn check_timeouts(& mut self){
+        for expired in self.timers.expired(Instant::now()){
+            match expired {
+                Expired::Resend(session_id, fragment_index) => {
+                    self.resend_fragment(session_id, fragment_index); //no ack in time, the fragment, its ack or its nack was lost
+                }
+                Expired::GaveUp(session_id) => {
+                    self.sessions.close(SessionKey::Outgoing(session_id), SessionState::Failed, Instant::now());
+                    self.fragments_sent.remove(&session_id);
+                    if let Some((destination_id, _)) = self.packet_sent.remove(&session_id){
+                        self.media_locations.retain(|_, holder| *holder != destination_id); //its medias are located again
+                        if let Err(_) = self.send_event.send(WebBrowserEvents::RequestFailed(self.config.id, destination_id, session_id)){
+                            println!("failed to notify SC about a failed request")
                         }
-                    }else { return; }
+                    }
                 }
-                Err(_) => println!("failed to find the route after receiving nack")
             }
+        }
+    }
 
+    /// Fetches still waiting for a list or a position at their deadline fail, no server answered
+    fn check_fetches(& mut self){
+        let now = Instant::now();
+        let expired: Vec<String> = self.fetch_deadlines.iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in expired {
+            self.fetch_deadlines.remove(&path);
+            let listed = self.waiting_list.remove(&path);
+            let located = self.waiting_position.remove(&path);
+            if listed || located { //otherwise the fetch already went on
+                self.fetch_failed(path, "no server answered in time".to_string());
+            }
+        }
+    }
+
+    fn check_downloads(& mut self){
+        for stalled in self.downloads.stalled(Instant::now()){
+            match stalled {
+                Stalled::Resume(server, file_id, range) => {
+                    self.get_range(server, file_id, range); //nothing arrived for a while, the missing pieces are asked again
+                }
+                Stalled::Broken(server, file_id) => {
+                    if self.media_locations.get(&file_id) == Some(&server) {
+                        self.media_locations.remove(&file_id); //the next fetch asks the position again
+                    }
+                    self.fetch_failed(file_id, "the download stopped, the server is not answering".to_string());
+                }
+            }
         }
     }
 
@@ -512,12 +931,52 @@ impl WebBrowser {
         if let PacketType::Ack(ack) = packet.pack_type{
             self.problematic_nodes.clear(); //if successful clear the problematic nodes
 
-            self.fragments_sent.retain(|index, _| *index != ack.fragment_index); //this filters the hashmap, removing the ones with that index that were received
+            if let Some(session_fragments) = self.fragments_sent.get_mut(&packet.session_id){
+                session_fragments.remove(&ack.fragment_index); //only that fragment of that session was received
+            }
+            if self.timers.ack(packet.session_id, ack.fragment_index){
+                self.fragments_sent.remove(&packet.session_id); //every fragment of the session was acked
+                self.packet_sent.remove(&packet.session_id);
+                self.sessions.close(SessionKey::Outgoing(packet.session_id), SessionState::Complete, Instant::now());
+            }else {
+                self.sessions.touch(SessionKey::Outgoing(packet.session_id), Instant::now());
+            }
             let data = self.node_data.get_mut(&packet.routing_header.hops.iter().rev().nth(1).unwrap()).unwrap();
             data.forwarded += 1; //update the forwarded field to then compute reliability
         }
     }
 
+    /// Opens the session of a request that found a route, its fragments are kept until they are all acked
+    fn store_sent(& mut self, session_id: u64, fragments: HashMap<u64, Fragment>){
+        self.sessions.start(SessionKey::Outgoing(session_id), fragments.len() as u64, Instant::now());
+        self.fragments_sent.insert(session_id, fragments);
+    }
+
+    fn sweep_sessions(& mut self){
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(src_id, session_id) => {
+                    self.incoming_fragments.remove(&(session_id, src_id)); //free the fragments received so far
+                }
+                SessionKey::Outgoing(session_id) => {
+                    self.fragments_sent.remove(&session_id);
+                    if let Some((server, _)) = self.packet_sent.remove(&session_id) {
+                        self.media_locations.retain(|_, holder| *holder != server); //a request to it failed, its medias are located again
+                    }
+                    self.timers.forget(session_id);
+                }
+            }
+            if let Err(_) = self.send_event.send(WebBrowserEvents::StuckSession(self.config.id, key, state)){
+                println!("failed to notify SC about a stuck session")
+            }
+        });
+        if let Some(duplicates) = duplicates{
+            if let Err(_) = self.send_event.send(WebBrowserEvents::Duplicates(self.config.id, duplicates)){
+                println!("failed to notify SC about duplicated fragments")
+            }
+        }
+    }
+
 
     fn flooding(& mut self){
         let flood_id = self.unique_flood_id;
@@ -688,6 +1147,11 @@ impl WebBrowser {
             if let Err(err) = sender.send(packet.clone()){
                 println!("Error sending command: {}", err);
             }
+            if let PacketType::MsgFragment(fragment) = &packet.pack_type{
+                if self.fragments_sent.contains_key(&packet.session_id){
+                    self.timers.start(packet.session_id, fragment.fragment_index, Instant::now()); //wait for its ack
+                }
+            }
             Ok(())
         }else {
             self.topology_graph.remove_edge(self.config.id, *destination_id); //if the sender was not found (remove direct neighbor) we erase the edge.
@@ -710,15 +1174,10 @@ impl WebBrowser {
         ack_packet
     }
 
-    fn save_file(& self, path_folder: &str, fmd: FileMetaData)-> Result<String, String>{
-        let full_path = format!("{}/{}.{}", path_folder, fmd.title, fmd.extension);
+    fn save_file(& self, path_folder: &str, file_id: &str, bytes: &[u8])-> Result<String, String>{
+        let full_path = format!("{}/{}", path_folder, file_id);
 
-        let decode = match BASE64.decode(fmd.content){ //decode the content of the file
-            Ok(decode) => decode,
-            Err(_) => return Err("Failed to decode the file".to_string()),
-        };
-
-        fs::write(&full_path, decode) //write the decoded file to the path (into SC folder)
+        fs::write(&full_path, bytes) //write the file to the path (into SC folder)
             .map_err(|e| format!("Failed to save the file: {}", e))?;
         
         Ok(full_path)
@@ -726,5 +1185,3 @@ impl WebBrowser {
 
 
 }
-
-
@@ -4,6 +4,7 @@ use petgraph::prelude::UnGraphMap;
 use serde::{Deserialize, Serialize};
 use wg_2024::network::NodeId;
 use wg_2024::packet::Packet;
+use crate::common_data::hypertext::Page;
 use crate::gui::login_window::NodeType as MyNodeType;
 
 //comandi sim_control
@@ -11,29 +12,51 @@ use crate::gui::login_window::NodeType as MyNodeType;
 pub enum CommandChat {
     SearchChatServers,
     RegisterClient(NodeId),//node id server
+    Unregister(NodeId),//node id server
     SendMessage(NodeId, NodeId, String),//node id del client a cui mandare la string, node id server da cui passare
     SendTopologyGraph,
     RemoveSender(NodeId),
     AddSender(NodeId, Sender<Packet>), //works the same as drones
-    PdrChanged(NodeId)
+    PdrChanged(NodeId),
+    ReadMessages(NodeId, NodeId), //node id of the client whose messages were shown, node id server they came through
+    CreateRoom(NodeId, String), //node id server, room name
+    JoinRoom(NodeId, String), //node id server, room name
+    LeaveRoom(NodeId, String), //node id server, room name
+    RoomMembers(NodeId, String), //node id server, room name
+    SendRoomMessage(NodeId, String, String), //node id server, room name, message
+    GetHistory(NodeId, NodeId, u64) //node id server, node id of the other client of the conversation, first index wanted
 }
 ///The NodeId identifies the client that sent the ChatClientEvent
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ChatClientEvent{
     ClientList((NodeId, NodeId) ,Vec<NodeId>), //NodeId Client, NodeId Server, Vec<ClientIds>
-    IncomingMessage((NodeId,NodeId,NodeId),String), //NodeId Client a cui è arrivato msg, NodeId server, NodeId del client da cui il messaggio è arrivato msg
+    IncomingMessage((NodeId,NodeId,NodeId),String,NodeId), //NodeId Client a cui è arrivato msg, NodeId server, NodeId del client da cui il messaggio è arrivato msg; msg; NodeId server the sender is registered on (another one if relayed by a peer)
     RegisteredSuccess((NodeId,NodeId),Result<(), String>), //NodeId registered client and NodeId server { either Ok(()) or Err("something".to_string()) }
+    Unregistered((NodeId,NodeId)), //NodeId client and NodeId server that removed it
+    Presence((NodeId,NodeId), NodeId, bool), //NodeId client, NodeId server; NodeId client that joined (true) or left (false) the server
     ChatServers(NodeId, Vec<NodeId>),
     ClientType(ClientType,NodeId),
     InfoRequest(NodeId, RequestEvent, u64),
-    Graph(NodeId, UnGraphMap<NodeId, u32>)
+    Graph(NodeId, UnGraphMap<NodeId, u32>),
+    DeliveryFailed(NodeId, NodeId, u64), //NodeId client, NodeId destination server, session id given up after too many retransmissions
+    StuckSession(NodeId, SessionKey, SessionState), //NodeId client, session dropped, Failed or Expired
+    Duplicates(NodeId, u64), //NodeId client, duplicated or replayed fragments discarded so far
+    MessageStatus((NodeId,NodeId,NodeId), u64, MessageStatus), //NodeId client that sent the message, NodeId server, NodeId recipient; message id; status reached
+    Room((NodeId,NodeId), String, Result<String, String>), //NodeId client, NodeId server; room name; outcome of create, join or leave
+    RoomMembers((NodeId,NodeId), String, Vec<NodeId>), //NodeId client, NodeId server; room name; members, empty once the client isn't one of them
+    IncomingRoomMessage((NodeId,NodeId,NodeId), String, String), //NodeId client, NodeId server, NodeId client that posted; room name; message
+    History((NodeId,NodeId,NodeId), Result<Vec<HistoryEntry>, String>) //NodeId client, NodeId server, NodeId other client of the conversation; messages kept by the server
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RequestEvent{
     AskType(u64),
     Register(u64),
+    Unregister(u64),
     SendMessage(u64),
+    Receipt(u64),
+    Room(u64),
+    History(u64),
 }
 
 pub enum ServerCommands{
@@ -49,7 +72,9 @@ pub enum ServerEvent{
     GraphMax(NodeId, Vec<(NodeId, wg_2024::packet::NodeType, Vec<NodeId>)>), // (id server, Vec<(id of the node, packet type of the node, node connections)>)
     TextPacketInfo(NodeId, MyNodeType, TextServerEvent, u64), //(id server, server_type (ChatServer, TextServer,...), type of message, session_id)
     MediaPacketInfo(NodeId, MyNodeType, MediaServerEvent, u64), //(id server, server_type (ChatServer, TextServer,...), type of message, session_id)
-    ChatPacketInfo(NodeId, MyNodeType, ChatServerEvent, u64)  //(id server, server_type (ChatServer, TextServer,...), type of message, session_id)
+    ChatPacketInfo(NodeId, MyNodeType, ChatServerEvent, u64),  //(id server, server_type (ChatServer, TextServer,...), type of message, session_id)
+    StuckSession(NodeId, MyNodeType, SessionKey, SessionState), //(id server, server_type, session dropped, Failed or Expired)
+    Duplicates(NodeId, MyNodeType, u64) //(id server, server_type, duplicated or replayed fragments discarded so far)
 }
 #[derive(Debug,Clone)]
 pub enum ChatServerEvent{
@@ -58,31 +83,49 @@ pub enum ChatServerEvent{
     SendingClientList(u64),
     ForwardingMessage(u64),
     ClientElimination(u64),
+    ForwardingReceipt(u64),
+    RoomUpdate(u64),
+    ForwardingRoomMessage(u64),
+    AnnouncingClients(u64),
+    RelayingToPeer(u64),
+    SendingHistory(u64),
+    SendingPresence(u64),
+    AskingServerType(u64),
 }
 #[derive(Debug,Clone)]
 pub enum TextServerEvent{
     SendingFileList(u64),
     SendingPosition(u64),
-    SendingText(u64),
+    SendingText(u64, PayloadSize), //(fragments, bytes of the text)
     SendingServerTypeText(u64),
     SendingServerTypeReq(u64),
     AskingForPathRes(u64),
+    SendingNotModified(u64),
+    SendingSearchResults(u64),
 }
 #[derive(Debug,Clone)]
 pub enum MediaServerEvent{
     SendingServerTypeMedia(u64),
     SendingPathRes(u64), //send paths to the text server
-    SendingMedia(u64)
+    SendingMedia(u64, PayloadSize), //(fragments, bytes of the media)
+    SendingNotModified(u64),
 }
 
 //comandi da client a server
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ChatRequest{
     ServerType,
     RegisterClient(NodeId),//node id del client stesso
     GetListClients,
     SendMessage(MessageChat, NodeId), //message and server id communcation server (per ora poi se riesco a trovare modo cambio)
     EndChat(NodeId),//node id del client stesso
+    Receipt(Receipt), //sent by the recipient of a message, relayed to its sender
+    CreateRoom(String, NodeId), //room name, node id del client stesso
+    JoinRoom(String, NodeId), //room name, node id del client stesso
+    LeaveRoom(String, NodeId), //room name, node id del client stesso
+    RoomMembers(String), //room name
+    SendRoomMessage(RoomMessage), //fanned out to every other member of the room
+    GetHistory(NodeId, u64), //node id of the other client of the conversation, first index wanted
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -92,15 +135,61 @@ pub enum ChatResponse{
     RegisteredClients(Vec<NodeId>),
     SendMessage(Result<String, String>),
     EndChat(bool),
-    ForwardMessage(MessageChat)
+    ForwardMessage(MessageChat),
+    ForwardReceipt(Receipt),
+    Room(String, Result<String, String>), //room name, outcome of create, join or leave
+    RoomMembers(String, Vec<NodeId>), //room name, members; sent to every member when they change
+    ForwardRoomMessage(RoomMessage),
+    Federate(Vec<NodeId>), //sent to the other servers: clients registered on this chat server
+    RelayMessage(MessageChat, NodeId), //to the peer chat server the destination is registered on, node id of the origin server
+    RelayReceipt(Receipt), //to the peer chat server the sender of the message is registered on
+    ForwardRelayedMessage(MessageChat, NodeId), //ForwardMessage of a message relayed by a peer, node id of the origin server
+    History(NodeId, Result<Vec<HistoryEntry>, String>), //node id of the other client of the conversation, messages from the index asked
+    Presence(NodeId, bool) //sent to every registered client: node id of the client that joined (true) or left (false)
 }
 
-#[derive(Serialize,Deserialize,Debug, Clone)]
+#[derive(Serialize,Deserialize,Debug, Clone, PartialEq)]
 pub struct MessageChat{ //which needs to be fragmented
     //pub general: Packet,
     pub content: String,
     pub from_id: NodeId,//id client sender
-    pub to_id: NodeId //id destination client
+    pub to_id: NodeId, //id destination client
+    #[serde(default)]
+    pub message_id: u64 //numbered from 0 for every (server, destination client) conversation of the sender
+}
+
+/// How far a chat message got, receipts only move it forward
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum MessageStatus{
+    #[default]
+    Sent,      //accepted by the server
+    Queued,    //the destination client is unreachable, the message waits in its mailbox on the server
+    Delivered, //reached the destination client
+    Read       //shown to the destination client, a Read receipt covers every message up to its id
+}
+
+/// Application level ack of a chat message, it travels back to the sender through the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt{
+    pub message_id: u64,
+    pub from_id: NodeId, //id client that received the message
+    pub to_id: NodeId,   //id client that sent it
+    pub status: MessageStatus
+}
+
+/// Message kept in the history of a conversation, indexes start from 0 and never change
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry{
+    pub index: u64,
+    pub message: MessageChat
+}
+
+/// Message posted in a room of a chat server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomMessage{
+    pub room: String,
+    pub content: String,
+    pub from_id: NodeId //id client that posted it
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -117,6 +206,84 @@ pub enum ClientType{
 }
 
 
+//ENVELOPE wrapping every message between clients and servers
+/// Version of the envelope format, messages with a different one are rejected
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Role of the node that sent an envelope
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SenderRole{
+    ChatClient,
+    WebBrowser,
+    ChatServer,
+    TextServer,
+    MediaServer
+}
+
+/// Application message, the kind tells the receiver how to read the body
+/// so it doesn't have to try every type until one deserializes
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", content = "body")]
+pub enum Message{
+    ChatRequest(ChatRequest),
+    ChatResponse(ChatResponse),
+    WebBrowserCommands(WebBrowserCommands),
+    TextServer(TextServer),
+    MediaServer(MediaServer)
+}
+
+/// What actually travels in the fragments: {"version":1,"sender":"WebBrowser","accepts":["Json","Binary"],"compressions":["Deflate"],"message":{"kind":"WebBrowserCommands","body":...}}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope{
+    pub version: u16,
+    pub sender: SenderRole,
+    #[serde(default)]
+    pub accepts: Vec<Encoding>, //encodings the sender can read in the answer, empty for nodes that only know json
+    #[serde(default)]
+    pub compressions: Vec<Compression>, //compressions the sender can read in the answer, empty for nodes that don't compress
+    pub message: Message
+}
+
+/// How the content of a file travels
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+pub enum Encoding{
+    #[default]
+    Json,   //base64 string inside the json
+    Binary  //raw bytes appended after the json envelope
+}
+
+/// Session of a node: the fragments it is receiving from a peer, or the ones it sent and waits to be acked
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SessionKey{
+    Incoming(NodeId, u64), //(sender, session id)
+    Outgoing(u64)          //(session id)
+}
+
+/// Lifecycle of a session: Open until it completes, or until it is given up (Failed) or left idle too long (Expired)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState{
+    Open,
+    Complete,
+    Failed,  //given up, or evicted to stay under the memory limit
+    Expired  //no fragment, ack or nack for too long
+}
+
+/// How the whole frame of a message travels
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+pub enum Compression{
+    #[default]
+    None,
+    Deflate //frame deflated behind its own marker byte
+}
+
+/// Bytes of a message before and after compression, equal when it wasn't compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct PayloadSize{
+    pub raw: u64,
+    pub compressed: u64,
+}
+
+
 //COMMANDS TEXT, MEDIA SERVERS AND WEB BROWSER
 pub type MediaId = String;
 pub type TextId = String;
@@ -124,7 +291,46 @@ pub type TextId = String;
 pub struct FileMetaData{
     pub(crate) title: String, 
     pub(crate) extension: String,
-    pub(crate) content: String,
+    pub(crate) content: String, //base64, empty when the file travels with Encoding::Binary
+    #[serde(skip)]
+    pub(crate) raw: Option<Vec<u8>>, //bytes of the file when it travels with Encoding::Binary
+    #[serde(default)]
+    pub(crate) validator: Validator, //of the file bytes, whichever encoding they travel with
+    #[serde(default)]
+    pub(crate) range: Option<ByteRange>, //part of the file carried, None when it is the whole file
+}
+/// Size and checksum of a file: a client holding a copy with the same validator doesn't need it again
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Validator{
+    pub size: u64,
+    pub checksum: u64,
+}
+/// Bytes of a file from `offset` on, at most `length` of them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange{
+    pub offset: u64,
+    pub length: u64,
+}
+/// Which files a text server reports, every filter given must match
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SearchQuery{
+    pub extension: Option<String>, //without the dot, e.g. "png"
+    pub kind: Option<FileKind>,
+    pub keywords: Vec<String>, //each one must be in the file name, case insensitive
+    pub content: Option<String>, //phrase searched in the lines of the text files the server holds
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind{
+    Text,
+    Media,
+}
+/// File matching a search
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit{
+    pub name: String,
+    pub mime: String,
+    pub size: Option<u64>, //None for the medias held by another server
+    pub lines: Vec<(usize, String)>, //line number (from 1) and text of the lines holding the phrase searched
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum WebBrowserCommands{
@@ -132,6 +338,9 @@ pub enum WebBrowserCommands{
     GetPosition(MediaId), //to ask the position of the media (need an id of the media, could be u8)
     GetMedia(MediaId), //to ask the media to the correct media server (also here need the id of the wanted media),
     GetText(TextId), //to ask a text file a text
+    GetIfModified(String, Validator), //text or media the client already has, sent again only if its validator changed
+    GetRange(String, ByteRange), //piece of a text or media, so a broken transfer goes on from the last piece received
+    Search(SearchQuery), //files known to the text server matching the query, with their metadata
     GetServerType
 }
 //probably also need a way to ask the server type
@@ -141,15 +350,18 @@ pub enum TextServer{
     ServerTypeText(ServerType),
     PathResolution, //text server asks all media servers which media he has
     SendFileList(Vec<String>), //send the resolved text list to the client
-    PositionMedia(NodeId), //send exact position of the media to the client
-    Text(FileMetaData)
+    PositionMedia(MediaId, NodeId), //send exact position of the media to the client, with the media it refers to
+    Text(FileMetaData),
+    NotModified(TextId), //the copy of the client is still valid
+    SearchResults(Vec<SearchHit>) //files matching the search of the client
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MediaServer{
     ServerTypeMedia(ServerType),
-    SendPath(Vec<MediaId>), //send paths to the text server
-    SendMedia(FileMetaData) //send correct media to the client who asked
+    SendPath(Vec<(MediaId, u64)>), //send paths to the text server, each media with its size in bytes
+    SendMedia(FileMetaData), //send correct media to the client who asked
+    NotModified(MediaId) //the copy of the client is still valid
 }
 
 #[derive(Debug)]
@@ -158,6 +370,8 @@ pub enum ContentCommands{
     GetMediaPosition(NodeId, MediaId), //sent to client with id of media needed, same problem with id of text server
     GetMedia(NodeId, MediaId), //sent to client with id of media, node id of the media server, probably better automated if possible
     GetText(NodeId, TextId), //sent to client, text id of the text file needed
+    Fetch(String), //sent to client, path of a listed file: the client finds where it is and saves it
+    Search(NodeId, SearchQuery), //sent to client, node id of the text server to search
     SearchTypeServers,
     SendTopologyGraph,
     AddSender(NodeId, Sender<Packet>),
@@ -169,7 +383,7 @@ pub enum BackGroundFlood{
 }
 
 //from client to SC
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum WebBrowserEvents{ //not complete
     MediaServers(NodeId, Vec<NodeId>), //node id client, list of media servers found after the SearchTypeServers command is sent
     TextServers(NodeId, Vec<NodeId>), //node id client, list of test servers found after the SearchTypeServers command is sent
@@ -178,13 +392,22 @@ pub enum WebBrowserEvents{ //not complete
     SavedTextFile(NodeId, String), //node id client, path to file saved in SC folder in multimedia
     SavedMedia(NodeId, String),
     InfoRequest(NodeId, ContentRequest,  u64),
-    Graph(NodeId, UnGraphMap<NodeId, u32>)
+    Graph(NodeId, UnGraphMap<NodeId, u32>),
+    RequestFailed(NodeId, NodeId, u64), //node id client, node id destination server, session id given up after too many retransmissions
+    StuckSession(NodeId, SessionKey, SessionState), //node id client, session dropped, Failed or Expired
+    Duplicates(NodeId, u64), //node id client, duplicated or replayed fragments discarded so far
+    FetchFailed(NodeId, String, String), //node id client, path of the file, why it can't be fetched
+    PageLoaded(NodeId, Page), //node id client, hypertext document saved with every media it embeds
+    CacheHit(NodeId, NodeId, String), //node id client, node id server, file served from the saved copy without downloading it
+    CacheMiss(NodeId, NodeId, String), //node id client, node id server, file downloaded (never saved, or changed on the server)
+    SearchResults(NodeId, NodeId, Vec<SearchHit>) //node id client, node id text server, files matching the search
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ContentRequest{
     AskTypes(u64),
     GetList(u64),
     GetPosition(u64),
     GetMedia(u64),
-    GetText(u64)
+    GetText(u64),
+    Search(u64)
 }
@@ -0,0 +1,630 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use wg_2024::packet::{Fragment, FRAGMENT_DSIZE};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use crate::common_data::sessions::MAX_SESSION_BYTES;
+use crate::common_data::common::{ByteRange, ChatRequest, ChatResponse, Compression, Encoding, Envelope, FileMetaData, MediaServer, Message, PayloadSize, SenderRole, TextServer, Validator, WebBrowserCommands, PROTOCOL_VERSION};
+
+/// Payload of a single fragment with the number of bytes actually used
+pub type Chunk = ([u8; FRAGMENT_DSIZE], u8);
+/// Most fragments a message may have, the ones announcing more could never fit in the session buffers
+pub const MAX_FRAGMENTS: u64 = (MAX_SESSION_BYTES / FRAGMENT_DSIZE) as u64;
+
+/// Everything that can go wrong while splitting or putting back together a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FragmentError {
+    Duplicate(u64),                                   // fragment index already received
+    InvalidTotal(u64),                                // total_n_fragments is 0 or more than MAX_FRAGMENTS
+    OutOfRange { index: u64, total: u64 },            // index not smaller than the total
+    TotalMismatch { expected: u64, found: u64 },      // total_n_fragments differs from the first fragment of the session
+    InvalidLength { index: u64, length: u8 },         // length bigger than FRAGMENT_DSIZE
+    Incomplete { missing: u64 },                      // asked for the message before every fragment arrived
+    InvalidUtf8(String),
+    NotAnEnvelope(String),                            // no version or kind, e.g. sent by a node that doesn't use envelopes
+    InvalidFrame(String),                             // binary frame whose lengths don't add up
+    Inflate(String),                                  // compressed frame that doesn't inflate, or inflates past MAX_INFLATED
+    UnsupportedVersion(u16),
+    UnknownKind(String),
+    WrongSender { kind: String, sender: SenderRole },  // message its sender's role never sends, e.g. a ChatResponse from a client
+    Serialize(String),
+    Deserialize(String),
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::Duplicate(index) => write!(f, "fragment {} received twice", index),
+            FragmentError::InvalidTotal(total) => write!(f, "message of {} fragments not accepted (1 to {})", total, MAX_FRAGMENTS),
+            FragmentError::OutOfRange { index, total } => write!(f, "fragment {} out of range, the message has {} fragments", index, total),
+            FragmentError::TotalMismatch { expected, found } => write!(f, "fragment says the message has {} fragments, expected {}", found, expected),
+            FragmentError::InvalidLength { index, length } => write!(f, "fragment {} has length {} (max {})", index, length, FRAGMENT_DSIZE),
+            FragmentError::Incomplete { missing } => write!(f, "message incomplete, {} fragments missing", missing),
+            FragmentError::InvalidUtf8(err) => write!(f, "message is not valid utf-8: {}", err),
+            FragmentError::NotAnEnvelope(err) => write!(f, "message is not an envelope: {}", err),
+            FragmentError::InvalidFrame(err) => write!(f, "invalid binary frame: {}", err),
+            FragmentError::Inflate(err) => write!(f, "couldn't inflate compressed frame: {}", err),
+            FragmentError::UnsupportedVersion(version) => write!(f, "envelope version {} not supported (expected {})", version, PROTOCOL_VERSION),
+            FragmentError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            FragmentError::WrongSender { kind, sender } => write!(f, "{} messages can't come from a {:?}", kind, sender),
+            FragmentError::Serialize(err) => write!(f, "couldn't serialize message: {}", err),
+            FragmentError::Deserialize(err) => write!(f, "couldn't deserialize message: {}", err),
+        }
+    }
+}
+
+impl Error for FragmentError {}
+
+/// Splits raw bytes in FRAGMENT_DSIZE chunks, the last one zero padded
+pub fn split(bytes: &[u8]) -> Vec<Chunk> {
+    bytes.chunks(FRAGMENT_DSIZE).map(|chunk| {
+        let mut data = [0u8; FRAGMENT_DSIZE];
+        data[..chunk.len()].copy_from_slice(chunk);
+        (data, chunk.len() as u8)
+    }).collect()
+}
+
+/// Splits raw bytes in fragments, ordered by index
+pub fn fragment_bytes(bytes: &[u8]) -> Vec<Fragment> {
+    let chunks = split(bytes);
+    let total = chunks.len() as u64;
+    chunks.into_iter().enumerate()
+        .map(|(index, (data, length))| Fragment { fragment_index: index as u64, total_n_fragments: total, length, data })
+        .collect()
+}
+
+/// Parses the json carried by a reassembled message
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, FragmentError> {
+    let text = std::str::from_utf8(bytes).map_err(|e| FragmentError::InvalidUtf8(e.to_string()))?;
+    serde_json::from_str(text).map_err(|e| FragmentError::Deserialize(e.to_string()))
+}
+
+/// Message types that can be the body of an Envelope, KIND is the name of the matching Message variant
+pub trait Payload: Serialize {
+    const KIND: &'static str;
+
+    /// File carried by the message, its bytes may travel outside the json
+    fn file(&self) -> Option<&FileMetaData> {
+        None
+    }
+}
+
+impl Payload for ChatRequest { const KIND: &'static str = "ChatRequest"; }
+impl Payload for ChatResponse { const KIND: &'static str = "ChatResponse"; }
+impl Payload for WebBrowserCommands { const KIND: &'static str = "WebBrowserCommands"; }
+
+impl Payload for TextServer {
+    const KIND: &'static str = "TextServer";
+
+    fn file(&self) -> Option<&FileMetaData> {
+        match self {
+            TextServer::Text(file) => Some(file),
+            _ => None,
+        }
+    }
+}
+
+impl Payload for MediaServer {
+    const KIND: &'static str = "MediaServer";
+
+    fn file(&self) -> Option<&FileMetaData> {
+        match self {
+            MediaServer::SendMedia(file) => Some(file),
+            _ => None,
+        }
+    }
+}
+
+/// Kinds understood by this version of the protocol
+const KINDS: [&str; 5] = [ChatRequest::KIND, ChatResponse::KIND, WebBrowserCommands::KIND, TextServer::KIND, MediaServer::KIND];
+
+/// Encodings every node built from this tree can read, advertised in each envelope
+pub const ACCEPTED_ENCODINGS: [Encoding; 2] = [Encoding::Json, Encoding::Binary];
+
+/// Compressions every node built from this tree can read, advertised in each envelope
+pub const ACCEPTED_COMPRESSIONS: [Compression; 1] = [Compression::Deflate];
+
+/// First byte of a binary frame, a json envelope always starts with '{'
+const BINARY_MARKER: u8 = 0;
+
+/// First byte of a compressed frame, followed by the deflated json or binary frame
+const DEFLATE_MARKER: u8 = 1;
+
+/// Frames smaller than this fit in a handful of fragments, compressing them isn't worth it
+const COMPRESSION_THRESHOLD: usize = 4 * FRAGMENT_DSIZE;
+
+/// Biggest frame a compressed message may inflate to, so a few fragments can't eat the memory
+const MAX_INFLATED: usize = 64 * 1024 * 1024;
+
+impl Encoding {
+    /// Encoding to answer with, given what the requester advertised in its envelope
+    pub fn negotiate(accepts: &[Encoding]) -> Encoding {
+        if accepts.contains(&Encoding::Binary) { Encoding::Binary } else { Encoding::Json }
+    }
+}
+
+impl Compression {
+    /// Compression to answer with, given what the requester advertised in its envelope
+    pub fn negotiate(accepts: &[Compression]) -> Compression {
+        if accepts.contains(&Compression::Deflate) { Compression::Deflate } else { Compression::None }
+    }
+}
+
+impl FileMetaData {
+    pub fn new(title: String, extension: String, bytes: Vec<u8>, encoding: Encoding) -> FileMetaData {
+        let validator = Validator::of(&bytes);
+        match encoding {
+            Encoding::Json => FileMetaData { title, extension, content: BASE64.encode(&bytes), raw: None, validator, range: None },
+            Encoding::Binary => FileMetaData { title, extension, content: String::new(), raw: Some(bytes), validator, range: None },
+        }
+    }
+
+    /// Piece of a file, clamped to its end; the validator is the one of the whole file,
+    /// so the client notices if the file changed between two pieces
+    pub fn chunk(title: String, extension: String, bytes: &[u8], range: ByteRange, encoding: Encoding) -> FileMetaData {
+        let start = (range.offset as usize).min(bytes.len());
+        let end = start.saturating_add(range.length as usize).min(bytes.len());
+        let mut file = FileMetaData::new(title, extension, bytes[start..end].to_vec(), encoding);
+        file.validator = Validator::of(bytes);
+        file.range = Some(ByteRange { offset: start as u64, length: (end - start) as u64 });
+        file
+    }
+
+    /// Bytes of the file, whichever encoding it travelled with
+    pub fn into_bytes(self) -> Result<Vec<u8>, String> {
+        match self.raw {
+            Some(raw) => Ok(raw),
+            None => BASE64.decode(self.content).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl Validator {
+    /// Size and FNV-1a hash of the bytes, the same on every run
+    pub fn of(bytes: &[u8]) -> Validator {
+        let checksum = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+        Validator { size: bytes.len() as u64, checksum }
+    }
+}
+
+impl Message {
+    fn file_mut(&mut self) -> Option<&mut FileMetaData> {
+        match self {
+            Message::TextServer(TextServer::Text(file)) => Some(file),
+            Message::MediaServer(MediaServer::SendMedia(file)) => Some(file),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::ChatRequest(_) => ChatRequest::KIND,
+            Message::ChatResponse(_) => ChatResponse::KIND,
+            Message::WebBrowserCommands(_) => WebBrowserCommands::KIND,
+            Message::TextServer(_) => TextServer::KIND,
+            Message::MediaServer(_) => MediaServer::KIND,
+        }
+    }
+
+    /// True if a node with the `sender` role sends messages like this one
+    pub fn sent_by(&self, sender: SenderRole) -> bool {
+        match self {
+            Message::ChatRequest(_) => sender == SenderRole::ChatClient,
+            Message::ChatResponse(_) => sender == SenderRole::ChatServer, //to clients and to the other chat servers
+            Message::WebBrowserCommands(_) => sender == SenderRole::WebBrowser,
+            Message::TextServer(TextServer::ServerTypeReq) => !matches!(sender, SenderRole::ChatClient | SenderRole::WebBrowser), //every server asks the others their type
+            Message::TextServer(_) => sender == SenderRole::TextServer,
+            Message::MediaServer(_) => matches!(sender, SenderRole::MediaServer | SenderRole::TextServer), //text_max serves its medias itself
+        }
+    }
+}
+
+// Same json layout as Envelope, but borrowing the body so senders don't need to own or clone it
+#[derive(Serialize)]
+struct OutgoingEnvelope<'a, T: Payload> {
+    version: u16,
+    sender: SenderRole,
+    accepts: &'static [Encoding],
+    compressions: &'static [Compression],
+    message: OutgoingMessage<'a, T>,
+}
+
+#[derive(Serialize)]
+struct OutgoingMessage<'a, T: Payload> {
+    kind: &'static str,
+    body: &'a T,
+}
+
+// Fields read before the body, to reject a message without guessing its type
+#[derive(Deserialize)]
+struct Header {
+    version: u16,
+    message: HeaderMessage,
+}
+
+#[derive(Deserialize)]
+struct HeaderMessage {
+    kind: String,
+}
+
+/// Wraps a message in an Envelope and splits it in fragments, ordered by index
+pub fn fragment_envelope<T: Payload>(message: &T, sender: SenderRole) -> Result<Vec<Fragment>, FragmentError> {
+    fragment_compressed(message, sender, Compression::None).map(|(fragments, _)| fragments)
+}
+
+/// Same as fragment_envelope, also returning the size of the frame before and after compression
+/// A file built with Encoding::Binary travels as a binary frame:
+/// BINARY_MARKER, length of the json (u32 big endian), json envelope, raw bytes of the file
+/// With Compression::Deflate a big frame is sent as DEFLATE_MARKER followed by the deflated frame,
+/// unless deflating doesn't make it smaller
+pub fn fragment_compressed<T: Payload>(message: &T, sender: SenderRole, compression: Compression) -> Result<(Vec<Fragment>, PayloadSize), FragmentError> {
+    let envelope = OutgoingEnvelope {
+        version: PROTOCOL_VERSION,
+        sender,
+        accepts: &ACCEPTED_ENCODINGS,
+        compressions: &ACCEPTED_COMPRESSIONS,
+        message: OutgoingMessage { kind: T::KIND, body: message },
+    };
+    let json = serde_json::to_vec(&envelope).map_err(|e| FragmentError::Serialize(e.to_string()))?;
+
+    let frame = match message.file().and_then(|file| file.raw.as_ref()) {
+        Some(raw) => {
+            let mut frame = Vec::with_capacity(5 + json.len() + raw.len());
+            frame.push(BINARY_MARKER);
+            frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&json);
+            frame.extend_from_slice(raw);
+            frame
+        }
+        None => json,
+    };
+
+    let raw_size = frame.len() as u64;
+    let frame = match compression {
+        Compression::Deflate if frame.len() >= COMPRESSION_THRESHOLD => {
+            let deflated = compress_to_vec(&frame, 6);
+            if deflated.len() + 1 < frame.len() {
+                let mut compressed = Vec::with_capacity(1 + deflated.len());
+                compressed.push(DEFLATE_MARKER);
+                compressed.extend_from_slice(&deflated);
+                compressed
+            } else {
+                frame
+            }
+        }
+        _ => frame,
+    };
+    let size = PayloadSize { raw: raw_size, compressed: frame.len() as u64 };
+    Ok((fragment_bytes(&frame), size))
+}
+
+/// Reads an Envelope, checking its version and kind before deserializing the body
+/// and that its sender's role sends messages of that kind
+pub fn open_envelope(bytes: &[u8]) -> Result<Envelope, FragmentError> {
+    if let Some((&DEFLATE_MARKER, deflated)) = bytes.split_first() {
+        let frame = decompress_to_vec_with_limit(deflated, MAX_INFLATED)
+            .map_err(|e| FragmentError::Inflate(format!("{:?}", e.status)))?;
+        // a frame is deflated once, a second marker means a corrupted or hostile message
+        if frame.first() == Some(&DEFLATE_MARKER) {
+            return Err(FragmentError::InvalidFrame("compressed twice".to_string()));
+        }
+        return open_envelope(&frame);
+    }
+    let (json, raw) = match bytes.split_first() {
+        Some((&BINARY_MARKER, frame)) => {
+            if frame.len() < 4 {
+                return Err(FragmentError::InvalidFrame("missing json length".to_string()));
+            }
+            let (length, rest) = frame.split_at(4);
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            if rest.len() < length {
+                return Err(FragmentError::InvalidFrame(format!("json length {} but only {} bytes", length, rest.len())));
+            }
+            let (json, raw) = rest.split_at(length);
+            (json, Some(raw))
+        }
+        _ => (bytes, None),
+    };
+
+    let header: Header = decode(json).map_err(|e| match e {
+        FragmentError::Deserialize(err) => FragmentError::NotAnEnvelope(err),
+        other => other,
+    })?;
+    if header.version != PROTOCOL_VERSION {
+        return Err(FragmentError::UnsupportedVersion(header.version));
+    }
+    if !KINDS.contains(&header.message.kind.as_str()) {
+        return Err(FragmentError::UnknownKind(header.message.kind));
+    }
+
+    let mut envelope: Envelope = decode(json)?;
+    if !envelope.message.sent_by(envelope.sender) {
+        return Err(FragmentError::WrongSender { kind: envelope.message.kind().to_string(), sender: envelope.sender });
+    }
+    if let Some(raw) = raw {
+        match envelope.message.file_mut() {
+            Some(file) => file.raw = Some(raw.to_vec()),
+            None => return Err(FragmentError::InvalidFrame(format!("{} messages carry no file", envelope.message.kind()))),
+        }
+    }
+    Ok(envelope)
+}
+
+/// Collects the fragments of a single session until the message is complete
+/// Every node (clients, fillo and max servers) keeps one per (sender, session)
+#[derive(Debug, Clone)]
+pub struct Reassembler {
+    total: u64,
+    chunks: Vec<Option<Chunk>>,
+    received: u64,
+    duplicates: u64,
+}
+
+impl Reassembler {
+    /// The total comes from the first fragment of the session, it is checked before allocating anything
+    pub fn new(total: u64) -> Result<Reassembler, FragmentError> {
+        if total == 0 || total > MAX_FRAGMENTS {
+            return Err(FragmentError::InvalidTotal(total));
+        }
+        Ok(Reassembler {
+            total,
+            chunks: vec![None; total as usize],
+            received: 0,
+            duplicates: 0,
+        })
+    }
+
+    /// Reassembler of the session `key` in `buffers`, started by the first fragment that arrives
+    pub fn entry<K: Hash + Eq>(buffers: &mut HashMap<K, Reassembler>, key: K, total: u64) -> Result<&mut Reassembler, FragmentError> {
+        match buffers.entry(key) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(Reassembler::new(total)?)),
+        }
+    }
+
+    /// Stores a fragment, returns Ok(true) once every fragment of the message is there
+    /// A duplicate is counted and reported but leaves the stored data untouched
+    pub fn insert(&mut self, fragment: &Fragment) -> Result<bool, FragmentError> {
+        let index = fragment.fragment_index;
+        if fragment.total_n_fragments != self.total {
+            return Err(FragmentError::TotalMismatch { expected: self.total, found: fragment.total_n_fragments });
+        }
+        if index >= self.total {
+            return Err(FragmentError::OutOfRange { index, total: self.total });
+        }
+        if fragment.length as usize > FRAGMENT_DSIZE {
+            return Err(FragmentError::InvalidLength { index, length: fragment.length });
+        }
+
+        let slot = &mut self.chunks[index as usize];
+        if slot.is_some() {
+            self.duplicates += 1;
+            return Err(FragmentError::Duplicate(index));
+        }
+        *slot = Some((fragment.data, fragment.length));
+        self.received += 1;
+        Ok(self.is_complete())
+    }
+
+    /// Number of fragments that arrived more than once
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.total
+    }
+
+    pub fn missing_count(&self) -> u64 {
+        self.total - self.received
+    }
+
+    /// Indexes still to be received, in increasing order
+    pub fn missing(&self) -> Vec<u64> {
+        self.chunks.iter().enumerate()
+            .filter(|(_, chunk)| chunk.is_none())
+            .map(|(index, _)| index as u64)
+            .collect()
+    }
+
+    /// Bytes of the whole message, in fragment order
+    pub fn bytes(&self) -> Result<Vec<u8>, FragmentError> {
+        if !self.is_complete() {
+            return Err(FragmentError::Incomplete { missing: self.missing_count() });
+        }
+        let mut bytes = Vec::with_capacity(self.chunks.len() * FRAGMENT_DSIZE);
+        for (data, length) in self.chunks.iter().flatten() {
+            bytes.extend_from_slice(&data[..*length as usize]);
+        }
+        Ok(bytes)
+    }
+
+    /// Opens the Envelope carried by the fragments
+    pub fn envelope(&self) -> Result<Envelope, FragmentError> {
+        open_envelope(&self.bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use wg_2024::packet::FRAGMENT_DSIZE;
+    use crate::common_data::common::{ChatRequest, Compression, Encoding, FileMetaData, MediaServer, Message, MessageChat, SenderRole, TextServer, WebBrowserCommands, PROTOCOL_VERSION};
+    use super::{fragment_bytes, fragment_compressed, fragment_envelope, open_envelope, FragmentError, Reassembler, MAX_FRAGMENTS};
+
+    fn long_request() -> ChatRequest {
+        ChatRequest::SendMessage(MessageChat::new("a".repeat(3 * FRAGMENT_DSIZE), 10, 11), 20)
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let mut fragments = fragment_envelope(&long_request(), SenderRole::ChatClient).unwrap();
+        assert!(fragments.len() > 3);
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::new(fragments[0].total_n_fragments).unwrap();
+        for fragment in &fragments {
+            reassembler.insert(fragment).unwrap();
+        }
+        assert!(reassembler.is_complete());
+        let envelope = reassembler.envelope().unwrap();
+        assert_eq!(envelope.version, PROTOCOL_VERSION);
+        assert_eq!(envelope.sender, SenderRole::ChatClient);
+        match envelope.message {
+            Message::ChatRequest(request) => {
+                assert_eq!(serde_json::to_string(&request).unwrap(), serde_json::to_string(&long_request()).unwrap());
+            },
+            other => panic!("got a {} message", other.kind()),
+        }
+    }
+
+    #[test]
+    fn skips_duplicates_and_reports_missing() {
+        let fragments = fragment_bytes(&[7u8; 4 * FRAGMENT_DSIZE + 1]);
+        let mut reassembler = Reassembler::new(5).unwrap();
+
+        assert_eq!(reassembler.insert(&fragments[1]), Ok(false));
+        assert_eq!(reassembler.insert(&fragments[1]), Err(FragmentError::Duplicate(1)));
+        assert_eq!(reassembler.insert(&fragments[3]), Ok(false));
+        assert_eq!(reassembler.duplicates(), 1);
+        assert_eq!(reassembler.missing(), vec![0, 2, 4]);
+        assert_eq!(reassembler.bytes(), Err(FragmentError::Incomplete { missing: 3 }));
+
+        for index in [0, 2] {
+            reassembler.insert(&fragments[index]).unwrap();
+        }
+        assert_eq!(reassembler.insert(&fragments[4]), Ok(true));
+        assert_eq!(reassembler.bytes().unwrap().len(), 4 * FRAGMENT_DSIZE + 1);
+    }
+
+    #[test]
+    fn rejects_fragments_of_another_message() {
+        let mut fragments = fragment_bytes(&[1u8; 2 * FRAGMENT_DSIZE]);
+        let mut reassembler = Reassembler::new(2).unwrap();
+
+        fragments[0].total_n_fragments = 3;
+        assert_eq!(reassembler.insert(&fragments[0]), Err(FragmentError::TotalMismatch { expected: 2, found: 3 }));
+
+        fragments[1].fragment_index = 2;
+        assert_eq!(reassembler.insert(&fragments[1]), Err(FragmentError::OutOfRange { index: 2, total: 2 }));
+        assert_eq!(reassembler.missing_count(), 2);
+    }
+
+    #[test]
+    fn rejects_forged_totals_before_allocating() {
+        assert_eq!(Reassembler::new(0).unwrap_err(), FragmentError::InvalidTotal(0));
+        assert_eq!(Reassembler::new(u64::MAX).unwrap_err(), FragmentError::InvalidTotal(u64::MAX));
+        assert!(Reassembler::new(1).is_ok());
+
+        let mut buffers = HashMap::new();
+        assert!(Reassembler::entry(&mut buffers, (1u8, 0u64), MAX_FRAGMENTS + 1).is_err());
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_envelopes() {
+        let unknown_kind = br#"{"version":1,"sender":"ChatClient","message":{"kind":"Telepathy","body":null}}"#;
+        assert_eq!(open_envelope(unknown_kind).unwrap_err(), FragmentError::UnknownKind("Telepathy".to_string()));
+
+        let newer_version = br#"{"version":2,"sender":"ChatClient","message":{"kind":"ChatRequest","body":"ServerType"}}"#;
+        assert_eq!(open_envelope(newer_version).unwrap_err(), FragmentError::UnsupportedVersion(2));
+
+        // a bare message, as sent before the envelope existed
+        let bare = serde_json::to_vec(&ChatRequest::ServerType).unwrap();
+        assert!(matches!(open_envelope(&bare), Err(FragmentError::NotAnEnvelope(_))));
+    }
+
+    #[test]
+    fn rejects_messages_their_sender_never_sends() {
+        let forged = br#"{"version":1,"sender":"ChatClient","message":{"kind":"ChatResponse","body":{"ServerTypeChat":"CommunicationServer"}}}"#;
+        assert_eq!(open_envelope(forged).unwrap_err(), FragmentError::WrongSender { kind: "ChatResponse".to_string(), sender: SenderRole::ChatClient });
+
+        // every server asks the others their type, text_max also answers as a media server
+        for sender in [SenderRole::ChatServer, SenderRole::TextServer, SenderRole::MediaServer] {
+            let fragments = fragment_envelope(&TextServer::ServerTypeReq, sender).unwrap();
+            let bytes: Vec<u8> = fragments.iter().flat_map(|f| f.data[..f.length as usize].to_vec()).collect();
+            assert!(open_envelope(&bytes).is_ok(), "ServerTypeReq from a {:?}", sender);
+        }
+        assert!(Message::MediaServer(MediaServer::SendPath(Vec::new())).sent_by(SenderRole::TextServer));
+        assert!(!Message::TextServer(TextServer::ServerTypeReq).sent_by(SenderRole::WebBrowser));
+        assert!(!Message::WebBrowserCommands(WebBrowserCommands::GetList).sent_by(SenderRole::ChatServer));
+    }
+
+    #[test]
+    fn binary_files_need_fewer_fragments() {
+        let image: Vec<u8> = (0..20 * FRAGMENT_DSIZE).map(|i| (i % 251) as u8).collect();
+        let as_json = MediaServer::SendMedia(FileMetaData::new("cat".to_string(), "png".to_string(), image.clone(), Encoding::Json));
+        let as_binary = MediaServer::SendMedia(FileMetaData::new("cat".to_string(), "png".to_string(), image.clone(), Encoding::Binary));
+
+        let json_fragments = fragment_envelope(&as_json, SenderRole::MediaServer).unwrap();
+        let binary_fragments = fragment_envelope(&as_binary, SenderRole::MediaServer).unwrap();
+        assert!(binary_fragments.len() < json_fragments.len());
+
+        for fragments in [json_fragments, binary_fragments] {
+            let mut reassembler = Reassembler::new(fragments.len() as u64).unwrap();
+            for fragment in &fragments {
+                reassembler.insert(fragment).unwrap();
+            }
+            match reassembler.envelope().unwrap().message {
+                Message::MediaServer(MediaServer::SendMedia(file)) => {
+                    assert_eq!(file.title, "cat");
+                    assert_eq!(file.into_bytes().unwrap(), image);
+                },
+                other => panic!("got a {} message", other.kind()),
+            }
+        }
+    }
+
+    #[test]
+    fn answers_in_json_to_nodes_without_binary() {
+        // envelopes sent before the encodings existed have no "accepts"
+        let old_request = br#"{"version":1,"sender":"WebBrowser","message":{"kind":"WebBrowserCommands","body":"GetList"}}"#;
+        let envelope = open_envelope(old_request).unwrap();
+        assert_eq!(Encoding::negotiate(&envelope.accepts), Encoding::Json);
+        assert_eq!(Compression::negotiate(&envelope.compressions), Compression::None);
+
+        let fragments = fragment_envelope(&WebBrowserCommands::GetList, SenderRole::WebBrowser).unwrap();
+        let mut reassembler = Reassembler::new(fragments.len() as u64).unwrap();
+        for fragment in &fragments {
+            reassembler.insert(fragment).unwrap();
+        }
+        let envelope = reassembler.envelope().unwrap();
+        assert_eq!(Encoding::negotiate(&envelope.accepts), Encoding::Binary);
+        assert_eq!(Compression::negotiate(&envelope.compressions), Compression::Deflate);
+    }
+
+    #[test]
+    fn compresses_large_texts() {
+        let canto = "Nel mezzo del cammin di nostra vita\nmi ritrovai per una selva oscura,\n".repeat(200).into_bytes();
+        let text = TextServer::Text(FileMetaData::new("inferno".to_string(), "txt".to_string(), canto.clone(), Encoding::Binary));
+
+        let (plain, plain_size) = fragment_compressed(&text, SenderRole::TextServer, Compression::None).unwrap();
+        let (deflated, size) = fragment_compressed(&text, SenderRole::TextServer, Compression::Deflate).unwrap();
+        assert_eq!(plain_size.raw, plain_size.compressed);
+        assert_eq!(size.raw, plain_size.raw);
+        assert!(size.compressed < size.raw / 4);
+        assert!(deflated.len() < plain.len() / 4);
+
+        let mut reassembler = Reassembler::new(deflated.len() as u64).unwrap();
+        for fragment in &deflated {
+            reassembler.insert(fragment).unwrap();
+        }
+        match reassembler.envelope().unwrap().message {
+            Message::TextServer(TextServer::Text(file)) => assert_eq!(file.into_bytes().unwrap(), canto),
+            other => panic!("got a {} message", other.kind()),
+        }
+
+        // small messages aren't worth compressing
+        let (_, size) = fragment_compressed(&WebBrowserCommands::GetList, SenderRole::WebBrowser, Compression::Deflate).unwrap();
+        assert_eq!(size.raw, size.compressed);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use crate::common_data::common::MediaId;
+
+/// Piece of a hypertext document, in the order it is shown
+#[derive(Debug, Clone, PartialEq)]
+pub enum Block {
+    Text(String),
+    Media { id: MediaId, caption: String },
+}
+
+/// Text file served by a text server, where a line like `![caption](media_id)` embeds a media
+/// by its id and every other line is plain text
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Document {
+    pub blocks: Vec<Block>,
+}
+
+impl Document {
+    pub fn parse(source: &str) -> Document {
+        let mut blocks = Vec::new();
+        let mut text: Vec<&str> = Vec::new();
+        for line in source.lines() {
+            match media_link(line) {
+                Some((caption, id)) => {
+                    if !text.is_empty() {
+                        blocks.push(Block::Text(text.join("\n")));
+                        text.clear();
+                    }
+                    blocks.push(Block::Media { id, caption });
+                }
+                None => text.push(line),
+            }
+        }
+        if !text.is_empty() {
+            blocks.push(Block::Text(text.join("\n")));
+        }
+        Document { blocks }
+    }
+
+    /// Medias embedded in the document, each once, in the order they appear
+    pub fn media(&self) -> Vec<MediaId> {
+        let mut media: Vec<MediaId> = Vec::new();
+        for block in &self.blocks {
+            if let Block::Media { id, .. } = block {
+                if !media.contains(id) {
+                    media.push(id.clone());
+                }
+            }
+        }
+        media
+    }
+
+    /// A document without medias is plain text
+    pub fn is_hypertext(&self) -> bool {
+        self.blocks.iter().any(|block| matches!(block, Block::Media { .. }))
+    }
+}
+
+fn media_link(line: &str) -> Option<(String, MediaId)> {
+    let (caption, id) = line.trim().strip_prefix("![")?.strip_suffix(')')?.split_once("](")?;
+    if id.trim().is_empty() {
+        return None;
+    }
+    Some((caption.to_string(), id.trim().to_string()))
+}
+
+/// Document fetched by a web browser together with its medias
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub path: String, //where the document was saved
+    pub document: Document,
+    pub media: HashMap<MediaId, Result<String, String>>, //media id -> where it was saved, or why it couldn't be fetched
+}
+
+impl Page {
+    pub fn new(path: String, document: Document) -> Page {
+        Page { path, document, media: HashMap::new() }
+    }
+
+    /// Stores the outcome of the fetch of a media, ignored if the document doesn't embed it
+    pub fn attach(&mut self, media_id: &str, saved: Result<String, String>) {
+        if self.document.media().iter().any(|id| id == media_id) {
+            self.media.insert(media_id.to_string(), saved);
+        }
+    }
+
+    /// The medias still missing won't arrive anymore, they count as failed
+    pub fn give_up(&mut self, reason: &str) {
+        for id in self.document.media() {
+            self.media.entry(id).or_insert_with(|| Err(reason.to_string()));
+        }
+    }
+
+    /// Every media embedded arrived or failed
+    pub fn is_loaded(&self) -> bool {
+        self.document.media().iter().all(|id| self.media.contains_key(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, Document, Page};
+
+    #[test]
+    fn media_lines_split_the_text() {
+        let document = Document::parse("Planets\n![Red planet](mars.png)\nfirst line\nsecond line\n  ![](earth.png)  \n![broken](\n![twice](mars.png)");
+
+        assert_eq!(document.blocks, vec![
+            Block::Text("Planets".to_string()),
+            Block::Media { id: "mars.png".to_string(), caption: "Red planet".to_string() },
+            Block::Text("first line\nsecond line".to_string()),
+            Block::Media { id: "earth.png".to_string(), caption: String::new() },
+            Block::Text("![broken](".to_string()),
+            Block::Media { id: "mars.png".to_string(), caption: "twice".to_string() },
+        ]);
+        assert_eq!(document.media(), vec!["mars.png".to_string(), "earth.png".to_string()]);
+        assert!(document.is_hypertext());
+        assert!(!Document::parse("Nel mezzo del cammin di nostra vita").is_hypertext());
+    }
+
+    #[test]
+    fn page_is_loaded_once_every_media_arrived() {
+        let mut page = Page::new("page.txt".to_string(), Document::parse("![](mars.png)\n![](earth.png)"));
+        assert!(!page.is_loaded());
+
+        page.attach("moon.png", Ok("moon.png".to_string())); //not in the document
+        page.attach("mars.png", Ok("SC/mars.png".to_string()));
+        assert!(!page.is_loaded());
+        assert!(!page.media.contains_key("moon.png"));
+
+        // a media that can't be fetched doesn't keep the page waiting
+        page.attach("earth.png", Err("not listed by any text server".to_string()));
+        assert!(page.is_loaded());
+    }
+
+    #[test]
+    fn a_page_given_up_fails_only_the_missing_medias() {
+        let mut page = Page::new("page.txt".to_string(), Document::parse("![](mars.png)\n![](earth.png)"));
+        page.attach("mars.png", Ok("SC/mars.png".to_string()));
+        page.give_up("no server answered in time");
+
+        assert!(page.is_loaded());
+        assert_eq!(page.media["mars.png"], Ok("SC/mars.png".to_string()));
+        assert_eq!(page.media["earth.png"], Err("no server answered in time".to_string()));
+    }
+}
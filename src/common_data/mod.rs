@@ -1 +1,5 @@
-pub mod common;
\ No newline at end of file
+pub mod common;
+pub mod fragmentation;
+pub mod hypertext;
+pub mod search;
+pub mod sessions;
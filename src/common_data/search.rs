@@ -0,0 +1,144 @@
+use crate::common_data::common::{FileKind, SearchHit, SearchQuery};
+
+/// Lines of a text file reported for a content search, the others are only counted out
+pub const MAX_LINES: usize = 20;
+
+impl FileKind {
+    /// Text servers hold only .txt files, everything else is a media
+    pub fn of(name: &str) -> FileKind {
+        if name.ends_with(".txt") { FileKind::Text } else { FileKind::Media }
+    }
+}
+
+impl SearchQuery {
+    /// Query typed in a search box: `ext:png` and `type:text`/`type:media` filter the files,
+    /// a phrase in double quotes is searched inside the text files, every other word must be in the name
+    pub fn parse(input: &str) -> SearchQuery {
+        let mut query = SearchQuery::default();
+        let mut phrases = Vec::new();
+        for (i, part) in input.split('"').enumerate() {
+            if i % 2 == 1 { //between quotes
+                if !part.trim().is_empty() {
+                    phrases.push(part.trim());
+                }
+                continue;
+            }
+            for word in part.split_whitespace() {
+                if let Some(extension) = word.strip_prefix("ext:") {
+                    query.extension = Some(extension.trim_start_matches('.').to_lowercase());
+                } else if let Some(kind) = word.strip_prefix("type:") {
+                    query.kind = match kind.to_lowercase().as_str() {
+                        "text" => Some(FileKind::Text),
+                        "media" => Some(FileKind::Media),
+                        _ => query.kind,
+                    };
+                } else {
+                    query.keywords.push(word.to_lowercase());
+                }
+            }
+        }
+        if !phrases.is_empty() {
+            query.content = Some(phrases.join(" "));
+        }
+        query
+    }
+
+    pub fn matches_name(&self, name: &str) -> bool {
+        let lower = name.to_lowercase();
+        let extension = lower.rsplit_once('.').map(|(_, extension)| extension).unwrap_or("");
+        self.extension.as_ref().map_or(true, |wanted| wanted.to_lowercase() == extension)
+            && self.kind.map_or(true, |kind| kind == FileKind::of(&lower))
+            && self.keywords.iter().all(|keyword| lower.contains(&keyword.to_lowercase()))
+    }
+
+    /// Hit for the file `name`, `bytes` are its content when the server holds it;
+    /// with a content search only the text files holding the phrase match
+    pub fn search(&self, name: &str, bytes: Option<&[u8]>) -> Option<SearchHit> {
+        if !self.matches_name(name) {
+            return None;
+        }
+        let mut lines = Vec::new();
+        if let Some(phrase) = &self.content {
+            let text = String::from_utf8_lossy(bytes.filter(|_| FileKind::of(name) == FileKind::Text)?);
+            let phrase = phrase.to_lowercase();
+            lines = text.lines().enumerate()
+                .filter(|(_, line)| line.to_lowercase().contains(&phrase))
+                .take(MAX_LINES)
+                .map(|(number, line)| (number + 1, line.trim().to_string()))
+                .collect();
+            if lines.is_empty() {
+                return None;
+            }
+        }
+        Some(SearchHit {
+            name: name.to_string(),
+            mime: mime_type(name).to_string(),
+            size: bytes.map(|bytes| bytes.len() as u64),
+            lines,
+        })
+    }
+}
+
+pub fn mime_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension.to_lowercase()).as_deref() {
+        Some("txt") => "text/plain",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common_data::common::{FileKind, SearchQuery};
+    use super::{mime_type, MAX_LINES};
+
+    const INFERNO: &[u8] = b"Nel mezzo del cammin di nostra vita\nmi ritrovai per una selva oscura,\nche la diritta via era smarrita.";
+
+    #[test]
+    fn search_box_is_parsed_into_filters() {
+        let query = SearchQuery::parse("Divina ext:.TXT type:text \"selva oscura\" commedia");
+        assert_eq!(query.extension, Some("txt".to_string()));
+        assert_eq!(query.kind, Some(FileKind::Text));
+        assert_eq!(query.keywords, vec!["divina".to_string(), "commedia".to_string()]);
+        assert_eq!(query.content, Some("selva oscura".to_string()));
+
+        assert_eq!(SearchQuery::parse("   "), SearchQuery::default());
+    }
+
+    #[test]
+    fn names_match_every_filter() {
+        let query = SearchQuery::parse("MARS type:media");
+        assert!(query.matches_name("mars.png"));
+        assert!(!query.matches_name("earth.png"));
+        assert!(!query.matches_name("mars.txt"));
+
+        assert!(SearchQuery::parse("ext:png").matches_name("moon.PNG"));
+        assert!(SearchQuery::default().matches_name("anything"));
+    }
+
+    #[test]
+    fn content_is_searched_only_in_texts_held() {
+        let query = SearchQuery::parse("\"SELVA OSCURA\"");
+        let hit = query.search("inferno.txt", Some(INFERNO)).expect("phrase not found");
+        assert_eq!(hit.lines, vec![(2, "mi ritrovai per una selva oscura,".to_string())]);
+        assert_eq!(hit.size, Some(INFERNO.len() as u64));
+        assert_eq!(hit.mime, "text/plain");
+
+        assert_eq!(query.search("inferno.txt", None), None); //held by another server, it can't be read
+        assert_eq!(query.search("inferno.png", Some(INFERNO)), None);
+        assert_eq!(SearchQuery::parse("\"paradiso\"").search("inferno.txt", Some(INFERNO)), None);
+
+        let many = "selva\n".repeat(MAX_LINES * 2);
+        let hit = SearchQuery::parse("\"selva\"").search("selve.txt", Some(many.as_bytes())).unwrap();
+        assert_eq!(hit.lines.len(), MAX_LINES);
+    }
+
+    #[test]
+    fn medias_known_by_name_have_no_size() {
+        let hit = SearchQuery::parse("earth").search("earth.png", None).unwrap();
+        assert_eq!((hit.mime.as_str(), hit.size), ("image/png", None));
+        assert_eq!(mime_type("notes"), "application/octet-stream");
+    }
+}
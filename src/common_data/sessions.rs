@@ -0,0 +1,366 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use wg_2024::packet::FRAGMENT_DSIZE;
+use crate::common_data::common::{SessionKey, SessionState};
+
+/// An open session without fragments, acks or nacks for this long is expired
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes a node may keep buffered in open sessions, the least recently active ones are evicted past it
+pub const MAX_SESSION_BYTES: usize = 64 * 1024 * 1024;
+/// Closed sessions are remembered this long, so late or duplicated fragments don't reopen them
+pub const LINGER: Duration = Duration::from_secs(30);
+/// Incoming sessions remembered one by one for every sender once forgotten, past it the oldest ones only count as replays
+pub const MAX_FORGOTTEN: usize = 1024;
+/// sweep() does nothing if called more often than this, report_duplicates() reports at most this often
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes buffered by a session made of `fragments` fragments
+pub fn session_bytes(fragments: u64) -> usize {
+    (fragments as usize).saturating_mul(FRAGMENT_DSIZE)
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    state: SessionState,
+    last_activity: Instant,
+    bytes: usize,
+}
+
+/// Incoming sessions of a sender already forgotten: every id below `below`, and the ones in `ids`
+#[derive(Debug, Clone, Default)]
+struct Forgotten {
+    below: u64,
+    ids: BTreeSet<u64>,
+}
+
+impl Forgotten {
+    fn contains(&self, session_id: u64) -> bool {
+        session_id < self.below || self.ids.contains(&session_id)
+    }
+
+    fn insert(&mut self, session_id: u64) {
+        if session_id >= self.below {
+            self.ids.insert(session_id);
+        }
+        while self.ids.len() > MAX_FORGOTTEN { //too many holes: the oldest ones are given up
+            if let Some(oldest) = self.ids.pop_first() {
+                self.below = oldest + 1;
+            }
+        }
+        while self.ids.remove(&self.below) { //the lowest ids are contiguous, only the watermark is kept
+            self.below += 1;
+        }
+    }
+}
+
+/// State machine of every session of a node (clients, fillo and max servers):
+/// Open -> Complete | Failed | Expired, the node drops its buffers when a session leaves Open
+#[derive(Debug, Clone)]
+pub struct SessionTable {
+    sessions: HashMap<SessionKey, Session>,
+    idle_timeout: Duration,
+    max_bytes: usize,
+    open_bytes: usize,
+    last_sweep: Option<Instant>,
+    closed: Vec<(SessionKey, SessionState)>, //closed by start() or fail(), handed over by the next sweep_into()
+    forgotten: HashMap<NodeId, Forgotten>, //incoming sessions forgotten for every sender, any of them arriving again is a replay
+    duplicates: u64,                 //fragments discarded because already received, or of a session already closed
+    reported: (u64, Option<Instant>),
+}
+
+impl Default for SessionTable {
+    fn default() -> Self {
+        SessionTable::new(IDLE_TIMEOUT, MAX_SESSION_BYTES)
+    }
+}
+
+impl SessionTable {
+    pub fn new(idle_timeout: Duration, max_bytes: usize) -> Self {
+        SessionTable {
+            sessions: HashMap::new(),
+            idle_timeout,
+            max_bytes,
+            open_bytes: 0,
+            last_sweep: None,
+            closed: Vec::new(),
+            forgotten: HashMap::new(),
+            duplicates: 0,
+            reported: (0, None),
+        }
+    }
+
+    /// None for sessions never seen. An incoming session forgotten after LINGER is still Complete
+    pub fn state(&self, key: SessionKey) -> Option<SessionState> {
+        if let Some(session) = self.sessions.get(&key) {
+            return Some(session.state);
+        }
+        match key {
+            SessionKey::Incoming(src, session_id) if self.forgotten.get(&src).is_some_and(|forgotten| forgotten.contains(session_id)) => Some(SessionState::Complete),
+            _ => None,
+        }
+    }
+
+    /// Opens a session holding `bytes`, returns the sessions evicted to make room for it
+    /// A session bigger than the whole limit is failed right away and is part of the result,
+    /// so is the session it replaces if the key was still open
+    pub fn open(&mut self, key: SessionKey, bytes: usize, now: Instant) -> Vec<(SessionKey, SessionState)> {
+        let mut evicted = Vec::new();
+        if self.sessions.get(&key).is_some_and(|session| session.state == SessionState::Open) {
+            self.close(key, SessionState::Failed, now); //reopened without completing: don't count it twice
+            evicted.push((key, SessionState::Failed));
+        }
+        self.sessions.insert(key, Session { state: SessionState::Open, last_activity: now, bytes });
+        self.open_bytes += bytes;
+
+        if bytes > self.max_bytes {
+            self.close(key, SessionState::Failed, now);
+            evicted.push((key, SessionState::Failed));
+            return evicted;
+        }
+        while self.open_bytes > self.max_bytes {
+            let oldest = self.sessions.iter()
+                .filter(|(other, session)| **other != key && session.state == SessionState::Open)
+                .min_by_key(|(other, session)| (session.last_activity, **other))
+                .map(|(other, _)| *other);
+            match oldest {
+                Some(oldest) => {
+                    self.close(oldest, SessionState::Failed, now);
+                    evicted.push((oldest, SessionState::Failed));
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Opens the session of a node made of `fragments` fragments, false if it can't fit in memory
+    /// The sessions closed to make room for it are handed over by the next sweep_into()
+    pub fn start(&mut self, key: SessionKey, fragments: u64, now: Instant) -> bool {
+        let evicted = self.open(key, session_bytes(fragments), now);
+        self.closed.extend(evicted);
+        self.state(key) == Some(SessionState::Open)
+    }
+
+    /// Something arrived for an open session, its idle timer starts over
+    pub fn touch(&mut self, key: SessionKey, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(&key) {
+            if session.state == SessionState::Open {
+                session.last_activity = now;
+            }
+        }
+    }
+
+    /// Closes an open session that will never complete, e.g. a fragment nacked too many times:
+    /// like the evicted ones it's handed over by the next sweep_into()
+    pub fn fail(&mut self, key: SessionKey, now: Instant) {
+        if self.state(key) == Some(SessionState::Open) {
+            self.close(key, SessionState::Failed, now);
+            self.closed.push((key, SessionState::Failed));
+        }
+    }
+
+    /// Moves an open session to Complete, Failed or Expired, it lingers for LINGER before being forgotten
+    pub fn close(&mut self, key: SessionKey, state: SessionState, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(&key) {
+            if session.state == SessionState::Open {
+                self.open_bytes -= session.bytes;
+                session.state = state;
+                session.last_activity = now;
+            }
+        }
+    }
+
+    /// Expires the open sessions idle for too long and forgets the closed ones past LINGER
+    /// Returns the sessions just expired, sorted, so the node can drop their buffers and report them
+    pub fn sweep(&mut self, now: Instant) -> Vec<(SessionKey, SessionState)> {
+        if self.last_sweep.is_some_and(|last| now.duration_since(last) < SWEEP_INTERVAL) {
+            return Vec::new();
+        }
+        self.last_sweep = Some(now);
+
+        let mut expired: Vec<SessionKey> = self.sessions.iter()
+            .filter(|(_, session)| session.state == SessionState::Open && now.duration_since(session.last_activity) >= self.idle_timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        expired.sort();
+        for key in &expired {
+            self.close(*key, SessionState::Expired, now);
+        }
+        let forgotten = &mut self.forgotten;
+        self.sessions.retain(|key, session| {
+            let keep = session.state == SessionState::Open || now.duration_since(session.last_activity) < LINGER;
+            if let (false, SessionKey::Incoming(src, session_id)) = (keep, key) {
+                forgotten.entry(*src).or_default().insert(*session_id);
+            }
+            keep
+        });
+        expired.into_iter().map(|key| (key, SessionState::Expired)).collect()
+    }
+
+    /// Sweeps the table and hands to `report` every session closed by start() or fail() or just expired,
+    /// for the node to drop its buffers and report it; a session opened again meanwhile is skipped,
+    /// the buffers under its key belong to the new one. Returns the duplicates to report, if due
+    pub fn sweep_into(&mut self, now: Instant, mut report: impl FnMut(SessionKey, SessionState)) -> Option<u64> {
+        let expired = self.sweep(now);
+        for (key, state) in std::mem::take(&mut self.closed).into_iter().chain(expired) {
+            if self.state(key) != Some(SessionState::Open) {
+                report(key, state);
+            }
+        }
+        self.report_duplicates(now)
+    }
+
+    /// Counts a fragment discarded because it was already received, or because its session is closed
+    pub fn duplicate(&mut self) {
+        self.duplicates += 1;
+    }
+
+    /// Total of duplicates discarded so far, if it changed since the last report and SWEEP_INTERVAL passed
+    pub fn report_duplicates(&mut self, now: Instant) -> Option<u64> {
+        let (reported, last) = self.reported;
+        if self.duplicates == reported || last.is_some_and(|last| now.duration_since(last) < SWEEP_INTERVAL) {
+            return None;
+        }
+        self.reported = (self.duplicates, Some(now));
+        Some(self.duplicates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::common_data::common::{SessionKey, SessionState};
+    use super::{SessionTable, LINGER, MAX_FORGOTTEN, SWEEP_INTERVAL};
+
+    const IDLE: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn expires_idle_sessions_and_ignores_late_fragments() {
+        let mut sessions = SessionTable::new(IDLE, 1024);
+        let start = Instant::now();
+        let stuck = SessionKey::Incoming(3, 10);
+        let busy = SessionKey::Outgoing(11);
+        assert!(sessions.open(stuck, 128, start).is_empty());
+        assert!(sessions.open(busy, 128, start).is_empty());
+
+        sessions.touch(busy, start + IDLE / 2);
+        assert_eq!(sessions.sweep(start + IDLE), vec![(stuck, SessionState::Expired)]);
+        assert_eq!(sessions.state(busy), Some(SessionState::Open));
+
+        // a late fragment of the expired session finds it closed instead of opening it again
+        assert_eq!(sessions.state(stuck), Some(SessionState::Expired));
+        sessions.close(busy, SessionState::Complete, start + IDLE);
+        sessions.sweep(start + IDLE + LINGER);
+        assert_eq!(sessions.state(busy), None);
+        // forgotten, but still a replay; the older sessions of the same sender never seen are not
+        assert_eq!(sessions.state(stuck), Some(SessionState::Complete));
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 9)), None);
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 11)), None);
+        assert_eq!(sessions.state(SessionKey::Incoming(4, 10)), None);
+    }
+
+    #[test]
+    fn evicts_the_oldest_sessions_past_the_limit() {
+        let mut sessions = SessionTable::new(IDLE, 1024);
+        let now = Instant::now();
+        let first = SessionKey::Incoming(1, 0);
+        let second = SessionKey::Incoming(2, 0);
+        let third = SessionKey::Outgoing(0);
+        sessions.open(first, 512, now);
+        sessions.open(second, 512, now + Duration::from_millis(1));
+        assert_eq!(sessions.open(third, 512, now + Duration::from_millis(2)), vec![(first, SessionState::Failed)]);
+
+        // a session bigger than the whole limit never opens
+        let huge = SessionKey::Incoming(4, 0);
+        assert_eq!(sessions.open(huge, 4096, now), vec![(huge, SessionState::Failed)]);
+        assert_eq!(sessions.state(second), Some(SessionState::Open));
+    }
+
+    #[test]
+    fn reopening_an_open_session_displaces_it() {
+        let mut sessions = SessionTable::new(IDLE, 1024);
+        let now = Instant::now();
+        let key = SessionKey::Incoming(1, 0);
+        assert!(sessions.open(key, 512, now).is_empty());
+        assert_eq!(sessions.open(key, 512, now), vec![(key, SessionState::Failed)]);
+        assert_eq!(sessions.state(key), Some(SessionState::Open));
+        // the displaced session doesn't count against the limit anymore
+        assert!(sessions.open(SessionKey::Outgoing(0), 512, now).is_empty());
+    }
+
+    #[test]
+    fn closed_sessions_are_handed_over_by_the_sweep() {
+        let mut sessions = SessionTable::new(IDLE, 1024);
+        let start = Instant::now();
+        let first = SessionKey::Outgoing(0);
+        let second = SessionKey::Outgoing(1);
+        let huge = SessionKey::Incoming(2, 0);
+        assert!(sessions.start(first, 4, start));
+        assert!(!sessions.start(huge, 100, start)); //can't fit in memory
+        assert!(sessions.start(second, 6, start + Duration::from_millis(1))); //evicts the first one
+
+        let mut closed = Vec::new();
+        sessions.duplicate();
+        let duplicates = sessions.sweep_into(start + IDLE * 2, |key, state| closed.push((key, state)));
+        assert_eq!(closed, vec![
+            (huge, SessionState::Failed),
+            (first, SessionState::Failed),
+            (second, SessionState::Expired),
+        ]);
+        assert_eq!(duplicates, Some(1));
+
+        closed.clear();
+        sessions.sweep_into(start + IDLE * 2 + SWEEP_INTERVAL, |key, state| closed.push((key, state)));
+        assert!(closed.is_empty());
+
+        // a failed session is handed over once, without waiting for it to expire
+        let third = SessionKey::Outgoing(2);
+        sessions.start(third, 4, start);
+        sessions.fail(third, start);
+        sessions.fail(third, start);
+        sessions.sweep_into(start, |key, state| closed.push((key, state)));
+        assert_eq!(closed, vec![(third, SessionState::Failed)]);
+    }
+
+    #[test]
+    fn reports_duplicates_at_most_once_per_interval() {
+        let mut sessions = SessionTable::default();
+        let now = Instant::now();
+        assert_eq!(sessions.report_duplicates(now), None);
+        sessions.duplicate();
+        sessions.duplicate();
+        assert_eq!(sessions.report_duplicates(now), Some(2));
+        sessions.duplicate();
+        assert_eq!(sessions.report_duplicates(now + SWEEP_INTERVAL / 2), None);
+        assert_eq!(sessions.report_duplicates(now + SWEEP_INTERVAL), Some(3));
+        assert_eq!(sessions.report_duplicates(now + 2 * SWEEP_INTERVAL), None);
+    }
+
+    #[test]
+    fn forgets_exactly_the_sessions_it_saw() {
+        let mut sessions = SessionTable::new(IDLE, usize::MAX);
+        let start = Instant::now();
+        for session_id in [0, 2, 3] {
+            let key = SessionKey::Incoming(3, session_id);
+            sessions.open(key, 128, start);
+            sessions.close(key, SessionState::Complete, start);
+        }
+        sessions.sweep(start + LINGER);
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 0)), Some(SessionState::Complete));
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 1)), None); //still to arrive
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 3)), Some(SessionState::Complete));
+
+        // with too many holes the oldest ones are given up and count as replays
+        let mut now = start + LINGER;
+        for session_id in 5..(4 + MAX_FORGOTTEN as u64) {
+            let key = SessionKey::Incoming(3, session_id);
+            sessions.open(key, 128, now);
+            sessions.close(key, SessionState::Complete, now);
+            now += LINGER;
+            sessions.sweep(now);
+        }
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 1)), Some(SessionState::Complete));
+        assert_eq!(sessions.state(SessionKey::Incoming(3, 4)), None);
+    }
+}
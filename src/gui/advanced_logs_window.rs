@@ -239,6 +239,13 @@ fn log_window(
                         }
                     }
 
+                    ui.separator();
+                    let duplicates = sim_log.duplicates.get(&node.0).copied().unwrap_or_default();
+                    ui.label(format!("Duplicated fragments discarded (wasted retransmissions): {}", duplicates));
+                    if let Some((hits, misses)) = sim_log.cache_lookups.get(&node.0) {
+                        ui.label(format!("Files served from the cache: {} | downloaded: {}", hits, misses));
+                    }
+
                     ui.separator();
                     ui.label("Last sent message:");
 
@@ -7,7 +7,8 @@ pub fn spawn_butterfly(
     clients: &mut SeenClients,
 ) -> Vec<NodeConfig>
 {
-    let config = parse_config();
+    // The topology was already parsed successfully when the simulation started
+    let Ok(config) = parse_config() else { return Vec::new() };
     let horizontal_spacing = 100.0;
     let vertical_spacing = 60.0;
 
@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use bevy::prelude::*;
 use bevy::window::PrimaryWindow;
 use bevy_egui::{egui, EguiContexts};
 use crate::simulation_control::simulation_control::SimulationController;
 use wg_2024::network::NodeId;
-use crate::common_data::common::ClientType;
+use crate::common_data::common::{ClientType, HistoryEntry, MessageStatus};
 use crate::gui::login_window::Clickable;
 use crate::gui::login_window::AppState;
 
@@ -23,14 +23,31 @@ impl Plugin for ChatSystemPlugin {
 #[derive(Resource, Default)]
 pub struct ChatState {
     message_input: HashMap<NodeId, String>,
+    room_message_input: HashMap<NodeId, String>,
     active_chat_node: HashMap<NodeId, Option<NodeId>>,
     active_chat_server: HashMap<NodeId, Option<NodeId>>,
     pub registered_clients: HashMap<(NodeId, NodeId), bool>,
+    pub client_list: HashMap<(NodeId, NodeId), Vec<NodeId>>, //(client, server) -> clients registered on the server, kept live by its join/leave notifications
     pub chat_messages: HashMap<(NodeId, (NodeId, NodeId)), Vec<String>>,
     pub chat_responses: HashMap<(NodeId, (NodeId, NodeId)), Vec<String>>,
+    pub message_status: HashMap<(NodeId, (NodeId, NodeId)), Vec<MessageStatus>>, //indexed like chat_messages: clients number every conversation from 0
+    read_replies: HashMap<(NodeId, (NodeId, NodeId)), usize>, //replies already shown, the new ones get a Read receipt
+    active_tab: HashMap<NodeId, ChatTab>,
+    room_name_input: HashMap<NodeId, String>,
+    active_room: HashMap<NodeId, Option<String>>,
+    pub rooms: HashMap<(NodeId, NodeId), BTreeMap<String, Vec<NodeId>>>, //(client, server) -> rooms the client is in, with their members
+    pub room_messages: HashMap<(NodeId, (NodeId, String)), Vec<(NodeId, String)>>, //(server, (client, room)) -> (client that posted, message)
+    pub room_outcome: HashMap<(NodeId, NodeId), String>,
+    pub history: HashMap<(NodeId, (NodeId, NodeId)), Vec<HistoryEntry>>, //(server, (client, peer)) -> messages recovered from the server
     pub chat_clients: Vec<NodeId>,
     pub chat_servers: HashMap<NodeId, Vec<NodeId>>
 }
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum ChatTab {
+    #[default]
+    Direct,
+    Rooms
+}
 #[derive(Resource, Default)]
 pub struct OpenWindows {
     pub windows: Vec<(NodeId,ClientType)>,
@@ -119,162 +136,20 @@ fn display_windows(
                 window.show(contexts, |ui| {
                     ui.label(format!("This is a window for Client {}", window_id));
                     ui.separator();
-                    ui.heading("Available Clients");
-                    let available_clients = chat_state.chat_clients.iter().filter(|id| **id != window_id).cloned().collect::<Vec<u8>>();
-
-                    let active_server = chat_state.active_chat_server.get(&window_id).cloned().flatten();
-
-                    for client in available_clients {
-                        let is_registered = if let Some(server_id) = active_server {
-                            chat_state.registered_clients.get(&(client, server_id))
-                                .copied()
-                                .unwrap_or(false)
-                        } else {
-                            false
-                        };
-
-                        let button_text = format!("Chat with Client {} {}",
-                                                  client,
-                                                  if is_registered { "✓" } else { "" }
-                        );
-
-
-                        let button = ui.button(button_text);
+                    let mut tab = chat_state.active_tab.get(&window_id).copied().unwrap_or_default();
+                    ui.horizontal(|ui| {
+                        ui.selectable_value(&mut tab, ChatTab::Direct, "Direct");
+                        ui.selectable_value(&mut tab, ChatTab::Rooms, "Rooms");
+                    });
+                    chat_state.active_tab.insert(window_id, tab);
+                    ui.separator();
 
-                        if button.clicked() {
-                            if chat_state.active_chat_node.get(&window_id) == Some(&Some(client)) {
-                                chat_state.active_chat_node.insert(window_id, None);
-                            } else if is_registered {
-                                chat_state.active_chat_node.insert(window_id, Some(client));
-                            }
-                        }
+                    if tab == ChatTab::Direct {
+                        show_direct(ui, window_id, &mut sim, &mut chat_state);
+                    } else {
+                        show_rooms(ui, window_id, &mut sim, &mut chat_state);
                     }
 
-                    ui.group(|ui| {
-                        let available_width = ui.available_width().min(370.0);
-                        ui.set_max_width(available_width);
-
-                        ui.vertical(|ui| {
-                            let chat_partner = chat_state.active_chat_node.get(&window_id).cloned().flatten();
-
-                            ui.heading(
-                                if let Some(partner_id) = chat_partner {
-                                    format!("Chat with Client {}", partner_id)
-                                } else {
-                                    "Chat with None".to_string()
-                                }
-                            );
-
-                            egui::ScrollArea::vertical()
-                                .max_height(200.0)
-                                .show(ui, |ui| {
-                                    if let (Some(partner_id), Some(server_id)) = (chat_partner, active_server) {
-                                        let messages = chat_state.chat_messages.get_mut(&(server_id, (window_id, partner_id)));
-                                        let messages = match messages {
-                                            Some(m) => {
-                                                m.clone()
-                                            },
-                                            None => Vec::new(),
-                                        };
-                                        let replies = chat_state.chat_responses.get_mut(&(server_id, (partner_id, window_id)));
-                                        let replies = match replies {
-                                            Some(r) => {
-                                                r.clone()
-                                            },
-                                            None => Vec::new(),
-                                        };
-
-
-                                        if !messages.is_empty() {
-                                            for msg in messages {
-                                                ui.horizontal(|ui| {
-                                                    let text_width = available_width - 10.0;
-                                                    ui.set_max_width(text_width);
-                                                    ui.label(format!("You: {}", msg));
-                                                });
-                                            }
-                                        } else {
-                                            ui.label("No messages yet. Start the conversation!");
-                                        }
-                                        if !replies.is_empty() {
-                                            for reply in replies {
-                                                ui.horizontal(|ui| {
-                                                    let text_width = available_width - 10.0;
-                                                    ui.set_max_width(text_width);
-                                                    ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
-                                                        ui.label(format!("Client {} : {}", partner_id, reply));
-                                                    });
-                                                });
-                                            }
-                                        }
-                                    }
-                                });
-                        });
-
-                        ui.separator();
-
-
-                        let chat_partner = chat_state.active_chat_node.get(&window_id).cloned().flatten();
-                        let current_server = chat_state.active_chat_server.get(&window_id).cloned().flatten();
-
-                        let can_chat = if let (Some(partner_id), Some(server_id)) = (chat_partner, current_server) {
-                            chat_state.registered_clients.get(&(window_id, server_id)).copied().unwrap_or(false) &&
-                                chat_state.registered_clients.get(&(partner_id, server_id)).copied().unwrap_or(false)
-                        } else {
-                            false
-                        };
-
-
-                        if can_chat {
-                            let partner_id = chat_partner.unwrap();
-                            let server_id = current_server.unwrap();
-
-
-                            let current_input = chat_state.message_input.get(&window_id).cloned().unwrap_or_default();
-
-
-                            let mut input_text = current_input;
-
-                            let input_response = ui.add(
-                                egui::TextEdit::singleline(&mut input_text)
-                                    .frame(true)
-                                    .hint_text("Type your message here...")
-                                    .desired_width(ui.available_width() - 80.0)
-                            );
-
-                            chat_state.message_input.insert(window_id, input_text.clone());
-
-                            let send_button = ui.button("📨 Send");
-
-
-                            if (send_button.clicked() ||
-                                (input_response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))))
-                                && !input_text.is_empty()
-                            {
-                                sim.send_message(
-                                    input_text.clone(),
-                                    window_id,
-                                    partner_id,
-                                    server_id
-                                );
-                                if let Some(messages) = chat_state.chat_messages.get_mut(&(server_id, (window_id, partner_id))) {
-                                    messages.push(input_text.clone());
-                                } else {
-                                    let mut messages = Vec::new();
-                                    messages.push(input_text.clone());
-                                    chat_state.chat_messages.insert((server_id, (window_id, partner_id)), messages);
-                                }
-                                chat_state.message_input.insert(window_id, String::new());
-                            }
-                        } else {
-                            ui.add_enabled(false, egui::TextEdit::singleline(&mut String::new())
-                                .hint_text("Select a registered client to chat")
-                                .desired_width(ui.available_width() - 80.0));
-
-                            ui.add_enabled(false, egui::Button::new("📨 Send"));
-                        }
-                    });
-
                     ui.separator();
 
                     ui.horizontal(|ui| {
@@ -308,6 +183,12 @@ fn display_windows(
                                 sim.register_client(window_id.clone(), server_id.clone());
                             }
                         }
+                        if ui.button("Unregister").clicked() {
+                            if let Some(server_id) = chat_state.active_chat_server.get(&window_id).cloned().flatten() {
+                                sim.unregister_client(window_id, server_id);
+                                chat_state.active_chat_node.insert(window_id, None);
+                            }
+                        }
                     });
 
 
@@ -339,4 +220,307 @@ fn display_windows(
         open_windows.windows.remove(i);
     }
 
-}
\ No newline at end of file
+}
+
+/// Chat servers relay messages to each other, a client registered on any of them can be reached
+fn registered_anywhere(registered_clients: &HashMap<(NodeId, NodeId), bool>, client: NodeId) -> bool {
+    registered_clients.iter().any(|((id, _), registered)| *id == client && *registered)
+}
+
+/// Messages of the conversation the server kept, the button asks for the ones after the last recovered
+fn show_history(ui: &mut egui::Ui, window_id: NodeId, partner_id: NodeId, server_id: NodeId, sim: &mut SimulationController, chat_state: &ChatState) {
+    let history = chat_state.history.get(&(server_id, (window_id, partner_id))).cloned().unwrap_or_default();
+    ui.horizontal(|ui| {
+        if ui.button("Recover history").clicked() {
+            let since = history.last().map(|entry| entry.index + 1).unwrap_or(0);
+            sim.get_history(window_id, partner_id, server_id, since);
+        }
+        ui.label(format!("{} messages kept by Server {}", history.len(), server_id));
+    });
+    if history.is_empty() {
+        return;
+    }
+    egui::CollapsingHeader::new("History")
+        .id_salt(format!("history_{}_{}_{}", window_id, partner_id, server_id))
+        .show(ui, |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    for entry in history {
+                        if entry.message.from_id == window_id {
+                            ui.label(format!("You: {}", entry.message.content));
+                        } else {
+                            ui.label(format!("Client {} : {}", entry.message.from_id, entry.message.content));
+                        }
+                    }
+                });
+        });
+}
+
+/// Direct tab of a chat window: pick a registered client and chat with it through the selected server
+fn show_direct(ui: &mut egui::Ui, window_id: NodeId, sim: &mut SimulationController, chat_state: &mut ChatState) {
+    ui.heading("Available Clients");
+    let available_clients = chat_state.chat_clients.iter().filter(|id| **id != window_id).cloned().collect::<Vec<u8>>();
+
+    let active_server = chat_state.active_chat_server.get(&window_id).cloned().flatten();
+
+    for client in available_clients {
+        let is_registered = if let Some(server_id) = active_server {
+            chat_state.client_list.get(&(window_id, server_id))
+                .is_some_and(|clients| clients.contains(&client))
+        } else {
+            false
+        };
+        //registered on another chat server, the servers relay the messages
+        let is_federated = !is_registered && active_server.is_some() && registered_anywhere(&chat_state.registered_clients, client);
+
+        let button_text = format!("Chat with Client {} {}",
+                                  client,
+                                  if is_registered { "✓" } else if is_federated { "✓ (other server)" } else { "" }
+        );
+
+
+        let button = ui.button(button_text);
+
+        if button.clicked() {
+            if chat_state.active_chat_node.get(&window_id) == Some(&Some(client)) {
+                chat_state.active_chat_node.insert(window_id, None);
+            } else if is_registered || is_federated {
+                chat_state.active_chat_node.insert(window_id, Some(client));
+            }
+        }
+    }
+
+    ui.group(|ui| {
+        let available_width = ui.available_width().min(370.0);
+        ui.set_max_width(available_width);
+
+        ui.vertical(|ui| {
+            let chat_partner = chat_state.active_chat_node.get(&window_id).cloned().flatten();
+
+            ui.heading(
+                if let Some(partner_id) = chat_partner {
+                    format!("Chat with Client {}", partner_id)
+                } else {
+                    "Chat with None".to_string()
+                }
+            );
+
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    if let (Some(partner_id), Some(server_id)) = (chat_partner, active_server) {
+                        let messages = chat_state.chat_messages.get_mut(&(server_id, (window_id, partner_id)));
+                        let messages = match messages {
+                            Some(m) => {
+                                m.clone()
+                            },
+                            None => Vec::new(),
+                        };
+                        let replies = chat_state.chat_responses.get_mut(&(server_id, (partner_id, window_id)));
+                        let replies = match replies {
+                            Some(r) => {
+                                r.clone()
+                            },
+                            None => Vec::new(),
+                        };
+                        let statuses = chat_state.message_status.get(&(server_id, (window_id, partner_id))).cloned().unwrap_or_default();
+
+                        let read = chat_state.read_replies.entry((server_id, (partner_id, window_id))).or_insert(0);
+                        if replies.len() > *read {
+                            sim.read_messages(window_id, partner_id, server_id);
+                            *read = replies.len();
+                        }
+
+
+                        if !messages.is_empty() {
+                            for (message_id, msg) in messages.iter().enumerate() {
+                                let ticks = match statuses.get(message_id).copied().unwrap_or_default() {
+                                    MessageStatus::Sent => "",
+                                    MessageStatus::Queued => " (queued)",
+                                    MessageStatus::Delivered => " ✓",
+                                    MessageStatus::Read => " ✓✓",
+                                };
+                                ui.horizontal(|ui| {
+                                    let text_width = available_width - 10.0;
+                                    ui.set_max_width(text_width);
+                                    ui.label(format!("You: {}{}", msg, ticks));
+                                });
+                            }
+                        } else {
+                            ui.label("No messages yet. Start the conversation!");
+                        }
+                        if !replies.is_empty() {
+                            for reply in replies {
+                                ui.horizontal(|ui| {
+                                    let text_width = available_width - 10.0;
+                                    ui.set_max_width(text_width);
+                                    ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
+                                        ui.label(format!("Client {} : {}", partner_id, reply));
+                                    });
+                                });
+                            }
+                        }
+                    }
+                });
+        });
+
+        ui.separator();
+
+
+        let chat_partner = chat_state.active_chat_node.get(&window_id).cloned().flatten();
+        let current_server = chat_state.active_chat_server.get(&window_id).cloned().flatten();
+
+        let can_chat = if let (Some(partner_id), Some(server_id)) = (chat_partner, current_server) {
+            chat_state.registered_clients.get(&(window_id, server_id)).copied().unwrap_or(false) &&
+                registered_anywhere(&chat_state.registered_clients, partner_id)
+        } else {
+            false
+        };
+
+
+        if can_chat {
+            let partner_id = chat_partner.unwrap();
+            let server_id = current_server.unwrap();
+
+
+            let current_input = chat_state.message_input.get(&window_id).cloned().unwrap_or_default();
+
+
+            let mut input_text = current_input;
+
+            let input_response = ui.add(
+                egui::TextEdit::singleline(&mut input_text)
+                    .frame(true)
+                    .hint_text("Type your message here...")
+                    .desired_width(ui.available_width() - 80.0)
+            );
+
+            chat_state.message_input.insert(window_id, input_text.clone());
+
+            let send_button = ui.button("📨 Send");
+
+
+            if (send_button.clicked() ||
+                (input_response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))))
+                && !input_text.is_empty()
+            {
+                sim.send_message(
+                    input_text.clone(),
+                    window_id,
+                    partner_id,
+                    server_id
+                );
+                if let Some(messages) = chat_state.chat_messages.get_mut(&(server_id, (window_id, partner_id))) {
+                    messages.push(input_text.clone());
+                } else {
+                    let mut messages = Vec::new();
+                    messages.push(input_text.clone());
+                    chat_state.chat_messages.insert((server_id, (window_id, partner_id)), messages);
+                }
+                chat_state.message_input.insert(window_id, String::new());
+            }
+        } else {
+            ui.add_enabled(false, egui::TextEdit::singleline(&mut String::new())
+                .hint_text("Select a registered client to chat")
+                .desired_width(ui.available_width() - 80.0));
+
+            ui.add_enabled(false, egui::Button::new("📨 Send"));
+        }
+    });
+
+    let chat_partner = chat_state.active_chat_node.get(&window_id).cloned().flatten();
+    if let (Some(partner_id), Some(server_id)) = (chat_partner, active_server) {
+        show_history(ui, window_id, partner_id, server_id, sim, chat_state);
+    }
+}
+
+/// Room tab of a chat window: create or join a room of the selected server, then post in it
+fn show_rooms(ui: &mut egui::Ui, window_id: NodeId, sim: &mut SimulationController, chat_state: &mut ChatState) {
+    let Some(server_id) = chat_state.active_chat_server.get(&window_id).cloned().flatten() else {
+        ui.label("Select a server to see its rooms");
+        return;
+    };
+    if !chat_state.registered_clients.get(&(window_id, server_id)).copied().unwrap_or(false) {
+        ui.label(format!("Register to Server {} to use its rooms", server_id));
+        return;
+    }
+
+    ui.heading("Rooms");
+    let mut room_name = chat_state.room_name_input.get(&window_id).cloned().unwrap_or_default();
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut room_name).hint_text("Room name").desired_width(150.0));
+        if ui.button("Create").clicked() && !room_name.is_empty() {
+            sim.create_room(window_id, server_id, room_name.clone());
+        }
+        if ui.button("Join").clicked() && !room_name.is_empty() {
+            sim.join_room(window_id, server_id, room_name.clone());
+        }
+    });
+    chat_state.room_name_input.insert(window_id, room_name);
+    if let Some(outcome) = chat_state.room_outcome.get(&(window_id, server_id)) {
+        ui.label(outcome.clone());
+    }
+
+    let rooms = chat_state.rooms.get(&(window_id, server_id)).cloned().unwrap_or_default();
+    let mut active_room = chat_state.active_room.get(&window_id).cloned().flatten().filter(|room| rooms.contains_key(room));
+    for (room, members) in rooms.iter() {
+        let selected = active_room.as_ref() == Some(room);
+        if ui.selectable_label(selected, format!("{} ({} members)", room, members.len())).clicked() {
+            active_room = if selected { None } else { Some(room.clone()) };
+        }
+    }
+    chat_state.active_room.insert(window_id, active_room.clone());
+
+    let Some(room) = active_room else {
+        return;
+    };
+    ui.group(|ui| {
+        let available_width = ui.available_width().min(370.0);
+        ui.set_max_width(available_width);
+
+        ui.horizontal(|ui| {
+            ui.heading(format!("Room {}", room));
+            if ui.button("Leave").clicked() {
+                sim.leave_room(window_id, server_id, room.clone());
+            }
+        });
+        let members = rooms[&room].iter().map(|member| member.to_string()).collect::<Vec<String>>().join(", ");
+        ui.label(format!("Members: {}", members));
+
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .show(ui, |ui| {
+                let messages = chat_state.room_messages.get(&(server_id, (window_id, room.clone()))).cloned().unwrap_or_default();
+                if messages.is_empty() {
+                    ui.label("No messages yet. Start the conversation!");
+                }
+                for (sender, msg) in messages {
+                    if sender == window_id {
+                        ui.label(format!("You: {}", msg));
+                    } else {
+                        ui.label(format!("Client {} : {}", sender, msg));
+                    }
+                }
+            });
+
+        ui.separator();
+
+        let mut input_text = chat_state.room_message_input.get(&window_id).cloned().unwrap_or_default();
+        let input_response = ui.add(
+            egui::TextEdit::singleline(&mut input_text)
+                .frame(true)
+                .hint_text("Type your message here...")
+                .desired_width(ui.available_width() - 80.0)
+        );
+        let send_button = ui.button("📨 Send");
+        if (send_button.clicked() ||
+            (input_response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))))
+            && !input_text.is_empty()
+        {
+            sim.send_room_message(input_text.clone(), window_id, server_id, room.clone());
+            input_text.clear();
+        }
+        chat_state.room_message_input.insert(window_id, input_text);
+    });
+}
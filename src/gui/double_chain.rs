@@ -7,7 +7,8 @@ pub fn spawn_double_chain(
     clients: &mut SeenClients,
 ) -> Vec<NodeConfig>
 {
-    let config = parse_config();
+    // The topology was already parsed successfully when the simulation started
+    let Ok(config) = parse_config() else { return Vec::new() };
     let node_count = config.client.len() + config.server.len() + config.drone.len();
 
     let horizontal_spacing = 80.0;
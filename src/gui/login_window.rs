@@ -377,6 +377,8 @@ pub struct DisplayableLog{
     pub nack_log: HashMap<(NodeType, NodeId), String>,
     pub graph : HashMap<NodeId,UnGraphMap<NodeId, u32>>,
     pub server_graph : HashMap<NodeId, Graph<(NodeId,wg_2024::packet::NodeType), f64, petgraph::Directed>>,
+    pub duplicates: HashMap<NodeId, u64>,
+    pub cache_lookups: HashMap<NodeId, (u64, u64)>,
 }
 
 #[derive(Resource, Default)]
@@ -392,6 +394,8 @@ pub struct SimLog{
     pub nack_log: HashMap<(NodeType,NodeId), String>,
     pub graph : HashMap<NodeId,UnGraphMap<NodeId, u32>>,
     pub server_graph : HashMap<NodeId, Graph<(NodeId,wg_2024::packet::NodeType), f64, petgraph::Directed>>,
+    pub duplicates: HashMap<NodeId, u64>, //duplicated or replayed fragments each node discarded so far
+    pub cache_lookups: HashMap<NodeId, (u64, u64)>, //web browser -> (files served from its cache, files downloaded)
     pub is_updated: bool,
 }
 fn sync_log(
@@ -410,6 +414,8 @@ fn sync_log(
             displayable_log.nack_log=state.nack_log.clone();
             displayable_log.graph=state.graph.clone();
             displayable_log.server_graph=state.server_graph.clone();
+            displayable_log.duplicates=state.duplicates.clone();
+            displayable_log.cache_lookups=state.cache_lookups.clone();
 
             if let Ok(mut state) = SHARED_LOG.try_write() {
                 state.is_updated = false;
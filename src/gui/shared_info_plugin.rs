@@ -1,9 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
 use bevy::app::{App, Plugin, Update};
 use bevy::prelude::{in_state, IntoSystemConfigs, NextState, Res, ResMut, Resource};
 use once_cell::sync::Lazy;
 use wg_2024::network::NodeId;
+use crate::common_data::common::{HistoryEntry, MessageStatus, SearchHit};
+use crate::common_data::hypertext::Page;
 use crate::gui::chat_windows::ChatState;
 use crate::gui::login_window::{AppState, NodeType};
 use crate::gui::web_media_plugin::WebState;
@@ -24,7 +26,12 @@ pub struct ThreadInfo {
     pub n_servers: usize,
     pub server_types: Vec<(NodeType, NodeId)>,
     pub responses: HashMap<(NodeId,(NodeId,NodeId)),Vec<String>>,
+    pub message_status: HashMap<(NodeId,(NodeId,NodeId)),Vec<MessageStatus>>, //(server,(sender,recipient)) -> status of every message, indexed by message id
     pub client_list: HashMap<(NodeId,NodeId), Vec<NodeId>>,
+    pub rooms: HashMap<(NodeId,NodeId), BTreeMap<String, Vec<NodeId>>>, //(client,server) -> rooms the client is in, with their members
+    pub room_messages: HashMap<(NodeId,(NodeId,String)), Vec<(NodeId,String)>>, //(server,(client,room)) -> (client that posted, message)
+    pub room_outcome: HashMap<(NodeId,NodeId), String>, //(client,server) -> outcome of the last create, join or leave
+    pub history: HashMap<(NodeId,(NodeId,NodeId)), Vec<HistoryEntry>>, //(server,(client,peer)) -> messages recovered from the server, sorted by index
     pub chat_servers: HashMap<NodeId, Vec<NodeId>>,
     pub registered_clients: HashMap<(NodeId,NodeId), bool>,
     pub chat_clients: Vec<NodeId>,
@@ -35,6 +42,9 @@ pub struct ThreadInfo {
     pub target_media_server: HashMap<NodeId, NodeId>,
     pub actual_media_path: HashMap<NodeId, String>,
     pub actual_file_path: HashMap<NodeId, String>,
+    pub fetch_errors: HashMap<NodeId, String>, //web browser -> why its last fetch failed
+    pub pages: HashMap<NodeId, Page>, //web browser -> last hypertext document loaded with its medias
+    pub search_results: HashMap<NodeId, (NodeId, Vec<SearchHit>)>, //web browser -> text server searched and files matching
     pub is_updated: bool,
     pub ready_setup: bool,
 
@@ -202,7 +212,13 @@ fn sync_backend_to_frontend(
         if state.is_updated {
 
             chat_state.chat_responses = state.responses.clone();
+            chat_state.message_status = state.message_status.clone();
+            chat_state.rooms = state.rooms.clone();
+            chat_state.room_messages = state.room_messages.clone();
+            chat_state.room_outcome = state.room_outcome.clone();
+            chat_state.history = state.history.clone();
             chat_state.registered_clients = state.registered_clients.clone();
+            chat_state.client_list = state.client_list.clone();
             chat_state.chat_servers = state.chat_servers.clone();
             chat_state.chat_clients=state.chat_clients.clone();
             web_state.text_servers=state.text_servers.clone();
@@ -211,6 +227,9 @@ fn sync_backend_to_frontend(
             web_state.target_media_server=state.target_media_server.clone();
             web_state.actual_media_path=state.actual_media_path.clone();
             web_state.actual_file_path=state.actual_file_path.clone();
+            web_state.fetch_errors=state.fetch_errors.clone();
+            web_state.pages=state.pages.clone();
+            web_state.search_results=state.search_results.clone();
 
 
 
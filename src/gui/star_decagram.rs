@@ -4,7 +4,8 @@ use crate::gui::login_window::{NodeConfig, NodeType};
 use crate::gui::shared_info_plugin::{NodeCategory, SeenClients};
 
 pub fn spawn_star_decagram(clients: &SeenClients) -> Vec<NodeConfig> {
-    let config = parse_config();
+    // The topology was already parsed successfully when the simulation started
+    let Ok(config) = parse_config() else { return Vec::new() };
     let radius = 200.0;
     let mut nodes = Vec::new();
 
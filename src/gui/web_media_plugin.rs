@@ -1,11 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{BufRead, BufReader};
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts};
 use crate::simulation_control::simulation_control::SimulationController;
 use wg_2024::network::NodeId;
-use crate::common_data::common::ClientType;
+use crate::common_data::common::{ClientType, FileKind, SearchHit, SearchQuery};
+use crate::common_data::hypertext::{Block, Document, Page};
 use crate::gui::chat_windows::{handle_clicks, OpenWindows};
 use crate::gui::login_window::AppState;
 
@@ -34,10 +35,14 @@ impl Default for MediaDisplayType {
     }
 }
 
+/// Saved path of every media of a page -> its image and texture, once loaded
+type PageImages = HashMap<String, (Handle<Image>, Option<(egui::TextureId, egui::Vec2)>)>;
+
 #[derive(Resource, Default)]
 struct ImageState {
     handles: HashMap<NodeId, Option<Handle<Image>>>,
-    egui_textures: HashMap<NodeId, Option<(egui::TextureId, egui::Vec2)>>
+    egui_textures: HashMap<NodeId, Option<(egui::TextureId, egui::Vec2)>>,
+    page_images: HashMap<NodeId, PageImages>,
 }
 
 #[derive(Resource)]
@@ -47,6 +52,7 @@ struct TextFileCache {
     total_lines: HashMap<NodeId, usize>,
     lines_per_page: usize,
     page_input: HashMap<NodeId, String>,
+    hypertext: HashSet<NodeId>, //the file embeds medias, its plain text is shown until the page with them loads
 }
 
 impl Default for TextFileCache {
@@ -57,6 +63,7 @@ impl Default for TextFileCache {
             total_lines: HashMap::new(),
             lines_per_page: 50,
             page_input: HashMap::new(),
+            hypertext: HashSet::new(),
         }
     }
 }
@@ -70,6 +77,9 @@ impl TextFileCache {
         let lines: Vec<String> = reader.lines().collect::<Result<Vec<_>, _>>()?;
 
         let total = lines.len();
+        if Document::parse(&lines.join("\n")).is_hypertext() {
+            self.hypertext.insert(window_id);
+        }
         self.file_lines.insert(window_id, lines);
         self.total_lines.insert(window_id, total);
         self.current_page.insert(window_id, 0);
@@ -128,6 +138,7 @@ impl TextFileCache {
         self.current_page.remove(&window_id);
         self.total_lines.remove(&window_id);
         self.page_input.remove(&window_id);
+        self.hypertext.remove(&window_id);
     }
 }
 
@@ -206,6 +217,22 @@ fn window_format(
                 }
             }
 
+            if let Some(page) = web_state.pages.get(&window_id) {
+                let page_images = state.page_images.entry(window_id).or_default();
+                for saved in page.media.values().flatten() {
+                    let (handle, texture) = page_images.entry(saved.clone()).or_insert_with(|| {
+                        let bevy_path = saved.strip_prefix("assets/").unwrap_or(saved).to_string();
+                        (asset_server.load(bevy_path), None)
+                    });
+                    if texture.is_none() {
+                        if let Some(image) = images.get(&*handle) {
+                            let size = egui::Vec2::new(image.width() as f32, image.height() as f32);
+                            *texture = Some((contexts.add_image(handle.clone()), size));
+                        }
+                    }
+                }
+            }
+
             let window_ui_id = egui::Id::new(format!("web_browser_window_{}", window_id));
 
             let window = egui::Window::new(format!("Web Browser: {}", window_id))
@@ -262,7 +289,6 @@ fn window_format(
                                                     web_state.actual_media_path.remove(&window_id);
                                                     web_state.media_paths.remove(&window_id);
                                                     web_state.actual_file_path.remove(&window_id);
-                                                    web_state.received_medias.remove(&window_id);
                                                     web_state.current_display_type.insert(window_id, MediaDisplayType::None);
                                                     web_state.last_loaded_path.remove(&window_id);
                                                     web_state.server_for_current_media.remove(&window_id);
@@ -320,32 +346,10 @@ fn window_format(
                                                 ui.add(button)
                                             };
                                             if response.clicked(){
-                                                if let Some(selected_text_server) = web_state.selected_text_server.get(&window_id).cloned().flatten() {
-                                                    if let Some(_) = state.handles.get(&window_id) {
+                                                if web_state.selected_text_server.get(&window_id).cloned().flatten().is_some() {
+                                                    if open_file(window_id, media_path, &mut web_state, &mut state, &mut text_cache, &sim) {
                                                         should_clear_image = true
                                                     }
-
-                                                    web_state.currently_selected_media.insert(window_id, Some(media_path.clone()));
-                                                    web_state.actual_media_path.remove(&window_id);
-                                                    web_state.media_paths.remove(&window_id);
-                                                    web_state.actual_file_path.remove(&window_id);
-                                                    web_state.received_medias.remove(&window_id);
-                                                    web_state.media_servers.remove(&window_id);
-                                                    web_state.target_media_server.remove(&window_id);
-                                                    state.handles.insert(window_id, None);
-                                                    state.egui_textures.insert(window_id, None);
-                                                    web_state.current_display_type.insert(window_id, MediaDisplayType::None);
-                                                    web_state.last_loaded_path.remove(&window_id);
-                                                    text_cache.clear(window_id);
-
-                                                    if media_path.ends_with(".txt") {
-                                                        web_state.loading_image.insert(window_id, String::new());
-                                                        web_state.current_display_type.insert(window_id, MediaDisplayType::TextFile);
-                                                        sim.get_text_file(window_id, selected_text_server, media_path.clone());
-                                                    } else {
-                                                        web_state.received_medias.insert(window_id, media_path.clone());
-                                                        sim.get_media_position(window_id, selected_text_server, media_path.clone());
-                                                    }
                                                 } else {
                                                     ui.label("Search failed, text server unreachable");
                                                 }
@@ -366,17 +370,61 @@ fn window_format(
                     }
 
                     ui.separator();
+                    ui.heading("Search");
 
-                    if let Some(media_server) = web_state.target_media_server.get(&window_id).cloned() {
-                        if let Some(media_path) = web_state.received_medias.remove(&window_id) {
-                            web_state.loading_image.insert(window_id, media_path.clone());
-                            sim.get_media_from(window_id, media_server, media_path.clone());
-                        }
+                    ui.horizontal(|ui| {
+                        let search_input = web_state.search_input.entry(window_id).or_default();
+                        let search_input_id = ui.make_persistent_id(format!("search_input_{}", window_id));
+                        ui.push_id(search_input_id, |ui| {
+                            ui.add(egui::TextEdit::singleline(search_input)
+                                .hint_text("mars ext:png type:media \"selva oscura\"")
+                                .desired_width(300.0));
+                        });
+
+                        let search_button_id = ui.make_persistent_id(format!("search_button_{}", window_id));
+                        ui.push_id(search_button_id, |ui| {
+                            let selected_text_server = web_state.selected_text_server.get(&window_id).cloned().flatten();
+                            if ui.add_enabled(selected_text_server.is_some(), egui::Button::new("Search")).clicked() {
+                                if let Some(selected_text_server) = selected_text_server {
+                                    let input = web_state.search_input.get(&window_id).cloned().unwrap_or_default();
+                                    sim.search(window_id, selected_text_server, SearchQuery::parse(&input));
+                                }
+                            }
+                        });
+                    });
+
+                    if let Some((text_server, hits)) = web_state.search_results.get(&window_id).cloned() {
+                        ui.label(format!("{} files found on text server {}", hits.len(), text_server));
+                        let results_scroll_id = ui.make_persistent_id(format!("search_results_{}", window_id));
+                        ui.push_id(results_scroll_id, |ui| {
+                            egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                                for (idx, hit) in hits.iter().enumerate() {
+                                    let result_id = ui.make_persistent_id(format!("search_result_{}_{}", window_id, idx));
+                                    ui.push_id(result_id, |ui| {
+                                        ui.horizontal(|ui| {
+                                            if ui.button(&hit.name).clicked() {
+                                                if open_file(window_id, &hit.name, &mut web_state, &mut state, &mut text_cache, &sim) {
+                                                    should_clear_image = true
+                                                }
+                                            }
+                                            ui.small(describe_hit(hit));
+                                        });
+                                        for (number, line) in &hit.lines {
+                                            ui.small(format!("{}: {}", number, line));
+                                        }
+                                    });
+                                }
+                            });
+                        });
                     }
 
                     ui.separator();
                     ui.heading("Media View");
 
+                    if let Some(error) = web_state.fetch_errors.get(&window_id) {
+                        ui.colored_label(egui::Color32::RED, error);
+                    }
+
                     let media_view_id = ui.make_persistent_id(format!("media_view_area_{}", window_id));
                     ui.push_id(media_view_id, |ui| {
                         match web_state.current_display_type.get(&window_id).unwrap_or(&MediaDisplayType::None) {
@@ -423,7 +471,6 @@ fn window_format(
 
                                                         state.handles.insert(window_id, None);
                                                         state.egui_textures.insert(window_id, None);
-                                                        web_state.received_medias.remove(&window_id);
                                                         web_state.actual_media_path.remove(&window_id);
                                                         web_state.last_loaded_path.remove(&window_id);
                                                         web_state.media_servers.remove(&window_id);
@@ -443,7 +490,11 @@ fn window_format(
                                 }
                             },
                             MediaDisplayType::TextFile => {
-                                if let Some(path_to_file) = web_state.actual_file_path.get(&window_id) {
+                                let page = web_state.pages.get(&window_id)
+                                    .filter(|page| web_state.actual_file_path.get(&window_id) == Some(&page.path));
+                                if let Some(page) = page {
+                                    show_page(ui, window_id, page, state.page_images.get(&window_id));
+                                } else if let Some(path_to_file) = web_state.actual_file_path.get(&window_id) {
                                     if !text_cache.file_lines.contains_key(&window_id) {
                                         match text_cache.load_file(window_id, path_to_file) {
                                             Ok(_) => {},
@@ -456,6 +507,9 @@ fn window_format(
                                         }
                                     }
 
+                                    if text_cache.hypertext.contains(&window_id) {
+                                        ui.label("Loading the medias of the page...");
+                                    }
                                     if let Some(content) = text_cache.get_page_content(&window_id) {
                                         let text_scroll_id = ui.make_persistent_id(format!("text_scroll_{}", window_id));
                                         ui.push_id(text_scroll_id, |ui| {
@@ -569,6 +623,11 @@ fn window_format(
 
                 state.handles.insert(window_id, None);
                 state.egui_textures.insert(window_id, None);
+                if let Some(page_images) = state.page_images.remove(&window_id) {
+                    for (handle, _) in page_images.values() {
+                        contexts.remove_image(handle);
+                    }
+                }
                 web_state.loading_image.remove(&window_id);
                 web_state.currently_selected_media.remove(&window_id);
                 web_state.actual_media_path.remove(&window_id);
@@ -576,10 +635,10 @@ fn window_format(
                 web_state.actual_file_path.remove(&window_id);
                 web_state.selected_text_server.remove(&window_id);
                 web_state.selected_media_server.remove(&window_id);
-                web_state.received_medias.remove(&window_id);
                 web_state.current_display_type.remove(&window_id);
                 web_state.last_loaded_path.remove(&window_id);
                 web_state.server_for_current_media.remove(&window_id);
+                web_state.search_input.remove(&window_id);
                 text_cache.clear(window_id);
             }
         }
@@ -601,14 +660,99 @@ pub struct WebState {
     pub target_media_server: HashMap<NodeId, NodeId>,
     pub actual_media_path: HashMap<NodeId, String>,
     pub actual_file_path: HashMap<NodeId, String>,
+    pub fetch_errors: HashMap<NodeId, String>,
     selected_text_server: HashMap<NodeId, Option<NodeId>>,
     selected_media_server: HashMap<NodeId, Option<NodeId>>,
-    received_medias: HashMap<NodeId, String>,
     current_display_type: HashMap<NodeId, MediaDisplayType>,
     last_loaded_path: HashMap<NodeId, String>,
     server_for_current_media: HashMap<NodeId, Option<NodeId>>,
     currently_selected_media: HashMap<NodeId, Option<String>>,
     loading_image: HashMap<NodeId, String>,
+    pub pages: HashMap<NodeId, Page>,
+    pub search_results: HashMap<NodeId, (NodeId, Vec<SearchHit>)>,
+    search_input: HashMap<NodeId, String>,
+}
+
+/// Shows `file` in the window once the browser fetched it, wherever it is; true if the image shown must be freed
+fn open_file(
+    window_id: NodeId,
+    file: &String,
+    web_state: &mut WebState,
+    state: &mut ImageState,
+    text_cache: &mut TextFileCache,
+    sim: &SimulationController,
+) -> bool {
+    let should_clear_image = state.handles.get(&window_id).is_some();
+
+    web_state.currently_selected_media.insert(window_id, Some(file.clone()));
+    web_state.actual_media_path.remove(&window_id);
+    web_state.media_paths.remove(&window_id);
+    web_state.actual_file_path.remove(&window_id);
+    web_state.media_servers.remove(&window_id);
+    web_state.target_media_server.remove(&window_id);
+    state.handles.insert(window_id, None);
+    state.egui_textures.insert(window_id, None);
+    web_state.current_display_type.insert(window_id, MediaDisplayType::None);
+    web_state.last_loaded_path.remove(&window_id);
+    text_cache.clear(window_id);
+
+    if FileKind::of(file) == FileKind::Text {
+        web_state.loading_image.insert(window_id, String::new());
+        web_state.current_display_type.insert(window_id, MediaDisplayType::TextFile);
+    } else {
+        web_state.loading_image.insert(window_id, file.clone());
+    }
+    sim.fetch(window_id, file.clone()); //the browser finds the server holding it
+    should_clear_image
+}
+
+fn describe_hit(hit: &SearchHit) -> String {
+    match hit.size {
+        Some(size) => format!("{}, {:.1} KB", hit.mime, size as f64 / 1024.0),
+        None => format!("{}, held by another server", hit.mime),
+    }
+}
+
+/// Hypertext document with its medias in place, the ones that couldn't be fetched are named
+fn show_page(ui: &mut egui::Ui, window_id: NodeId, page: &Page, page_images: Option<&PageImages>) {
+    let page_scroll_id = ui.make_persistent_id(format!("page_scroll_{}", window_id));
+    ui.push_id(page_scroll_id, |ui| {
+        egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+            for block in &page.document.blocks {
+                match block {
+                    Block::Text(text) => {
+                        ui.label(text);
+                    }
+                    Block::Media { id, caption } => {
+                        match page.media.get(id) {
+                            Some(Ok(saved)) => {
+                                match page_images.and_then(|images| images.get(saved)).and_then(|(_, texture)| *texture) {
+                                    Some((texture_id, size)) => {
+                                        let scale_factor = (ui.available_width() / size.x).min(1.0);
+                                        let sized_texture = egui::load::SizedTexture::new(texture_id, size);
+                                        ui.add(egui::Image::new(egui::ImageSource::Texture(sized_texture))
+                                            .fit_to_exact_size(size * scale_factor));
+                                    }
+                                    None => {
+                                        ui.label("Loading image...");
+                                    }
+                                }
+                            }
+                            Some(Err(reason)) => {
+                                ui.colored_label(egui::Color32::RED, format!("{} is missing: {}", id, reason));
+                            }
+                            None => {
+                                ui.label("Loading image...");
+                            }
+                        }
+                        if !caption.is_empty() {
+                            ui.small(caption);
+                        }
+                    }
+                }
+            }
+        });
+    });
 }
 fn trim_into_file_name(actual_path: &String)->String{
     let retval=actual_path.split('/').last().unwrap_or("").to_string();
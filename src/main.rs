@@ -5,10 +5,16 @@ mod clients;
 mod network_initializer;
 mod servers;
 mod common_data;
+#[cfg(test)]
+mod test_support;
 
 pub fn main(){
     // gui::test_try::main();
-     gui::login_window::main();
+    if std::env::args().any(|arg| arg == "--headless") {
+        simulation_control::headless::main();
+    } else {
+        gui::login_window::main();
+    }
     // simulation_control::simulation_control::test();
     //clients::assembler::main();
     // servers::Text_max::main()
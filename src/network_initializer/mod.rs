@@ -1,2 +1,3 @@
 pub mod network_initializer;
-pub mod connection_validity;
\ No newline at end of file
+pub mod connection_validity;
+pub mod node_roles;
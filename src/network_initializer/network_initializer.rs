@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::{fs, thread};
+use std::path::Path;
 use bagel_bomber::BagelBomber;
 use bevy::prelude::{ResMut, Vec2};
 use crossbeam_channel::{unbounded, Receiver, Sender};
@@ -21,39 +22,231 @@ use crate::clients::chat_client::ChatClient;
 use crate::clients::web_browser::WebBrowser;
 use crate::common_data::common::{BackGroundFlood, ChatClientEvent, CommandChat, ContentCommands, ServerCommands, ServerEvent, WebBrowserEvents};
 use crate::gui::login_window::{NodeConfig, NodeType};
-use crate::simulation_control::simulation_control::SimulationController;
+use crate::simulation_control::simulation_control::{EventTap, SimulationController};
+use crate::simulation_control::event_recorder::EventRecorder;
 use crate::gui::shared_info_plugin::{NodeCategory, ERROR_VERIFY, SHARED_STATE};
+use crate::network_initializer::node_roles::{ClientKind, DeclaredRoles, DroneKind, ServerKind};
 use crate::network_initializer::connection_validity::{validate_drone_pdr, validate_duplex_connections, validate_generic_configuration, would_break_connectivity};
 use crate::servers::chat_server_fillo::Server;
 use crate::servers::text_server_fillo::Server as TextServerBaia;
 use crate::servers::media_server_fillo::Server as MediaServerBaia;
 use crate::servers::chat_max::Server as ChatMax;
 use crate::servers::text_max::Server as TextMax;
+use crate::servers::history::History;
+
+/// Environment variable that can be used instead of `--topology`
+pub const TOPOLOGY_ENV: &str = "ROLLING_DRONES_TOPOLOGY";
+/// Environment variable that can be used instead of `--servers`
+pub const SERVERS_ENV: &str = "ROLLING_DRONES_SERVERS";
+
+/// Environment variable that can be used instead of `--vendor-offset`
+pub const VENDOR_OFFSET_ENV: &str = "ROLLING_DRONES_VENDOR_OFFSET";
+/// Environment variable that can be used instead of `--record`
+pub const RECORD_ENV: &str = "ROLLING_DRONES_RECORD";
+/// Environment variable that can be used instead of `--chat-history`
+pub const CHAT_HISTORY_ENV: &str = "ROLLING_DRONES_CHAT_HISTORY";
+
+/// Reads `--vendor-offset <n>` or ROLLING_DRONES_VENDOR_OFFSET, 0 when omitted
+/// It shifts the round-robin that gives a vendor to the drones without an `implementation`
+pub fn vendor_offset_from_args() -> Result<usize, String> {
+    match runtime_option("--vendor-offset", VENDOR_OFFSET_ENV) {
+        Some(offset) => offset.parse::<usize>()
+            .map_err(|e| format!("Invalid vendor offset '{}': {}", offset, e)),
+        None => Ok(0),
+    }
+}
+
+/// Where the DroneEvents are written: `--record <path>` or ROLLING_DRONES_RECORD, nothing is recorded when omitted
+pub fn record_path() -> Option<String> {
+    runtime_option("--record", RECORD_ENV)
+}
+
+/// Which implementation of the servers gets spawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerFamily {
+    Fillo, // chat_server_fillo, text_server_fillo, media_server_fillo
+    Max,   // chat_max, text_max
+}
+
+impl ServerFamily {
+    /// Reads `--servers <max|fillo>` or ROLLING_DRONES_SERVERS,
+    /// falling back to the "max" feature flag when none is given
+    pub fn from_args() -> Result<ServerFamily, String> {
+        match runtime_option("--servers", SERVERS_ENV) {
+            Some(name) => match name.to_lowercase().as_str() {
+                "max" => Ok(ServerFamily::Max),
+                "fillo" | "baia" => Ok(ServerFamily::Fillo),
+                other => Err(format!("Unknown server family '{}', expected 'max' or 'fillo'", other)),
+            },
+            None if cfg!(feature = "max") => Ok(ServerFamily::Max),
+            None => Ok(ServerFamily::Fillo),
+        }
+    }
+}
+
+/// Looks for `--name value` or `--name=value` on the command line, then for the environment variable
+fn runtime_option(name: &str, env: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    for (i, arg) in args.iter().enumerate() {
+        if arg == name {
+            if let Some(value) = args.get(i + 1) {
+                return Some(value.clone());
+            }
+        } else if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    std::env::var(env).ok().filter(|value| !value.is_empty())
+}
+
+/// History of a chat server: saved under `--chat-history <dir>` or ROLLING_DRONES_CHAT_HISTORY
+/// (e.g. assets/chat_history), one folder per server id, and only kept in memory otherwise
+fn chat_history(id: NodeId) -> History {
+    match runtime_option("--chat-history", CHAT_HISTORY_ENV) {
+        Some(dir) => History::persistent(Path::new(&dir).join(id.to_string())),
+        None => History::new(),
+    }
+}
+
+/// Path of the topology to load: `--topology <path>` or ROLLING_DRONES_TOPOLOGY,
+/// otherwise the one selected by the active feature flags
+/// A bare name like `sub_net` is looked up inside assets/configurations
+pub fn topology_path() -> String {
+    match runtime_option("--topology", TOPOLOGY_ENV) {
+        Some(path) => {
+            let in_assets = format!("assets/configurations/{}.toml", path);
+            if !Path::new(&path).exists() && Path::new(&in_assets).exists() {
+                in_assets
+            } else {
+                path
+            }
+        },
+        None if cfg!(feature = "web") => {
+            // Web topology for web-based simulations
+            "assets/configurations/web_topology.toml".to_string()
+        },
+        None if cfg!(feature = "full") => {
+            // Full topology for complete simulations
+            "assets/configurations/full_topology.toml".to_string()
+        },
+        None => {
+            // Default chat topology
+            "assets/configurations/chat_topology.toml".to_string()
+        }
+    }
+}
 
-/// Parses the configuration file based on the active feature flags
+/// Parses the configuration file chosen at runtime (see topology_path)
 /// Returns a Config struct containing the network topology
-pub fn parse_config() -> Config {
-    let file_str = if cfg!(feature = "web") {
-        // Web topology for web-based simulations
-        fs::read_to_string("assets/configurations/web_topology.toml").unwrap()
-    } else if cfg!(feature = "full") {
-        // Full topology for complete simulations
-        fs::read_to_string("assets/configurations/full_topology.toml").unwrap()
-    } else {
-        // Default chat topology
-        fs::read_to_string("assets/configurations/chat_topology.toml").unwrap()
-    };
-    toml::from_str(&file_str).unwrap()
+pub fn parse_config() -> Result<Config, String> {
+    parse_topology().map(|(config, _)| config)
 }
 
-/// Sets up all communication channels, spawns drones, servers, and clients
-/// and initializes the simulation controller
-#[allow(unused_mut)]
+/// Same as parse_config, but also returns the roles declared by clients and servers
+pub fn parse_topology() -> Result<(Config, DeclaredRoles), String> {
+    let path = topology_path();
+    let file_str = fs::read_to_string(&path)
+        .map_err(|e| format!("Couldn't read topology file {}: {}", path, e))?;
+    let config = toml::from_str(&file_str)
+        .map_err(|e| format!("Topology file {} is malformed: {}", path, e))?;
+    let roles = toml::from_str(&file_str)
+        .map_err(|e| format!("Roles in topology file {} are malformed: {}", path, e))?;
+    Ok((config, roles))
+}
+
+/// Bevy system run on entering AppState::SetUp
+/// Boots the network and stores the handles inside the SimulationController resource
 pub fn start_simulation(
     mut simulation_controller: ResMut<SimulationController>
 ) {
-    // Parse configuration from TOML file
-    let config = parse_config();
+    // Parse configuration from TOML file, pick the server family and decide every node's role
+    let setup = parse_topology()
+        .and_then(|(config, roles)| ServerFamily::from_args().map(|family| (config, roles, family)))
+        .and_then(|(config, roles, family)| vendor_offset_from_args().map(|offset| (config, roles, family, offset)))
+        .and_then(|(config, roles, family, offset)| prepare_simulation(&config, &roles, family, offset).map(|setup| (config, setup)));
+
+    match setup {
+        Ok((config, setup)) => {
+            // The topology checks are reported to the GUI through ERROR_VERIFY by spawn_simulation itself
+            let _ = spawn_simulation(config, setup, &mut simulation_controller);
+        },
+        // Nothing was spawned, the GUI shows why and stays in AppState::SetUp
+        Err(err) => report_setup_error(err),
+    }
+}
+
+/// Shows an error that prevented the simulation from starting in the GUI
+fn report_setup_error(err: String) {
+    if let Ok(mut state) = ERROR_VERIFY.write() {
+        state.generic_misconfiguration = (false, Err(err));
+        state.is_updated = true;
+    }
+}
+
+/// Boots the network without Bevy, returning the SimulationController as a plain object
+/// The controller's event loop is already running in its own thread
+/// Client events are copied to event_tap, if given
+pub fn start_headless(
+    config: Config,
+    roles: &DeclaredRoles,
+    family: ServerFamily,
+    vendor_offset: usize,
+    event_tap: Option<EventTap>
+) -> Result<SimulationController, String> {
+    let mut simulation_controller = SimulationController::default();
+    simulation_controller.vendor_offset = vendor_offset;
+    simulation_controller.event_tap = event_tap;
+    init_simulation(config, roles, family, &mut simulation_controller)?;
+    Ok(simulation_controller)
+}
+
+/// Everything decided before a node is spawned
+struct Setup {
+    vendor_offset: usize,
+    drone_kinds: Vec<DroneKind>,
+    client_kinds: Vec<ClientKind>,
+    server_kinds: Vec<(ServerKind, Option<String>)>,
+    recorder: Option<EventRecorder>,
+}
+
+/// Sets up all communication channels, spawns drones, servers, and clients
+/// and initializes the simulation controller
+/// The drone vendors are shifted by simulation_controller.vendor_offset, the DroneEvents are recorded when `--record` is given
+/// Returns the error that prevented the start or the first one found by the topology checks, if any
+pub fn init_simulation(
+    config: Config,
+    roles: &DeclaredRoles,
+    family: ServerFamily,
+    simulation_controller: &mut SimulationController
+) -> Result<(), String> {
+    let setup = prepare_simulation(&config, roles, family, simulation_controller.vendor_offset)?;
+    spawn_simulation(config, setup, simulation_controller)
+}
+
+/// Decides every node's role and opens the recording, if asked, nothing is spawned yet
+fn prepare_simulation(config: &Config, roles: &DeclaredRoles, family: ServerFamily, vendor_offset: usize) -> Result<Setup, String> {
+    let drone_kinds = roles.resolve_drones(config, vendor_offset);
+    let client_kinds = roles.resolve_clients(config)?;
+    let server_kinds = roles.resolve_servers(config, family)?;
+
+    // Recorded runs write down the order of every DroneEvent
+    let recorder = match record_path() {
+        Some(path) => Some(EventRecorder::create(&path, vendor_offset)?),
+        None => None,
+    };
+    Ok(Setup { vendor_offset, drone_kinds, client_kinds, server_kinds, recorder })
+}
+
+/// Spawns the network decided by prepare_simulation and runs the topology checks,
+/// whose outcome is also written in ERROR_VERIFY for the GUI
+fn spawn_simulation(
+    config: Config,
+    setup: Setup,
+    simulation_controller: &mut SimulationController
+) -> Result<(), String> {
+    let Setup { vendor_offset, drone_kinds, client_kinds, server_kinds, recorder } = setup;
+    simulation_controller.vendor_offset = vendor_offset;
+    simulation_controller.recorder = recorder;
 
     // Set up all communication channels between nodes
     let (packet_channels, command_chat_channel,
@@ -83,47 +276,31 @@ pub fn start_simulation(
     // Spawn all drone instances and get IDs of Rustafarian drones (since they decided to use non protocol based hop_indexes)
     let rustafarian_ids=spawn_drones(
         &config,
+        &drone_kinds,
         &mut controller_drones,
         &mut packet_drones,
         &packet_channels,
         node_event_send.clone()
     );
 
-    // Spawn servers based on feature flags
-    #[cfg(feature = "max")]
-    {
-        spawn_servers_max(
-            &config,
-            &packet_channels,
-            &background_flooding,
-            &mut background_flood,
-            &server_commands,
-            &mut text_servers,
-            &mut chat_servers,
-            server_event_send.clone(),
-        );
-    }
-    #[cfg(not(feature = "max"))]
-    {
-        spawn_servers_baia(
-            &config,
-            &packet_channels,
-            &background_flooding,
-            &mut background_flood,
-            &server_commands,
-            &mut text_servers,
-            &mut media_servers,
-            &mut chat_servers,
-            server_event_send.clone(),
-        );
-    }
-
-    // Calculate total number of servers
-    let n_servers=text_servers.len()+chat_servers.len()+media_servers.len();
+    // Spawn servers with their resolved roles
+    spawn_servers(
+        &config,
+        &server_kinds,
+        &packet_channels,
+        &background_flooding,
+        &mut background_flood,
+        &server_commands,
+        &mut text_servers,
+        &mut media_servers,
+        &mut chat_servers,
+        server_event_send.clone(),
+    );
 
     // Spawn client instances (chat clients and web browsers)
     spawn_clients(
         &config,
+        &client_kinds,
         &packet_channels,
         &command_chat_channel,
         &command_web_channel,
@@ -133,12 +310,11 @@ pub fn start_simulation(
         &mut background_flood,
         chat_event_send.clone(),
         web_event_send.clone(),
-        n_servers
     );
 
     // Update the simulation controller with all initialized components
     update_simulation_controller(
-        &mut simulation_controller,
+        simulation_controller,
         node_event_send.clone(),
         controller_drones,
         node_event_recv,
@@ -200,6 +376,10 @@ pub fn start_simulation(
     let wrong_pdr=validate_drone_pdr(&convert_to_config(config.clone(), nodes.clone()));
     let connection_error=validate_duplex_connections(&convert_to_config(config.clone(), nodes.clone()));
     let generic_misconfiguration= validate_generic_configuration(&convert_to_config(config, nodes.clone()));
+    let outcome = connection_error.clone()
+        .and(wrong_pdr.clone())
+        .and(isolated_node.clone())
+        .and(generic_misconfiguration.clone());
 
     // Tells GUI output of the checks, GUI will decide
     // whether to change the AppState to InGame (if no errors)
@@ -217,6 +397,8 @@ pub fn start_simulation(
         state.nodes=nodes;
         state.is_updated=true;
     }
+
+    outcome
 }
 
 /// Sets up all communication channels needed for the simulation
@@ -279,6 +461,7 @@ fn create_neighbours_map(config: &Config) -> HashMap<NodeId, Vec<NodeId>> {
 /// Returns a list of Rustafarian drone IDs (useful since Rustafarian doesn't follow protocol when sending hop_index of nacks)
 fn spawn_drones(
     config: &Config,
+    drone_kinds: &[DroneKind],
     controller_drones: &mut HashMap<NodeId, Sender<DroneCommand>>,
     packet_drones: &mut HashMap<NodeId, Sender<Packet>>,
     packet_channels: &HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)>,
@@ -287,14 +470,14 @@ fn spawn_drones(
     let mut rustafarian_drone_ids = Vec::new();
 
     // Iterate through all drones in config and spawn them
-    for (i,cfg_drone) in config.drone.iter().cloned().enumerate() {
+    for (cfg_drone, kind) in config.drone.iter().cloned().zip(drone_kinds.iter().copied()) {
         // Create control channel for this drone (used by SC to send commands to drone, e.g. RemoveSender)
         let (controller_drone_send, controller_drone_recv) = unbounded();
         controller_drones.insert(cfg_drone.id, controller_drone_send);
         packet_drones.insert(cfg_drone.id, packet_channels[&cfg_drone.id].0.clone());
 
-        // Every 10th drone starting at index 3 is a Rustafarian drone (problematic drone)
-        if i % 10 == 3{
+        // Rustafarian drones are problematic, the SC needs to know them
+        if kind == DroneKind::Rustafarian {
             rustafarian_drone_ids.push(cfg_drone.id);
         }
 
@@ -316,7 +499,7 @@ fn spawn_drones(
                 packet_recv,
                 packet_send,
                 cfg_drone.pdr,
-                i
+                kind
             );
 
             if let Some(mut drone) = drone {
@@ -327,7 +510,7 @@ fn spawn_drones(
     rustafarian_drone_ids
 }
 
-/// Creates a drone instance based on the index
+/// Creates a drone instance of the given implementation
 fn create_drone(
     id: NodeId,
     node_event_send: Sender<DroneEvent>,
@@ -335,29 +518,26 @@ fn create_drone(
     packet_recv: Receiver<Packet>,
     packet_send: HashMap<u8, Sender<Packet>>,
     pdr: f32,
-    i: usize
+    kind: DroneKind
 ) -> Option<Box<dyn Drone>> {
-    // Select drone type based on index modulo 10
-    match i % 10 {
-        0 => Some(Box::new(BagelBomber::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        1 => Some(Box::new(SkyLinkDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        2 => Some(Box::new(FungiDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        3 => Some(Box::new(RustafarianDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        4 => Some(Box::new(RustezeDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        5 => Some(Box::new(LockheedRustin::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        6 => Some(Box::new(RustDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        7 => Some(Box::new(RustBustersDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        8 => Some(Box::new(LeDron::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        9 => Some(Box::new(Krusty_C::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
-        _ => Some(Box::new(LockheedRustin::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+    match kind {
+        DroneKind::BagelBomber => Some(Box::new(BagelBomber::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::Skylink => Some(Box::new(SkyLinkDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::Fungi => Some(Box::new(FungiDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::Rustafarian => Some(Box::new(RustafarianDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::Rusteze => Some(Box::new(RustezeDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::LockheedRustin => Some(Box::new(LockheedRustin::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::RustDrone => Some(Box::new(RustDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::Rustbusters => Some(Box::new(RustBustersDrone::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::LedronJames => Some(Box::new(LeDron::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
+        DroneKind::KrustyClub => Some(Box::new(Krusty_C::new(id, node_event_send, controller_drone_recv, packet_recv, packet_send, pdr))),
     }
 }
 
-/// Spawns servers for the "baia" configuration
-/// Server types are determined based on the number of clients and servers
-#[allow(dead_code)]
-fn spawn_servers_baia(
+/// Spawns every server with the kind and content manifest resolved from the topology
+fn spawn_servers(
     config: &Config,
+    server_kinds: &[(ServerKind, Option<String>)],
     packet_channels: &HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)>,
     background_flood: &HashMap<NodeId, (Sender<BackGroundFlood>, Receiver<BackGroundFlood>)>,
     flooding: &mut HashMap<NodeId, Sender<BackGroundFlood>>,
@@ -367,10 +547,9 @@ fn spawn_servers_baia(
     chat_servers: &mut HashMap<NodeId, Sender<ServerCommands>>,
     server_event_send: Sender<ServerEvent>
 ) {
-    let n_clients = config.client.len();
     let n_servers = config.server.len();
 
-    for (i, cfg_server) in config.server.iter().cloned().enumerate() {
+    for (cfg_server, (kind, content)) in config.server.iter().cloned().zip(server_kinds) {
         // Set up channels for this server
         let rcv = packet_channels[&cfg_server.id].1.clone();
         let packet_send = cfg_server.connected_drone_ids.iter()
@@ -379,78 +558,19 @@ fn spawn_servers_baia(
         let rcv_flood = background_flood[&cfg_server.id].1.clone();
         flooding.insert(cfg_server.id, background_flood[&cfg_server.id].0.clone());
         let rcv_command = server_commands[&cfg_server.id].1.clone();
-
-        // Spawn different server types based on client/server configuration
-        match (n_clients, n_servers) {
-            (1, 1) => {
-                // Single client, single server: text server
-                spawn_text_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                  server_event_send.clone(), text_servers, server_commands,
-                                  "assets/multimedia/paths/text_server1.txt", n_servers);
-            },
-            (2, 1) => {
-                // Two clients, one server: chat server
-                spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                  server_event_send.clone(), chat_servers, server_commands, n_servers);
-            },
-            (2, 2) => {
-                // Two clients, two servers: both chat servers
-                spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                  server_event_send.clone(), chat_servers, server_commands, n_servers);
-            },
-            (3, 1) => {
-                // Three clients, one server: chat server
-                spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                  server_event_send.clone(), chat_servers, server_commands, n_servers);
-            },
-            (3, 2) => {
-                // Three clients, two servers: chat and text
-                match i {
-                    0 => spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                           server_event_send.clone(), chat_servers, server_commands, n_servers),
-                    1 => spawn_text_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                           server_event_send.clone(), text_servers, server_commands,
-                                           "assets/multimedia/paths/text_server1.txt", n_servers),
-                    _ => unreachable!()
-                }
-            },
-            (1, 3) => {
-                // One client, three servers: mixed media, text, and media
-                match i {
-                    0 => spawn_media_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                            server_event_send.clone(), media_servers, server_commands,"assets/multimedia/paths/media_server2.txt", n_servers),
-                    1 => spawn_text_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                           server_event_send.clone(), text_servers, server_commands,
-                                           "assets/multimedia/paths/text_server1.txt", n_servers),
-                    2 => spawn_media_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                            server_event_send.clone(), media_servers, server_commands,
-                                            "assets/multimedia/paths/media_server1.txt", n_servers),
-                    _ => unreachable!()
-                }
-            },
-            _ => {
-                // Default configuration for 3+ servers
-                if n_servers >= 3 {
-                    match i  {
-                        0 => spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), chat_servers, server_commands, n_servers),
-                        1 => spawn_text_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), text_servers, server_commands,
-                                               "assets/multimedia/paths/text_server1.txt", n_servers),
-                        2 => spawn_media_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                                server_event_send.clone(), media_servers, server_commands,
-                                                "assets/multimedia/paths/media_server1.txt", n_servers),
-                        _=> spawn_media_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), media_servers, server_commands,
-                                               "assets/multimedia/paths/media_server2.txt", n_servers),
-
-                    }
-                } else {
-                    // Fallback to chat server
-                    spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                      server_event_send.clone(), chat_servers, server_commands, n_servers);
-                }
-            }
+        let path = content.as_deref().unwrap_or_default();
+
+        match kind {
+            ServerKind::ChatFillo => spawn_chat_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
+                                                       server_event_send.clone(), chat_servers, server_commands, n_servers),
+            ServerKind::TextFillo => spawn_text_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
+                                                       server_event_send.clone(), text_servers, server_commands, path, n_servers),
+            ServerKind::MediaFillo => spawn_media_server(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
+                                                         server_event_send.clone(), media_servers, server_commands, path, n_servers),
+            ServerKind::ChatMax => spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
+                                                         server_event_send.clone(), chat_servers, server_commands, n_servers),
+            ServerKind::TextMax => spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
+                                                         server_event_send.clone(), text_servers, server_commands, path, n_servers),
         }
     }
 }
@@ -468,7 +588,7 @@ fn spawn_chat_server(
     server_commands: &HashMap<NodeId, (Sender<ServerCommands>, Receiver<ServerCommands>)>,
     n_servers: usize
 ) {
-    let mut server = Server::new(id, rcv, packet_send, rcv_flood, rcv_command, server_event_send);
+    let mut server = Server::new(id, rcv, packet_send, rcv_flood, rcv_command, server_event_send).with_history(chat_history(id));
     thread::spawn(move || {
         server.run();
     });
@@ -521,9 +641,10 @@ fn spawn_media_server(
 }
 
 /// Spawns client instances (chat clients and web browsers)
-/// Client types are determined based on the number of clients and servers
+/// Client types come from the roles resolved from the topology
 fn spawn_clients(
     config: &Config,
+    client_kinds: &[ClientKind],
     packet_channels: &HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)>,
     command_chat_channel: &HashMap<NodeId, (Sender<CommandChat>, Receiver<CommandChat>)>,
     command_web_channel: &HashMap<NodeId, (Sender<ContentCommands>, Receiver<ContentCommands>)>,
@@ -533,11 +654,10 @@ fn spawn_clients(
     flooding: &mut HashMap<NodeId, Sender<BackGroundFlood>>,
     chat_event_send: Sender<ChatClientEvent>,
     web_event_send: Sender<WebBrowserEvents>,
-    n_servers: usize
 ) {
     let n_clients = config.client.len();
 
-    for (i, cfg_client) in config.client.iter().cloned().enumerate() {
+    for (cfg_client, kind) in config.client.iter().cloned().zip(client_kinds) {
         // Set up channels for this client
         let packet_send: HashMap<NodeId, Sender<Packet>> = cfg_client.connected_drone_ids.iter()
             .map(|nid| (*nid, packet_channels[nid].0.clone()))
@@ -546,43 +666,11 @@ fn spawn_clients(
         let rcv_flood = background_flood[&cfg_client.id].1.clone();
         flooding.insert(cfg_client.id, background_flood[&cfg_client.id].0.clone());
 
-        // Spawn different client types based on configuration
-        match (n_clients, n_servers) {
-            (1, _) => {
-                // Single client: web browser
-                spawn_web_browser(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                  command_web_channel, web_client, web_event_send.clone(), n_clients);
-            },
-            (2, _) => {
-                // Two clients: both chat clients
-                spawn_chat_client(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                  command_chat_channel, client, chat_event_send.clone(), n_clients);
-            },
-            (3, 1) => {
-                // Three clients, one server: all chat clients
-                spawn_chat_client(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                  command_chat_channel, client, chat_event_send.clone(), n_clients);
-            },
-            (3, 2) | (3,3) => {
-                // Three clients, 2-3 servers: 2 chat, 1 web
-                match i {
-                    0 | 1 => spawn_chat_client(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                               command_chat_channel, client, chat_event_send.clone(), n_clients),
-                    2 => spawn_web_browser(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                           command_web_channel, web_client, web_event_send.clone(), n_clients),
-                    _ => unreachable!()
-                }
-            },
-            _ => {
-                // Default: first 2 are chat clients, rest are web browsers
-                if i <  2 {
-                    spawn_chat_client(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                      command_chat_channel, client, chat_event_send.clone(), n_clients);
-                } else {
-                    spawn_web_browser(cfg_client.id, rcv_packet, packet_send, rcv_flood,
-                                      command_web_channel, web_client, web_event_send.clone(), n_clients);
-                }
-            }
+        match kind {
+            ClientKind::Chat => spawn_chat_client(cfg_client.id, rcv_packet, packet_send, rcv_flood,
+                                                  command_chat_channel, client, chat_event_send.clone(), n_clients),
+            ClientKind::Web => spawn_web_browser(cfg_client.id, rcv_packet, packet_send, rcv_flood,
+                                                 command_web_channel, web_client, web_event_send.clone(), n_clients),
         }
     }
 }
@@ -728,7 +816,7 @@ fn update_simulation_controller(
 /// This controller will manage the entire simulation lifecycle
 fn create_simulation_controller(
     node_event_send: Sender<DroneEvent>,
-    simulation_controller: ResMut<SimulationController>,
+    simulation_controller: &SimulationController,
     chat_event_recv: Receiver<ChatClientEvent>,
     web_event_recv: Receiver<WebBrowserEvents>,
     server_event_recv: Receiver<ServerEvent>,
@@ -753,103 +841,13 @@ fn create_simulation_controller(
         background_flooding: simulation_controller.background_flooding.clone(),
         chat_active,
         web_active,
-        rustafarian_ids //SC needs to know Rustafarian_ids since they follow a different paradigm to send nacks
+        rustafarian_ids, //SC needs to know Rustafarian_ids since they follow a different paradigm to send nacks
+        vendor_offset: simulation_controller.vendor_offset,
+        recorder: simulation_controller.recorder.clone(),
+        event_tap: simulation_controller.event_tap.clone()
     }
 }
 
-/// Spawns servers for the "max" configuration
-#[allow(dead_code)]
-fn spawn_servers_max(
-    config: &Config,
-    packet_channels: &HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)>,
-    background_flood: &HashMap<NodeId, (Sender<BackGroundFlood>, Receiver<BackGroundFlood>)>,
-    flooding: &mut HashMap<NodeId, Sender<BackGroundFlood>>,
-    server_commands: &HashMap<NodeId, (Sender<ServerCommands>, Receiver<ServerCommands>)>,
-    text_servers: &mut HashMap<NodeId, Sender<ServerCommands>>,
-    chat_servers: &mut HashMap<NodeId, Sender<ServerCommands>>,
-    server_event_send: Sender<ServerEvent>
-) {
-    let n_clients = config.client.len();
-    let n_servers = config.server.len();
-
-    for (i, cfg_server) in config.server.iter().cloned().enumerate() {
-        // Set up channels for this server
-        let rcv = packet_channels[&cfg_server.id].1.clone();
-        let packet_send = cfg_server.connected_drone_ids.iter()
-            .map(|nid| (*nid, packet_channels[nid].0.clone()))
-            .collect::<HashMap<_, _>>();
-        let rcv_flood = background_flood[&cfg_server.id].1.clone();
-        flooding.insert(cfg_server.id, background_flood[&cfg_server.id].0.clone());
-
-        let rcv_command = server_commands[&cfg_server.id].1.clone();
-
-        // Spawn different server types based on client/server configuration
-        match (n_clients, n_servers) {
-            (1, 1) => {
-                // Single client, single server: text server
-                spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                      server_event_send.clone(), text_servers, server_commands,
-                                      "assets/multimedia/path_max/max_server.txt", n_servers);
-            },
-            (2, 1) => {
-                // Two clients, one server: chat server
-                spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                      server_event_send.clone(), chat_servers, server_commands, n_servers);
-            },
-            (2, 2) => {
-                // Two clients, two servers: both chat servers
-                spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                      server_event_send.clone(), chat_servers, server_commands, n_servers);
-            },
-            (3, 1) => {
-                // Three clients, one server: chat server
-                spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                      server_event_send.clone(), chat_servers, server_commands, n_servers);
-            },
-            (3, 2) => {
-                // Three clients, two servers: chat and text
-                match i {
-                    0 => spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), chat_servers, server_commands, n_servers),
-                    1 => spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), text_servers, server_commands,
-                                               "assets/multimedia/path_max/max_server.txt", n_servers),
-                    _ => unreachable!()
-                }
-            },
-            (1, 3) => {
-                // One client, three servers: all text servers with different content
-                match i {
-                    1 => spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), text_servers, server_commands,
-                                               "assets/multimedia/path_max/max_server.txt", n_servers),
-                    _ => spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                               server_event_send.clone(), text_servers, server_commands,
-                                               "assets/multimedia/path_max/max_server2.txt", n_servers),
-                }
-            },
-            _ => {
-                // Default configuration for 3+ servers
-                if n_servers >= 3 {
-                    match i {
-                        0 => spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                                   server_event_send.clone(), chat_servers, server_commands, n_servers),
-                        1 => spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                                   server_event_send.clone(), text_servers, server_commands,
-                                                   "assets/multimedia/path_max/max_server.txt", n_servers),
-                        _ => spawn_text_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                                   server_event_send.clone(), text_servers, server_commands,
-                                                   "assets/multimedia/path_max/max_server2.txt", n_servers),
-                    }
-                } else {
-                    // Fallback to chat server
-                    spawn_chat_server_max(cfg_server.id, rcv, packet_send, rcv_flood, rcv_command,
-                                          server_event_send.clone(), chat_servers, server_commands, n_servers);
-                }
-            }
-        }
-    }
-}
 /// Spawns a "max" ChatServer instance
 #[allow(dead_code)]
 fn spawn_chat_server_max(
@@ -863,7 +861,7 @@ fn spawn_chat_server_max(
     server_commands: &HashMap<NodeId, (Sender<ServerCommands>, Receiver<ServerCommands>)>,
     n_servers: usize
 ) {
-    let mut server = ChatMax::new(id, rcv, packet_send, rcv_flood, rcv_command, server_event_send);
+    let mut server = ChatMax::new(id, rcv, packet_send, rcv_flood, rcv_command, server_event_send).with_history(chat_history(id));
     thread::spawn(move || {
         server.run();
     });
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use wg_2024::config::Config;
+use wg_2024::network::NodeId;
+use crate::network_initializer::network_initializer::ServerFamily;
+
+/// Role a client or server can declare in the topology file, e.g.
+/// ```toml
+/// [[server]]
+/// id = 10
+/// connected_drone_ids = [1, 2]
+/// role = "text_server"
+/// content = "assets/multimedia/paths/text_server1.txt"
+/// ```
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRole {
+    ChatClient,
+    WebBrowser,
+    ChatServer,       // implementation picked by --servers
+    TextServer,       // implementation picked by --servers
+    MediaServer,      // implementation picked by --servers, rejected with "max" that has no media server
+    ChatServerFillo,
+    TextServerFillo,
+    MediaServerFillo,
+    ChatServerMax,
+    TextServerMax,    // serves both texts and medias
+}
+
+/// Third party drone implementation, declared in the topology with `implementation = "..."`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DroneKind {
+    BagelBomber,
+    #[serde(alias = "skylink_drone")]
+    Skylink,
+    #[serde(alias = "fungi_drone")]
+    Fungi,
+    #[serde(alias = "rustafarian_drone")]
+    Rustafarian, // doesn't follow the protocol when setting the hop_index of nacks
+    #[serde(alias = "rusteze_drone")]
+    Rusteze,
+    #[serde(alias = "lockheedrustin")]
+    LockheedRustin,
+    #[serde(alias = "wg_2024_rust")]
+    RustDrone,
+    #[serde(alias = "rustbusters_drone")]
+    Rustbusters,
+    #[serde(alias = "ledron")]
+    LedronJames,
+    #[serde(alias = "krusty_c")]
+    KrustyClub,
+}
+
+impl DroneKind {
+    /// Implementation used when the topology doesn't declare one, cycling on the drone index
+    pub fn round_robin(i: usize) -> DroneKind {
+        match i % 10 {
+            0 => DroneKind::BagelBomber,
+            1 => DroneKind::Skylink,
+            2 => DroneKind::Fungi,
+            3 => DroneKind::Rustafarian,
+            4 => DroneKind::Rusteze,
+            5 => DroneKind::LockheedRustin,
+            6 => DroneKind::RustDrone,
+            7 => DroneKind::Rustbusters,
+            8 => DroneKind::LedronJames,
+            _ => DroneKind::KrustyClub,
+        }
+    }
+}
+
+/// Client implementation that gets spawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientKind {
+    Chat,
+    Web,
+}
+
+/// Server implementation that gets spawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerKind {
+    ChatFillo,
+    TextFillo,
+    MediaFillo,
+    ChatMax,
+    TextMax,
+}
+
+impl ServerKind {
+    /// Manifest used when the topology doesn't name one
+    fn default_content(&self) -> Option<&'static str> {
+        match self {
+            ServerKind::TextFillo => Some("assets/multimedia/paths/text_server1.txt"),
+            ServerKind::MediaFillo => Some("assets/multimedia/paths/media_server1.txt"),
+            ServerKind::TextMax => Some("assets/multimedia/path_max/max_server.txt"),
+            ServerKind::ChatFillo | ServerKind::ChatMax => None,
+        }
+    }
+}
+
+/// Extra fields of [[client]] and [[server]] entries, the rest is read by wg_2024::config::Config
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeclaredNode {
+    pub id: NodeId,
+    #[serde(default)]
+    pub role: Option<NodeRole>,
+    #[serde(default)]
+    pub content: Option<String>,
+}
+
+/// Extra field of [[drone]] entries
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeclaredDrone {
+    pub id: NodeId,
+    #[serde(default)]
+    pub implementation: Option<DroneKind>,
+}
+
+/// Roles declared in the topology file, nodes without a role fall back to the count-based defaults
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DeclaredRoles {
+    #[serde(default)]
+    pub drone: Vec<DeclaredDrone>,
+    #[serde(default)]
+    pub client: Vec<DeclaredNode>,
+    #[serde(default)]
+    pub server: Vec<DeclaredNode>,
+}
+
+impl DeclaredRoles {
+    /// Decides the implementation of every drone, in the same order as config.drone
+    /// vendor_offset shifts the round-robin, so the same topology can be tried with other vendors
+    pub fn resolve_drones(&self, config: &Config, vendor_offset: usize) -> Vec<DroneKind> {
+        let declared: HashMap<NodeId, DroneKind> = self.drone.iter()
+            .filter_map(|d| d.implementation.map(|kind| (d.id, kind)))
+            .collect();
+
+        config.drone.iter().enumerate()
+            .map(|(i, drone)| declared.get(&drone.id).copied().unwrap_or(DroneKind::round_robin(i + vendor_offset)))
+            .collect()
+    }
+
+    /// Decides the kind of every client, in the same order as config.client
+    pub fn resolve_clients(&self, config: &Config) -> Result<Vec<ClientKind>, String> {
+        let declared: HashMap<NodeId, &DeclaredNode> = self.client.iter().map(|n| (n.id, n)).collect();
+        let n_clients = config.client.len();
+        let n_servers = config.server.len();
+
+        config.client.iter().enumerate().map(|(i, client)| {
+            match declared.get(&client.id).and_then(|n| n.role) {
+                Some(NodeRole::ChatClient) => Ok(ClientKind::Chat),
+                Some(NodeRole::WebBrowser) => Ok(ClientKind::Web),
+                Some(role) => Err(format!("Client {} can't have the server role {:?}", client.id, role)),
+                None => Ok(default_client_kind(n_clients, n_servers, i)),
+            }
+        }).collect()
+    }
+
+    /// Decides the kind and the content manifest of every server, in the same order as config.server
+    pub fn resolve_servers(&self, config: &Config, family: ServerFamily) -> Result<Vec<(ServerKind, Option<String>)>, String> {
+        let declared: HashMap<NodeId, &DeclaredNode> = self.server.iter().map(|n| (n.id, n)).collect();
+        let n_clients = config.client.len();
+        let n_servers = config.server.len();
+
+        config.server.iter().enumerate().map(|(i, server)| {
+            let node = declared.get(&server.id);
+            let kind = match node.and_then(|n| n.role) {
+                Some(NodeRole::ChatServer) => match family {
+                    ServerFamily::Fillo => ServerKind::ChatFillo,
+                    ServerFamily::Max => ServerKind::ChatMax,
+                },
+                Some(NodeRole::TextServer) => match family {
+                    ServerFamily::Fillo => ServerKind::TextFillo,
+                    ServerFamily::Max => ServerKind::TextMax,
+                },
+                Some(NodeRole::MediaServer) => match family {
+                    ServerFamily::Fillo => ServerKind::MediaFillo,
+                    ServerFamily::Max => return Err(format!(
+                        "Server {} is a media_server, the max servers have none (text_server_max serves its medias itself): declare it text_server or media_server_fillo",
+                        server.id)),
+                },
+                Some(NodeRole::ChatServerFillo) => ServerKind::ChatFillo,
+                Some(NodeRole::TextServerFillo) => ServerKind::TextFillo,
+                Some(NodeRole::MediaServerFillo) => ServerKind::MediaFillo,
+                Some(NodeRole::ChatServerMax) => ServerKind::ChatMax,
+                Some(NodeRole::TextServerMax) => ServerKind::TextMax,
+                Some(role) => return Err(format!("Server {} can't have the client role {:?}", server.id, role)),
+                None => {
+                    // No role declared, keep the old behaviour based on the number of nodes
+                    let (kind, content) = match family {
+                        ServerFamily::Fillo => default_server_kind_fillo(n_clients, n_servers, i),
+                        ServerFamily::Max => default_server_kind_max(n_clients, n_servers, i),
+                    };
+                    let content = node.and_then(|n| n.content.clone()).or(content.map(str::to_string));
+                    return Ok((kind, content));
+                }
+            };
+            let content = node.and_then(|n| n.content.clone()).or(kind.default_content().map(str::to_string));
+            Ok((kind, content))
+        }).collect()
+    }
+}
+
+/// Client kind chosen when the topology doesn't declare one
+fn default_client_kind(n_clients: usize, n_servers: usize, i: usize) -> ClientKind {
+    match (n_clients, n_servers) {
+        // Single client: web browser
+        (1, _) => ClientKind::Web,
+        // Two clients: both chat clients
+        (2, _) => ClientKind::Chat,
+        // Three clients, one server: all chat clients
+        (3, 1) => ClientKind::Chat,
+        // Default: first 2 are chat clients, rest are web browsers
+        _ => if i < 2 { ClientKind::Chat } else { ClientKind::Web },
+    }
+}
+
+/// "fillo" server kind chosen when the topology doesn't declare one
+fn default_server_kind_fillo(n_clients: usize, n_servers: usize, i: usize) -> (ServerKind, Option<&'static str>) {
+    let chat = (ServerKind::ChatFillo, None);
+    let text = (ServerKind::TextFillo, Some("assets/multimedia/paths/text_server1.txt"));
+    let media1 = (ServerKind::MediaFillo, Some("assets/multimedia/paths/media_server1.txt"));
+    let media2 = (ServerKind::MediaFillo, Some("assets/multimedia/paths/media_server2.txt"));
+
+    match (n_clients, n_servers) {
+        // Single client, single server: text server
+        (1, 1) => text,
+        // Up to three clients, at most two servers: chat servers
+        (2, 1) | (2, 2) | (3, 1) => chat,
+        // Three clients, two servers: chat and text
+        (3, 2) => if i == 0 { chat } else { text },
+        // One client, three servers: media, text and media
+        (1, 3) => match i {
+            0 => media2,
+            1 => text,
+            _ => media1,
+        },
+        // Default configuration for 3+ servers
+        _ if n_servers >= 3 => match i {
+            0 => chat,
+            1 => text,
+            2 => media1,
+            _ => media2,
+        },
+        // Fallback to chat server
+        _ => chat,
+    }
+}
+
+/// "max" server kind chosen when the topology doesn't declare one
+fn default_server_kind_max(n_clients: usize, n_servers: usize, i: usize) -> (ServerKind, Option<&'static str>) {
+    let chat = (ServerKind::ChatMax, None);
+    let text = (ServerKind::TextMax, Some("assets/multimedia/path_max/max_server.txt"));
+    let text2 = (ServerKind::TextMax, Some("assets/multimedia/path_max/max_server2.txt"));
+
+    match (n_clients, n_servers) {
+        // Single client, single server: text server
+        (1, 1) => text,
+        // Up to three clients, at most two servers: chat servers
+        (2, 1) | (2, 2) | (3, 1) => chat,
+        // Three clients, two servers: chat and text
+        (3, 2) => if i == 0 { chat } else { text },
+        // One client, three servers: all text servers with different content
+        (1, 3) => if i == 1 { text } else { text2 },
+        // Default configuration for 3+ servers
+        _ if n_servers >= 3 => match i {
+            0 => chat,
+            1 => text,
+            _ => text2,
+        },
+        // Fallback to chat server
+        _ => chat,
+    }
+}
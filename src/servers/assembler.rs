@@ -1,41 +1,28 @@
-use serde::Serialize;
-use wg_2024::packet::{Fragment, Packet, FRAGMENT_DSIZE};
+use wg_2024::packet::Packet;
 use std::error::Error;
-use serde::de::DeserializeOwned;
 use wg_2024::network::SourceRoutingHeader;
 use wg_2024::packet::PacketType::MsgFragment;
-use crate::common_data::common::{ChatRequest, ChatResponse, MediaServer, MessageChat, ServerType, TextServer, WebBrowserCommands};
+use crate::common_data::common::{ChatRequest, ChatResponse, Compression, MediaServer, MessageChat, PayloadSize, SenderRole, ServerType, TextServer, WebBrowserCommands};
+use crate::common_data::fragmentation::{self, Payload};
 
 pub trait Fragmentation{
-    fn serialize_data(&self, routing_header:SourceRoutingHeader, session_id : u64)->Result<Vec<Packet>, Box<dyn Error>> where Self:Serialize{
-        let serialized_data = serde_json::to_string(&self)?;
-        let tot_size = serialized_data.len();
-        let tot_fragment = ((tot_size + FRAGMENT_DSIZE - 1 ) / FRAGMENT_DSIZE )as u64;
-        let mut vec = Vec::new();
-
-        for i in 0..tot_fragment{
-            let start = i as usize * FRAGMENT_DSIZE;
-            let end = usize::min(start+FRAGMENT_DSIZE,tot_size);
-            let fragment = &serialized_data[start..end];
-            let packet = Packet{
-                routing_header: routing_header.clone(),
-                session_id,
-                pack_type:MsgFragment(Fragment::from_string(i, tot_fragment, fragment.to_string()))
-            };
-            vec.push(packet);
-        }
+    // the message is wrapped in an Envelope so the receiver can dispatch on its kind
+    fn serialize_data(&self, routing_header:SourceRoutingHeader, session_id : u64, sender: SenderRole)->Result<Vec<Packet>, Box<dyn Error>> where Self:Payload + Sized{
+        let (vec, _) = self.serialize_compressed(routing_header, session_id, sender, Compression::None)?;
         Ok(vec)
     }
-
-    fn deserialize_data(fragments:&mut Vec<Fragment>) ->Result<Self,String> where Self:DeserializeOwned + Sized{
-        fragments.sort_by_key(|f| f.fragment_index);
-        let serialized_data = fragments.iter()
-            .flat_map(|fragment| &fragment.data[..fragment.length as usize])
-            .cloned()
+    // used for texts and medias, compressed when the requester advertised it
+    fn serialize_compressed(&self, routing_header:SourceRoutingHeader, session_id : u64, sender: SenderRole, compression: Compression)->Result<(Vec<Packet>, PayloadSize), Box<dyn Error>> where Self:Payload + Sized{
+        let (fragments, size) = fragmentation::fragment_compressed(self, sender, compression)?;
+        let vec = fragments
+            .into_iter()
+            .map(|fragment| Packet{
+                routing_header: routing_header.clone(),
+                session_id,
+                pack_type:MsgFragment(fragment)
+            })
             .collect();
-        let convert_to_string = String::from_utf8(serialized_data).map_err(|e| e.to_string())?;
-        let message = serde_json::from_str(&convert_to_string).map_err(|e| e.to_string())?;
-        Ok(message)
+        Ok((vec, size))
     }
 }
 
@@ -1,11 +1,19 @@
 use std::cmp::Reverse;
 use crossbeam_channel::select_biased;
 use crate::servers::utilities_max::*;
+use crate::servers::federation::Federation;
+use crate::servers::flow_control::FlowControl;
+use crate::servers::history::History;
+use crate::servers::mailbox::Mailbox;
+use crate::servers::rooms::Rooms;
+use crate::common_data::fragmentation::{FragmentError, Reassembler};
+use crate::common_data::sessions::{SessionTable, SWEEP_INTERVAL};
 use crate::common_data::common::*;
 use crate::common_data::common::ServerType;
 use crossbeam_channel::{Receiver, Sender};
 use std::collections::{BinaryHeap, HashMap};
 use bevy::render::render_resource::encase::private::RuntimeSizedArray;
+use std::time::Instant;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{Ack, FloodRequest, Fragment, Nack, NackType, NodeType, Packet, PacketType};
 use bevy::utils::HashSet;
@@ -18,8 +26,15 @@ pub struct Server {
     next_session_id: u64,
     nodes_map: Vec<(NodeId, NodeType, Vec<NodeId>)>,
     processed_sessions: HashSet<(NodeId, u64)>,
-    fragment_recv: HashMap<(NodeId, u64), Data>,
+    fragment_recv: HashMap<(NodeId, u64), Reassembler>,
     fragment_send: HashMap<u64, Data>,
+    flow: FlowControl,
+    mailbox: Mailbox,
+    forwarding: HashMap<u64, MessageChat>, // sessione in uscita -> messaggio inoltrato, torna nella casella se la sessione fallisce
+    rooms: Rooms,
+    federation: Federation,
+    history: History,
+    sessions: SessionTable,
     packet_recv: Receiver<Packet>,
     pub packet_send: HashMap<NodeId, Sender<Packet>>,
     already_visited: HashSet<(NodeId, u64)>,
@@ -44,6 +59,13 @@ impl Server {
             processed_sessions: HashSet::new(),
             fragment_recv: HashMap::new(),
             fragment_send: HashMap::new(),
+            flow: FlowControl::new(),
+            mailbox: Mailbox::new(),
+            forwarding: HashMap::new(),
+            rooms: Rooms::new(),
+            federation: Federation::new(),
+            history: History::new(),
+            sessions: SessionTable::default(),
             packet_recv,
             packet_send,
             already_visited: HashSet::new(),
@@ -55,6 +77,11 @@ impl Server {
         }
     }
     // mi serviva per pushare
+    /// Sostituisce lo storico in memoria, ad esempio con uno salvato su disco
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
 
 
     pub fn run(&mut self) {
@@ -99,7 +126,10 @@ impl Server {
                         }
                     }
                 }
+                // ⏱️ nessun evento: controllo le sessioni ferme
+                default(SWEEP_INTERVAL) => {}
             }
+            self.sweep_sessions();
         }
     }
     fn handle_packet(&mut self, packet: Packet) {
@@ -133,44 +163,60 @@ impl Server {
         let ack = create_ack(packet.clone());
         self.send_packet(ack);
 
-        // 2) Estrai index, total e chi chiede
-        let total_frags = fragment.total_n_fragments as usize;
-        let frag_idx = fragment.fragment_index as usize;
-        if frag_idx >= total_frags { return; }
-
+        // 2) Estrai chi chiede (index e total li controlla il Reassembler)
         let who_ask = match packet.routing_header.hops.get(0).cloned() {
             Some(h) => h,
             None => return,
         };
         let session_key = (who_ask, *session);
 
-        // 3) Inserisco/aggiorno il dato nel buffer
-        let entry = self.fragment_recv.entry(session_key)
-            .or_insert_with(|| Data::new(
-                ([0u8; 128], 0u8),      // placeholder per ogni slot
-                0,                      // posizione iniziale dummy
-                total_frags as u64,     // numero totale di frammenti
-                0,                      // contatore iniziale = 0
-                who_ask,                // chi chiede
-            ));
-
-        // 4) Se non ricevuto prima, memorizzo
-        if entry.dati[frag_idx].1 == 0 {
-            entry.dati[frag_idx] = (fragment.data, fragment.length);
-            entry.counter += 1;
+        // 3) Frammenti tardivi o duplicati di una sessione già chiusa vengono ignorati
+        let key = SessionKey::Incoming(who_ask, *session);
+        match self.sessions.state(key) {
+            Some(SessionState::Open) => self.sessions.touch(key, Instant::now()),
+            Some(_) => {
+                // duplicato tardivo o replay di una sessione già chiusa
+                self.sessions.duplicate();
+                return;
+            }
+            None => {
+                if !self.sessions.start(key, fragment.total_n_fragments, Instant::now()) {
+                    return;
+                }
+            }
         }
 
+        // 3.1) Inserisco il frammento nel buffer della sessione (il totale viene controllato prima di allocare)
+        let inserted = Reassembler::entry(&mut self.fragment_recv, session_key, fragment.total_n_fragments)
+            .and_then(|entry| entry.insert(&fragment));
+
+        // 4) Duplicati e frammenti non validi vengono scartati
+        let complete = match inserted {
+            Ok(complete) => complete,
+            Err(e) => {
+                match e {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    // totale non valido: la sessione non è mai partita
+                    FragmentError::InvalidTotal(_) => self.sessions.close(key, SessionState::Failed, Instant::now()),
+                    _ => {}
+                }
+                log::debug!("handle_message: frammento scartato da {} (session {}): {}", who_ask, session, e);
+                return;
+            }
+        };
+
         // 5) Se completo, processo e pulisco
-        if entry.counter == entry.total_expected as u64 {
+        if complete {
             self.processed_sessions.insert(session_key);
             self.handle_command(session_key);
             self.fragment_recv.remove(&session_key);
+            self.sessions.close(key, SessionState::Complete, Instant::now());
         }
     }
     fn send_response(&mut self, id: NodeId, response: Risposta, session: &u64) {
         if let Risposta::Chat(chat) = response {
             // 1) serializzo in Box<[chunk]>
-            let dati: Box<[([u8;128], u8)]> = serialize(&chat);
+            let dati: Box<[([u8;128], u8)]> = serialize(&chat, SenderRole::ChatServer);
             let total = dati.len();
 
             // 2) event tracing (unchanged) …
@@ -194,30 +240,87 @@ impl Server {
                 ChatResponse::ForwardMessage(_) => {
                     event = ChatServerEvent::ForwardingMessage(total as u64);
                 }
+                ChatResponse::ForwardReceipt(_) => {
+                    event = ChatServerEvent::ForwardingReceipt(total as u64);
+                }
+                ChatResponse::Room(..) | ChatResponse::RoomMembers(..) => {
+                    event = ChatServerEvent::RoomUpdate(total as u64);
+                }
+                ChatResponse::ForwardRoomMessage(_) => {
+                    event = ChatServerEvent::ForwardingRoomMessage(total as u64);
+                }
+                ChatResponse::Federate(_) => {
+                    event = ChatServerEvent::AnnouncingClients(total as u64);
+                }
+                ChatResponse::RelayMessage(..) | ChatResponse::RelayReceipt(_) => {
+                    event = ChatServerEvent::RelayingToPeer(total as u64);
+                }
+                ChatResponse::ForwardRelayedMessage(..) => {
+                    event = ChatServerEvent::ForwardingMessage(total as u64);
+                }
+                ChatResponse::Presence(..) => {
+                    event = ChatServerEvent::SendingPresence(total as u64);
+                }
+                ChatResponse::History(..) => {
+                    event = ChatServerEvent::SendingHistory(total as u64);
+                }
             }
-            let type_ = MyNodeType::ChatServer;
-            let server_event = ServerEvent::ChatPacketInfo(self.server_id, type_, event, *session);
-            let _ = self.send_event.send(server_event);
+            if let ChatResponse::ForwardMessage(message) = chat {
+                self.forwarding.insert(*session, message);
+            }
+            self.send_data(id, dati, event, session);
+        }
+    }
+    // Chiede il tipo a un altro server, i chat server rispondono con ServerTypeChat
+    fn ask_server_type(&mut self, server: NodeId) {
+        let dati: Box<[([u8;128], u8)]> = serialize(&TextServer::ServerTypeReq, SenderRole::ChatServer);
+        let event = ChatServerEvent::AskingServerType(dati.len() as u64);
+        let session_id = self.get_session();
+        self.send_data(server, dati, event, &session_id);
+    }
+    fn send_data(&mut self, id: NodeId, dati: Box<[([u8;128], u8)]>, event: ChatServerEvent, session: &u64) {
+        let total = dati.len();
+        let type_ = MyNodeType::ChatServer;
+        let server_event = ServerEvent::ChatPacketInfo(self.server_id, type_, event, *session);
+        let _ = self.send_event.send(server_event);
 
-            // 3) costruisco Data *semplificato*
-            let data = Data {
-                dati,
-                total_expected: total,
-                counter: total as u64,
-                who_ask: id,
-                acked: vec![false; total],
-                retry_count: vec![0; total],
-                next_to_send: WINDOW_SIZE.min(total),
-            };
-            self.fragment_send.insert(*session, data);
+        // 3) costruisco Data *semplificato*
+        let data = Data {
+            dati,
+            total_expected: total,
+            counter: total as u64,
+            who_ask: id,
+            acked: vec![false; total],
+            retry_count: vec![0; total],
+        };
+        self.fragment_send.insert(*session, data);
+        self.sessions.start(SessionKey::Outgoing(*session), total as u64, Instant::now());
 
-            // 4) invio i primi frammenti della finestra
-            for idx in 0..WINDOW_SIZE.min(total) {
-                self.send_single_fragment(*session, idx);
+        // 4) accodo tutto, partono i frammenti che la finestra verso id permette
+        self.flow.enqueue(id, *session, 0..total as u64);
+        self.flush_window(id);
+    }
+    fn flush_window(&mut self, dest: NodeId) {
+        if !self.send_ready(dest) {
+            self.flooding();
+        }
+    }
+    // Invia i frammenti che la finestra verso dest permette, false se dest non è raggiungibile:
+    // quelli rimasti tornano in testa alla coda, in ordine, e aspettano i percorsi del prossimo flood
+    fn send_ready(&mut self, dest: NodeId) -> bool {
+        let ready = self.flow.ready(dest);
+        for (n, &(session, idx)) in ready.iter().enumerate() {
+            if !self.send_single_fragment(session, idx as usize) {
+                for &(session, idx) in ready[n..].iter().rev() {
+                    self.flow.requeue(dest, session, idx);
+                }
+                return false;
             }
         }
+        true
     }
-    fn send_single_fragment(&mut self, session: u64, idx: usize) {
+    // false solo se non c'è un percorso verso il destinatario
+    fn send_single_fragment(&mut self, session: u64, idx: usize) -> bool {
         if let Some(data) = self.fragment_send.get(&session) {
             let who = data.who_ask;
             if let Some(path) = self.routing(who) {
@@ -228,8 +331,10 @@ impl Server {
                 self.send_packet(pkt);
             } else {
                 log::warn!("send_single_fragment: nessun percorso a {} (session {})", who, session);
+                return false;
             }
         }
+        true
     }
     fn handle_ack(&mut self, ack: Ack, session: u64, path: &[NodeId]) {
         let alpha = 0.2;
@@ -238,29 +343,57 @@ impl Server {
             *entry = (1.0 - alpha) * (*entry) + alpha * 1.0;
         }
 
-        // Sliding-window e cleanup classici
-        let mut next_idx = None;
+        // Sliding-window condivisa e cleanup classici
+        let mut destination = None;
         if let Some(d) = self.fragment_send.get_mut(&session) {
             let idx = ack.fragment_index as usize;
             if idx < d.total_expected && !d.acked[idx] {
                 d.acked[idx]    = true;
                 d.counter       = d.counter.saturating_sub(1);
-                if d.next_to_send < d.total_expected {
-                    next_idx = Some(d.next_to_send);
-                    d.next_to_send += 1;
-                }
+                destination = Some(d.who_ask);
             }
         }
-        if let Some(i) = next_idx {
-            self.send_single_fragment(session, i);
+        if let Some(dest) = destination {
+            self.flow.on_ack(dest, session, ack.fragment_index);
+            self.flush_window(dest);
         }
         if let Some(d) = self.fragment_send.get(&session) {
             if d.counter == 0 {
                 self.fragment_send.remove(&session);
+                self.forwarding.remove(&session);
+                self.flow.forget(session);
+                self.sessions.close(SessionKey::Outgoing(session), SessionState::Complete, Instant::now());
+            } else {
+                self.sessions.touch(SessionKey::Outgoing(session), Instant::now());
+            }
+        }
+    }
+    // Libera i buffer delle sessioni che non finiranno mai e le segnala al controller, insieme ai duplicati scartati
+    fn sweep_sessions(&mut self) {
+        let mut undelivered = Vec::new();
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(who, session) => {
+                    self.fragment_recv.remove(&(who, session));
+                }
+                SessionKey::Outgoing(session) => {
+                    self.fragment_send.remove(&session);
+                    self.flow.forget(session);
+                    undelivered.extend(self.forwarding.remove(&session)); // torna nella casella qui sotto
+                }
             }
+            log::warn!("sweep_sessions: sessione {:?} chiusa come {:?}", key, state);
+            let _ = self.send_event.send(ServerEvent::StuckSession(self.server_id, MyNodeType::ChatServer, key, state));
+        });
+        for message in undelivered {
+            self.requeue(message);
+        }
+        if let Some(duplicates) = duplicates {
+            let _ = self.send_event.send(ServerEvent::Duplicates(self.server_id, MyNodeType::ChatServer, duplicates));
         }
     }
     fn handle_nack(&mut self, fragment: Nack, _pos: &u64, session: &u64, packet: Packet) {
+        self.sessions.touch(SessionKey::Outgoing(*session), Instant::now());
 
         if let NackType::ErrorInRouting(bad) = fragment.nack_type{
             let bad_next = packet.routing_header.hops[0];
@@ -274,6 +407,10 @@ impl Server {
             let alpha = 0.2;
             let entry = self.statistics.entry(bad_hop).or_insert(0.5);
             *entry = (1.0 - alpha) * (*entry) + alpha * 0.0;
+            // Un drone perde pacchetti: la finestra verso il destinatario si dimezza
+            if let Some(data) = self.fragment_send.get(session) {
+                self.flow.on_dropped(data.who_ask);
+            }
         }
         // Logica standard di retry
         let retries :u32;
@@ -287,7 +424,13 @@ impl Server {
             if data.retry_count[idx] < (MAX_RETRIES as usize).try_into().unwrap() {
                 data.retry_count[idx] += 1;
                 retries = data.retry_count[idx];
-            } else { return; }
+            } else {
+                // Tentativi finiti: la sessione non finirà mai, sweep_sessions ne libera i buffer e la segnala
+                log::warn!("handle_nack: frammento {} della sessione {} perso dopo {} tentativi", idx, session, MAX_RETRIES);
+                self.flow.forget(*session);
+                self.sessions.fail(SessionKey::Outgoing(*session), Instant::now());
+                return;
+            }
         }
 
         // Riprova su nuovo percorso
@@ -302,6 +445,9 @@ impl Server {
             let pkt2  = Packet::new_fragment(SourceRoutingHeader::new(path, 1), *session, frag2);
             self.send_packet(pkt2);
         } else {
+            // Il posto nella finestra torna libero, il frammento riparte per primo col prossimo flood
+            self.flow.requeue(who, *session, fragment.fragment_index);
+            self.flooding();
             log::warn!("handle_nack: nessun percorso a {} per retry frag {} sess {}",
                        who, fragment.fragment_index, session);
         }
@@ -387,6 +533,14 @@ impl Server {
                     self.nodes_map.push((node_id, node_type, conns));
                 }
             }
+            // 1.1) I nuovi percorsi possono raggiungere client con messaggi in attesa
+            self.flush_mailbox();
+            // 1.2) e altri chat server a cui annunciare i nostri client
+            self.announce_clients();
+            // 1.3) e i destinatari dei frammenti rimasti senza percorso
+            for dest in self.flow.waiting() {
+                self.send_ready(dest);
+            }
 
             // 2) Forward flood-response se non sono iniziator
             if initiator_id != self.server_id {
@@ -529,9 +683,21 @@ impl Server {
     fn handle_command(&mut self, session_key: (NodeId, u64)) {
         let (id_client, _) = session_key;
 
-        let data = self.fragment_recv.get(&session_key).unwrap();
-        let d = data.dati.clone();
-        let command: ComandoChat = deserialize_comando_chat(d);
+        let bytes = match self.fragment_recv.get(&session_key).map(|data| data.bytes()) {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) => {
+                log::error!("handle_command: messaggio incompleto da {}: {}", id_client, e);
+                return;
+            }
+            None => return,
+        };
+        let command: ComandoChat = match deserialize_comando_chat(&bytes) {
+            Ok(command) => command,
+            Err(e) => {
+                log::warn!("handle_command: messaggio scartato da {}: {}", id_client, e);
+                return;
+            }
+        };
 
         match command {
             ComandoChat::Client(request) => match request {
@@ -541,10 +707,21 @@ impl Server {
                     self.send_response(id_client, response, &session_id);
                 }
                 ChatRequest::RegisterClient(client_id) => {
-                    self.registered_clients.push(client_id);
+                    if !self.registered_clients.contains(&client_id) {
+                        self.registered_clients.push(client_id);
+                    }
                     let response = Risposta::Chat(ChatResponse::RegisterClient(true));
                     let session_id = self.get_session();
                     self.send_response(id_client, response, &session_id);
+                    // La lista da cui partono le notifiche di presenza
+                    let list = Risposta::Chat(ChatResponse::RegisteredClients(self.registered_clients.clone()));
+                    let session_id = self.get_session();
+                    self.send_response(id_client, list, &session_id);
+                    self.send_presence(client_id, true);
+                    // Consegno i messaggi arrivati mentre il client era irraggiungibile
+                    self.flush_mailbox();
+                    self.federation.changed();
+                    self.announce_clients();
                 }
                 ChatRequest::GetListClients => {
                     let response = Risposta::Chat(ChatResponse::RegisteredClients(self.registered_clients.clone()));
@@ -555,7 +732,12 @@ impl Server {
                     let sender = message.from_id;
                     let receiver = message.to_id;
                     match self.is_present(receiver, sender) {
+                        Ok(_) if self.routing(receiver).is_none() => {
+                            self.history.record(message.clone());
+                            self.queue_message(message);
+                        }
                         Ok(string) => {
+                            self.history.record(message.clone());
                             let r1 = Risposta::Chat(ChatResponse::SendMessage(Ok(string)));
                             let r2 = Risposta::Chat(ChatResponse::ForwardMessage(message.clone()));
 
@@ -565,6 +747,19 @@ impl Server {
                             self.send_response(sender, r1, &session_sender);
                             self.send_response(receiver, r2, &session_receiver);
                         }
+                        Err(_) if self.registered_clients.contains(&sender) && self.federation.owner(receiver).is_some() => {
+                            // Il destinatario è registrato su un altro chat server
+                            let peer = self.federation.owner(receiver).unwrap();
+                            let r1 = Risposta::Chat(ChatResponse::SendMessage(Ok(format!("The server will relay the message to server {}", peer))));
+                            self.history.record(message.clone());
+                            let r2 = Risposta::Chat(ChatResponse::RelayMessage(message, self.server_id));
+
+                            let session_sender = self.get_session();
+                            let session_peer = self.get_session();
+
+                            self.send_response(sender, r1, &session_sender);
+                            self.send_response(peer, r2, &session_peer);
+                        }
                         Err(string) => {
                             let r1 = Risposta::Chat(ChatResponse::SendMessage(Err(string)));
                             let session_id = self.get_session();
@@ -572,11 +767,90 @@ impl Server {
                         }
                     }
                 }
+                ChatRequest::Receipt(receipt) => {
+                    // La ricevuta torna al mittente solo se entrambi i client sono registrati
+                    match self.is_present(receipt.to_id, receipt.from_id) {
+                        Ok(_) => {
+                            let to_id = receipt.to_id;
+                            let response = Risposta::Chat(ChatResponse::ForwardReceipt(receipt));
+                            let session_id = self.get_session();
+                            self.send_response(to_id, response, &session_id);
+                        }
+                        Err(_) if self.registered_clients.contains(&receipt.from_id) && self.federation.owner(receipt.to_id).is_some() => {
+                            let peer = self.federation.owner(receipt.to_id).unwrap();
+                            let response = Risposta::Chat(ChatResponse::RelayReceipt(receipt));
+                            let session_id = self.get_session();
+                            self.send_response(peer, response, &session_id);
+                        }
+                        Err(e) => {
+                            log::warn!("handle_command: ricevuta di {} scartata: {}", receipt.from_id, e);
+                        }
+                    }
+                }
                 ChatRequest::EndChat(id) => {
                     self.registered_clients.retain(|&x| x != id);
                     let response = Risposta::Chat(ChatResponse::EndChat(true));
                     let session_id = self.get_session();
                     self.send_response(id_client, response, &session_id);
+                    self.send_presence(id, false);
+                    // Il client esce anche da tutte le stanze
+                    for room in self.rooms.leave_all(id) {
+                        self.send_members(&room);
+                    }
+                    self.federation.changed();
+                    self.announce_clients();
+                }
+                // Le stanze si usano solo per conto di chi manda la richiesta
+                ChatRequest::CreateRoom(room, client_id) => {
+                    let result = self.room_client(client_id, id_client).and_then(|client| self.rooms.create(&room, client));
+                    self.room_update(room, result, id_client);
+                }
+                ChatRequest::JoinRoom(room, client_id) => {
+                    let result = self.room_client(client_id, id_client).and_then(|client| self.rooms.join(&room, client));
+                    self.room_update(room, result, id_client);
+                }
+                ChatRequest::LeaveRoom(room, client_id) => {
+                    let result = match client_id == id_client {
+                        true => self.rooms.leave(&room, id_client),
+                        false => Err(format!("Client {} can't leave a room for client {}", id_client, client_id)),
+                    };
+                    self.room_update(room, result, id_client);
+                }
+                ChatRequest::GetHistory(peer, since) => {
+                    // Lo storico della conversazione tra chi lo chiede e peer, dall'indice since
+                    let result = match self.registered_clients.contains(&id_client) {
+                        true => Ok(self.history.since(id_client, peer, since)),
+                        false => Err("Only registered clients can read their history".to_string()),
+                    };
+                    let response = Risposta::Chat(ChatResponse::History(peer, result));
+                    let session_id = self.get_session();
+                    self.send_response(id_client, response, &session_id);
+                }
+                ChatRequest::RoomMembers(room) => {
+                    let members = self.rooms.members(&room);
+                    let response = Risposta::Chat(ChatResponse::RoomMembers(room, members));
+                    let session_id = self.get_session();
+                    self.send_response(id_client, response, &session_id);
+                }
+                ChatRequest::SendRoomMessage(message) => {
+                    let sender = id_client;
+                    let members = self.rooms.members(&message.room);
+                    if message.from_id == sender && self.registered_clients.contains(&sender) && members.contains(&sender) {
+                        let r1 = Risposta::Chat(ChatResponse::SendMessage(Ok("The server will forward the message to the room".to_string())));
+                        let session_id = self.get_session();
+                        self.send_response(sender, r1, &session_id);
+                        // Inoltro a tutti gli altri membri della stanza
+                        for member in members.into_iter().filter(|&m| m != sender) {
+                            let response = Risposta::Chat(ChatResponse::ForwardRoomMessage(message.clone()));
+                            let session_id = self.get_session();
+                            self.send_response(member, response, &session_id);
+                        }
+                    } else {
+                        log::warn!("handle_command: {} non è nella stanza {}", sender, message.room);
+                        let r1 = Risposta::Chat(ChatResponse::SendMessage(Err(format!("Only the members of room {} can post in it", message.room))));
+                        let session_id = self.get_session();
+                        self.send_response(sender, r1, &session_id);
+                    }
                 }
             },
             ComandoChat::Text(text) => match text {
@@ -588,9 +862,161 @@ impl Server {
                 _ => {}
             },
             ComandoChat::WebBrowser(_) => {}
+            ComandoChat::Peer(ChatResponse::ServerTypeChat(ServerType::CommunicationServer)) => {
+                self.federation.discovered(id_client);
+                self.announce_clients();
+            }
+            ComandoChat::Peer(_) if !self.federation.is_peer(id_client) => {
+                log::warn!("handle_command: messaggio da {} scartato, non è un chat server conosciuto", id_client);
+            }
+            ComandoChat::Peer(peer) => match peer {
+                ChatResponse::Federate(clients) => {
+                    let first = self.federation.update(id_client, clients);
+                    // Il peer non conosce ancora i nostri client
+                    if self.federation.announce(id_client) || first {
+                        let response = Risposta::Chat(ChatResponse::Federate(self.registered_clients.clone()));
+                        let session_id = self.get_session();
+                        self.send_response(id_client, response, &session_id);
+                    }
+                }
+                ChatResponse::RelayMessage(message, origin) => {
+                    let receiver = message.to_id;
+                    if self.registered_clients.contains(&receiver) {
+                        self.history.record(message.clone());
+                        let response = Risposta::Chat(ChatResponse::ForwardRelayedMessage(message, origin));
+                        let session_id = self.get_session();
+                        self.send_response(receiver, response, &session_id);
+                    } else {
+                        log::warn!("handle_command: messaggio inoltrato da {} scartato, {} non è registrato", id_client, receiver);
+                    }
+                }
+                ChatResponse::RelayReceipt(receipt) => {
+                    let to_id = receipt.to_id;
+                    if self.registered_clients.contains(&to_id) {
+                        let response = Risposta::Chat(ChatResponse::ForwardReceipt(receipt));
+                        let session_id = self.get_session();
+                        self.send_response(to_id, response, &session_id);
+                    }
+                }
+                _ => {}
+            },
         }
         self.processed_sessions.remove(&session_key);
     }
+    // Il destinatario è registrato ma irraggiungibile: il messaggio aspetta nella sua casella
+    fn queue_message(&mut self, message: MessageChat) {
+        let sender = message.from_id;
+        let receipt = Receipt { message_id: message.message_id, from_id: message.to_id, to_id: sender, status: MessageStatus::Queued };
+        match self.mailbox.push(message) {
+            Ok(()) => {
+                let r1 = Risposta::Chat(ChatResponse::SendMessage(Ok("The destination client is unreachable, the server queued the message".to_string())));
+                let r2 = Risposta::Chat(ChatResponse::ForwardReceipt(receipt));
+                let session_response = self.get_session();
+                let session_receipt = self.get_session();
+                self.send_response(sender, r1, &session_response);
+                self.send_response(sender, r2, &session_receipt);
+            }
+            Err(message) => {
+                log::warn!("queue_message: casella di {} piena, messaggio di {} rifiutato", message.to_id, sender);
+                let r1 = Risposta::Chat(ChatResponse::SendMessage(Err("The mailbox of the destination client is full".to_string())));
+                let session_id = self.get_session();
+                self.send_response(sender, r1, &session_id);
+            }
+        }
+    }
+    // Un messaggio inoltrato che non è arrivato torna nella casella del destinatario, il mittente lo sa in coda
+    fn requeue(&mut self, message: MessageChat) {
+        let sender = message.from_id;
+        let receipt = Receipt { message_id: message.message_id, from_id: message.to_id, to_id: sender, status: MessageStatus::Queued };
+        match self.mailbox.push(message) {
+            Ok(()) => {
+                if self.registered_clients.contains(&sender) {
+                    let response = Risposta::Chat(ChatResponse::ForwardReceipt(receipt));
+                    let session_id = self.get_session();
+                    self.send_response(sender, response, &session_id);
+                }
+            }
+            Err(message) => {
+                log::warn!("requeue: casella di {} piena, messaggio di {} scartato", message.to_id, sender);
+            }
+        }
+    }
+    // Consegna i messaggi in attesa dei client registrati per cui ora esiste un percorso
+    fn flush_mailbox(&mut self) {
+        for client in self.mailbox.waiting() {
+            if self.registered_clients.contains(&client) && self.routing(client).is_some() {
+                for message in self.mailbox.take(client) {
+                    let response = Risposta::Chat(ChatResponse::ForwardMessage(message));
+                    let session_id = self.get_session();
+                    self.send_response(client, response, &session_id);
+                }
+            }
+        }
+    }
+    // Avvisa tutti gli altri client registrati che client è entrato (true) o uscito (false)
+    fn send_presence(&mut self, client: NodeId, joined: bool) {
+        for other in self.registered_clients.clone() {
+            if other != client {
+                let response = Risposta::Chat(ChatResponse::Presence(client, joined));
+                let session_id = self.get_session();
+                self.send_response(other, response, &session_id);
+            }
+        }
+    }
+    // Chiede il tipo ai server mai interpellati e manda i client registrati a ogni chat server che non li ha ancora
+    fn announce_clients(&mut self) {
+        let servers: Vec<NodeId> = self.nodes_map.iter()
+            .filter(|(id, node_type, _)| *node_type == NodeType::Server && *id != self.server_id)
+            .map(|(id, _, _)| *id)
+            .collect();
+        for server in servers {
+            if self.federation.ask(server) {
+                self.ask_server_type(server);
+            }
+        }
+        for server in self.federation.servers() {
+            if self.federation.announce(server) {
+                let response = Risposta::Chat(ChatResponse::Federate(self.registered_clients.clone()));
+                let session_id = self.get_session();
+                self.send_response(server, response, &session_id);
+            }
+        }
+    }
+    // Il client per cui vale create o join: chi ha mandato la richiesta, se registrato
+    fn room_client(&self, client_id: NodeId, id_client: NodeId) -> Result<NodeId, String> {
+        if client_id != id_client {
+            Err(format!("Client {} can't use the rooms for client {}", id_client, client_id))
+        } else if !self.registered_clients.contains(&id_client) {
+            Err("Only registered clients can use the rooms".to_string())
+        } else {
+            Ok(id_client)
+        }
+    }
+    // Risponde a create, join o leave; se la stanza è cambiata avvisa tutti i membri
+    fn room_update(&mut self, room: String, result: Result<String, String>, client: NodeId) {
+        let changed = result.is_ok();
+        let response = Risposta::Chat(ChatResponse::Room(room.clone(), result));
+        let session_id = self.get_session();
+        self.send_response(client, response, &session_id);
+        if changed {
+            self.send_members(&room);
+            if !self.rooms.members(&room).contains(&client) {
+                // Il client è uscito: la sua lista dei membri si svuota
+                let response = Risposta::Chat(ChatResponse::RoomMembers(room, Vec::new()));
+                let session_id = self.get_session();
+                self.send_response(client, response, &session_id);
+            }
+        }
+    }
+    // Manda a ogni membro la lista aggiornata dei membri della stanza
+    fn send_members(&mut self, room: &str) {
+        let members = self.rooms.members(room);
+        for &member in members.iter() {
+            let response = Risposta::Chat(ChatResponse::RoomMembers(room.to_string(), members.clone()));
+            let session_id = self.get_session();
+            self.send_response(member, response, &session_id);
+        }
+    }
     fn is_present(&self, receiver: NodeId, sender: NodeId) -> Result<String, String> {
         if self.registered_clients.contains(&sender) && self.registered_clients.contains(&receiver) {
             Ok("The server will forward the message to the final client".to_string())
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::Debug;
+use std::time::Instant;
 use petgraph::graph::{Graph, NodeIndex};
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use petgraph::Incoming;
@@ -10,6 +11,13 @@ use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Fragment, NackType, NodeType, Packet, PacketType};
 use crate::common_data::common::*;
 use crate::servers::assembler::*;
+use crate::servers::federation::Federation;
+use crate::servers::flow_control::FlowControl;
+use crate::servers::history::History;
+use crate::servers::mailbox::Mailbox;
+use crate::servers::rooms::Rooms;
+use crate::common_data::fragmentation::{FragmentError, Payload, Reassembler};
+use crate::common_data::sessions::{SessionTable, SWEEP_INTERVAL};
 use crate::gui::login_window::NodeType as MyNodeType;
 
 #[derive(Serialize, Clone, Debug)]
@@ -32,8 +40,15 @@ pub struct Server{
     packet_recv: Receiver<Packet>,
     already_visited: HashSet<(NodeId,u64)>,
     pub packet_send: HashMap<NodeId, Sender<Packet>>,
-    fragments_recv : HashMap<(NodeId,u64),Vec<Fragment>>,
+    fragments_recv : HashMap<(NodeId,u64),Reassembler>,
     fragments_send : HashMap<u64,(NodeId,NodeType,Vec<Fragment>)>,
+    flow : FlowControl,
+    mailbox : Mailbox,
+    forwarding : HashMap<u64,MessageChat>, //outgoing session -> message it forwards, back in the mailbox if the session fails
+    rooms : Rooms,
+    federation : Federation,
+    history : History,
+    sessions : SessionTable,
     rcv_flood: Receiver<BackGroundFlood>,
     rcv_command: Receiver<ServerCommands>,
     send_event: Sender<ServerEvent>
@@ -55,11 +70,23 @@ impl Server{
             packet_send,
             fragments_recv : HashMap::new(),
             fragments_send : HashMap::new(),
+            flow : FlowControl::new(),
+            mailbox : Mailbox::new(),
+            forwarding : HashMap::new(),
+            rooms : Rooms::new(),
+            federation : Federation::new(),
+            history : History::new(),
+            sessions : SessionTable::default(),
             rcv_flood,
             rcv_command,
             send_event
         }
     }
+    /// Replaces the in-memory history, e.g. with one persisted on disk
+    pub fn with_history(mut self, history: History) -> Self{
+        self.history = history;
+        self
+    }
     pub(crate) fn run(&mut self) {
         loop {
             select_biased!{
@@ -97,7 +124,9 @@ impl Server{
                         }
                     }
                 }
+                default(SWEEP_INTERVAL) => {}
             }
+            self.sweep_sessions();
         }
     }
     fn send_topology_graph(&self){
@@ -177,94 +206,262 @@ impl Server{
 
 
     fn send_packet(&mut self, p:ChatResponse, id:NodeId, nt:NodeType){
+        if let Some(vec) = self.send_message(&p, id, nt){
+            match p {
+                ChatResponse::ServerTypeChat(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::SendingServerTypeChat(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::RegisterClient(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ClientRegistration(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::RegisteredClients(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::SendingClientList(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::SendMessage(_) => {}
+                ChatResponse::EndChat(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ClientElimination(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::ForwardMessage(mc) => {
+                    self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ForwardingMessage(vec.len() as u64),self.session_id)).unwrap();
+                    self.forwarding.insert(self.session_id, mc);
+                }
+                ChatResponse::ForwardReceipt(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ForwardingReceipt(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::Room(..) | ChatResponse::RoomMembers(..) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::RoomUpdate(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::ForwardRoomMessage(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ForwardingRoomMessage(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::Federate(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::AnnouncingClients(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::RelayMessage(..) | ChatResponse::RelayReceipt(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::RelayingToPeer(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::ForwardRelayedMessage(..) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ForwardingMessage(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::Presence(..) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::SendingPresence(vec.len() as u64),self.session_id)).unwrap();}
+                ChatResponse::History(..) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::SendingHistory(vec.len() as u64),self.session_id)).unwrap();}
+            }
+            self.session_id+=1;
+        }
+    }
+
+    //asks another server its type, the chat servers answer with ServerTypeChat
+    fn ask_server_type(&mut self, server: NodeId){
+        if let Some(vec) = self.send_message(&TextServer::ServerTypeReq, server, NodeType::Server){
+            self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::AskingServerType(vec.len() as u64),self.session_id)).unwrap();
+            self.session_id+=1;
+        }
+    }
+
+    //fragments the message and queues it towards `id` under the current session, the packets are returned to log the event
+    fn send_message<T: Fragmentation + Payload + Debug>(&mut self, p:&T, id:NodeId, nt:NodeType) -> Option<Vec<Packet>>{
         // println!("flooding : {:?}", self.flooding); //fa vedere tutte le flood response salvaate nel server
         // println!("graph del chatserver {:?}: {:?}",self.server_id, self.neigh_map); //fa vedere il grafo (tutti i nodi e tutti gli edges)
         if let Some(srh) = self.best_path_custom_cost(id,nt){
             // println!("srh : {:?}",srh);
-            if let Ok(vec) = p.serialize_data(srh,self.session_id){
+            if let Ok(vec) = p.serialize_data(srh,self.session_id,SenderRole::ChatServer){
                 let mut fragments_send = Vec::new();
                 for i in vec.iter(){
                     if let PacketType::MsgFragment(fragment) = i.clone().pack_type{
                         fragments_send.push(fragment);
                     }
-                    self.forward_packet(i.clone());
                 }
+                //the fragments leave as fast as the window towards the destination allows
+                self.flow.enqueue(id, self.session_id, fragments_send.iter().map(|f| f.fragment_index));
                 self.fragments_send.insert(self.session_id.clone(), (id,nt,fragments_send));
-                match p {
-                    ChatResponse::ServerTypeChat(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::SendingServerTypeChat(vec.len() as u64),self.session_id)).unwrap();}
-                    ChatResponse::RegisterClient(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ClientRegistration(vec.len() as u64),self.session_id)).unwrap();}
-                    ChatResponse::RegisteredClients(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::SendingClientList(vec.len() as u64),self.session_id)).unwrap();}
-                    ChatResponse::SendMessage(_) => {}
-                    ChatResponse::EndChat(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ClientElimination(vec.len() as u64),self.session_id)).unwrap();}
-                    ChatResponse::ForwardMessage(_) => {self.send_event.send(ServerEvent::ChatPacketInfo(self.server_id, MyNodeType::ChatServer, ChatServerEvent::ForwardingMessage(vec.len() as u64),self.session_id)).unwrap();}
-                }
-                self.session_id+=1;
+                self.sessions.start(SessionKey::Outgoing(self.session_id), vec.len() as u64, Instant::now());
+                self.flush_window(id);
                 //aggiungere un field nella struct server per salvare tutti i vari pacchetti nel caso in cui fossero droppati ecc.
+                return Some(vec);
             }
         }else {
             println!("sono il chatserver {:?} no route found for sending packet {:?} to {:?} {:?}!",self.server_id,p,nt,id);
         }
+        None
     }
 
     fn handle_msg_fragment(&mut self, p:Packet){
         self.forward_packet(create_ack(p.clone()));
         if let PacketType::MsgFragment(fragment) = p.pack_type{
-            if self.fragments_recv.contains_key(&(p.routing_header.hops.clone()[0],p.session_id)){
-                if let Some(vec) = self.fragments_recv.get_mut(&(p.routing_header.hops[0],p.session_id)){
-                    vec.push(fragment.clone());
-                }else {
-                    println!("This else shouldn't be reached, it means that the server has no vec of fragments (received) associated to the key");
+            let key = (p.routing_header.hops[0], p.session_id);
+            let session = SessionKey::Incoming(key.0, key.1);
+            match self.sessions.state(session) {
+                Some(SessionState::Open) => self.sessions.touch(session, Instant::now()),
+                Some(_) => { //late duplicate or replay of a session already closed
+                    self.sessions.duplicate();
+                    return;
                 }
-            }else {
-                let mut vec = Vec::new();
-                vec.push(fragment.clone());
-                self.fragments_recv.insert((p.routing_header.hops.clone()[0], p.session_id), vec);
-            }
-            if let Some(vec) = self.fragments_recv.get_mut(&(p.routing_header.hops[0],p.session_id)){
-                if fragment.total_n_fragments == vec.len() as u64{
-                    if let Ok(totalmsg) = ChatRequest::deserialize_data(vec){
-                        match totalmsg{
-                            ChatRequest::ServerType => {
-                                // println!("Server type request received from client: {:?}!", p.routing_header.hops.clone()[0]);
-                                self.send_packet(ChatResponse::ServerTypeChat(self.clone().server_type), p.routing_header.hops[0], NodeType::Client);
-                            }
-                            ChatRequest::RegisterClient(n) => {
-                                // println!("Register client request received from client: {:?}!", p.routing_header.hops.clone()[0]);
+                None => {
+                    if !self.sessions.start(session, fragment.total_n_fragments, Instant::now()){
+                        return;
+                    }
+                }
+            }
+            let inserted = Reassembler::entry(&mut self.fragments_recv, key, fragment.total_n_fragments)
+                .and_then(|reassembler| reassembler.insert(&fragment));
+            if let Err(err) = inserted {
+                match err {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    FragmentError::InvalidTotal(_) => self.sessions.close(session, SessionState::Failed, Instant::now()), //never started
+                    _ => {}
+                }
+                // duplicates and malformed fragments must not trigger the command a second time
+                println!("server {:?} discarded a fragment from {:?}: {}", self.server_id, key.0, err);
+                return;
+            }
+            if !self.fragments_recv[&key].is_complete(){
+                return;
+            }
+            //complete: its fragments are no longer needed, only the session state is kept
+            let result = self.fragments_recv.remove(&key).unwrap().envelope();
+            let state = if result.is_ok() { SessionState::Complete } else { SessionState::Failed };
+            self.sessions.close(session, state, Instant::now());
+            let message = match result {
+                Ok(envelope) => envelope.message,
+                Err(err) => {
+                    println!("server {:?} rejected a message from {:?}: {}", self.server_id, key.0, err);
+                    return;
+                }
+            };
+            match message {
+                Message::ChatRequest(totalmsg) => {
+                    match totalmsg{
+                        ChatRequest::ServerType => {
+                            // println!("Server type request received from client: {:?}!", p.routing_header.hops.clone()[0]);
+                            self.send_packet(ChatResponse::ServerTypeChat(self.clone().server_type), p.routing_header.hops[0], NodeType::Client);
+                        }
+                        ChatRequest::RegisterClient(n) => {
+                            // println!("Register client request received from client: {:?}!", p.routing_header.hops.clone()[0]);
+                            if !self.registered_clients.contains(&n){
                                 self.registered_clients.push(n);
-                                self.send_packet(ChatResponse::RegisterClient(true), p.routing_header.hops[0], NodeType::Client);
                             }
-                            ChatRequest::GetListClients => {
-                                // println!("Get client list request received from client: {:?}!", p.routing_header.hops.clone()[0]);
-                                self.send_packet(ChatResponse::RegisteredClients(self.clone().registered_clients), p.routing_header.hops[0], NodeType::Client);
-                            }
-                            ChatRequest::SendMessage(mc, _) => {
-                                // println!("Send message request received from client: {:?}!", p.routing_header.hops.clone()[0]);
-                                // println!("Registered clients: {:?}",self.registered_clients);
-                                if self.registered_clients.contains(&mc.from_id) && self.registered_clients.contains(&mc.to_id){
+                            self.send_packet(ChatResponse::RegisterClient(true), p.routing_header.hops[0], NodeType::Client);
+                            //the list the presence notifications start from
+                            self.send_packet(ChatResponse::RegisteredClients(self.registered_clients.clone()), p.routing_header.hops[0], NodeType::Client);
+                            self.send_presence(n, true);
+                            self.flush_mailbox(); //the messages queued while the client was unreachable
+                            self.federation.changed();
+                            self.announce_clients();
+                        }
+                        ChatRequest::GetListClients => {
+                            // println!("Get client list request received from client: {:?}!", p.routing_header.hops.clone()[0]);
+                            self.send_packet(ChatResponse::RegisteredClients(self.clone().registered_clients), p.routing_header.hops[0], NodeType::Client);
+                        }
+                        ChatRequest::SendMessage(mc, _) => {
+                            // println!("Send message request received from client: {:?}!", p.routing_header.hops.clone()[0]);
+                            // println!("Registered clients: {:?}",self.registered_clients);
+                            if self.registered_clients.contains(&mc.from_id) && self.registered_clients.contains(&mc.to_id){
+                                self.history.record(mc.clone());
+                                if self.best_path_custom_cost(mc.to_id, NodeType::Client).is_some(){
                                     self.send_packet(ChatResponse::SendMessage(Ok("The server will forward the message to the final client".to_string())), p.routing_header.hops[0], NodeType::Client);
                                     self.send_packet(ChatResponse::ForwardMessage(mc.clone()), mc.to_id, NodeType::Client);
                                 }else {
-                                    self.send_packet(ChatResponse::SendMessage(Err("Error with the registration of the two involved clients".to_string())), p.routing_header.hops[0], NodeType::Client);
+                                    self.queue_message(mc, p.routing_header.hops[0]);
                                 }
+                            }else if let (true, Some(peer)) = (self.registered_clients.contains(&mc.from_id), self.federation.owner(mc.to_id)) {
+                                //the destination is registered on another chat server
+                                self.send_packet(ChatResponse::SendMessage(Ok(format!("The server will relay the message to server {}", peer))), p.routing_header.hops[0], NodeType::Client);
+                                self.history.record(mc.clone());
+                                self.send_packet(ChatResponse::RelayMessage(mc, self.server_id), peer, NodeType::Server);
+                            }else {
+                                self.send_packet(ChatResponse::SendMessage(Err("Error with the registration of the two involved clients".to_string())), p.routing_header.hops[0], NodeType::Client);
                             }
-                            ChatRequest::EndChat(n) => {
-                                // println!("end chat request received from client: {:?}!", p.routing_header.hops.clone()[0]);
-                                self.registered_clients.retain(|x| *x != n);
-                                self.send_packet(ChatResponse::EndChat(true), p.routing_header.hops[0], NodeType::Client);
+                        }
+                        ChatRequest::Receipt(receipt) => {
+                            //relayed only between registered clients, like the message it acknowledges
+                            if self.registered_clients.contains(&receipt.from_id) && self.registered_clients.contains(&receipt.to_id){
+                                let to_id = receipt.to_id;
+                                self.send_packet(ChatResponse::ForwardReceipt(receipt), to_id, NodeType::Client);
+                            }else if let (true, Some(peer)) = (self.registered_clients.contains(&receipt.from_id), self.federation.owner(receipt.to_id)) {
+                                self.send_packet(ChatResponse::RelayReceipt(receipt), peer, NodeType::Server);
+                            }else {
+                                println!("server {:?} dropped a receipt from {:?}: the clients aren't registered", self.server_id, receipt.from_id);
                             }
                         }
-                    }else {
-                        if let Ok(totalmsg) = TextServer::deserialize_data(vec) {
-                            match totalmsg {
-                                TextServer::ServerTypeReq => {
-                                    // println!("Server type request received from server: {:?}!", p.routing_header.hops.clone()[0]);
-                                    self.send_packet(ChatResponse::ServerTypeChat(self.clone().server_type), p.routing_header.hops[0], NodeType::Server);
+                        ChatRequest::EndChat(n) => {
+                            // println!("end chat request received from client: {:?}!", p.routing_header.hops.clone()[0]);
+                            self.registered_clients.retain(|x| *x != n);
+                            self.send_packet(ChatResponse::EndChat(true), p.routing_header.hops[0], NodeType::Client);
+                            self.send_presence(n, false);
+                            for room in self.rooms.leave_all(n){
+                                self.send_members(&room);
+                            }
+                            self.federation.changed();
+                            self.announce_clients();
+                        }
+                        //the rooms are used by the client that sent the request, never on behalf of another one
+                        ChatRequest::CreateRoom(room, n) => {
+                            let client = p.routing_header.hops[0];
+                            let result = self.room_client(n, client).and_then(|client| self.rooms.create(&room, client));
+                            self.room_update(room, result, client);
+                        }
+                        ChatRequest::JoinRoom(room, n) => {
+                            let client = p.routing_header.hops[0];
+                            let result = self.room_client(n, client).and_then(|client| self.rooms.join(&room, client));
+                            self.room_update(room, result, client);
+                        }
+                        ChatRequest::LeaveRoom(room, n) => {
+                            let client = p.routing_header.hops[0];
+                            let result = if n == client { self.rooms.leave(&room, client) } else { Err(format!("Client {} can't leave a room for client {}", client, n)) };
+                            self.room_update(room, result, client);
+                        }
+                        ChatRequest::GetHistory(peer, since) => {
+                            let client = p.routing_header.hops[0];
+                            let result = if self.registered_clients.contains(&client) {
+                                Ok(self.history.since(client, peer, since))
+                            } else {
+                                Err("Only registered clients can read their history".to_string())
+                            };
+                            self.send_packet(ChatResponse::History(peer, result), client, NodeType::Client);
+                        }
+                        ChatRequest::RoomMembers(room) => {
+                            let members = self.rooms.members(&room);
+                            self.send_packet(ChatResponse::RoomMembers(room, members), p.routing_header.hops[0], NodeType::Client);
+                        }
+                        ChatRequest::SendRoomMessage(rm) => {
+                            let members = self.rooms.members(&rm.room);
+                            if rm.from_id == p.routing_header.hops[0] && self.registered_clients.contains(&rm.from_id) && members.contains(&rm.from_id){
+                                self.send_packet(ChatResponse::SendMessage(Ok("The server will forward the message to the room".to_string())), p.routing_header.hops[0], NodeType::Client);
+                                for member in members.into_iter().filter(|member| *member != rm.from_id){
+                                    self.send_packet(ChatResponse::ForwardRoomMessage(rm.clone()), member, NodeType::Client);
                                 }
-                                _ => { println!("I shouldn't receive these commands"); }
+                            }else {
+                                self.send_packet(ChatResponse::SendMessage(Err(format!("Only the members of room {} can post in it", rm.room))), p.routing_header.hops[0], NodeType::Client);
                             }
                         }
                     }
                 }
+                Message::TextServer(totalmsg) => {
+                    match totalmsg {
+                        TextServer::ServerTypeReq => {
+                            // println!("Server type request received from server: {:?}!", p.routing_header.hops.clone()[0]);
+                            self.send_packet(ChatResponse::ServerTypeChat(self.clone().server_type), p.routing_header.hops[0], NodeType::Server);
+                        }
+                        _ => { println!("I shouldn't receive these commands"); }
+                    }
+                }
+                Message::ChatResponse(totalmsg) => {
+                    //from the other chat servers
+                    let peer = p.routing_header.hops[0];
+                    if let ChatResponse::ServerTypeChat(ServerType::CommunicationServer) = totalmsg {
+                        self.federation.discovered(peer);
+                        self.announce_clients();
+                        return;
+                    }
+                    if !self.federation.is_peer(peer){
+                        println!("server {:?} dropped a message from {:?}: it isn't a known chat server", self.server_id, peer);
+                        return;
+                    }
+                    match totalmsg {
+                        ChatResponse::Federate(clients) => {
+                            let first = self.federation.update(peer, clients);
+                            if self.federation.announce(peer) || first { //the peer doesn't know our clients yet
+                                self.send_packet(ChatResponse::Federate(self.registered_clients.clone()), peer, NodeType::Server);
+                            }
+                        }
+                        ChatResponse::RelayMessage(mc, origin) => {
+                            if self.registered_clients.contains(&mc.to_id){
+                                self.history.record(mc.clone());
+                                self.send_packet(ChatResponse::ForwardRelayedMessage(mc.clone(), origin), mc.to_id, NodeType::Client);
+                            }else {
+                                println!("server {:?} dropped a message relayed by {:?}: client {:?} isn't registered", self.server_id, peer, mc.to_id);
+                            }
+                        }
+                        ChatResponse::RelayReceipt(receipt) => {
+                            if self.registered_clients.contains(&receipt.to_id){
+                                let to_id = receipt.to_id;
+                                self.send_packet(ChatResponse::ForwardReceipt(receipt), to_id, NodeType::Client);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                other => { println!("server {:?} can't handle {} messages", self.server_id, other.kind()); }
             }
         }
     }
@@ -272,9 +469,21 @@ impl Server{
     fn handle_ack(&mut self, packet : Packet){
         let s_id=packet.session_id;
         if let PacketType::Ack(ack) = packet.pack_type{
-            self.fragments_send.get_mut(&s_id).unwrap().2.retain(|x| x.fragment_index!=ack.fragment_index);
+            if let Some(info) = self.fragments_send.get_mut(&s_id){
+                info.2.retain(|x| x.fragment_index!=ack.fragment_index);
+                let dest = info.0;
+                let done = info.2.is_empty();
+                self.flow.on_ack(dest, s_id, ack.fragment_index);
+                if done {
+                    self.fragments_send.remove(&s_id); //every fragment was acked
+                    self.forwarding.remove(&s_id);
+                    self.sessions.close(SessionKey::Outgoing(s_id), SessionState::Complete, Instant::now());
+                }else {
+                    self.sessions.touch(SessionKey::Outgoing(s_id), Instant::now());
+                }
+                self.flush_window(dest);
+            }
         }
-        //forse da sistemare perchè c'è un unwrap, anche se in teoria gli ack che arrivano al mio server hanno un session id corretto
         for i in packet.routing_header.hops.iter().skip(1){
             let ni = self.find_node(*i, NodeType::Drone);
             match  ni{
@@ -296,7 +505,8 @@ impl Server{
         }
     }
 
-    fn packet_recover(&mut self, s_id: u64, lost_fragment_index: u64){
+    //sends again one of the fragments of a session on the current best path, false if there's no route to its destination
+    fn packet_recover(&mut self, s_id: u64, lost_fragment_index: u64) -> bool{
         // println!("did I call packet_recover? I'm chatserver {}",self.server_id);
         if self.fragments_send.contains_key(&s_id){
             let info = self.fragments_send.get(&s_id).unwrap();
@@ -312,19 +522,173 @@ impl Server{
                         self.forward_packet(pack);
                         break;
                     }else {
-                        println!("there isn't a route to reach the packet destination (shouldn't happen)");
+                        println!("there isn't a route to {:?} anymore, the fragment waits for a new flood", info.0);
+                        return false;
                     }
                 }
             }
         }else {
             println!("This else shouldn't be reached, it means that the server has no vec of fragments (sent) associated to the key");
         }
+        true
+    }
+
+    //resends a nacked fragment, without a route it gives its slot back and waits at the head of the queue for a new flood
+    fn resend(&mut self, s_id: u64, fragment_index: u64){
+        if !self.packet_recover(s_id, fragment_index){
+            if let Some(info) = self.fragments_send.get(&s_id){
+                self.flow.requeue(info.0, s_id, fragment_index);
+            }
+            self.flooding();
+        }
+    }
+
+    //sends the queued fragments the window towards dest allows, each one on the current best path;
+    //if there's no route to dest they wait for the routes a new flood finds
+    fn flush_window(&mut self, dest: NodeId){
+        if !self.send_ready(dest){
+            self.flooding();
+        }
+    }
+
+    //false if there's no route to dest: the fragments that couldn't leave go back to the head of the queue, in order
+    fn send_ready(&mut self, dest: NodeId) -> bool{
+        let ready = self.flow.ready(dest);
+        for (n, &(s_id, fragment_index)) in ready.iter().enumerate(){
+            if !self.packet_recover(s_id, fragment_index){
+                for &(s_id, fragment_index) in ready[n..].iter().rev(){
+                    self.flow.requeue(dest, s_id, fragment_index);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    //the destination is registered but unreachable: the message waits in its mailbox and the sender is told so
+    fn queue_message(&mut self, mc: MessageChat, requester: NodeId){
+        let receipt = Receipt{ message_id: mc.message_id, from_id: mc.to_id, to_id: mc.from_id, status: MessageStatus::Queued };
+        match self.mailbox.push(mc) {
+            Ok(()) => {
+                self.send_packet(ChatResponse::SendMessage(Ok("The destination client is unreachable, the server queued the message".to_string())), requester, NodeType::Client);
+                self.send_packet(ChatResponse::ForwardReceipt(receipt), requester, NodeType::Client);
+            }
+            Err(_) => {
+                self.send_packet(ChatResponse::SendMessage(Err("The mailbox of the destination client is full".to_string())), requester, NodeType::Client);
+            }
+        }
+    }
+
+    //a forwarded message that never got through goes back to the mailbox of its destination, the sender is told it's queued
+    fn requeue(&mut self, mc: MessageChat){
+        let sender = mc.from_id;
+        let receipt = Receipt{ message_id: mc.message_id, from_id: mc.to_id, to_id: sender, status: MessageStatus::Queued };
+        match self.mailbox.push(mc) {
+            Ok(()) => {
+                if self.registered_clients.contains(&sender){
+                    self.send_packet(ChatResponse::ForwardReceipt(receipt), sender, NodeType::Client);
+                }
+            }
+            Err(mc) => {
+                println!("server {:?} dropped a message for {:?}: its mailbox is full", self.server_id, mc.to_id);
+            }
+        }
     }
 
+    //forwards the queued messages of every registered client a route was found for
+    fn flush_mailbox(&mut self){
+        for client in self.mailbox.waiting(){
+            if self.registered_clients.contains(&client) && self.best_path_custom_cost(client, NodeType::Client).is_some(){
+                for mc in self.mailbox.take(client){
+                    self.send_packet(ChatResponse::ForwardMessage(mc), client, NodeType::Client);
+                }
+            }
+        }
+    }
+
+    //tells every other registered client that `client` joined or left
+    fn send_presence(&mut self, client: NodeId, joined: bool){
+        for other in self.registered_clients.clone(){
+            if other != client{
+                self.send_packet(ChatResponse::Presence(client, joined), other, NodeType::Client);
+            }
+        }
+    }
+
+    //asks their type to the servers never asked and sends the registered clients to every chat server that doesn't have them yet
+    fn announce_clients(&mut self){
+        let servers: Vec<NodeId> = self.neigh_map.node_weights()
+            .filter(|(id, nt)| *nt == NodeType::Server && *id != self.server_id)
+            .map(|(id, _)| *id)
+            .collect();
+        for server in servers{
+            if self.federation.ask(server){
+                self.ask_server_type(server);
+            }
+        }
+        for server in self.federation.servers(){
+            if self.federation.announce(server){
+                self.send_packet(ChatResponse::Federate(self.registered_clients.clone()), server, NodeType::Server);
+            }
+        }
+    }
+
+    //the client a create or join is for: the one that sent it, if registered
+    fn room_client(&self, n: NodeId, client: NodeId) -> Result<NodeId, String>{
+        if n != client {
+            Err(format!("Client {} can't use the rooms for client {}", client, n))
+        }else if !self.registered_clients.contains(&client){
+            Err("Only registered clients can use the rooms".to_string())
+        }else {
+            Ok(client)
+        }
+    }
+
+    //answers a create, join or leave and, if it changed the room, tells every member who is in it now
+    fn room_update(&mut self, room: String, result: Result<String, String>, client: NodeId){
+        let changed = result.is_ok();
+        self.send_packet(ChatResponse::Room(room.clone(), result), client, NodeType::Client);
+        if changed {
+            self.send_members(&room);
+            if !self.rooms.members(&room).contains(&client){
+                self.send_packet(ChatResponse::RoomMembers(room, Vec::new()), client, NodeType::Client); //the client left
+            }
+        }
+    }
+
+    fn send_members(&mut self, room: &str){
+        let members = self.rooms.members(room);
+        for member in members.iter(){
+            self.send_packet(ChatResponse::RoomMembers(room.to_string(), members.clone()), *member, NodeType::Client);
+        }
+    }
+
+    //drops the buffers of the sessions that will never complete and reports them, with the duplicates discarded, to the SC
+    fn sweep_sessions(&mut self){
+        let mut undelivered = Vec::new();
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(src, s_id) => { self.fragments_recv.remove(&(src, s_id)); }
+                SessionKey::Outgoing(s_id) => {
+                    self.fragments_send.remove(&s_id);
+                    self.flow.forget(s_id);
+                    undelivered.extend(self.forwarding.remove(&s_id)); //back in the mailbox below
+                }
+            }
+            self.send_event.send(ServerEvent::StuckSession(self.server_id, MyNodeType::ChatServer, key, state)).unwrap();
+        });
+        for mc in undelivered{
+            self.requeue(mc);
+        }
+        if let Some(duplicates) = duplicates{
+            self.send_event.send(ServerEvent::Duplicates(self.server_id, MyNodeType::ChatServer, duplicates)).unwrap();
+        }
+    }
 
     fn handle_nack(&mut self, packet : Packet){
         let id = packet.routing_header.hops[0];
         let s_id=packet.session_id;
+        self.sessions.touch(SessionKey::Outgoing(s_id), Instant::now());
         if let PacketType::Nack(nack) = packet.pack_type{
             match nack.clone().nack_type{
                 NackType::ErrorInRouting(crashed_id) => {
@@ -354,13 +718,16 @@ impl Server{
                         self.neigh_map.remove_edge(edge2.unwrap());
                     }
                     // println!("graph del chat dopo aver tolto gli edges del drone crashato {:?}", self.neigh_map);
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::DestinationIsDrone => {
                     println!("This error shouldn't happen!");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::Dropped => {
+                    if let Some(info) = self.fragments_send.get(&s_id){
+                        self.flow.on_dropped(info.0); //a drone is struggling, slow down towards that destination
+                    }
                         let mut first = true;
                         for i in packet.routing_header.hops.iter(){
                             let ni = self.find_node(*i, NodeType::Drone);
@@ -388,11 +755,11 @@ impl Server{
                                 None => {}
                             }
                         }
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::UnexpectedRecipient(_) => {
                     println!("This error shouldn't happen!");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
             }
         }
@@ -497,6 +864,11 @@ impl Server{
                             }
                     }
                     //println!("graph del chatserver {:?}, {:?}", self.server_id, self.neigh_map);
+                    self.flush_mailbox(); //the new routes may reach clients with queued messages
+                    self.announce_clients(); //and other chat servers
+                    for dest in self.flow.waiting(){ //and the fragments that had no route
+                        self.send_ready(dest);
+                    }
                 } else {
                     // println!("you received an outdated version of the flooding");
                 }
@@ -0,0 +1,110 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use wg_2024::network::NodeId;
+
+/// Chat servers (fillo and max) that found each other: every server asks the others their type,
+/// announces its registered clients to the chat servers among them and relays to those the messages for their clients
+#[derive(Debug, Clone, Default)]
+pub struct Federation {
+    servers: BTreeSet<NodeId>,            //servers that answered they are chat servers
+    asked: HashSet<NodeId>,               //servers already asked their type
+    peers: BTreeMap<NodeId, Vec<NodeId>>, //peer chat server -> clients registered on it
+    announced: HashSet<NodeId>,           //chat servers that already got our current client list
+}
+
+impl Federation {
+    pub fn new() -> Federation {
+        Federation::default()
+    }
+
+    /// True if `server` still has to be asked its type, it then counts as asked
+    pub fn ask(&mut self, server: NodeId) -> bool {
+        self.asked.insert(server)
+    }
+
+    /// `server` answered it is a chat server: its messages are accepted from now on
+    pub fn discovered(&mut self, server: NodeId) {
+        self.servers.insert(server);
+    }
+
+    /// Only the chat servers that answered the type request can federate and relay
+    pub fn is_peer(&self, server: NodeId) -> bool {
+        self.servers.contains(&server)
+    }
+
+    /// Chat servers known so far
+    pub fn servers(&self) -> Vec<NodeId> {
+        self.servers.iter().copied().collect()
+    }
+
+    /// Stores the clients announced by a peer, true if it's the first list it sent:
+    /// ours may have been dropped while the peer didn't know us yet, it has to get it back
+    pub fn update(&mut self, peer: NodeId, clients: Vec<NodeId>) -> bool {
+        self.peers.insert(peer, clients).is_none()
+    }
+
+    /// True if `server` still has to get our client list, it then counts as announced
+    pub fn announce(&mut self, server: NodeId) -> bool {
+        self.announced.insert(server)
+    }
+
+    /// Our registered clients changed, every chat server has to get them again
+    pub fn changed(&mut self) {
+        self.announced.clear();
+    }
+
+    /// Peer the client is registered on, the lowest id if more than one
+    pub fn owner(&self, client: NodeId) -> Option<NodeId> {
+        self.peers.iter()
+            .find(|(_, clients)| clients.contains(&client))
+            .map(|(peer, _)| *peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Federation;
+
+    const PEER: u8 = 20;
+    const OTHER_PEER: u8 = 21;
+    const CLIENT: u8 = 5;
+
+    #[test]
+    fn finds_the_peer_of_a_client() {
+        let mut federation = Federation::new();
+        assert_eq!(federation.owner(CLIENT), None);
+
+        federation.update(OTHER_PEER, vec![CLIENT]);
+        federation.update(PEER, vec![CLIENT, 6]);
+        assert_eq!(federation.owner(CLIENT), Some(PEER));
+        // a newer announcement replaces the old client list
+        federation.update(PEER, vec![6]);
+        assert_eq!(federation.owner(CLIENT), Some(OTHER_PEER));
+    }
+
+    #[test]
+    fn announces_once_per_change() {
+        let mut federation = Federation::new();
+        assert!(federation.announce(PEER));
+        assert!(!federation.announce(PEER));
+        federation.changed();
+        assert!(federation.announce(PEER));
+    }
+
+    #[test]
+    fn only_chat_servers_that_answered_are_peers() {
+        let mut federation = Federation::new();
+        assert!(federation.ask(PEER));
+        assert!(!federation.ask(PEER));
+        assert!(!federation.is_peer(PEER));
+
+        federation.discovered(PEER);
+        assert!(federation.is_peer(PEER));
+        assert!(!federation.is_peer(OTHER_PEER));
+        assert_eq!(federation.servers(), vec![PEER]);
+
+        // the first list of a peer is answered even if it already got ours
+        assert!(federation.announce(PEER));
+        assert!(federation.update(PEER, vec![CLIENT]));
+        assert!(!federation.update(PEER, vec![CLIENT, 6]));
+    }
+}
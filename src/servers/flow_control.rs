@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use wg_2024::network::NodeId;
+
+/// Fragments a new destination may have in flight before the first Ack comes back
+pub const INITIAL_WINDOW: f64 = 8.0;
+/// The window never shrinks below one fragment, so a transfer always makes progress
+pub const MIN_WINDOW: f64 = 1.0;
+/// Cap on the fragments in flight towards a single destination
+pub const MAX_WINDOW: f64 = 128.0;
+
+/// Window towards one destination, (session id, fragment index) identify a fragment
+#[derive(Debug, Clone)]
+struct Window {
+    size: f64,
+    threshold: f64,                   //below it the window doubles every round trip, above it grows by one
+    in_flight: HashSet<(u64, u64)>,
+    queue: VecDeque<(u64, u64)>,      //waiting for a free slot, in the order they were enqueued
+}
+
+impl Window {
+    fn new() -> Window {
+        Window { size: INITIAL_WINDOW, threshold: MAX_WINDOW, in_flight: HashSet::new(), queue: VecDeque::new() }
+    }
+}
+
+/// Congestion window shared by every server (fillo and max): grows while Acks come back,
+/// halves on NackType::Dropped and caps the fragments in flight per destination
+#[derive(Debug, Clone, Default)]
+pub struct FlowControl {
+    windows: HashMap<NodeId, Window>,
+}
+
+impl FlowControl {
+    pub fn new() -> FlowControl {
+        FlowControl::default()
+    }
+
+    /// Queues the fragments of a session, they leave through ready()
+    pub fn enqueue(&mut self, destination: NodeId, session_id: u64, indexes: impl IntoIterator<Item = u64>) {
+        let window = self.windows.entry(destination).or_insert_with(Window::new);
+        window.queue.extend(indexes.into_iter().map(|index| (session_id, index)));
+    }
+
+    /// Fragments that can be sent now, they count as in flight until acked
+    pub fn ready(&mut self, destination: NodeId) -> Vec<(u64, u64)> {
+        let Some(window) = self.windows.get_mut(&destination) else {
+            return Vec::new();
+        };
+        let mut ready = Vec::new();
+        while (window.in_flight.len() as f64) < window.size.floor() {
+            match window.queue.pop_front() {
+                Some(fragment) => {
+                    window.in_flight.insert(fragment);
+                    ready.push(fragment);
+                }
+                None => break,
+            }
+        }
+        ready
+    }
+
+    /// Frees the slot of an acked fragment and grows the window, duplicate Acks are ignored
+    pub fn on_ack(&mut self, destination: NodeId, session_id: u64, fragment_index: u64) {
+        let Some(window) = self.windows.get_mut(&destination) else {
+            return;
+        };
+        if !window.in_flight.remove(&(session_id, fragment_index)) {
+            return;
+        }
+        window.size = if window.size < window.threshold {
+            window.size + 1.0
+        } else {
+            window.size + 1.0 / window.size
+        }.min(MAX_WINDOW);
+    }
+
+    /// A drone dropped a fragment: halve the window, the fragment itself stays in flight while it's resent
+    pub fn on_dropped(&mut self, destination: NodeId) {
+        if let Some(window) = self.windows.get_mut(&destination) {
+            window.size = (window.size / 2.0).max(MIN_WINDOW);
+            window.threshold = window.size;
+        }
+    }
+
+    /// A fragment in flight couldn't leave, e.g. no route to the destination: it frees its slot
+    /// and goes back to the head of the queue, so it's the first to leave once it can
+    pub fn requeue(&mut self, destination: NodeId, session_id: u64, fragment_index: u64) {
+        if let Some(window) = self.windows.get_mut(&destination) {
+            if window.in_flight.remove(&(session_id, fragment_index)) {
+                window.queue.push_front((session_id, fragment_index));
+            }
+        }
+    }
+
+    /// Destinations with fragments still waiting to leave
+    pub fn waiting(&self) -> Vec<NodeId> {
+        self.windows.iter()
+            .filter(|(_, window)| !window.queue.is_empty())
+            .map(|(destination, _)| *destination)
+            .collect()
+    }
+
+    /// Drops whatever is left of a session, freeing its slots
+    pub fn forget(&mut self, session_id: u64) {
+        for window in self.windows.values_mut() {
+            window.in_flight.retain(|(session, _)| *session != session_id);
+            window.queue.retain(|(session, _)| *session != session_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlowControl, INITIAL_WINDOW, MAX_WINDOW};
+
+    const CLIENT: u8 = 5;
+    const OTHER_CLIENT: u8 = 6;
+
+    #[test]
+    fn caps_fragments_in_flight_per_destination() {
+        let mut flow = FlowControl::new();
+        flow.enqueue(CLIENT, 1, 0..100);
+        flow.enqueue(OTHER_CLIENT, 2, 0..3);
+
+        let first = flow.ready(CLIENT);
+        assert_eq!(first.len(), INITIAL_WINDOW as usize);
+        assert_eq!(first[0], (1, 0));
+        assert!(flow.ready(CLIENT).is_empty());
+        // a slow destination doesn't hold back the others
+        assert_eq!(flow.ready(OTHER_CLIENT).len(), 3);
+
+        // every Ack frees its slot and grows the window by one
+        flow.on_ack(CLIENT, 1, 0);
+        assert_eq!(flow.ready(CLIENT), vec![(1, 8), (1, 9)]);
+        // duplicated Acks don't count
+        flow.on_ack(CLIENT, 1, 0);
+        assert!(flow.ready(CLIENT).is_empty());
+    }
+
+    #[test]
+    fn shrinks_on_drops_and_never_exceeds_the_cap() {
+        let mut flow = FlowControl::new();
+        flow.enqueue(CLIENT, 1, 0..1000);
+        let in_flight = flow.ready(CLIENT);
+
+        // half of the window is lost: the dropped fragments stay in flight while they're resent
+        flow.on_dropped(CLIENT);
+        for (session, index) in &in_flight[..4] {
+            flow.on_ack(CLIENT, *session, *index);
+        }
+        assert!(flow.ready(CLIENT).is_empty());
+        for (session, index) in &in_flight[4..] {
+            flow.on_ack(CLIENT, *session, *index);
+        }
+        assert_eq!(flow.ready(CLIENT).len(), 5);
+        flow.forget(1);
+        assert!(flow.ready(CLIENT).is_empty());
+
+        // without drops the window keeps growing, but only up to the cap
+        flow.enqueue(OTHER_CLIENT, 2, 0..2000);
+        let mut peak = 0;
+        let mut ready = flow.ready(OTHER_CLIENT);
+        while !ready.is_empty() {
+            peak = peak.max(ready.len());
+            for (session, index) in ready {
+                flow.on_ack(OTHER_CLIENT, session, index);
+            }
+            ready = flow.ready(OTHER_CLIENT);
+        }
+        assert_eq!(peak, MAX_WINDOW as usize);
+    }
+
+    #[test]
+    fn fragments_that_never_left_free_their_slot() {
+        let mut flow = FlowControl::new();
+        flow.enqueue(CLIENT, 1, 0..10);
+        let first = flow.ready(CLIENT);
+        assert!(flow.waiting().contains(&CLIENT));
+
+        // no route: the fragments go back in order, without any Ack, and leave first once they can
+        for (session, index) in first.iter().rev() {
+            flow.requeue(CLIENT, *session, *index);
+        }
+        flow.requeue(CLIENT, 1, 9); //never left, nothing to free
+        assert_eq!(flow.ready(CLIENT), first);
+
+        // a session given up frees its slots too
+        flow.forget(1);
+        assert!(flow.waiting().is_empty());
+        flow.enqueue(CLIENT, 2, 0..1);
+        assert_eq!(flow.ready(CLIENT), vec![(2, 0)]);
+    }
+}
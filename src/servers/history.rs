@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use wg_2024::network::NodeId;
+use crate::common_data::common::{HistoryEntry, MessageChat};
+
+/// Messages kept in memory for every conversation, the oldest are dropped past it
+pub const MAX_HISTORY: usize = 1000;
+
+/// Log of every conversation a chat server (fillo and max) forwarded, so a client can recover the messages it missed
+/// When persistent, every message is also appended to `<dir>/<client>_<client>.jsonl` and reloaded on the next run
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    conversations: HashMap<(NodeId, NodeId), Vec<HistoryEntry>>, //key (lowest client id, highest client id)
+    dir: Option<PathBuf>,
+}
+
+fn conversation(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+    (a.min(b), a.max(b))
+}
+
+impl History {
+    /// Only in memory, lost on exit
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Loads the conversations saved in `dir` by a previous run, lines that can't be read are skipped
+    pub fn persistent(dir: PathBuf) -> History {
+        let mut history = History { conversations: HashMap::new(), dir: None };
+        if let Ok(files) = fs::read_dir(&dir) {
+            for file in files.flatten() {
+                let Ok(text) = fs::read_to_string(file.path()) else {
+                    continue;
+                };
+                for line in text.lines() {
+                    match serde_json::from_str::<HistoryEntry>(line) {
+                        Ok(entry) => history.insert(entry),
+                        Err(err) => println!("Skipped a line of {:?}: {}", file.path(), err),
+                    }
+                }
+            }
+        }
+        for entries in history.conversations.values_mut() {
+            entries.sort_by_key(|entry| entry.index);
+        }
+        history.dir = Some(dir);
+        history
+    }
+
+    /// Adds a message to its conversation, returns its index
+    pub fn record(&mut self, message: MessageChat) -> u64 {
+        let key = conversation(message.from_id, message.to_id);
+        let index = self.conversations.get(&key)
+            .and_then(|entries| entries.last())
+            .map(|entry| entry.index + 1)
+            .unwrap_or(0);
+        let entry = HistoryEntry { index, message };
+        if let Some(dir) = &self.dir {
+            if let Err(err) = append(dir, key, &entry) {
+                println!("Failed to save the history in {:?}: {}", dir, err);
+            }
+        }
+        self.insert(entry);
+        index
+    }
+
+    /// Messages of the conversation between the two clients from index `since` on
+    pub fn since(&self, a: NodeId, b: NodeId, since: u64) -> Vec<HistoryEntry> {
+        self.conversations.get(&conversation(a, b))
+            .map(|entries| entries.iter().filter(|entry| entry.index >= since).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn insert(&mut self, entry: HistoryEntry) {
+        let entries = self.conversations.entry(conversation(entry.message.from_id, entry.message.to_id)).or_default();
+        entries.push(entry);
+        if entries.len() > MAX_HISTORY {
+            entries.remove(0);
+        }
+    }
+}
+
+fn append(dir: &Path, key: (NodeId, NodeId), entry: &HistoryEntry) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("{}_{}.jsonl", key.0, key.1)))
+        .map_err(|e| e.to_string())?;
+    writeln!(file, "{}", line).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::common_data::common::MessageChat;
+    use super::History;
+
+    const ALICE: u8 = 5;
+    const BOB: u8 = 6;
+    const CAROL: u8 = 7;
+
+    fn message(from_id: u8, to_id: u8, content: &str) -> MessageChat {
+        MessageChat { content: content.to_string(), from_id, to_id, message_id: 0 }
+    }
+
+    #[test]
+    fn indexes_every_conversation_from_zero() {
+        let mut history = History::new();
+        assert_eq!(history.record(message(ALICE, BOB, "hi")), 0);
+        assert_eq!(history.record(message(BOB, ALICE, "hello")), 1);
+        assert_eq!(history.record(message(ALICE, CAROL, "hey")), 0);
+
+        // both ends see the same conversation
+        let recovered = history.since(BOB, ALICE, 1);
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].message.content, "hello");
+        assert_eq!(history.since(ALICE, BOB, 0).len(), 2);
+        assert!(history.since(ALICE, BOB, 2).is_empty());
+    }
+
+    #[test]
+    fn reloads_what_was_persisted() {
+        let dir = std::env::temp_dir().join(format!("rolling_drones_history_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut history = History::persistent(dir.clone());
+        history.record(message(ALICE, BOB, "hi"));
+        history.record(message(BOB, ALICE, "hello"));
+
+        // a restarted server goes on from where it stopped
+        let mut restarted = History::persistent(dir.clone());
+        assert_eq!(restarted.since(ALICE, BOB, 0), history.since(ALICE, BOB, 0));
+        assert_eq!(restarted.record(message(ALICE, BOB, "back")), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
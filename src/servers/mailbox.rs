@@ -0,0 +1,73 @@
+use std::collections::{HashMap, VecDeque};
+use wg_2024::network::NodeId;
+use crate::common_data::common::MessageChat;
+
+/// Messages a chat server keeps for a single unreachable client, the next ones are refused
+pub const MAILBOX_SIZE: usize = 32;
+
+/// Store-and-forward for the chat servers (fillo and max): messages for registered clients
+/// without a route wait here until a flood finds one or the client registers again
+#[derive(Debug, Clone, Default)]
+pub struct Mailbox {
+    queues: HashMap<NodeId, VecDeque<MessageChat>>,
+}
+
+impl Mailbox {
+    pub fn new() -> Mailbox {
+        Mailbox::default()
+    }
+
+    /// Queues a message for its destination, gives it back if that mailbox is full
+    pub fn push(&mut self, message: MessageChat) -> Result<(), MessageChat> {
+        let queue = self.queues.entry(message.to_id).or_default();
+        if queue.len() >= MAILBOX_SIZE {
+            return Err(message);
+        }
+        queue.push_back(message);
+        Ok(())
+    }
+
+    /// Takes every message waiting for a client, in the order they were queued
+    pub fn take(&mut self, client: NodeId) -> Vec<MessageChat> {
+        self.queues.remove(&client).map(Vec::from).unwrap_or_default()
+    }
+
+    /// Clients with at least one message waiting, sorted
+    pub fn waiting(&self) -> Vec<NodeId> {
+        let mut clients: Vec<NodeId> = self.queues.keys().copied().collect();
+        clients.sort();
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common_data::common::MessageChat;
+    use super::{Mailbox, MAILBOX_SIZE};
+
+    const SENDER: u8 = 5;
+    const CLIENT: u8 = 6;
+    const OTHER_CLIENT: u8 = 7;
+
+    fn message(to_id: u8, message_id: u64) -> MessageChat {
+        MessageChat { content: format!("message {}", message_id), from_id: SENDER, to_id, message_id }
+    }
+
+    #[test]
+    fn keeps_the_order_and_refuses_past_the_limit() {
+        let mut mailbox = Mailbox::new();
+        for id in 0..MAILBOX_SIZE as u64 {
+            assert!(mailbox.push(message(CLIENT, id)).is_ok());
+        }
+        // the full mailbox gives the message back, the others still accept
+        let refused = mailbox.push(message(CLIENT, MAILBOX_SIZE as u64)).unwrap_err();
+        assert_eq!(refused.message_id, MAILBOX_SIZE as u64);
+        assert!(mailbox.push(message(OTHER_CLIENT, 0)).is_ok());
+        assert_eq!(mailbox.waiting(), vec![CLIENT, OTHER_CLIENT]);
+
+        let taken = mailbox.take(CLIENT);
+        assert_eq!(taken.iter().map(|m| m.message_id).collect::<Vec<_>>(), (0..MAILBOX_SIZE as u64).collect::<Vec<_>>());
+        assert!(mailbox.take(CLIENT).is_empty());
+        assert_eq!(mailbox.waiting(), vec![OTHER_CLIENT]);
+    }
+}
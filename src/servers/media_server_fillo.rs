@@ -1,13 +1,12 @@
 #![allow(dead_code)]
-use base64::engine::general_purpose::STANDARD as BASE64;
 use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::Debug;
+use std::time::Instant;
 use std::fs;
 use petgraph::graph::{Graph, NodeIndex};
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
-use base64::Engine;
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use petgraph::Incoming;
 use petgraph::prelude::EdgeRef;
@@ -16,6 +15,9 @@ use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Fragment, NackType, NodeType, Packet, PacketType};
 use crate::common_data::common::*;
 use crate::servers::assembler::*;
+use crate::servers::flow_control::FlowControl;
+use crate::common_data::fragmentation::{FragmentError, Reassembler};
+use crate::common_data::sessions::{SessionTable, SWEEP_INTERVAL};
 use crate::gui::login_window::NodeType as MyNodeType;
 
 #[derive(Serialize, Clone, Debug)]
@@ -38,8 +40,10 @@ pub struct Server{
     packet_recv: Receiver<Packet>,
     already_visited: HashSet<(NodeId,u64)>,
     pub packet_send: HashMap<NodeId, Sender<Packet>>,
-    fragments_recv : HashMap<(NodeId,u64),Vec<Fragment>>,
+    fragments_recv : HashMap<(NodeId,u64),Reassembler>,
     fragments_send : HashMap<u64,(NodeId,NodeType,Vec<Fragment>)>,
+    flow : FlowControl,
+    sessions : SessionTable,
     rcv_flood: Receiver<BackGroundFlood>,
     rcv_command: Receiver<ServerCommands>,
     send_event: Sender<ServerEvent>
@@ -80,6 +84,8 @@ impl Server{
             packet_send,
             fragments_recv : HashMap::new(),
             fragments_send : HashMap::new(),
+            flow : FlowControl::new(),
+            sessions : SessionTable::default(),
             rcv_flood,
             rcv_command,
             send_event
@@ -116,7 +122,9 @@ impl Server{
                         }
                     }
                 }
+                default(SWEEP_INTERVAL) => {}
             }
+            self.sweep_sessions();
         }
     }
     fn send_topology_graph(&self) {
@@ -201,19 +209,23 @@ impl Server{
         //println!("graph del media {:?} : {:?}",self.server_id , self.neigh_map); //fa vedere il grafo (tutti i nodi e tutti gli edges)
         if let Some(srh) = self.best_path_custom_cost(id,nt){
             // println!("srh : {:?}",srh);
-            if let Ok(vec) = p.serialize_data(srh,self.session_id){
+            if let Ok(vec) = p.serialize_data(srh,self.session_id,SenderRole::MediaServer){
                 let mut fragments_send = Vec::new();
                 for i in vec.iter(){
                     if let PacketType::MsgFragment(fragment) = i.clone().pack_type{
                         fragments_send.push(fragment);
                     }
-                    self.forward_packet(i.clone());
                 }
+                //the fragments leave as fast as the window towards the destination allows
+                self.flow.enqueue(id, self.session_id, fragments_send.iter().map(|f| f.fragment_index));
                 self.fragments_send.insert(self.session_id.clone(), (id,nt,fragments_send));
+                self.sessions.start(SessionKey::Outgoing(self.session_id), vec.len() as u64, Instant::now());
+                self.flush_window(id);
                 match p {
                     MediaServer::ServerTypeMedia(_) => {self.send_event.send(ServerEvent::MediaPacketInfo(self.server_id, MyNodeType::MediaServer, MediaServerEvent::SendingServerTypeMedia(vec.len() as u64),self.session_id)).unwrap();}
                     MediaServer::SendPath(_) => {self.send_event.send(ServerEvent::MediaPacketInfo(self.server_id, MyNodeType::MediaServer, MediaServerEvent::SendingPathRes(vec.len() as u64),self.session_id)).unwrap();}
-                    MediaServer::SendMedia(_) => {self.send_event.send(ServerEvent::MediaPacketInfo(self.server_id, MyNodeType::MediaServer, MediaServerEvent::SendingMedia(vec.len() as u64),self.session_id)).unwrap();}
+                    MediaServer::SendMedia(_) => {} //reported by send_image, with its sizes
+                    MediaServer::NotModified(_) => {self.send_event.send(ServerEvent::MediaPacketInfo(self.server_id, MyNodeType::MediaServer, MediaServerEvent::SendingNotModified(vec.len() as u64),self.session_id)).unwrap();}
                 }
                 self.session_id+=1;
                 //aggiungere un field nella struct server per salvare tutti i vari pacchetti nel caso in cui fossero droppati ecc.
@@ -223,31 +235,32 @@ impl Server{
         }
     }
 
-    fn send_image(&mut self, path:&str, id:NodeId, nt:NodeType){
+    fn send_image(&mut self, path:&str, id:NodeId, nt:NodeType, encoding:Encoding, compression:Compression, range:Option<ByteRange>){
         let pos = path.rfind('.').unwrap();
         let posofslash = path.rfind('/').unwrap();
-        let mut filebytes = "".to_string();
+        let mut filebytes = Vec::new();
         match fs::read(Path::new(path)){
-            Ok(fb) => {filebytes = BASE64.encode(&fb);},
+            Ok(fb) => {filebytes = fb;},
             Err(_) => {println!("could not read file");}
         }
-        let fmd = FileMetaData{
-            title: path[posofslash+1..pos].to_string(),
-            extension:path[pos+1..].to_string(),
-            content: filebytes,
+        let fmd = match range {
+            Some(range) => FileMetaData::chunk(path[posofslash+1..pos].to_string(), path[pos+1..].to_string(), &filebytes, range, encoding),
+            None => FileMetaData::new(path[posofslash+1..pos].to_string(), path[pos+1..].to_string(), filebytes, encoding),
         };
         if let Some(srh)=self.best_path_custom_cost(id,nt){
-            if let Ok(vec) = MediaServer::SendMedia(fmd).serialize_data(srh.clone(),self.session_id){
+            if let Ok((vec, size)) = MediaServer::SendMedia(fmd).serialize_compressed(srh.clone(),self.session_id,SenderRole::MediaServer,compression){
                 let mut fragments_send = Vec::new();
                 for i in vec.iter(){
                     if let PacketType::MsgFragment(fragment) = i.clone().pack_type{
                         fragments_send.push(fragment);
                     }
-                    self.forward_packet(i.clone());
                 }
-                // println!("finito di mandare l'immagine richiesta");
+                //the fragments leave as fast as the window towards the destination allows
+                self.flow.enqueue(id, self.session_id, fragments_send.iter().map(|f| f.fragment_index));
                 self.fragments_send.insert(self.session_id.clone(), (id,nt,fragments_send));
-                self.send_event.send(ServerEvent::MediaPacketInfo(self.server_id, MyNodeType::MediaServer, MediaServerEvent::SendingMedia(vec.len() as u64),self.session_id)).unwrap();
+                self.sessions.start(SessionKey::Outgoing(self.session_id), vec.len() as u64, Instant::now());
+                self.flush_window(id);
+                self.send_event.send(ServerEvent::MediaPacketInfo(self.server_id, MyNodeType::MediaServer, MediaServerEvent::SendingMedia(vec.len() as u64, size),self.session_id)).unwrap();
                 self.session_id+=1;
             }
         }else {
@@ -258,58 +271,102 @@ impl Server{
     fn handle_msg_fragment(&mut self, p:Packet){
         self.forward_packet(create_ack(p.clone()));
         if let PacketType::MsgFragment(fragment) = p.pack_type{
-            if self.fragments_recv.contains_key(&(p.routing_header.hops.clone()[0],p.session_id)){
-                if let Some(vec) = self.fragments_recv.get_mut(&(p.routing_header.hops[0],p.session_id)){
-                    vec.push(fragment.clone());
-                }else {
-                    println!("This else shouldn't be reached, it means that the server has no vec of fragments (received) associated to the key");
+            let key = (p.routing_header.hops[0], p.session_id);
+            let session = SessionKey::Incoming(key.0, key.1);
+            match self.sessions.state(session) {
+                Some(SessionState::Open) => self.sessions.touch(session, Instant::now()),
+                Some(_) => { //late duplicate or replay of a session already closed
+                    self.sessions.duplicate();
+                    return;
+                }
+                None => {
+                    if !self.sessions.start(session, fragment.total_n_fragments, Instant::now()){
+                        return;
+                    }
                 }
-            }else {
-                let mut vec = Vec::new();
-                vec.push(fragment.clone());
-                self.fragments_recv.insert((p.routing_header.hops.clone()[0], p.session_id), vec);
             }
-            if let Some(vec) = self.fragments_recv.get_mut(&(p.routing_header.hops[0],p.session_id)){
-                if fragment.total_n_fragments == vec.len() as u64{
-                    if let Ok(totalmsg) = WebBrowserCommands::deserialize_data(vec){
-                        match totalmsg{
-                            WebBrowserCommands::GetList => {println!("I shouldn't receive this command");}
-                            WebBrowserCommands::GetPosition(_) => {println!("I shouldn't receive this command");}
-                            WebBrowserCommands::GetMedia(media_id) => {
-                                if self.paths.contains_key(&media_id){
-                                    let path = self.paths.get(&media_id).unwrap().clone();
-                                    self.send_image(path.as_str(),p.routing_header.hops[0],NodeType::Client); 
-                                }
-                            }
-                            WebBrowserCommands::GetText(_) => {println!("I shouldn't receive this command");}
-                            WebBrowserCommands::GetServerType => {
-                                // println!("problems in sending servertype");
-                                self.send_packet(MediaServer::ServerTypeMedia(self.clone().server_type), p.routing_header.hops[0], NodeType::Client);
+            let inserted = Reassembler::entry(&mut self.fragments_recv, key, fragment.total_n_fragments)
+                .and_then(|reassembler| reassembler.insert(&fragment));
+            if let Err(err) = inserted {
+                match err {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    FragmentError::InvalidTotal(_) => self.sessions.close(session, SessionState::Failed, Instant::now()), //never started
+                    _ => {}
+                }
+                // duplicates and malformed fragments must not trigger the command a second time
+                println!("server {:?} discarded a fragment from {:?}: {}", self.server_id, key.0, err);
+                return;
+            }
+            if !self.fragments_recv[&key].is_complete(){
+                return;
+            }
+            //complete: its fragments are no longer needed, only the session state is kept
+            let result = self.fragments_recv.remove(&key).unwrap().envelope();
+            let state = if result.is_ok() { SessionState::Complete } else { SessionState::Failed };
+            self.sessions.close(session, state, Instant::now());
+            let envelope = match result {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    println!("server {:?} rejected a message from {:?}: {}", self.server_id, key.0, err);
+                    return;
+                }
+            };
+            // files go back in the most compact encoding and compression the requester understands
+            let encoding = Encoding::negotiate(&envelope.accepts);
+            let compression = Compression::negotiate(&envelope.compressions);
+            match envelope.message {
+                Message::WebBrowserCommands(totalmsg) => {
+                    match totalmsg{
+                        WebBrowserCommands::GetList => {println!("I shouldn't receive this command");}
+                        WebBrowserCommands::GetPosition(_) => {println!("I shouldn't receive this command");}
+                        WebBrowserCommands::GetMedia(media_id) => {
+                            if self.paths.contains_key(&media_id){
+                                let path = self.paths.get(&media_id).unwrap().clone();
+                                self.send_image(path.as_str(),p.routing_header.hops[0],NodeType::Client,encoding,compression,None); 
                             }
                         }
-                    }else {
-                        if let Ok(totalmsg) = ChatResponse::deserialize_data(vec) {
-                            match totalmsg {
-                                _ => { println!("I shouldn't receive these commands"); }
-                            }
-                        }else {
-                            if let Ok(totalmsg) = TextServer::deserialize_data(vec){
-                                match totalmsg {
-                                    TextServer::ServerTypeReq => {
-                                        // println!("sono il media {:?} e sto mandando il mio servertype {:?} al text {:?}",self.server_id,self.server_type,p.routing_header.hops[0]);
-                                        // println!("grafo attuale del media {:?}: \n{:?} \nmentre cerca di mandare il suo server type a {:?}", self.server_id, self.neigh_map ,p.routing_header.hops[0]);
-                                        self.send_packet(MediaServer::ServerTypeMedia(self.clone().server_type), p.routing_header.hops[0], NodeType::Server);
-                                    }
-                                    TextServer::PathResolution => {
-                                        //println!("sono il media {:?} e sto mandando il mio pathres {:?} al text {:?}",self.server_id,self.server_type,p.routing_header.hops[0]);
-                                        self.send_packet(MediaServer::SendPath(self.clone().images_ids),p.routing_header.hops[0], NodeType::Server);
-                                    }
-                                    _ => {println!("I shouldn't receive these commands");}
+                        WebBrowserCommands::GetText(_) => {println!("I shouldn't receive this command");}
+                        WebBrowserCommands::GetIfModified(media_id, validator) => {
+                            if let Some(path) = self.paths.get(&media_id).cloned(){
+                                if fs::read(Path::new(&path)).ok().map(|bytes| Validator::of(&bytes)) == Some(validator){
+                                    self.send_packet(MediaServer::NotModified(media_id),p.routing_header.hops[0],NodeType::Client);
+                                }else {
+                                    self.send_image(path.as_str(),p.routing_header.hops[0],NodeType::Client,encoding,compression,None);
                                 }
                             }
                         }
+                        WebBrowserCommands::GetRange(media_id, range) => {
+                            if let Some(path) = self.paths.get(&media_id).cloned(){
+                                self.send_image(path.as_str(),p.routing_header.hops[0],NodeType::Client,encoding,compression,Some(range));
+                            }
+                        }
+                        WebBrowserCommands::Search(_) => {println!("I shouldn't receive this command");}
+                        WebBrowserCommands::GetServerType => {
+                            // println!("problems in sending servertype");
+                            self.send_packet(MediaServer::ServerTypeMedia(self.clone().server_type), p.routing_header.hops[0], NodeType::Client);
+                        }
+                    }
+                }
+                Message::ChatResponse(totalmsg) => {
+                    match totalmsg {
+                        _ => { println!("I shouldn't receive these commands"); }
+                    }
+                }
+                Message::TextServer(totalmsg) => {
+                    match totalmsg {
+                        TextServer::ServerTypeReq => {
+                            // println!("sono il media {:?} e sto mandando il mio servertype {:?} al text {:?}",self.server_id,self.server_type,p.routing_header.hops[0]);
+                            // println!("grafo attuale del media {:?}: \n{:?} \nmentre cerca di mandare il suo server type a {:?}", self.server_id, self.neigh_map ,p.routing_header.hops[0]);
+                            self.send_packet(MediaServer::ServerTypeMedia(self.clone().server_type), p.routing_header.hops[0], NodeType::Server);
+                        }
+                        TextServer::PathResolution => {
+                            //println!("sono il media {:?} e sto mandando il mio pathres {:?} al text {:?}",self.server_id,self.server_type,p.routing_header.hops[0]);
+                            self.send_packet(MediaServer::SendPath(self.media_sizes()),p.routing_header.hops[0], NodeType::Server);
+                        }
+                        _ => {println!("I shouldn't receive these commands");}
                     }
                 }
+                other => { println!("server {:?} can't handle {} messages", self.server_id, other.kind()); }
             }
         }
     }
@@ -317,9 +374,20 @@ impl Server{
     fn handle_ack(&mut self, packet : Packet){
         let s_id=packet.session_id;
         if let PacketType::Ack(ack) = packet.pack_type{
-            self.fragments_send.get_mut(&s_id).unwrap().2.retain(|x| x.fragment_index!=ack.fragment_index);
+            if let Some(info) = self.fragments_send.get_mut(&s_id){
+                info.2.retain(|x| x.fragment_index!=ack.fragment_index);
+                let dest = info.0;
+                let done = info.2.is_empty();
+                self.flow.on_ack(dest, s_id, ack.fragment_index);
+                if done {
+                    self.fragments_send.remove(&s_id); //every fragment was acked
+                    self.sessions.close(SessionKey::Outgoing(s_id), SessionState::Complete, Instant::now());
+                }else {
+                    self.sessions.touch(SessionKey::Outgoing(s_id), Instant::now());
+                }
+                self.flush_window(dest);
+            }
         }
-        //forse da sistemare perchè c'è un unwrap, anche se in teoria gli ack che arrivano al mio server hanno un session id corretto
         for i in packet.routing_header.hops.iter().skip(1){
             let ni = self.find_node(*i, NodeType::Drone);
             match  ni{
@@ -344,7 +412,8 @@ impl Server{
     
     }
 
-    fn packet_recover(&mut self, s_id: u64, lost_fragment_index: u64){
+    //sends again one of the fragments of a session on the current best path, false if there's no route to its destination
+    fn packet_recover(&mut self, s_id: u64, lost_fragment_index: u64) -> bool{
         if self.fragments_send.contains_key(&s_id){
             let info = self.fragments_send.get(&s_id).unwrap();
             for i in info.2.clone().iter(){
@@ -358,19 +427,70 @@ impl Server{
                         self.forward_packet(pack);
                         break;
                     }else {
-                        println!("i am the media {:?} there isn't a route to reach the packet destination (shouldn't happen)", self.server_id);
+                        println!("i am the media {:?} there isn't a route to {:?} anymore, the fragment waits for a new flood", self.server_id, info.0);
+                        return false;
                     }
                 }
             }
         }else {
             println!("This else shouldn't be reached, it means that the server has no vec of fragments (sent) associated to the key");
         }
+        true
     }
 
+    //resends a nacked fragment, without a route it gives its slot back and waits at the head of the queue for a new flood
+    fn resend(&mut self, s_id: u64, fragment_index: u64){
+        if !self.packet_recover(s_id, fragment_index){
+            if let Some(info) = self.fragments_send.get(&s_id){
+                self.flow.requeue(info.0, s_id, fragment_index);
+            }
+            self.flooding();
+        }
+    }
+
+    //sends the queued fragments the window towards dest allows, each one on the current best path;
+    //if there's no route to dest they wait for the routes a new flood finds
+    fn flush_window(&mut self, dest: NodeId){
+        if !self.send_ready(dest){
+            self.flooding();
+        }
+    }
+
+    //false if there's no route to dest: the fragments that couldn't leave go back to the head of the queue, in order
+    fn send_ready(&mut self, dest: NodeId) -> bool{
+        let ready = self.flow.ready(dest);
+        for (n, &(s_id, fragment_index)) in ready.iter().enumerate(){
+            if !self.packet_recover(s_id, fragment_index){
+                for &(s_id, fragment_index) in ready[n..].iter().rev(){
+                    self.flow.requeue(dest, s_id, fragment_index);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    //drops the buffers of the sessions that will never complete and reports them, with the duplicates discarded, to the SC
+    fn sweep_sessions(&mut self){
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(src, s_id) => { self.fragments_recv.remove(&(src, s_id)); }
+                SessionKey::Outgoing(s_id) => {
+                    self.fragments_send.remove(&s_id);
+                    self.flow.forget(s_id);
+                }
+            }
+            self.send_event.send(ServerEvent::StuckSession(self.server_id, MyNodeType::MediaServer, key, state)).unwrap();
+        });
+        if let Some(duplicates) = duplicates{
+            self.send_event.send(ServerEvent::Duplicates(self.server_id, MyNodeType::MediaServer, duplicates)).unwrap();
+        }
+    }
 
     fn handle_nack(&mut self, packet : Packet){
         let id = packet.routing_header.hops[0];
         let s_id=packet.session_id;
+        self.sessions.touch(SessionKey::Outgoing(s_id), Instant::now());
         if let PacketType::Nack(nack) = packet.pack_type{
             match nack.clone().nack_type{
                 NackType::ErrorInRouting(crashed_id) => {
@@ -400,13 +520,16 @@ impl Server{
                         self.neigh_map.remove_edge(edge2.unwrap());
                     }
                     // println!("graph del media dopo aver tolto gli edges del drone crashato {:?}", self.neigh_map);
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::DestinationIsDrone => {
                     println!("This error shouldn't happen!");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::Dropped => {
+                    if let Some(info) = self.fragments_send.get(&s_id){
+                        self.flow.on_dropped(info.0); //a drone is struggling, slow down towards that destination
+                    }
                     let mut first = true;
                     for i in packet.routing_header.hops.iter(){
                         let ni = self.find_node(*i, NodeType::Drone);
@@ -434,11 +557,11 @@ impl Server{
                             None => {}
                         }
                     }
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::UnexpectedRecipient(_) => {
                     println!("This error shouldn't happen!");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
             }
         }
@@ -540,6 +663,9 @@ impl Server{
                                 prev = newnodeid;
                             }
                     }
+                    for dest in self.flow.waiting(){ //the new routes may reach the destinations of the fragments that had none
+                        self.send_ready(dest);
+                    }
                 } else {
                     // println!("you received an outdated version of the flooding");
                 }
@@ -632,6 +758,17 @@ impl Server{
 
         None
     }
+
+    // the medias held with their size, so that text servers can report it in the search results;
+    // a media that can't be read can't be served either and is left out
+    fn media_sizes(&self) -> Vec<(MediaId, u64)> {
+        self.images_ids.iter()
+            .filter_map(|id| {
+                let metadata = fs::metadata(self.paths.get(id)?).ok()?;
+                Some((id.clone(), metadata.len()))
+            })
+            .collect()
+    }
 }
 
 
@@ -1,4 +1,9 @@
 pub(crate) mod assembler;
+pub(crate) mod federation;
+pub(crate) mod flow_control;
+pub(crate) mod history;
+pub(crate) mod mailbox;
+pub(crate) mod rooms;
 
 pub mod chat_server_fillo;
 
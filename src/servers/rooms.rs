@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use wg_2024::network::NodeId;
+
+/// Longest room name a chat server accepts
+pub const MAX_ROOM_NAME: usize = 32;
+
+/// Named rooms of a chat server (fillo and max): a message posted in a room is fanned out to every member,
+/// a room exists while it has at least one member
+#[derive(Debug, Clone, Default)]
+pub struct Rooms {
+    rooms: BTreeMap<String, Vec<NodeId>>, //room name -> members, in the order they joined
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms::default()
+    }
+
+    /// Creates a room with its creator as the only member
+    pub fn create(&mut self, room: &str, client: NodeId) -> Result<String, String> {
+        if room.trim().is_empty() || room.len() > MAX_ROOM_NAME {
+            return Err(format!("Invalid room name '{}'", room));
+        }
+        if self.rooms.contains_key(room) {
+            return Err(format!("Room {} already exists", room));
+        }
+        self.rooms.insert(room.to_string(), vec![client]);
+        Ok(format!("Room {} created", room))
+    }
+
+    pub fn join(&mut self, room: &str, client: NodeId) -> Result<String, String> {
+        let members = self.rooms.get_mut(room).ok_or(format!("Room {} doesn't exist", room))?;
+        if members.contains(&client) {
+            return Err(format!("Client {} is already in room {}", client, room));
+        }
+        members.push(client);
+        Ok(format!("Joined room {}", room))
+    }
+
+    /// Removes a client from a room, the room is closed when its last member leaves
+    pub fn leave(&mut self, room: &str, client: NodeId) -> Result<String, String> {
+        let members = self.rooms.get_mut(room).ok_or(format!("Room {} doesn't exist", room))?;
+        if !members.contains(&client) {
+            return Err(format!("Client {} isn't in room {}", client, room));
+        }
+        members.retain(|member| *member != client);
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        Ok(format!("Left room {}", room))
+    }
+
+    /// Removes a client from every room (it ended the chat), returns the rooms it was in
+    pub fn leave_all(&mut self, client: NodeId) -> Vec<String> {
+        let rooms: Vec<String> = self.rooms.iter()
+            .filter(|(_, members)| members.contains(&client))
+            .map(|(room, _)| room.clone())
+            .collect();
+        for room in &rooms {
+            let _ = self.leave(room, client);
+        }
+        rooms
+    }
+
+    /// Members of a room, empty if it doesn't exist
+    pub fn members(&self, room: &str) -> Vec<NodeId> {
+        self.rooms.get(room).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rooms, MAX_ROOM_NAME};
+
+    const ALICE: u8 = 5;
+    const BOB: u8 = 6;
+
+    #[test]
+    fn members_join_and_leave() {
+        let mut rooms = Rooms::new();
+        assert!(rooms.create("team", ALICE).is_ok());
+        assert!(rooms.create("team", BOB).is_err());
+        assert!(rooms.create("", BOB).is_err());
+        assert!(rooms.create(&"x".repeat(MAX_ROOM_NAME + 1), BOB).is_err());
+
+        assert!(rooms.join("team", BOB).is_ok());
+        assert!(rooms.join("team", BOB).is_err());
+        assert!(rooms.join("missing", BOB).is_err());
+        assert_eq!(rooms.members("team"), vec![ALICE, BOB]);
+
+        assert!(rooms.leave("team", ALICE).is_ok());
+        assert!(rooms.leave("team", ALICE).is_err());
+        assert_eq!(rooms.members("team"), vec![BOB]);
+        // the last member closes the room, its name can be used again
+        assert_eq!(rooms.leave_all(BOB), vec!["team".to_string()]);
+        assert!(rooms.members("team").is_empty());
+        assert!(rooms.create("team", BOB).is_ok());
+    }
+}
@@ -1,6 +1,7 @@
-use base64::engine::general_purpose::STANDARD as BASE64;
-use base64::Engine;
 use crate::servers::utilities_max::*;
+use crate::servers::flow_control::FlowControl;
+use crate::common_data::fragmentation::{FragmentError, Reassembler};
+use crate::common_data::sessions::{SessionTable, SWEEP_INTERVAL};
 use crate::common_data::common::*;
 use crate::common_data::common::ServerType;
 use crossbeam_channel::{ select_biased, Receiver, Sender};
@@ -9,6 +10,7 @@ use std::{fs, io};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{Ack, FloodRequest, Fragment, Nack, NackType, NodeType, Packet, PacketType};
 use bevy::utils::HashSet;
@@ -22,14 +24,16 @@ pub struct Server{
     next_session_id: u64,
     nodes_map: Vec<(NodeId, NodeType, Vec<NodeId>)>,
     processed_sessions: HashSet<(NodeId, u64)>,
-    fragment_recv: HashMap<(NodeId, u64), Data>,
+    fragment_recv: HashMap<(NodeId, u64), Reassembler>,
     fragment_send: HashMap<u64, Data>,
+    flow: FlowControl,
+    sessions: SessionTable,
     packet_recv: Receiver<Packet>,
     pub packet_send: HashMap<NodeId, Sender<Packet>>,
     path: String,
     file_list: Vec<(TextId, String)>,
     media_list: Vec<(MediaId, String)>,
-    media_others: HashMap<NodeId, Vec<MediaId>>,
+    media_others: HashMap<NodeId, Vec<(MediaId, u64)>>,
     already_visited: HashSet<(NodeId, u64)>,
     rcv_flood: Receiver<BackGroundFlood>,
     rcv_command: Receiver<ServerCommands>,
@@ -54,6 +58,8 @@ impl Server {
             processed_sessions: HashSet::new(),
             fragment_recv: HashMap::new(),
             fragment_send: HashMap::new(),
+            flow: FlowControl::new(),
+            sessions: SessionTable::default(),
             packet_recv: packet_recv,
             packet_send: packet_send,
             path: file_path.to_string(),
@@ -122,7 +128,11 @@ impl Server {
                         }
                     }
                 },
+
+                // 4.4 Nessun evento: controllo le sessioni ferme
+                default(SWEEP_INTERVAL) => {},
             }
+            self.sweep_sessions();
         }
     }
     fn handle_packet(&mut self, packet: Packet) {
@@ -157,43 +167,58 @@ impl Server {
         let ack = create_ack(packet.clone());
         self.send_packet(ack);
 
-        // 2) Estrai index, total e chi chiede
-        let total_frags = fragment.total_n_fragments as usize;
-        let frag_idx    = fragment.fragment_index       as usize;
-        if frag_idx >= total_frags { return; }
-
+        // 2) Estrai chi chiede (index e total li controlla il Reassembler)
         let who_ask = match packet.routing_header.hops.get(0).cloned() {
             Some(h) => h,
             None    => return,
         };
         let session_key = (who_ask, *session);
 
-        // 3) Inserisco/aggiorno il dato nel buffer
-        let entry = self.fragment_recv.entry(session_key)
-            .or_insert_with(|| Data::new(
-                ([0u8; 128], 0u8),      // placeholder per ogni slot
-                0,                      // posizione iniziale dummy
-                total_frags as u64,     // numero totale di frammenti
-                0,                      // contatore iniziale = 0
-                who_ask,                // chi chiede
-            ));
-
-        // 4) Se non ricevuto prima, memorizzo
-        if entry.dati[frag_idx].1 == 0 {
-            entry.dati[frag_idx] = (fragment.data, fragment.length);
-            entry.counter += 1;
+        // 3) Frammenti tardivi o duplicati di una sessione già chiusa vengono ignorati
+        let key = SessionKey::Incoming(who_ask, *session);
+        match self.sessions.state(key) {
+            Some(SessionState::Open) => self.sessions.touch(key, Instant::now()),
+            Some(_) => {
+                // duplicato tardivo o replay di una sessione già chiusa
+                self.sessions.duplicate();
+                return;
+            }
+            None => {
+                if !self.sessions.start(key, fragment.total_n_fragments, Instant::now()) {
+                    return;
+                }
+            }
         }
 
+        // 3.1) Inserisco il frammento nel buffer della sessione (il totale viene controllato prima di allocare)
+        let inserted = Reassembler::entry(&mut self.fragment_recv, session_key, fragment.total_n_fragments)
+            .and_then(|entry| entry.insert(&fragment));
+
+        // 4) Duplicati e frammenti non validi vengono scartati
+        let complete = match inserted {
+            Ok(complete) => complete,
+            Err(e) => {
+                match e {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    // totale non valido: la sessione non è mai partita
+                    FragmentError::InvalidTotal(_) => self.sessions.close(key, SessionState::Failed, Instant::now()),
+                    _ => {}
+                }
+                log::debug!("handle_message: frammento scartato da {} (session {}): {}", who_ask, session, e);
+                return;
+            }
+        };
+
         // 5) Se completo, processo e pulisco
-        if entry.counter == entry.total_expected as u64 {
+        if complete {
             self.processed_sessions.insert(session_key);
             self.handle_command(session_key);
             self.fragment_recv.remove(&session_key);
+            self.sessions.close(key, SessionState::Complete, Instant::now());
         }
     }
     fn send_data_fragments(&mut self, id: NodeId, dati: Box<[( [u8;128], u8 )]>, session: u64) {
         let total = dati.len();
-        let window = WINDOW_SIZE.min(total);
 
         // Crea e registra la sessione in fragment_send
         let data = Data {
@@ -203,16 +228,35 @@ impl Server {
             who_ask: id,
             acked: vec![false; total],
             retry_count: vec![0; total],
-            next_to_send: window,
         };
         self.fragment_send.insert(session, data);
+        self.sessions.start(SessionKey::Outgoing(session), total as u64, Instant::now());
 
-        // Invio di tutti i frammenti disponibili fino a next_to_send (es. primi 100)
-        for idx in 0..window {
-            self.send_single_fragment(session, idx);
+        // Metto in coda tutti i frammenti, partono quelli che la finestra verso il destinatario permette
+        self.flow.enqueue(id, session, 0..total as u64);
+        self.flush_window(id);
+    }
+    fn flush_window(&mut self, dest: NodeId) {
+        if !self.send_ready(dest) {
+            self.flooding();
         }
     }
-    fn send_single_fragment(&mut self, session: u64, idx: usize) {
+    // Invia i frammenti che la finestra verso dest permette, false se dest non è raggiungibile:
+    // quelli rimasti tornano in testa alla coda, in ordine, e aspettano i percorsi del prossimo flood
+    fn send_ready(&mut self, dest: NodeId) -> bool {
+        let ready = self.flow.ready(dest);
+        for (n, &(session, idx)) in ready.iter().enumerate() {
+            if !self.send_single_fragment(session, idx as usize) {
+                for &(session, idx) in ready[n..].iter().rev() {
+                    self.flow.requeue(dest, session, idx);
+                }
+                return false;
+            }
+        }
+        true
+    }
+    // false solo se non c'è un percorso verso il destinatario
+    fn send_single_fragment(&mut self, session: u64, idx: usize) -> bool {
         if let Some(data) = self.fragment_send.get(&session) {
             let who = data.who_ask;
             if let Some(path) = self.routing(who) {
@@ -223,8 +267,10 @@ impl Server {
                 self.send_packet(pkt);
             } else {
                 log::warn!("send_single_fragment: nessun percorso a {} (session {})", who, session);
+                return false;
             }
         }
+        true
     }
 
     fn handle_ack(&mut self, ack: Ack, session: u64, path: &[NodeId]) {
@@ -235,10 +281,10 @@ impl Server {
             *entry = (1.0 - alpha) * (*entry) + alpha * 1.0;
         }
 
-        let mut next_idx = None;
+        let mut destination = None;
         let mut session_completed = false;
 
-        // STEP 1: aggiorna dati e prepara eventuale invio
+        // STEP 1: aggiorna dati e libera il posto nella finestra
         if let Some(data) = self.fragment_send.get_mut(&session) {
             let idx = ack.fragment_index as usize;
             if idx >= data.total_expected || data.acked[idx] {
@@ -247,29 +293,50 @@ impl Server {
 
             data.acked[idx] = true;
             data.counter = data.counter.saturating_sub(1);
-
-            if data.next_to_send < data.total_expected {
-                next_idx = Some(data.next_to_send);
-                data.next_to_send += 1;
-            }
+            destination = Some(data.who_ask);
 
             if data.counter == 0 {
                 session_completed = true;
             }
         }
 
-        // STEP 2: chiamata esterna ora che il borrow è terminato
-        if let Some(i) = next_idx {
-            self.send_single_fragment(session, i);
+        // STEP 2: chiamata esterna ora che il borrow è terminato, la finestra cresce e parte il resto
+        if let Some(dest) = destination {
+            self.flow.on_ack(dest, session, ack.fragment_index);
+            self.flush_window(dest);
         }
 
         if session_completed {
             self.fragment_send.remove(&session);
+            self.flow.forget(session);
+            self.sessions.close(SessionKey::Outgoing(session), SessionState::Complete, Instant::now());
+        } else if destination.is_some() {
+            self.sessions.touch(SessionKey::Outgoing(session), Instant::now());
         }
     }
 
 
+    // Libera i buffer delle sessioni che non finiranno mai e le segnala al controller, insieme ai duplicati scartati
+    fn sweep_sessions(&mut self) {
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(who, session) => {
+                    self.fragment_recv.remove(&(who, session));
+                }
+                SessionKey::Outgoing(session) => {
+                    self.fragment_send.remove(&session);
+                    self.flow.forget(session);
+                }
+            }
+            log::warn!("sweep_sessions: sessione {:?} chiusa come {:?}", key, state);
+            let _ = self.send_event.send(ServerEvent::StuckSession(self.server_id, MyNodeType::TextServer, key, state));
+        });
+        if let Some(duplicates) = duplicates {
+            let _ = self.send_event.send(ServerEvent::Duplicates(self.server_id, MyNodeType::TextServer, duplicates));
+        }
+    }
     fn handle_nack(&mut self, fragment: Nack, _pos: &u64, session: &u64, packet: Packet) {
+        self.sessions.touch(SessionKey::Outgoing(*session), Instant::now());
 
         if let NackType::ErrorInRouting(bad) = fragment.nack_type{
             let bad_next = packet.routing_header.hops[0];
@@ -283,6 +350,10 @@ impl Server {
             let alpha = 0.2;
             let entry = self.statistics.entry(bad_hop).or_insert(0.5);
             *entry = (1.0 - alpha) * (*entry) + alpha * 0.0;
+            // Un drone perde pacchetti: la finestra verso il destinatario si dimezza
+            if let Some(data) = self.fragment_send.get(session) {
+                self.flow.on_dropped(data.who_ask);
+            }
         }
         // Logica standard di retry
         let retries :u32;
@@ -296,7 +367,13 @@ impl Server {
             if data.retry_count[idx] < (MAX_RETRIES as usize).try_into().unwrap() {
                 data.retry_count[idx] += 1;
                 retries = data.retry_count[idx];
-            } else { return; }
+            } else {
+                // Tentativi finiti: la sessione non finirà mai, sweep_sessions ne libera i buffer e la segnala
+                log::warn!("handle_nack: frammento {} della sessione {} perso dopo {} tentativi", idx, session, MAX_RETRIES);
+                self.flow.forget(*session);
+                self.sessions.fail(SessionKey::Outgoing(*session), Instant::now());
+                return;
+            }
         }
 
         // Riprova su nuovo percorso
@@ -311,6 +388,9 @@ impl Server {
             let pkt2  = Packet::new_fragment(SourceRoutingHeader::new(path, 1), *session, frag2);
             self.send_packet(pkt2);
         } else {
+            // Il posto nella finestra torna libero, il frammento riparte per primo col prossimo flood
+            self.flow.requeue(who, *session, fragment.fragment_index);
+            self.flooding();
             log::warn!("handle_nack: nessun percorso a {} per retry frag {} sess {}",
                        who, fragment.fragment_index, session);
         }
@@ -396,6 +476,10 @@ impl Server {
                     self.nodes_map.push((node_id, node_type, conns));
                 }
             }
+            // 1.1) I nuovi percorsi possono raggiungere i destinatari dei frammenti rimasti senza percorso
+            for dest in self.flow.waiting() {
+                self.send_ready(dest);
+            }
 
             // 2) Se sono iniziator, invio solo ai nuovi server
             if initiator_id == self.server_id {
@@ -555,11 +639,22 @@ impl Server {
         }
     }
     fn handle_command(&mut self, session_key: (NodeId, u64)) {
-        let (_, _) = session_key;
-        let data = self.fragment_recv.get(&session_key).unwrap();
-        let d = data.dati.clone();
-        let command = deserialize_comando_text(d);
-        let id_client = data.who_ask;
+        let (id_client, _) = session_key;
+        let bytes = match self.fragment_recv.get(&session_key).map(|data| data.bytes()) {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) => {
+                log::error!("handle_command: messaggio incompleto da {}: {}", id_client, e);
+                return;
+            }
+            None => return,
+        };
+        let (command, encoding, compression) = match deserialize_comando_text(&bytes) {
+            Ok(command) => command,
+            Err(e) => {
+                log::warn!("handle_command: messaggio scartato da {}: {}", id_client, e);
+                return;
+            }
+        };
         match command {
             ComandoText::Media(media) => {
                 match media {
@@ -578,6 +673,7 @@ impl Server {
                         } else {}
                     }
                     MediaServer::SendMedia(_) => {}
+                    MediaServer::NotModified(_) => {}
                 }
             }
             ComandoText::Text(text) => {
@@ -588,9 +684,9 @@ impl Server {
                     }
                     TextServer::PathResolution => {
                         // invio SOLAMENTE la lista dei media
-                        let media_ids = self.get_media_ids();
+                        let media_sizes = self.get_media_sizes();
                         let response =
-                            Risposta::Media(MediaServer::SendPath(media_ids));
+                            Risposta::Media(MediaServer::SendPath(media_sizes));
                         self.send_response(id_client, response);
                     }
                     _ => {}
@@ -611,11 +707,11 @@ impl Server {
                     }
                     WebBrowserCommands::GetPosition(media) => {
                         let id_server :NodeId;
-                        let server = self.find_position_media(media);
+                        let server = self.find_position_media(media.clone());
                         match server {
                             Ok(id) => {
                                 id_server = id;
-                                let response = Risposta::Text(TextServer::PositionMedia(id_server));
+                                let response = Risposta::Text(TextServer::PositionMedia(media, id_server));
                                 self.send_response(id_client, response);
                             }
                             _ => {}
@@ -628,15 +724,11 @@ impl Server {
                                     return;
                                 }
                                 match self.get_media(media_name.clone()) {
-                                    Ok(encoded_media) => {
+                                    Ok(bytes) => {
                                         let (title, extension) = title_and_extension(&media_name);
-                                        let file = FileMetaData {
-                                            title,
-                                            extension,
-                                            content: encoded_media,
-                                        };
+                                        let file = FileMetaData::new(title, extension, bytes, encoding);
                                         let response = Risposta::Media(MediaServer::SendMedia(file));
-                                        self.send_response(id_client, response);
+                                        self.send_response_compressed(id_client, response, compression);
                                     }
                                     _ => {}
                                 }
@@ -652,15 +744,11 @@ impl Server {
                                     return;
                                 }
                                 match self.get_text(text_name.clone()) {
-                                    Ok(encoded_text) => {
+                                    Ok(bytes) => {
                                         let (title, extension) = title_and_extension(&text_name);
-                                        let file = FileMetaData {
-                                            title,
-                                            extension,
-                                            content: encoded_text,
-                                        };
+                                        let file = FileMetaData::new(title, extension, bytes, encoding);
                                         let response = Risposta::Text(TextServer::Text(file));
-                                        self.send_response(id_client, response);
+                                        self.send_response_compressed(id_client, response, compression);
                                     }
                                     Err(_) => {}
                                 }
@@ -668,6 +756,61 @@ impl Server {
                             Err(_) => {}
                         }
                     }
+                    WebBrowserCommands::GetIfModified(name, validator) => {
+                        // il file viene rimandato solo se la copia del client non è più valida
+                        let (title, extension) = title_and_extension(&name);
+                        if self.find_position_text(name.clone()) == Ok(self.server_id) {
+                            if let Ok(bytes) = self.get_text(name.clone()) {
+                                let response = if Validator::of(&bytes) == validator {
+                                    Risposta::Text(TextServer::NotModified(name))
+                                } else {
+                                    Risposta::Text(TextServer::Text(FileMetaData::new(title, extension, bytes, encoding)))
+                                };
+                                self.send_response_compressed(id_client, response, compression);
+                            }
+                        } else if self.find_position_media(name.clone()) == Ok(self.server_id) {
+                            if let Ok(bytes) = self.get_media(name.clone()) {
+                                let response = if Validator::of(&bytes) == validator {
+                                    Risposta::Media(MediaServer::NotModified(name))
+                                } else {
+                                    Risposta::Media(MediaServer::SendMedia(FileMetaData::new(title, extension, bytes, encoding)))
+                                };
+                                self.send_response_compressed(id_client, response, compression);
+                            }
+                        }
+                    }
+                    WebBrowserCommands::GetRange(name, range) => {
+                        // solo il pezzo richiesto, il client ricompone il file
+                        let (title, extension) = title_and_extension(&name);
+                        if self.find_position_text(name.clone()) == Ok(self.server_id) {
+                            if let Ok(bytes) = self.get_text(name.clone()) {
+                                let file = FileMetaData::chunk(title, extension, &bytes, range, encoding);
+                                self.send_response_compressed(id_client, Risposta::Text(TextServer::Text(file)), compression);
+                            }
+                        } else if self.find_position_media(name.clone()) == Ok(self.server_id) {
+                            if let Ok(bytes) = self.get_media(name.clone()) {
+                                let file = FileMetaData::chunk(title, extension, &bytes, range, encoding);
+                                self.send_response_compressed(id_client, Risposta::Media(MediaServer::SendMedia(file)), compression);
+                            }
+                        }
+                    }
+                    WebBrowserCommands::Search(query) => {
+                        // i file locali vengono letti, dei media degli altri server si conosce solo il nome
+                        let mut hits = Vec::new();
+                        for (name, path) in self.file_list.iter().chain(self.media_list.iter()) {
+                            let bytes = fs::read(Path::new(path)).ok();
+                            hits.extend(query.search(name, bytes.as_deref()));
+                        }
+                        for (_, medias) in &self.media_others {
+                            for (name, size) in medias {
+                                if !hits.iter().any(|hit| &hit.name == name) {
+                                    hits.extend(query.search(name, None).map(|hit| SearchHit { size: Some(*size), ..hit }));
+                                }
+                            }
+                        }
+                        let response = Risposta::Text(TextServer::SearchResults(hits));
+                        self.send_response(id_client, response);
+                    }
                     WebBrowserCommands::GetServerType => {
                         let response = Risposta::Text(TextServer::ServerTypeText(self.server_type.clone()));
                         self.send_response(id_client, response);
@@ -680,10 +823,14 @@ impl Server {
         }
     }
     fn send_response(&mut self, id: NodeId, response: Risposta) {
+        self.send_response_compressed(id, response, Compression::None);
+    }
+    // testi e media vengono compressi se il client lo ha annunciato nella busta
+    fn send_response_compressed(&mut self, id: NodeId, response: Risposta, compression: Compression) {
         let session = self.get_session();
         match response {
             Risposta::Text(text) => {
-                let dati = serialize(&text);
+                let (dati, size) = serialize_compressed(&text, SenderRole::TextServer, compression);
                 let total = dati.len();
                 let event: TextServerEvent;
                 match text{
@@ -699,11 +846,17 @@ impl Server {
                     TextServer::SendFileList(_) => {
                         event = TextServerEvent::SendingFileList(total as u64);
                     }
-                    TextServer::PositionMedia(_) => {
+                    TextServer::PositionMedia(..) => {
                         event = TextServerEvent::SendingPosition(total as u64);
                     }
                     TextServer::Text(_) => {
-                        event = TextServerEvent::SendingText(total as u64);
+                        event = TextServerEvent::SendingText(total as u64, size);
+                    }
+                    TextServer::NotModified(_) => {
+                        event = TextServerEvent::SendingNotModified(total as u64);
+                    }
+                    TextServer::SearchResults(_) => {
+                        event = TextServerEvent::SendingSearchResults(total as u64);
                     }
                 }
                 let type_ = MyNodeType::TextServer;
@@ -712,7 +865,7 @@ impl Server {
                 self.send_data_fragments(id, dati, session);
             }
             Risposta::Media(media) => {
-                let dati = serialize(&media);
+                let (dati, size) = serialize_compressed(&media, SenderRole::TextServer, compression);
                 let total = dati.len();
                 let event: MediaServerEvent;
                 match media{
@@ -723,7 +876,10 @@ impl Server {
                         event = MediaServerEvent::SendingPathRes(total as u64);
                     }
                     MediaServer::SendMedia(_) => {
-                        event = MediaServerEvent::SendingMedia(total as u64);
+                        event = MediaServerEvent::SendingMedia(total as u64, size);
+                    }
+                    MediaServer::NotModified(_) => {
+                        event = MediaServerEvent::SendingNotModified(total as u64);
                     }
                 }
                 let type_ = MyNodeType::MediaServer;
@@ -824,7 +980,7 @@ impl Server {
             }
         }
         for (_, vec_strings) in &self.media_others {
-            for k in vec_strings {
+            for (k, _) in vec_strings {
                 if seen.insert(k.clone()) {
                     unique_list.push(k.clone());
                 }
@@ -841,7 +997,7 @@ impl Server {
             }
         }
         for i in self.media_others.clone() {
-            for k in i.1.clone() {
+            for (k, _) in i.1.clone() {
                 if k == media_id {
                     return Ok(i.0);
                 } else {
@@ -859,13 +1015,10 @@ impl Server {
         }
         Err(format!("Text file '{}' not found anywhere", text_id))
     }
-    fn get_media(&self, media_id: MediaId) -> Result<String, String> {
+    fn get_media(&self, media_id: MediaId) -> Result<Vec<u8>, String> {
         if let Some((_, file_path)) = self.media_list.iter().find(|(id, _)| *id == media_id) {
             match fs::read(Path::new(file_path)) {
-                Ok(bytes) => {
-                    let encoded = BASE64.encode(&bytes);
-                    Ok(encoded)
-                }
+                Ok(bytes) => Ok(bytes),
                 Err(_) => {
                     Err(format!("File '{}' not found", file_path))
                 }
@@ -874,13 +1027,10 @@ impl Server {
             Err(format!("File con ID {:?} non trovato", media_id))
         }
     }
-    fn get_text(&self, text_id: TextId) -> Result<String, String> {
+    fn get_text(&self, text_id: TextId) -> Result<Vec<u8>, String> {
         if let Some((_, file_path)) = self.file_list.iter().find(|(id, _)| *id == text_id) {
             match fs::read(Path::new(file_path)) {
-                Ok(bytes) => {
-                    let encoded = BASE64.encode(&bytes);
-                    Ok(encoded)
-                }
+                Ok(bytes) => Ok(bytes),
                 Err(_) => {
                     Err(format!("File '{}' not found", file_path))
                 }
@@ -913,10 +1063,14 @@ impl Server {
             neighbors.retain(|&n| n != bad);
         }
     }
-    fn get_media_ids(&self) -> Vec<MediaId> {
+    // i media con la loro dimensione, un media che non si riesce a leggere non viene annunciato
+    fn get_media_sizes(&self) -> Vec<(MediaId, u64)> {
         self.media_list
             .iter()
-            .map(|(media_id, _path)| media_id.clone())
+            .filter_map(|(media_id, path)| {
+                let metadata = fs::metadata(Path::new(path)).ok()?;
+                Some((media_id.clone(), metadata.len()))
+            })
             .collect()
     }
 
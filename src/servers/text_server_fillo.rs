@@ -1,13 +1,12 @@
 #![allow(dead_code)]
-use base64::engine::general_purpose::STANDARD as BASE64;
 use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::Debug;
+use std::time::Instant;
 use std::fs;
 use petgraph::graph::{Graph, NodeIndex};
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
-use base64::Engine;
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use petgraph::Incoming;
 use petgraph::prelude::EdgeRef;
@@ -16,6 +15,9 @@ use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Fragment, NackType, NodeType, Packet, PacketType};
 use crate::common_data::common::*;
 use crate::servers::assembler::*;
+use crate::servers::flow_control::FlowControl;
+use crate::common_data::fragmentation::{FragmentError, Reassembler};
+use crate::common_data::sessions::{SessionTable, SWEEP_INTERVAL};
 use crate::gui::login_window::NodeType as MyNodeType;
 
 #[derive(Serialize, Clone, Debug)]
@@ -36,12 +38,14 @@ pub struct Server{
     neigh_map: Graph<(NodeId,NodeType), f64, petgraph::Directed>,
     stats: HashMap<NodeIndex, Drops>,
     media_servers: Vec<NodeId>,
-    media_info: HashMap<NodeId, Vec<MediaId>>,
+    media_info: HashMap<NodeId, Vec<(MediaId, u64)>>,
     packet_recv: Receiver<Packet>,
     already_visited: HashSet<(NodeId,u64)>,
     pub packet_send: HashMap<NodeId, Sender<Packet>>,
-    fragments_recv : HashMap<(NodeId,u64),Vec<Fragment>>,
+    fragments_recv : HashMap<(NodeId,u64),Reassembler>,
     fragments_send : HashMap<u64,(NodeId,NodeType,Vec<Fragment>)>,
+    flow : FlowControl,
+    sessions : SessionTable,
     rcv_flood: Receiver<BackGroundFlood>,
     rcv_command: Receiver<ServerCommands>,
     send_event: Sender<ServerEvent>
@@ -84,6 +88,8 @@ impl Server{
             packet_send,
             fragments_recv : HashMap::new(),
             fragments_send : HashMap::new(),
+            flow : FlowControl::new(),
+            sessions : SessionTable::default(),
             rcv_flood,
             rcv_command,
             send_event
@@ -120,7 +126,9 @@ impl Server{
                         }
                     }
                 }
+                default(SWEEP_INTERVAL) => {}
             }
+            self.sweep_sessions();
         }
     }
     fn send_topology_graph(&self){
@@ -205,22 +213,27 @@ impl Server{
         // println!("graph del textserver {:?}: {:?}",self.server_id ,self.neigh_map); //fa vedere il grafo (tutti i nodi e tutti gli edges)
         if let Some(srh) = self.best_path_custom_cost(id,nt){
             // println!("srh : {:?}",srh);
-            if let Ok(vec) = p.serialize_data(srh,self.session_id){
+            if let Ok(vec) = p.serialize_data(srh,self.session_id,SenderRole::TextServer){
                 let mut fragments_send = Vec::new();
                 for i in vec.iter(){
                     if let PacketType::MsgFragment(fragment) = i.clone().pack_type{
                         fragments_send.push(fragment);
                     }
-                    self.forward_packet(i.clone());
                 }
+                //the fragments leave as fast as the window towards the destination allows
+                self.flow.enqueue(id, self.session_id, fragments_send.iter().map(|f| f.fragment_index));
                 self.fragments_send.insert(self.session_id.clone(), (id,nt,fragments_send));
+                self.sessions.start(SessionKey::Outgoing(self.session_id), vec.len() as u64, Instant::now());
+                self.flush_window(id);
                 match p {
                     TextServer::ServerTypeReq => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingServerTypeReq(vec.len() as u64),self.session_id)).unwrap();}
                     TextServer::ServerTypeText(_) => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingServerTypeText(vec.len() as u64),self.session_id)).unwrap();}
                     TextServer::PathResolution => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::AskingForPathRes(vec.len() as u64),self.session_id)).unwrap();}
                     TextServer::SendFileList(_) => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingFileList(vec.len() as u64),self.session_id)).unwrap();}
-                    TextServer::PositionMedia(_) => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingPosition(vec.len() as u64),self.session_id)).unwrap();}
+                    TextServer::PositionMedia(..) => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingPosition(vec.len() as u64),self.session_id)).unwrap();}
                     TextServer::Text(_) => {}
+                    TextServer::NotModified(_) => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingNotModified(vec.len() as u64),self.session_id)).unwrap();}
+                    TextServer::SearchResults(_) => {self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingSearchResults(vec.len() as u64),self.session_id)).unwrap();}
                 }
                 self.session_id+=1;
                 //aggiungere un field nella struct server per salvare tutti i vari pacchetti nel caso in cui fossero droppati ecc.
@@ -230,30 +243,32 @@ impl Server{
         }
     }
 
-    fn send_text(&mut self, path:&str, id:NodeId, nt:NodeType){
+    fn send_text(&mut self, path:&str, id:NodeId, nt:NodeType, encoding:Encoding, compression:Compression, range:Option<ByteRange>){
         let pos = path.rfind('.').unwrap();
         let posofslash = path.rfind('/').unwrap();
-        let mut filebytes = "".to_string();
+        let mut filebytes = Vec::new();
         match fs::read(Path::new(path)){
-            Ok(fb) => {filebytes = BASE64.encode(&fb);},
+            Ok(fb) => {filebytes = fb;},
             Err(_) => {println!("could not read file");}
         }
-        let fmd = FileMetaData{
-            title: path[posofslash+1..pos].to_string(),
-            extension:path[pos+1..].to_string(),
-            content: filebytes,
+        let fmd = match range {
+            Some(range) => FileMetaData::chunk(path[posofslash+1..pos].to_string(), path[pos+1..].to_string(), &filebytes, range, encoding),
+            None => FileMetaData::new(path[posofslash+1..pos].to_string(), path[pos+1..].to_string(), filebytes, encoding),
         };
         if let Some(srh)=self.best_path_custom_cost(id,nt){
-            if let Ok(vec) = TextServer::Text(fmd).serialize_data(srh.clone(),self.session_id){
+            if let Ok((vec, size)) = TextServer::Text(fmd).serialize_compressed(srh.clone(),self.session_id,SenderRole::TextServer,compression){
                 let mut fragments_send = Vec::new();
                 for i in vec.iter(){
                     if let PacketType::MsgFragment(fragment) = i.clone().pack_type{
                         fragments_send.push(fragment);
                     }
-                    self.forward_packet(i.clone());
                 }
+                //the fragments leave as fast as the window towards the destination allows
+                self.flow.enqueue(id, self.session_id, fragments_send.iter().map(|f| f.fragment_index));
                 self.fragments_send.insert(self.session_id.clone(), (id,nt,fragments_send));
-                self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingText(vec.len() as u64),self.session_id)).unwrap();
+                self.sessions.start(SessionKey::Outgoing(self.session_id), vec.len() as u64, Instant::now());
+                self.flush_window(id);
+                self.send_event.send(ServerEvent::TextPacketInfo(self.server_id, MyNodeType::TextServer, TextServerEvent::SendingText(vec.len() as u64, size),self.session_id)).unwrap();
                 self.session_id+=1;
             }
         }else { 
@@ -264,86 +279,146 @@ impl Server{
     fn handle_msg_fragment(&mut self, p:Packet){
         self.forward_packet(create_ack(p.clone()));
         if let PacketType::MsgFragment(fragment) = p.pack_type{
-            if self.fragments_recv.contains_key(&(p.routing_header.hops.clone()[0],p.session_id)){
-                if let Some(vec) = self.fragments_recv.get_mut(&(p.routing_header.hops[0],p.session_id)){
-                    vec.push(fragment.clone());
-                }else {
-                    println!("This else shouldn't be reached, it means that the server has no vec of fragments (received) associated to the key");
+            let key = (p.routing_header.hops[0], p.session_id);
+            let session = SessionKey::Incoming(key.0, key.1);
+            match self.sessions.state(session) {
+                Some(SessionState::Open) => self.sessions.touch(session, Instant::now()),
+                Some(_) => { //late duplicate or replay of a session already closed
+                    self.sessions.duplicate();
+                    return;
+                }
+                None => {
+                    if !self.sessions.start(session, fragment.total_n_fragments, Instant::now()){
+                        return;
+                    }
                 }
-            }else {
-                let mut vec = Vec::new();
-                vec.push(fragment.clone());
-                self.fragments_recv.insert((p.routing_header.hops.clone()[0], p.session_id), vec);
             }
-            if let Some(vec) = self.fragments_recv.get_mut(&(p.routing_header.hops[0],p.session_id)){
-                if fragment.total_n_fragments == vec.len() as u64{
-                    if let Ok(totalmsg) = WebBrowserCommands::deserialize_data(vec){
-                        match totalmsg{
-                            WebBrowserCommands::GetList => {
-                                for i in self.neigh_map.clone().node_weights(){
-                                    if i.1 == NodeType::Server && i.0!=self.server_id{
-                                        self.send_packet(TextServer::ServerTypeReq,i.0,NodeType::Server);
-                                    }
-                                }
-                                let mut total_list = Vec::new();
-                                for (_,i) in self.media_info.clone(){
-                                    for j in i{
-                                        total_list.push(j);
-                                    }
-                                }
-                                for i in self.texts_ids.clone(){
-                                    total_list.push(i);
+            let inserted = Reassembler::entry(&mut self.fragments_recv, key, fragment.total_n_fragments)
+                .and_then(|reassembler| reassembler.insert(&fragment));
+            if let Err(err) = inserted {
+                match err {
+                    FragmentError::Duplicate(_) => self.sessions.duplicate(),
+                    FragmentError::InvalidTotal(_) => self.sessions.close(session, SessionState::Failed, Instant::now()), //never started
+                    _ => {}
+                }
+                // duplicates and malformed fragments must not trigger the command a second time
+                println!("server {:?} discarded a fragment from {:?}: {}", self.server_id, key.0, err);
+                return;
+            }
+            if !self.fragments_recv[&key].is_complete(){
+                return;
+            }
+            //complete: its fragments are no longer needed, only the session state is kept
+            let result = self.fragments_recv.remove(&key).unwrap().envelope();
+            let state = if result.is_ok() { SessionState::Complete } else { SessionState::Failed };
+            self.sessions.close(session, state, Instant::now());
+            let envelope = match result {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    println!("server {:?} rejected a message from {:?}: {}", self.server_id, key.0, err);
+                    return;
+                }
+            };
+            // files go back in the most compact encoding and compression the requester understands
+            let encoding = Encoding::negotiate(&envelope.accepts);
+            let compression = Compression::negotiate(&envelope.compressions);
+            match envelope.message {
+                Message::WebBrowserCommands(totalmsg) => {
+                    match totalmsg{
+                        WebBrowserCommands::GetList => {
+                            for i in self.neigh_map.clone().node_weights(){
+                                if i.1 == NodeType::Server && i.0!=self.server_id{
+                                    self.send_packet(TextServer::ServerTypeReq,i.0,NodeType::Server);
                                 }
-                                // println!("text file list {:?}, media servers discovered {:?}",total_list.clone(), self.media_servers);
-                                self.send_packet(TextServer::SendFileList(total_list),p.routing_header.hops[0],NodeType::Client);
                             }
-                            WebBrowserCommands::GetPosition(media_id) => {
-                                for i in self.media_info.clone(){
-                                    if i.1.contains(&media_id){
-                                        // println!("il media si trova qui {:?}", i.0);
-                                        self.send_packet(TextServer::PositionMedia(i.0),p.routing_header.hops[0],NodeType::Client);
-                                    }                                 
+                            let mut total_list = Vec::new();
+                            for (_,i) in self.media_info.clone(){
+                                for (j,_) in i{
+                                    total_list.push(j);
                                 }
                             }
-                            WebBrowserCommands::GetMedia(_) => {println!("I shouldn't receive this command");}
-                            WebBrowserCommands::GetText(text_id) => {
-                                if self.paths.contains_key(&text_id){
-                                    let path = self.paths.get(&text_id).unwrap().clone();
-                                    self.send_text(path.as_str(),p.routing_header.hops[0],NodeType::Client);
-                                }
+                            for i in self.texts_ids.clone(){
+                                total_list.push(i);
                             }
-                            WebBrowserCommands::GetServerType => {
-                                self.send_packet(TextServer::ServerTypeText(self.clone().server_type), p.routing_header.hops[0], NodeType::Client);
+                            // println!("text file list {:?}, media servers discovered {:?}",total_list.clone(), self.media_servers);
+                            self.send_packet(TextServer::SendFileList(total_list),p.routing_header.hops[0],NodeType::Client);
+                        }
+                        WebBrowserCommands::GetPosition(media_id) => {
+                            for i in self.media_info.clone(){
+                                if i.1.iter().any(|(id,_)| id == &media_id){
+                                    // println!("il media si trova qui {:?}", i.0);
+                                    self.send_packet(TextServer::PositionMedia(media_id.clone(), i.0),p.routing_header.hops[0],NodeType::Client);
+                                }                                 
                             }
                         }
-                    }else {
-                        if let Ok(totalmsg) = ChatResponse::deserialize_data(vec) {
-                            match totalmsg {
-                                ChatResponse::ServerTypeChat(_) => {
-                                    //println!("sono il text {:?} e ho scoperto che {:?} è un chat per sicurezza {:?}",self.server_id,p.routing_header.hops[0], st)
+                        WebBrowserCommands::GetMedia(_) => {println!("I shouldn't receive this command");}
+                        WebBrowserCommands::GetText(text_id) => {
+                            if self.paths.contains_key(&text_id){
+                                let path = self.paths.get(&text_id).unwrap().clone();
+                                self.send_text(path.as_str(),p.routing_header.hops[0],NodeType::Client,encoding,compression,None);
+                            }
+                        }
+                        WebBrowserCommands::GetIfModified(text_id, validator) => {
+                            if let Some(path) = self.paths.get(&text_id).cloned(){
+                                if fs::read(Path::new(&path)).ok().map(|bytes| Validator::of(&bytes)) == Some(validator){
+                                    self.send_packet(TextServer::NotModified(text_id),p.routing_header.hops[0],NodeType::Client);
+                                }else {
+                                    self.send_text(path.as_str(),p.routing_header.hops[0],NodeType::Client,encoding,compression,None);
                                 }
-                                _ => { println!("I shouldn't receive these commands"); }
                             }
-                        }else {
-                            if let Ok(totalmsg) = MediaServer::deserialize_data(vec){
-                                match totalmsg {
-                                    MediaServer::ServerTypeMedia(_) => {
-                                        //println!("sono il text {:?} e ho scoperto che {:?} è un media",self.server_id,p.routing_header.hops[0]);
-                                        if !self.media_servers.contains(&p.routing_header.hops[0]){ 
-                                            self.media_servers.push(p.routing_header.hops[0]); 
-                                        }
-                                        self.send_packet(TextServer::PathResolution,p.routing_header.hops[0],NodeType::Server);
-                                    }
-                                    MediaServer::SendPath(path) => {
-                                        //println!("sono il text {:?} e ho ricevuto la path res di {:?}",self.server_id,p.routing_header.hops[0]);
-                                        self.media_info.insert(p.routing_header.hops[0], path);
+                        }
+                        WebBrowserCommands::GetRange(text_id, range) => {
+                            if let Some(path) = self.paths.get(&text_id).cloned(){
+                                self.send_text(path.as_str(),p.routing_header.hops[0],NodeType::Client,encoding,compression,Some(range));
+                            }
+                        }
+                        WebBrowserCommands::Search(query) => {
+                            //only the texts are read, the medias of the media servers are known by name
+                            let mut hits = Vec::new();
+                            for text_id in self.texts_ids.clone(){
+                                let bytes = self.paths.get(&text_id).and_then(|path| fs::read(Path::new(path)).ok());
+                                hits.extend(query.search(&text_id, bytes.as_deref()));
+                            }
+                            for (_,medias) in &self.media_info{
+                                for (media_id,size) in medias{
+                                    if hits.iter().any(|hit| &hit.name == media_id){
+                                        continue; //held by more than one media server, reported once
                                     }
-                                    MediaServer::SendMedia(_) => {println!("I shouldn't receive this command");}
+                                    hits.extend(query.search(media_id, None).map(|hit| SearchHit{ size: Some(*size), ..hit }));
                                 }
                             }
+                            self.send_packet(TextServer::SearchResults(hits),p.routing_header.hops[0],NodeType::Client);
+                        }
+                        WebBrowserCommands::GetServerType => {
+                            self.send_packet(TextServer::ServerTypeText(self.clone().server_type), p.routing_header.hops[0], NodeType::Client);
+                        }
+                    }
+                }
+                Message::ChatResponse(totalmsg) => {
+                    match totalmsg {
+                        ChatResponse::ServerTypeChat(_) => {
+                            //println!("sono il text {:?} e ho scoperto che {:?} è un chat per sicurezza {:?}",self.server_id,p.routing_header.hops[0], st)
+                        }
+                        _ => { println!("I shouldn't receive these commands"); }
+                    }
+                }
+                Message::MediaServer(totalmsg) => {
+                    match totalmsg {
+                        MediaServer::ServerTypeMedia(_) => {
+                            //println!("sono il text {:?} e ho scoperto che {:?} è un media",self.server_id,p.routing_header.hops[0]);
+                            if !self.media_servers.contains(&p.routing_header.hops[0]){ 
+                                self.media_servers.push(p.routing_header.hops[0]); 
+                            }
+                            self.send_packet(TextServer::PathResolution,p.routing_header.hops[0],NodeType::Server);
+                        }
+                        MediaServer::SendPath(path) => {
+                            //println!("sono il text {:?} e ho ricevuto la path res di {:?}",self.server_id,p.routing_header.hops[0]);
+                            self.media_info.insert(p.routing_header.hops[0], path);
                         }
+                        MediaServer::SendMedia(_) | MediaServer::NotModified(_) => {println!("I shouldn't receive this command");}
                     }
                 }
+                other => { println!("server {:?} can't handle {} messages", self.server_id, other.kind()); }
             }
         }
     }
@@ -351,9 +426,20 @@ impl Server{
     fn handle_ack(&mut self, packet : Packet){
         let s_id=packet.session_id;
         if let PacketType::Ack(ack) = packet.pack_type{
-            self.fragments_send.get_mut(&s_id).unwrap().2.retain(|x| x.fragment_index!=ack.fragment_index);
+            if let Some(info) = self.fragments_send.get_mut(&s_id){
+                info.2.retain(|x| x.fragment_index!=ack.fragment_index);
+                let dest = info.0;
+                let done = info.2.is_empty();
+                self.flow.on_ack(dest, s_id, ack.fragment_index);
+                if done {
+                    self.fragments_send.remove(&s_id); //every fragment was acked
+                    self.sessions.close(SessionKey::Outgoing(s_id), SessionState::Complete, Instant::now());
+                }else {
+                    self.sessions.touch(SessionKey::Outgoing(s_id), Instant::now());
+                }
+                self.flush_window(dest);
+            }
         }
-        //forse da sistemare perchè c'è un unwrap, anche se in teoria gli ack che arrivano al mio server hanno un session id corretto
         for i in packet.routing_header.hops.iter().skip(1){
             let ni = self.find_node(*i, NodeType::Drone);
             match  ni{
@@ -376,7 +462,8 @@ impl Server{
     
     }
 
-    fn packet_recover(&mut self, s_id: u64, lost_fragment_index: u64){
+    //sends again one of the fragments of a session on the current best path, false if there's no route to its destination
+    fn packet_recover(&mut self, s_id: u64, lost_fragment_index: u64) -> bool{
         if self.fragments_send.contains_key(&s_id){
             let info = self.fragments_send.get(&s_id).unwrap();
             //println!("i have to recover the packet because something wrong happened, resending to {:?} {:?} the number of packet i have to send is {:?}", info.1, info.0, info.2.clone().len());
@@ -393,19 +480,70 @@ impl Server{
                         self.forward_packet(pack);
                         break;
                     }else {
-                        println!("i am the text {:?} there isn't a route to reach the packet destination (shouldn't happen)",self.server_id);
+                        println!("i am the text {:?} there isn't a route to {:?} anymore, the fragment waits for a new flood",self.server_id, info.0);
+                        return false;
                     }
                 }
             }
         }else {
             println!("This else shouldn't be reached, it means that the server has no vec of fragments (sent) associated to the key");
         }
+        true
+    }
+
+    //resends a nacked fragment, without a route it gives its slot back and waits at the head of the queue for a new flood
+    fn resend(&mut self, s_id: u64, fragment_index: u64){
+        if !self.packet_recover(s_id, fragment_index){
+            if let Some(info) = self.fragments_send.get(&s_id){
+                self.flow.requeue(info.0, s_id, fragment_index);
+            }
+            self.flooding();
+        }
     }
 
+    //sends the queued fragments the window towards dest allows, each one on the current best path;
+    //if there's no route to dest they wait for the routes a new flood finds
+    fn flush_window(&mut self, dest: NodeId){
+        if !self.send_ready(dest){
+            self.flooding();
+        }
+    }
+
+    //false if there's no route to dest: the fragments that couldn't leave go back to the head of the queue, in order
+    fn send_ready(&mut self, dest: NodeId) -> bool{
+        let ready = self.flow.ready(dest);
+        for (n, &(s_id, fragment_index)) in ready.iter().enumerate(){
+            if !self.packet_recover(s_id, fragment_index){
+                for &(s_id, fragment_index) in ready[n..].iter().rev(){
+                    self.flow.requeue(dest, s_id, fragment_index);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    //drops the buffers of the sessions that will never complete and reports them, with the duplicates discarded, to the SC
+    fn sweep_sessions(&mut self){
+        let duplicates = self.sessions.sweep_into(Instant::now(), |key, state| {
+            match key {
+                SessionKey::Incoming(src, s_id) => { self.fragments_recv.remove(&(src, s_id)); }
+                SessionKey::Outgoing(s_id) => {
+                    self.fragments_send.remove(&s_id);
+                    self.flow.forget(s_id);
+                }
+            }
+            self.send_event.send(ServerEvent::StuckSession(self.server_id, MyNodeType::TextServer, key, state)).unwrap();
+        });
+        if let Some(duplicates) = duplicates{
+            self.send_event.send(ServerEvent::Duplicates(self.server_id, MyNodeType::TextServer, duplicates)).unwrap();
+        }
+    }
 
     fn handle_nack(&mut self, packet : Packet){
         let id = packet.routing_header.hops[0];
         let s_id=packet.session_id;
+        self.sessions.touch(SessionKey::Outgoing(s_id), Instant::now());
         if let PacketType::Nack(nack) = packet.pack_type{
             match nack.clone().nack_type{
                 NackType::ErrorInRouting(crashed_id) => {
@@ -435,13 +573,16 @@ impl Server{
                         self.neigh_map.remove_edge(edge2.unwrap());
                     }
                     // println!("graph del text dopo aver tolto gli edges del drone crashato {:?}", self.neigh_map);
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::DestinationIsDrone => {
                     println!("This error shouldn't happen!");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::Dropped => {
+                    if let Some(info) = self.fragments_send.get(&s_id){
+                        self.flow.on_dropped(info.0); //a drone is struggling, slow down towards that destination
+                    }
                     let mut first = true;
                     for i in packet.routing_header.hops.iter(){
                         let ni = self.find_node(*i, NodeType::Drone);
@@ -470,11 +611,11 @@ impl Server{
                         }
                     }
                    // println!("calling packet_recover because a drone dropped");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
                 NackType::UnexpectedRecipient(_) => {
                     println!("This error shouldn't happen!");
-                    self.packet_recover(s_id, nack.fragment_index);
+                    self.resend(s_id, nack.fragment_index);
                 }
             }
         }
@@ -587,6 +728,9 @@ impl Server{
                             self.send_packet(TextServer::ServerTypeReq,i.0,NodeType::Server);
                         }
                     }
+                    for dest in self.flow.waiting(){ //the new routes may reach the destinations of the fragments that had none
+                        self.send_ready(dest);
+                    }
                 }else {
                     // println!("you received an outdated version of the flooding");
                 }
@@ -0,0 +1,54 @@
+use std::fs::{self, File};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use wg_2024::controller::DroneEvent;
+use wg_2024::packet::PacketType;
+
+/// Writes every DroneEvent seen by the SC, in the order they arrived, to a file
+/// Used with `--record` so that the recordings of two runs can be compared: the runs are not replayed,
+/// thread scheduling and the vendors' own RNG for drops still differ between them
+#[derive(Clone)]
+pub struct EventRecorder {
+    file: Arc<Mutex<(u64, LineWriter<File>)>>,
+}
+
+impl EventRecorder {
+    pub fn create(path: &str, vendor_offset: usize) -> Result<EventRecorder, String> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Couldn't create folder for recording {}: {}", path, e))?;
+        }
+        let file = File::create(path)
+            .map_err(|e| format!("Couldn't create recording {}: {}", path, e))?;
+        let mut writer = LineWriter::new(file);
+        writeln!(writer, "# vendor offset {}", vendor_offset)
+            .map_err(|e| format!("Couldn't write recording {}: {}", path, e))?;
+
+        Ok(EventRecorder { file: Arc::new(Mutex::new((0, writer))) })
+    }
+
+    pub fn record(&self, event: &DroneEvent) -> Result<(), String> {
+        let (kind, packet) = match event {
+            DroneEvent::PacketSent(packet) => ("sent", packet),
+            DroneEvent::PacketDropped(packet) => ("dropped", packet),
+            DroneEvent::ControllerShortcut(packet) => ("shortcut", packet),
+        };
+        let pack_type = match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => format!("fragment {}/{}", fragment.fragment_index, fragment.total_n_fragments),
+            PacketType::Ack(ack) => format!("ack {}", ack.fragment_index),
+            PacketType::Nack(nack) => format!("nack {} {:?}", nack.fragment_index, nack.nack_type),
+            PacketType::FloodRequest(flood) => format!("flood_request {} from {}", flood.flood_id, flood.initiator_id),
+            PacketType::FloodResponse(flood) => format!("flood_response {}", flood.flood_id),
+        };
+
+        let mut guard = self.file.lock()
+            .map_err(|_| "Recording lost, a thread panicked while writing it".to_string())?;
+        let (counter, writer) = &mut *guard;
+        *counter += 1;
+        writeln!(writer, "{} {} session {} hops {:?} at {} {}",
+                 counter, kind, packet.session_id, packet.routing_header.hops,
+                 packet.routing_header.hop_index, pack_type)
+            .map_err(|e| format!("Failed to record drone event: {}", e))
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{self, BufRead, Write};
+use std::thread;
+use crossbeam_channel::{select_biased, unbounded, Receiver};
+use wg_2024::network::NodeId;
+use crate::common_data::common::{ChatClientEvent, SearchQuery, WebBrowserEvents};
+use crate::gui::shared_info_plugin::SHARED_STATE;
+use crate::network_initializer::network_initializer::{parse_topology, start_headless, vendor_offset_from_args, ServerFamily};
+use crate::simulation_control::simulation_control::{EventTap, SimulationController};
+
+/// Entry point used when the binary is launched with `--headless`
+/// Boots the network without any window and reads commands line by line from stdin,
+/// so scenarios can be scripted (e.g. `cargo run -- --headless < scenario.txt`)
+pub fn main() {
+    let setup = parse_topology()
+        .and_then(|(config, roles)| ServerFamily::from_args().map(|family| (config, roles, family)))
+        .and_then(|(config, roles, family)| vendor_offset_from_args().map(|offset| (config, roles, family, offset)));
+    let (config, roles, family, vendor_offset) = match setup {
+        Ok(setup) => setup,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Print what the clients report, the GUI would show it in its windows
+    let (chat_send, chat_events) = unbounded();
+    let (web_send, web_events) = unbounded();
+    thread::spawn(move || print_events(chat_events, web_events));
+    let event_tap = EventTap { chat: chat_send, web: web_send };
+
+    let mut sim = match start_headless(config, &roles, family, vendor_offset, Some(event_tap)) {
+        Ok(sim) => sim,
+        Err(err) => {
+            eprintln!("Topology rejected: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Same as the GUI does when entering AppState::InGame
+    sim.initiate_flood();
+    sim.initiate_flood();
+
+    println!("Headless simulation started, type 'help' for the list of commands");
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else { break };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match execute(&mut sim, line) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(err) => eprintln!("{}", err),
+        }
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Runs a single command against the controller
+/// Returns Ok(false) when the runner should stop
+pub fn execute(sim: &mut SimulationController, line: &str) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+
+    match command {
+        "help" => {
+            println!("flood");
+            println!("crash <drone>");
+            println!("pdr <drone> <value>");
+            println!("add_sender <node> <node>");
+            println!("remove_sender <node> <node>");
+            println!("chat_servers <client>");
+            println!("register <client> <server>");
+            println!("unregister <client> <server>");
+            println!("send <client> <destination> <server> <message...>");
+            println!("read <client> <sender> <server>");
+            println!("history <client> <peer> <server> [since]");
+            println!("room_create <client> <server> <room>");
+            println!("room_join <client> <server> <room>");
+            println!("room_leave <client> <server> <room>");
+            println!("room_members <client> <server> <room>");
+            println!("room_send <client> <server> <room> <message...>");
+            println!("web_servers <browser>");
+            println!("list <browser> <text_server>");
+            println!("text <browser> <text_server> <file>");
+            println!("position <browser> <text_server> <media>");
+            println!("media <browser> <media_server> <media>");
+            println!("fetch <browser> <file>");
+            println!("search <browser> <text_server> <query...>");
+            println!("wait <milliseconds>");
+            println!("status");
+            println!("quit");
+        },
+        "flood" => sim.initiate_flood(),
+        "crash" => sim.crash(node_arg(&args, 0)?),
+        "pdr" => {
+            let pdr = args.get(1)
+                .ok_or("missing pdr value")?
+                .parse::<f32>()
+                .map_err(|e| format!("invalid pdr: {}", e))?;
+            sim.pdr(node_arg(&args, 0)?, pdr);
+        },
+        "add_sender" => sim.add_sender(node_arg(&args, 0)?, node_arg(&args, 1)?),
+        "remove_sender" => sim.remove_sender(node_arg(&args, 0)?, node_arg(&args, 1)?),
+        "chat_servers" => sim.get_chat_servers(node_arg(&args, 0)?),
+        "register" => {
+            let client = chat_client_arg(sim, &args, 0)?;
+            sim.register_client(client, node_arg(&args, 1)?);
+        },
+        "unregister" => {
+            let client = chat_client_arg(sim, &args, 0)?;
+            sim.unregister_client(client, node_arg(&args, 1)?);
+        },
+        "send" => {
+            let client = chat_client_arg(sim, &args, 0)?;
+            let destination = node_arg(&args, 1)?;
+            let server = node_arg(&args, 2)?;
+            if args.len() < 4 {
+                return Err("missing message".to_string());
+            }
+            sim.send_message(args[3..].join(" "), client, destination, server);
+        },
+        "read" => {
+            let client = chat_client_arg(sim, &args, 0)?;
+            sim.read_messages(client, node_arg(&args, 1)?, node_arg(&args, 2)?);
+        },
+        "history" => {
+            let client = chat_client_arg(sim, &args, 0)?;
+            let since = match args.get(3) {
+                Some(since) => since.parse::<u64>().map_err(|e| format!("invalid index: {}", e))?,
+                None => 0,
+            };
+            sim.get_history(client, node_arg(&args, 1)?, node_arg(&args, 2)?, since);
+        },
+        "room_create" => sim.create_room(chat_client_arg(sim, &args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "room_join" => sim.join_room(chat_client_arg(sim, &args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "room_leave" => sim.leave_room(chat_client_arg(sim, &args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "room_members" => sim.room_members(chat_client_arg(sim, &args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "room_send" => {
+            let client = chat_client_arg(sim, &args, 0)?;
+            let server = node_arg(&args, 1)?;
+            let room = string_arg(&args, 2)?;
+            if args.len() < 4 {
+                return Err("missing message".to_string());
+            }
+            sim.send_room_message(args[3..].join(" "), client, server, room);
+        },
+        "web_servers" => sim.get_web_servers(node_arg(&args, 0)?),
+        "list" => sim.get_media_list(node_arg(&args, 0)?, node_arg(&args, 1)?),
+        "text" => sim.get_text_file(node_arg(&args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "position" => sim.get_media_position(node_arg(&args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "media" => sim.get_media_from(node_arg(&args, 0)?, node_arg(&args, 1)?, string_arg(&args, 2)?),
+        "fetch" => sim.fetch(node_arg(&args, 0)?, string_arg(&args, 1)?),
+        "search" => {
+            let browser = node_arg(&args, 0)?;
+            let text_server = node_arg(&args, 1)?;
+            sim.search(browser, text_server, SearchQuery::parse(&args[2.min(args.len())..].join(" ")));
+        },
+        "wait" => {
+            let ms = args.first()
+                .ok_or("missing duration")?
+                .parse::<u64>()
+                .map_err(|e| format!("invalid duration: {}", e))?;
+            std::thread::sleep(std::time::Duration::from_millis(ms));
+        },
+        "status" => print_status(),
+        "quit" | "exit" => return Ok(false),
+        other => return Err(format!("Unknown command '{}', type 'help'", other)),
+    }
+    Ok(true)
+}
+
+fn node_arg(args: &[&str], i: usize) -> Result<NodeId, String> {
+    let arg = args.get(i).ok_or(format!("missing argument {}", i + 1))?;
+    arg.parse::<NodeId>().map_err(|e| format!("invalid node id '{}': {}", arg, e))
+}
+
+fn string_arg(args: &[&str], i: usize) -> Result<String, String> {
+    args.get(i).map(|s| s.to_string()).ok_or(format!("missing argument {}", i + 1))
+}
+
+// register_client and send_message unwrap on unknown ids, check beforehand
+fn chat_client_arg(sim: &SimulationController, args: &[&str], i: usize) -> Result<NodeId, String> {
+    let id = node_arg(args, i)?;
+    if sim.client.contains_key(&id) {
+        Ok(id)
+    } else {
+        Err(format!("{} is not a chat client", id))
+    }
+}
+
+/// Prints the client events that carry results, skipping the per-packet noise
+fn print_events(chat_events: Receiver<ChatClientEvent>, web_events: Receiver<WebBrowserEvents>) {
+    loop {
+        select_biased! {
+            recv(chat_events) -> event => match event {
+                Ok(ChatClientEvent::InfoRequest(..)) | Ok(ChatClientEvent::Graph(..)) => {},
+                Ok(event) => println!("[chat] {:?}", event),
+                Err(_) => break,
+            },
+            recv(web_events) -> event => match event {
+                Ok(WebBrowserEvents::InfoRequest(..)) | Ok(WebBrowserEvents::Graph(..)) => {},
+                Ok(event) => println!("[web] {:?}", event),
+                Err(_) => break,
+            },
+        }
+    }
+}
+
+/// Dumps what the controller collected so far in SHARED_STATE
+fn print_status() {
+    if let Ok(state) = SHARED_STATE.read() {
+        println!("chat servers: {:?}", state.chat_servers);
+        println!("registered clients: {:?}", state.registered_clients);
+        println!("client lists: {:?}", state.client_list);
+        println!("messages: {:?}", state.responses);
+        println!("message status: {:?}", state.message_status);
+        println!("history: {:?}", state.history);
+        println!("rooms: {:?}", state.rooms);
+        println!("room messages: {:?}", state.room_messages);
+        println!("text servers: {:?}", state.text_servers);
+        println!("media servers: {:?}", state.media_servers);
+        println!("files: {:?}", state.client_medias);
+        println!("media locations: {:?}", state.target_media_server);
+        println!("saved texts: {:?}", state.actual_file_path);
+        println!("saved medias: {:?}", state.actual_media_path);
+    }
+}
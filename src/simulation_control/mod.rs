@@ -1 +1,3 @@
-pub mod simulation_control;
\ No newline at end of file
+pub mod simulation_control;
+pub mod headless;
+pub mod event_recorder;
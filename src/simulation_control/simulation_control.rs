@@ -1,4 +1,5 @@
 use crate::common_data::common::ServerEvent;
+use crate::simulation_control::event_recorder::EventRecorder;
 use crossbeam_channel::{select_biased, unbounded, Receiver, Sender};
 use std::collections::{HashMap, HashSet};
 use bevy::prelude::Resource;
@@ -11,7 +12,8 @@ use petgraph::graphmap::UnGraphMap;
 use wg_2024::packet::PacketType::{FloodRequest, MsgFragment};
 use crate::gui::login_window::{NodeType, SHARED_LOG};
 use crate::gui::shared_info_plugin::SHARED_STATE;
-use crate::common_data::common::{BackGroundFlood, ChatClientEvent, ChatServerEvent, ClientType, CommandChat, ContentCommands, ContentRequest, MediaServerEvent, RequestEvent, ServerCommands, TextServerEvent, WebBrowserEvents};
+use crate::common_data::common::{BackGroundFlood, ChatClientEvent, ChatServerEvent, ClientType, CommandChat, ContentCommands, MessageStatus, ContentRequest, HistoryEntry, MediaServerEvent, PayloadSize, RequestEvent, SearchHit, SearchQuery, ServerCommands, SessionKey, SessionState, TextServerEvent, WebBrowserEvents};
+use crate::common_data::hypertext::Page;
 
 
 #[derive(Clone,Resource)]
@@ -32,7 +34,18 @@ pub struct SimulationController {
     pub background_flooding: HashMap<NodeId, Sender<BackGroundFlood>>,
     pub chat_active: bool,
     pub web_active: bool,
-    pub rustafarian_ids: Vec<NodeId>
+    pub rustafarian_ids: Vec<NodeId>,
+    pub vendor_offset: usize, //shifts the round-robin of the drone vendors
+    pub recorder: Option<EventRecorder>,
+    pub event_tap: Option<EventTap>
+}
+
+/// Receives a copy of every event coming from the clients,
+/// lets the headless runner and the tests follow the simulation without the GUI
+#[derive(Clone)]
+pub struct EventTap {
+    pub chat: Sender<ChatClientEvent>,
+    pub web: Sender<WebBrowserEvents>,
 }
 
 
@@ -59,7 +72,10 @@ impl Default for SimulationController{
             background_flooding: HashMap::new(),
             chat_active: true,
             web_active: true,
-            rustafarian_ids: Vec::new()
+            rustafarian_ids: Vec::new(),
+            vendor_offset: 0,
+            recorder: None,
+            event_tap: None
         }
     }
 }
@@ -76,11 +92,17 @@ impl SimulationController {
             select_biased! {
                 recv(if self.chat_active { &self.chat_event } else { &no_chat_client }) -> event => {
                     if let Ok(chat_event) = event {
+                        if let Some(tap) = &self.event_tap {
+                            let _ = tap.chat.send(chat_event.clone());
+                        }
                         self.handle_chat_event(chat_event);
                     }
                 }
                 recv(if self.web_active { &self.web_event } else { &no_web_browser }) -> event => {
                     if let Ok(web_event) = event {
+                        if let Some(tap) = &self.event_tap {
+                            let _ = tap.web.send(web_event.clone());
+                        }
                         self.handle_web_event(web_event);
                     }
                 }
@@ -91,6 +113,10 @@ impl SimulationController {
                 }
                 recv(self.node_event_recv) -> command => {
                     if let Ok(drone_event) = command {
+                        if let Some(Err(err)) = self.recorder.as_ref().map(|recorder| recorder.record(&drone_event)) {
+                            println!("{}, the rest of the run is not recorded", err);
+                            self.recorder = None; //a recording with holes can't be compared
+                        }
                         self.handle_drone_event(drone_event, &mut flood_req_hash);
                     }
                 }
@@ -100,7 +126,7 @@ impl SimulationController {
 
     fn handle_chat_event(&mut self, chat_event: ChatClientEvent) {
         match chat_event {
-            ChatClientEvent::IncomingMessage((id_client, id_server, id_from), message) => {
+            ChatClientEvent::IncomingMessage((id_client, id_server, id_from), message, _) => {
                 self.handle_incoming_message((id_client, id_server, id_from), message);
             }
             ChatClientEvent::ClientList((id_client, id_server), registered_clients) => {
@@ -121,6 +147,126 @@ impl SimulationController {
             ChatClientEvent::InfoRequest(client, request_type, session) => {
                 self.handle_chat_info_request(client, request_type, session);
             }
+            ChatClientEvent::DeliveryFailed(client, destination, session) => {
+                self.handle_request_failed(client, destination, session);
+            }
+            ChatClientEvent::StuckSession(client, key, state) => {
+                self.handle_stuck_session("Client".to_string(), client, key, state);
+            }
+            ChatClientEvent::Duplicates(client, duplicates) => {
+                self.handle_duplicates(client, duplicates);
+            }
+            ChatClientEvent::MessageStatus(ids, message_id, status) => {
+                self.handle_message_status(ids, message_id, status);
+            }
+            ChatClientEvent::Room((id_client, id_server), room, result) => {
+                self.handle_room_outcome((id_client, id_server), room, result);
+            }
+            ChatClientEvent::RoomMembers((id_client, id_server), room, members) => {
+                self.handle_room_members((id_client, id_server), room, members);
+            }
+            ChatClientEvent::IncomingRoomMessage((id_client, id_server, id_from), room, message) => {
+                self.handle_room_message((id_client, id_server, id_from), room, message);
+            }
+            ChatClientEvent::History(ids, result) => {
+                self.handle_history(ids, result);
+            }
+            ChatClientEvent::Unregistered(ids) => {
+                self.handle_unregistered(ids);
+            }
+            ChatClientEvent::Presence(ids, client, joined) => {
+                self.handle_presence(ids, client, joined);
+            }
+        }
+    }
+
+    fn handle_unregistered(&self, ids: (NodeId, NodeId)) {
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.registered_clients.insert(ids, false);
+            state.client_list.remove(&ids);
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_presence(&self, ids: (NodeId, NodeId), client: NodeId, joined: bool) {
+        if let Ok(mut state) = SHARED_STATE.write() {
+            let clients = state.client_list.entry(ids).or_default();
+            if !joined {
+                clients.retain(|id| *id != client);
+            } else if !clients.contains(&client) {
+                clients.push(client);
+            }
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_history(&self, ids: (NodeId, NodeId, NodeId), result: Result<Vec<HistoryEntry>, String>) {
+        let (id_client, id_server, id_peer) = ids;
+        let entries = match result {
+            Ok(entries) => entries,
+            Err(err) => {
+                println!("Client {} couldn't get its history from server {}: {}", id_client, id_server, err);
+                return;
+            }
+        };
+        if let Ok(mut state) = SHARED_STATE.write() {
+            //the same entry may come back from overlapping requests, indexes identify it
+            let history = state.history.entry((id_server, (id_client, id_peer))).or_default();
+            for entry in entries {
+                if !history.iter().any(|known| known.index == entry.index) {
+                    history.push(entry);
+                }
+            }
+            history.sort_by_key(|entry| entry.index);
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_room_outcome(&self, ids: (NodeId, NodeId), room: String, result: Result<String, String>) {
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(err) => format!("Room {}: {}", room, err),
+        };
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.room_outcome.insert(ids, outcome);
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_room_members(&self, ids: (NodeId, NodeId), room: String, members: Vec<NodeId>) {
+        let (id_client, _) = ids;
+        if let Ok(mut state) = SHARED_STATE.write() {
+            let rooms = state.rooms.entry(ids).or_default();
+            if members.contains(&id_client) {
+                rooms.insert(room, members);
+            } else {
+                rooms.remove(&room);
+            }
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_room_message(&self, ids: (NodeId, NodeId, NodeId), room: String, message: String) {
+        let (id_client, id_server, id_from) = ids;
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.room_messages.entry((id_server, (id_client, room))).or_default().push((id_from, message));
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_message_status(&self, ids: (NodeId, NodeId, NodeId), message_id: u64, status: MessageStatus) {
+        let (id_client, id_server, id_to) = ids;
+        if let Ok(mut state) = SHARED_STATE.write() {
+            let statuses = state.message_status.entry((id_server, (id_client, id_to))).or_default();
+            if statuses.len() <= message_id as usize {
+                statuses.resize(message_id as usize + 1, MessageStatus::Sent);
+            }
+            //a Read receipt covers every message before it, receipts arriving late never move a message back
+            let first = if status == MessageStatus::Read { 0 } else { message_id as usize };
+            for current in statuses[first..=message_id as usize].iter_mut() {
+                *current = (*current).max(status);
+            }
+            state.is_updated = true;
         }
     }
 
@@ -208,12 +354,36 @@ impl SimulationController {
                     client, size
                 )
             }
+            RequestEvent::Unregister(size) => {
+                format!(
+                    "Chat Client {}: asked to be unregistered\nThe message was made of {} fragments\n",
+                    client, size
+                )
+            }
             RequestEvent::SendMessage(size) => {
                 format!(
                     "Chat Client {}: sent a text message\nThe message was made of {} fragments\n",
                     client, size
                 )
             }
+            RequestEvent::Receipt(size) => {
+                format!(
+                    "Chat Client {}: sent a delivery receipt\nThe message was made of {} fragments\n",
+                    client, size
+                )
+            }
+            RequestEvent::Room(size) => {
+                format!(
+                    "Chat Client {}: sent a room request\nThe message was made of {} fragments\n",
+                    client, size
+                )
+            }
+            RequestEvent::History(size) => {
+                format!(
+                    "Chat Client {}: asked for its chat history\nThe message was made of {} fragments\n",
+                    client, size
+                )
+            }
         };
 
         if let Ok(mut state) = SHARED_LOG.write() {
@@ -222,6 +392,41 @@ impl SimulationController {
         }
     }
 
+    fn handle_request_failed(&self, client: NodeId, destination: NodeId, session: u64) {
+        let message = format!(
+            "Client {}: gave up the request to {}\nsome fragments were never acked after every retransmission\n",
+            client, destination
+        );
+
+        if let Ok(mut state) = SHARED_LOG.write() {
+            state.msg_log.insert((client, session), message);
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_stuck_session(&self, node: String, node_id: NodeId, key: SessionKey, state: SessionState) {
+        let (session, direction) = match key {
+            SessionKey::Incoming(src, session) => (session, format!("incoming session {} from {}", session, src)),
+            SessionKey::Outgoing(session) => (session, format!("outgoing session {}", session)),
+        };
+        let message = format!(
+            "{} {}: dropped the {} ({:?})\nits fragments were freed before it could complete\n",
+            node, node_id, direction, state
+        );
+
+        if let Ok(mut state) = SHARED_LOG.write() {
+            state.msg_log.insert((node_id, session), message);
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_duplicates(&self, node_id: NodeId, duplicates: u64) {
+        if let Ok(mut state) = SHARED_LOG.write() {
+            state.duplicates.insert(node_id, duplicates);
+            state.is_updated = true;
+        }
+    }
+
     fn handle_web_event(&mut self, web_event: WebBrowserEvents) {
         match web_event {
             WebBrowserEvents::MediaServers(client, media_servers) => {
@@ -248,6 +453,63 @@ impl SimulationController {
             WebBrowserEvents::InfoRequest(client, request_type, session_id) => {
                 self.handle_web_info_request(client, request_type, session_id);
             }
+            WebBrowserEvents::RequestFailed(client, destination, session_id) => {
+                self.handle_request_failed(client, destination, session_id);
+            }
+            WebBrowserEvents::StuckSession(client, key, state) => {
+                self.handle_stuck_session("Client".to_string(), client, key, state);
+            }
+            WebBrowserEvents::Duplicates(client, duplicates) => {
+                self.handle_duplicates(client, duplicates);
+            }
+            WebBrowserEvents::FetchFailed(client, path, reason) => {
+                self.handle_fetch_failed(client, path, reason);
+            }
+            WebBrowserEvents::PageLoaded(client, page) => {
+                self.handle_page_loaded(client, page);
+            }
+            WebBrowserEvents::CacheHit(client, _, _) => {
+                self.handle_cache_lookup(client, true);
+            }
+            WebBrowserEvents::CacheMiss(client, _, _) => {
+                self.handle_cache_lookup(client, false);
+            }
+            WebBrowserEvents::SearchResults(client, server, hits) => {
+                self.handle_search_results(client, server, hits);
+            }
+        }
+    }
+
+    fn handle_search_results(&self, client: NodeId, server: NodeId, hits: Vec<SearchHit>) {
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.search_results.insert(client, (server, hits));
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_cache_lookup(&self, client: NodeId, hit: bool) {
+        if let Ok(mut state) = SHARED_LOG.write() {
+            let counters = state.cache_lookups.entry(client).or_default();
+            if hit {
+                counters.0 += 1;
+            } else {
+                counters.1 += 1;
+            }
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_page_loaded(&self, client: NodeId, page: Page) {
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.pages.insert(client, page);
+            state.is_updated = true;
+        }
+    }
+
+    fn handle_fetch_failed(&self, client: NodeId, path: String, reason: String) {
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.fetch_errors.insert(client, format!("Can't fetch {}: {}", path, reason));
+            state.is_updated = true;
         }
     }
 
@@ -356,6 +618,12 @@ impl SimulationController {
                     client, size
                 )
             }
+            ContentRequest::Search(size) => {
+                format!(
+                    "Web browser: {} asked a text server to search its files\n the message was made of {} fragments\n",
+                    client, size
+                )
+            }
         };
 
         if let Ok(mut state) = SHARED_LOG.write() {
@@ -381,6 +649,12 @@ impl SimulationController {
             ServerEvent::GraphMax(server_id, graph) => {
                 self.handle_server_graph_max(server_id, graph);
             }
+            ServerEvent::StuckSession(server_id, server_type, key, state) => {
+                self.handle_stuck_session(format!("{:?}", server_type), server_id, key, state);
+            }
+            ServerEvent::Duplicates(server_id, _, duplicates) => {
+                self.handle_duplicates(server_id, duplicates);
+            }
         }
     }
 
@@ -450,9 +724,21 @@ impl SimulationController {
                     server_type, server_id, size
                 )
             }
-            TextServerEvent::SendingText(size) => {
+            TextServerEvent::SendingText(size, payload) => {
+                format!(
+                    "{:?} {}: sent text to client\nthe message was made of {} fragments\n{}",
+                    server_type, server_id, size, describe_payload(payload)
+                )
+            }
+            TextServerEvent::SendingNotModified(size) => {
+                format!(
+                    "{:?} {}: told client its copy of the text is still valid\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            TextServerEvent::SendingSearchResults(size) => {
                 format!(
-                    "{:?} {}: sent text to client\nthe message was made of {} fragments\n",
+                    "{:?} {}: sent search results to client\nthe message was made of {} fragments\n",
                     server_type, server_id, size
                 )
             }
@@ -477,9 +763,15 @@ impl SimulationController {
                     server_type, server_id, size
                 )
             }
-            MediaServerEvent::SendingMedia(size) => {
+            MediaServerEvent::SendingMedia(size, payload) => {
+                format!(
+                    "{:?} {}: sent media to client\nthe message was made of {} fragments\n{}",
+                    server_type, server_id, size, describe_payload(payload)
+                )
+            }
+            MediaServerEvent::SendingNotModified(size) => {
                 format!(
-                    "{:?} {}: sent media to client\nthe message was made of {} fragments\n",
+                    "{:?} {}: told client its copy of the media is still valid\nthe message was made of {} fragments\n",
                     server_type, server_id, size
                 )
             }
@@ -522,6 +814,54 @@ impl SimulationController {
                     server_type, server_id, size
                 )
             }
+            ChatServerEvent::ForwardingReceipt(size) => {
+                format!(
+                    "{:?} {}: relayed a receipt to the sender of a message\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::RoomUpdate(size) => {
+                format!(
+                    "{:?} {}: sent a room update to client\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::ForwardingRoomMessage(size) => {
+                format!(
+                    "{:?} {}: forwarded a room message\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::AnnouncingClients(size) => {
+                format!(
+                    "{:?} {}: announced its registered clients to another server\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::RelayingToPeer(size) => {
+                format!(
+                    "{:?} {}: relayed a message to the chat server of its destination\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::SendingPresence(size) => {
+                format!(
+                    "{:?} {}: told a client that someone joined or left\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::AskingServerType(size) => {
+                format!(
+                    "{:?} {}: asked another server its type, looking for chat servers\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
+            ChatServerEvent::SendingHistory(size) => {
+                format!(
+                    "{:?} {}: sent the history of a conversation to client\nthe message was made of {} fragments\n",
+                    server_type, server_id, size
+                )
+            }
         };
 
         if let Ok(mut state) = SHARED_LOG.write() {
@@ -836,9 +1176,40 @@ impl SimulationController {
     pub fn send_message(&mut self, message: String, client_id: NodeId, destination_client: NodeId, chat_server: NodeId){
         self.client.get(&client_id).unwrap().send(CommandChat::SendMessage(destination_client, chat_server, message)).unwrap()
     }
+    /// The messages from sender_client were shown in the chat window of client_id, their senders get a Read receipt
+    pub fn read_messages(&self, client_id: NodeId, sender_client: NodeId, chat_server: NodeId){
+        self.client.get(&client_id).unwrap().send(CommandChat::ReadMessages(sender_client, chat_server)).unwrap()
+    }
+    /// Asks chat_server the messages between client_id and peer_client from index since on
+    pub fn get_history(&self, client_id: NodeId, peer_client: NodeId, chat_server: NodeId, since: u64){
+        self.client.get(&client_id).unwrap().send(CommandChat::GetHistory(chat_server, peer_client, since)).unwrap()
+    }
+    pub fn create_room(&self, client_id: NodeId, chat_server: NodeId, room: String){
+        self.client.get(&client_id).unwrap().send(CommandChat::CreateRoom(chat_server, room)).unwrap()
+    }
+    pub fn join_room(&self, client_id: NodeId, chat_server: NodeId, room: String){
+        self.client.get(&client_id).unwrap().send(CommandChat::JoinRoom(chat_server, room)).unwrap()
+    }
+    pub fn leave_room(&self, client_id: NodeId, chat_server: NodeId, room: String){
+        self.client.get(&client_id).unwrap().send(CommandChat::LeaveRoom(chat_server, room)).unwrap()
+    }
+    pub fn room_members(&self, client_id: NodeId, chat_server: NodeId, room: String){
+        self.client.get(&client_id).unwrap().send(CommandChat::RoomMembers(chat_server, room)).unwrap()
+    }
+    /// Posts in a room, the message is kept right away in the room history of client_id like the GUI does for direct chats
+    pub fn send_room_message(&self, message: String, client_id: NodeId, chat_server: NodeId, room: String){
+        if let Ok(mut state) = SHARED_STATE.write() {
+            state.room_messages.entry((chat_server, (client_id, room.clone()))).or_default().push((client_id, message.clone()));
+            state.is_updated = true;
+        }
+        self.client.get(&client_id).unwrap().send(CommandChat::SendRoomMessage(chat_server, room, message)).unwrap()
+    }
     pub fn register_client(&mut self, client_id: NodeId, server_id: NodeId){
         self.client.get(&client_id).unwrap().send(CommandChat::RegisterClient(server_id)).unwrap();
     }
+    pub fn unregister_client(&mut self, client_id: NodeId, server_id: NodeId){
+        self.client.get(&client_id).unwrap().send(CommandChat::Unregister(server_id)).unwrap();
+    }
 
     pub fn get_chat_servers(&self,client_id: NodeId){
         for (id,sender) in self.client.iter(){
@@ -874,6 +1245,26 @@ impl SimulationController {
             sender.send(ContentCommands::GetMedia(media_server, media_path)).unwrap();
         }
     }
+    /// Text file or media in one command, the browser finds the server holding it
+    pub fn fetch(&self, web_browser: NodeId, path: String){
+        if let Some(sender)= self.web_client.get(&web_browser){
+            if let Ok(mut state) = SHARED_STATE.write() {
+                state.fetch_errors.remove(&web_browser); //the error shown was about the previous fetch
+                state.is_updated = true;
+            }
+            sender.send(ContentCommands::Fetch(path)).unwrap();
+        }
+    }
+    /// Files of a text server matching the query, with their size and type
+    pub fn search(&self, web_browser: NodeId, text_server: NodeId, query: SearchQuery){
+        if let Some(sender)= self.web_client.get(&web_browser){
+            if let Ok(mut state) = SHARED_STATE.write() {
+                state.search_results.remove(&web_browser); //the results shown were about the previous search
+                state.is_updated = true;
+            }
+            sender.send(ContentCommands::Search(text_server, query)).unwrap();
+        }
+    }
 
 
 }
@@ -919,3 +1310,13 @@ impl AnySender {
         }
     }
 }
+
+/// Log line with the bytes of a text or media, and how much compression saved
+fn describe_payload(payload: PayloadSize) -> String {
+    if payload.compressed < payload.raw {
+        let saved = 100 - payload.compressed * 100 / payload.raw;
+        format!("{} bytes compressed to {} ({}% saved)\n", payload.raw, payload.compressed, saved)
+    } else {
+        format!("{} bytes, not compressed\n", payload.raw)
+    }
+}
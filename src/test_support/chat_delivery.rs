@@ -0,0 +1,197 @@
+use wg_2024::network::NodeId;
+use crate::common_data::common::{ChatClientEvent, MessageStatus};
+use crate::network_initializer::network_initializer::ServerFamily;
+use crate::test_support::TestNetwork;
+
+const CLIENT_A: NodeId = 10;
+const CLIENT_B: NodeId = 11;
+const SERVER: NodeId = 20;
+const OTHER_SERVER: NodeId = 21;
+
+/// Two chat clients at the ends of a line of drones, the chat server in the middle
+const CHAT_TOPOLOGY: &str = r#"
+[[drone]]
+id = 1
+connected_node_ids = [2, 10]
+pdr = 0.0
+
+[[drone]]
+id = 2
+connected_node_ids = [1, 3, 20]
+pdr = 0.0
+
+[[drone]]
+id = 3
+connected_node_ids = [2, 11]
+pdr = 0.0
+
+[[client]]
+id = 10
+connected_drone_ids = [1]
+role = "chat_client"
+
+[[client]]
+id = 11
+connected_drone_ids = [3]
+role = "chat_client"
+
+[[server]]
+id = 20
+connected_drone_ids = [2]
+role = "chat_server"
+"#;
+
+/// Same line of drones, each client next to its own chat server
+const FEDERATION_TOPOLOGY: &str = r#"
+[[drone]]
+id = 1
+connected_node_ids = [2, 10, 20]
+pdr = 0.0
+
+[[drone]]
+id = 2
+connected_node_ids = [1, 3]
+pdr = 0.0
+
+[[drone]]
+id = 3
+connected_node_ids = [2, 11, 21]
+pdr = 0.0
+
+[[client]]
+id = 10
+connected_drone_ids = [1]
+role = "chat_client"
+
+[[client]]
+id = 11
+connected_drone_ids = [3]
+role = "chat_client"
+
+[[server]]
+id = 20
+connected_drone_ids = [1]
+role = "chat_server"
+
+[[server]]
+id = 21
+connected_drone_ids = [3]
+role = "chat_server"
+"#;
+
+fn register(net: &mut TestNetwork, client: NodeId, server: NodeId) {
+    net.retry_chat("chat server discovery", |sim| sim.get_chat_servers(client), |event| match event {
+        ChatClientEvent::ChatServers(id, servers) if *id == client && servers.contains(&server) => Some(()),
+        _ => None,
+    });
+
+    let registration = net.retry_chat("registration", |sim| sim.register_client(client, server), |event| match event {
+        ChatClientEvent::RegisteredSuccess((id, id_server), result) if *id == client && *id_server == server => Some(result.clone()),
+        _ => None,
+    });
+    assert_eq!(registration, Ok(()), "client {} wasn't registered", client);
+}
+
+fn register_and_forward(family: ServerFamily) {
+    let mut net = TestNetwork::start(CHAT_TOPOLOGY, family);
+
+    for client in [CLIENT_A, CLIENT_B] {
+        register(&mut net, client, SERVER);
+    }
+
+    net.sim.send_message("hello from the other side".to_string(), CLIENT_A, CLIENT_B, SERVER);
+
+    let (from, content) = net.expect_chat("forwarded message", |event| match event {
+        ChatClientEvent::IncomingMessage((to, server, from), content, _) if *to == CLIENT_B && *server == SERVER => {
+            Some((*from, content.clone()))
+        },
+        _ => None,
+    });
+    assert_eq!(from, CLIENT_A);
+    assert_eq!(content, "hello from the other side");
+
+    // the receipts of the first message of the conversation travel back to CLIENT_A
+    for expected in [MessageStatus::Delivered, MessageStatus::Read] {
+        if expected == MessageStatus::Read {
+            net.sim.read_messages(CLIENT_B, CLIENT_A, SERVER);
+        }
+        net.expect_chat("receipt", |event| match event {
+            ChatClientEvent::MessageStatus((id, server, to), 0, status) if *id == CLIENT_A && *server == SERVER && *to == CLIENT_B && *status == expected => Some(()),
+            _ => None,
+        });
+    }
+
+    // the server kept the conversation, CLIENT_B can recover it
+    net.sim.get_history(CLIENT_B, CLIENT_A, SERVER, 0);
+    let history = net.expect_chat("history", |event| match event {
+        ChatClientEvent::History((id, server, peer), Ok(entries)) if *id == CLIENT_B && *server == SERVER && *peer == CLIENT_A => Some(entries.clone()),
+        _ => None,
+    });
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].message.content, "hello from the other side");
+
+    // a room created by CLIENT_A and joined by CLIENT_B gets its messages fanned out
+    net.sim.create_room(CLIENT_A, SERVER, "team".to_string());
+    net.expect_chat("room creation", |event| match event {
+        ChatClientEvent::Room((id, server), room, Ok(_)) if *id == CLIENT_A && *server == SERVER && room == "team" => Some(()),
+        _ => None,
+    });
+    net.sim.join_room(CLIENT_B, SERVER, "team".to_string());
+    net.expect_chat("room members", |event| match event {
+        ChatClientEvent::RoomMembers((id, server), room, members) if *id == CLIENT_A && *server == SERVER && room == "team" && members.contains(&CLIENT_B) => Some(()),
+        _ => None,
+    });
+    net.sim.send_room_message("hello team".to_string(), CLIENT_A, SERVER, "team".to_string());
+    let content = net.expect_chat("room message", |event| match event {
+        ChatClientEvent::IncomingRoomMessage((to, server, from), room, content) if *to == CLIENT_B && *server == SERVER && *from == CLIENT_A && room == "team" => Some(content.clone()),
+        _ => None,
+    });
+    assert_eq!(content, "hello team");
+
+    // CLIENT_B leaves, CLIENT_A is told right away
+    net.sim.unregister_client(CLIENT_B, SERVER);
+    net.expect_chat("presence", |event| match event {
+        ChatClientEvent::Presence((id, server), client, false) if *id == CLIENT_A && *server == SERVER && *client == CLIENT_B => Some(()),
+        _ => None,
+    });
+}
+
+fn relay_between_servers(family: ServerFamily) {
+    let mut net = TestNetwork::start(FEDERATION_TOPOLOGY, family);
+    register(&mut net, CLIENT_A, SERVER);
+    register(&mut net, CLIENT_B, OTHER_SERVER);
+
+    // SERVER relays once OTHER_SERVER announced CLIENT_B, until then the message is refused
+    let (server, origin) = net.retry_chat("relayed message", |sim| sim.send_message("hello from server 20".to_string(), CLIENT_A, CLIENT_B, SERVER), |event| match event {
+        ChatClientEvent::IncomingMessage((to, server, from), _, origin) if *to == CLIENT_B && *from == CLIENT_A => Some((*server, *origin)),
+        _ => None,
+    });
+    assert_eq!(server, OTHER_SERVER);
+    assert_eq!(origin, SERVER);
+
+    // the Delivered receipt travels back through both servers
+    net.expect_chat("relayed receipt", |event| match event {
+        ChatClientEvent::MessageStatus((id, server, to), _, MessageStatus::Delivered) if *id == CLIENT_A && *server == SERVER && *to == CLIENT_B => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn fillo_chat_servers_relay_to_each_other() {
+    relay_between_servers(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_chat_servers_relay_to_each_other() {
+    relay_between_servers(ServerFamily::Max);
+}
+
+#[test]
+fn fillo_chat_server_registers_and_forwards() {
+    register_and_forward(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_chat_server_registers_and_forwards() {
+    register_and_forward(ServerFamily::Max);
+}
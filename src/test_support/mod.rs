@@ -0,0 +1,131 @@
+//! In-process networks for the end-to-end tests
+//! Every test boots its own topology (drones, servers and clients in their threads plus the SC)
+//! and drives the clients through the SimulationController, asserting on the events they send back
+
+mod chat_delivery;
+mod web_retrieval;
+
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver};
+use wg_2024::config::Config;
+use crate::common_data::common::{ChatClientEvent, WebBrowserEvents};
+use crate::gui::login_window::SHARED_LOG;
+use crate::gui::shared_info_plugin::{ERROR_VERIFY, SHARED_STATE};
+use crate::network_initializer::network_initializer::{init_simulation, ServerFamily};
+use crate::network_initializer::node_roles::DeclaredRoles;
+use crate::simulation_control::simulation_control::{EventTap, SimulationController};
+
+/// How long a test waits for an event before failing
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a retried command waits for its answer before being sent again
+pub const RETRY_EVERY: Duration = Duration::from_secs(2);
+
+/// Only one network runs at a time: the networks share the node ids, the GUI statics and the files the SC saves
+static NETWORK: Mutex<()> = Mutex::new(());
+
+pub struct TestNetwork {
+    pub sim: SimulationController,
+    chat_events: Receiver<ChatClientEvent>,
+    web_events: Receiver<WebBrowserEvents>,
+    _running: MutexGuard<'static, ()>,
+}
+
+impl TestNetwork {
+    /// Boots the topology written in `topology` (same format as assets/configurations) and starts flooding
+    pub fn start(topology: &str, family: ServerFamily) -> TestNetwork {
+        let running = NETWORK.lock().unwrap_or_else(|poisoned| poisoned.into_inner()); //a failed test doesn't block the others
+        reset_shared_state();
+
+        let config: Config = toml::from_str(topology).expect("test topology is malformed");
+        let roles: DeclaredRoles = toml::from_str(topology).expect("test topology roles are malformed");
+
+        let (chat_send, chat_events) = unbounded();
+        let (web_send, web_events) = unbounded();
+        let mut sim = SimulationController::default();
+        sim.event_tap = Some(EventTap { chat: chat_send, web: web_send });
+
+        init_simulation(config, &roles, family, &mut sim).expect("test topology was rejected");
+        sim.initiate_flood();
+
+        TestNetwork { sim, chat_events, web_events, _running: running }
+    }
+
+    /// Waits for the first chat event accepted by `check`, panics after TIMEOUT
+    pub fn expect_chat<T>(&self, what: &str, mut check: impl FnMut(&ChatClientEvent) -> Option<T>) -> T {
+        wait_for(&self.chat_events, TIMEOUT, &mut check)
+            .unwrap_or_else(|| panic!("timed out waiting for {}", what))
+    }
+
+    /// Waits for the first web event accepted by `check`, panics after TIMEOUT
+    pub fn expect_web<T>(&self, what: &str, mut check: impl FnMut(&WebBrowserEvents) -> Option<T>) -> T {
+        wait_for(&self.web_events, TIMEOUT, &mut check)
+            .unwrap_or_else(|| panic!("timed out waiting for {}", what))
+    }
+
+    /// Sends a command until a chat event accepted by `check` comes back
+    /// Needed for the first requests, the clients only know the servers once the flooding is over
+    pub fn retry_chat<T>(
+        &mut self,
+        what: &str,
+        mut action: impl FnMut(&mut SimulationController),
+        mut check: impl FnMut(&ChatClientEvent) -> Option<T>
+    ) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            action(&mut self.sim);
+            if let Some(result) = wait_for(&self.chat_events, RETRY_EVERY, &mut check) {
+                return result;
+            }
+            self.sim.initiate_flood();
+        }
+        panic!("timed out waiting for {}", what)
+    }
+
+    /// Same as retry_chat for the web browsers
+    pub fn retry_web<T>(
+        &mut self,
+        what: &str,
+        mut action: impl FnMut(&mut SimulationController),
+        mut check: impl FnMut(&WebBrowserEvents) -> Option<T>
+    ) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            action(&mut self.sim);
+            if let Some(result) = wait_for(&self.web_events, RETRY_EVERY, &mut check) {
+                return result;
+            }
+            self.sim.initiate_flood();
+        }
+        panic!("timed out waiting for {}", what)
+    }
+}
+
+/// Forgets what the previous network left in the statics read by the GUI
+fn reset_shared_state() {
+    if let Ok(mut state) = SHARED_STATE.write() {
+        *state = Default::default();
+    }
+    if let Ok(mut log) = SHARED_LOG.write() {
+        *log = Default::default();
+    }
+    if let Ok(mut errors) = ERROR_VERIFY.write() {
+        *errors = Default::default();
+    }
+}
+
+/// Drains `events` until `check` accepts one or `timeout` expires
+fn wait_for<E, T>(events: &Receiver<E>, timeout: Duration, check: &mut impl FnMut(&E) -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        match events.recv_timeout(remaining) {
+            Ok(event) => {
+                if let Some(result) = check(&event) {
+                    return Some(result);
+                }
+            },
+            Err(_) => return None,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs;
+use wg_2024::network::NodeId;
+use crate::common_data::common::{SearchQuery, WebBrowserEvents};
+use crate::network_initializer::network_initializer::ServerFamily;
+use crate::test_support::TestNetwork;
+
+const BROWSER: NodeId = 10;
+const TEXT_SERVER: NodeId = 20;
+const MEDIA_SERVER: NodeId = 21;
+
+/// One web browser, a text server and a media server ("max" text servers hold the medias themselves)
+fn web_topology(family: ServerFamily) -> String {
+    let servers = match family {
+        ServerFamily::Fillo => r#"
+[[server]]
+id = 20
+connected_drone_ids = [2]
+role = "text_server"
+content = "assets/multimedia/paths/text_server1.txt"
+
+[[server]]
+id = 21
+connected_drone_ids = [3]
+role = "media_server"
+content = "assets/multimedia/paths/media_server1.txt"
+"#,
+        ServerFamily::Max => r#"
+[[server]]
+id = 20
+connected_drone_ids = [2]
+role = "text_server"
+content = "assets/multimedia/path_max/max_server.txt"
+
+[[server]]
+id = 21
+connected_drone_ids = [3]
+role = "text_server"
+content = "assets/multimedia/path_max/max_server2.txt"
+"#,
+    };
+
+    format!(r#"
+[[drone]]
+id = 1
+connected_node_ids = [2, 10]
+pdr = 0.0
+
+[[drone]]
+id = 2
+connected_node_ids = [1, 3, 20]
+pdr = 0.0
+
+[[drone]]
+id = 3
+connected_node_ids = [2, 21]
+pdr = 0.0
+
+[[client]]
+id = 10
+connected_drone_ids = [1]
+role = "web_browser"
+{}"#, servers)
+}
+
+/// Asks the text server for its file list until it contains a file with the wanted extension
+/// (text servers learn the medias of the media servers in the background)
+fn find_file(net: &mut TestNetwork, extension: &str) -> String {
+    net.retry_web("text server discovery", |sim| sim.get_web_servers(BROWSER), |event| match event {
+        WebBrowserEvents::TextServers(id, servers) if *id == BROWSER && servers.contains(&TEXT_SERVER) => Some(()),
+        _ => None,
+    });
+
+    net.retry_web("file list", |sim| sim.get_media_list(BROWSER, TEXT_SERVER), |event| match event {
+        WebBrowserEvents::ListFiles(id, files) if *id == BROWSER => {
+            files.iter().find(|file| file.ends_with(extension)).cloned()
+        },
+        _ => None,
+    })
+}
+
+/// Saved files are named after the requested one, wherever it was stored on the server
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn retrieve_text(family: ServerFamily) {
+    let mut net = TestNetwork::start(&web_topology(family), family);
+
+    let text = find_file(&mut net, ".txt");
+
+    net.sim.get_text_file(BROWSER, TEXT_SERVER, text.clone());
+    let path = net.expect_web("saved text file", |event| match event {
+        WebBrowserEvents::SavedTextFile(id, path) if *id == BROWSER => Some(path.clone()),
+        _ => None,
+    });
+
+    assert_eq!(file_name(&path), file_name(&text));
+    assert!(!fs::read(&path).expect("saved text file is missing").is_empty());
+}
+
+fn retrieve_media(family: ServerFamily) {
+    let mut net = TestNetwork::start(&web_topology(family), family);
+
+    let media = find_file(&mut net, ".png");
+
+    net.sim.get_media_position(BROWSER, TEXT_SERVER, media.clone());
+    let location = net.expect_web("media position", |event| match event {
+        WebBrowserEvents::MediaPosition(id, server) if *id == BROWSER => Some(*server),
+        _ => None,
+    });
+    assert!(location == TEXT_SERVER || location == MEDIA_SERVER, "media located on unknown server {}", location);
+
+    net.sim.get_media_from(BROWSER, location, media.clone());
+    let path = net.expect_web("saved media", |event| match event {
+        WebBrowserEvents::SavedMedia(id, path) if *id == BROWSER => Some(path.clone()),
+        _ => None,
+    });
+
+    assert_eq!(file_name(&path), file_name(&media));
+    assert!(!fs::read(&path).expect("saved media is missing").is_empty());
+}
+
+#[test]
+fn fillo_text_server_sends_text_files() {
+    retrieve_text(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_text_server_sends_text_files() {
+    retrieve_text(ServerFamily::Max);
+}
+
+#[test]
+fn fillo_servers_send_medias() {
+    retrieve_media(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_servers_send_medias() {
+    retrieve_media(ServerFamily::Max);
+}
+
+/// One fetch per file, the browser asks the position of the media by itself
+fn fetch_files(family: ServerFamily) {
+    let mut net = TestNetwork::start(&web_topology(family), family);
+
+    let text = find_file(&mut net, ".txt");
+    net.sim.fetch(BROWSER, text.clone());
+    let path = net.expect_web("fetched text file", |event| match event {
+        WebBrowserEvents::SavedTextFile(id, path) if *id == BROWSER => Some(path.clone()),
+        _ => None,
+    });
+    assert_eq!(file_name(&path), file_name(&text));
+
+    let media = find_file(&mut net, ".png");
+    for attempt in ["first", "cached"] { //the second fetch uses the position found by the first
+        net.sim.fetch(BROWSER, media.clone());
+        let path = net.expect_web(&format!("{} fetched media", attempt), |event| match event {
+            WebBrowserEvents::SavedMedia(id, path) if *id == BROWSER => Some(path.clone()),
+            _ => None,
+        });
+        assert_eq!(file_name(&path), file_name(&media));
+        let original = fs::read(format!("assets/multimedia/media/{}", file_name(&media))).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), original, "the pieces of the media were stitched wrong");
+    }
+}
+
+/// A file asked twice is downloaded once, the second time the saved copy is still fresh
+fn reuse_saved_copy(family: ServerFamily) {
+    let mut net = TestNetwork::start(&web_topology(family), family);
+
+    let text = find_file(&mut net, ".txt");
+    for downloaded in [true, false] {
+        net.sim.get_text_file(BROWSER, TEXT_SERVER, text.clone());
+        let hit = net.expect_web("cache lookup", |event| match event {
+            WebBrowserEvents::CacheMiss(id, server, file) if *id == BROWSER => Some((*server, file.clone(), false)),
+            WebBrowserEvents::CacheHit(id, server, file) if *id == BROWSER => Some((*server, file.clone(), true)),
+            _ => None,
+        });
+        assert_eq!(hit, (TEXT_SERVER, file_name(&text).to_string(), !downloaded));
+        net.expect_web("saved text file", |event| match event {
+            WebBrowserEvents::SavedTextFile(id, _) if *id == BROWSER => Some(()),
+            _ => None,
+        });
+    }
+}
+
+#[test]
+fn fillo_browser_reuses_saved_files() {
+    reuse_saved_copy(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_browser_reuses_saved_files() {
+    reuse_saved_copy(ServerFamily::Max);
+}
+
+#[test]
+fn fillo_servers_fetch_files() {
+    fetch_files(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_servers_fetch_files() {
+    fetch_files(ServerFamily::Max);
+}
+
+/// The document embeds two medias, held by different servers in the "max" topology
+fn load_page(family: ServerFamily) {
+    let mut net = TestNetwork::start(&web_topology(family), family);
+
+    find_file(&mut net, ".png"); //the text server knows the medias of the others
+    let document = find_file(&mut net, "solar_system.txt");
+    net.sim.fetch(BROWSER, document);
+    let page = net.expect_web("loaded page", |event| match event {
+        WebBrowserEvents::PageLoaded(id, page) if *id == BROWSER => Some(page.clone()),
+        _ => None,
+    });
+
+    assert_eq!(page.document.media(), vec!["mars.png".to_string(), "earth.png".to_string()]);
+    for media in page.document.media() {
+        let saved = page.media[&media].as_ref().expect("media of the page not fetched");
+        assert_eq!(file_name(saved), media);
+    }
+}
+
+#[test]
+fn fillo_servers_load_pages() {
+    load_page(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_servers_load_pages() {
+    load_page(ServerFamily::Max);
+}
+
+/// A phrase is found inside the text files, the other filters work on the names
+fn search_files(family: ServerFamily) {
+    let mut net = TestNetwork::start(&web_topology(family), family);
+
+    find_file(&mut net, ".png"); //the text server knows the medias of the others
+    net.sim.search(BROWSER, TEXT_SERVER, SearchQuery::parse("\"selva oscura\""));
+    let hits = net.expect_web("content search results", |event| match event {
+        WebBrowserEvents::SearchResults(id, server, hits) if *id == BROWSER && *server == TEXT_SERVER => Some(hits.clone()),
+        _ => None,
+    });
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].name, "divina_commedia.txt");
+    assert_eq!(hits[0].mime, "text/plain");
+    assert_eq!(hits[0].size, Some(fs::metadata("assets/multimedia/text_files/divina_commedia.txt").unwrap().len()));
+    assert!(hits[0].lines.iter().any(|(_, line)| line.contains("mi ritrovai per una selva oscura")));
+
+    net.sim.search(BROWSER, TEXT_SERVER, SearchQuery::parse("ext:png"));
+    let hits = net.expect_web("extension search results", |event| match event {
+        WebBrowserEvents::SearchResults(id, server, hits) if *id == BROWSER && *server == TEXT_SERVER => Some(hits.clone()),
+        _ => None,
+    });
+    assert!(!hits.is_empty());
+    assert!(hits.iter().all(|hit| hit.name.ends_with(".png") && hit.mime == "image/png"));
+}
+
+#[test]
+fn fillo_text_server_searches_files() {
+    search_files(ServerFamily::Fillo);
+}
+
+#[test]
+fn max_text_server_searches_files() {
+    search_files(ServerFamily::Max);
+}